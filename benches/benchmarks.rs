@@ -1,10 +1,9 @@
 // benches/benchmarks.rs
 
-use criterion::{BenchmarkId, Criterion, black_box, criterion_group, criterion_main};
-use tempfile::tempdir;
+use criterion::{BenchmarkId, Criterion, Throughput, black_box, criterion_group, criterion_main};
 use timefusion::{
-    database::Database,
-    persistent_queue::{IngestRecord, PersistentQueue},
+    database::{Database, parse_parquet_compression},
+    persistent_queue::OtelLogsAndSpans,
 };
 use tokio::runtime::Runtime;
 use uuid::Uuid;
@@ -25,149 +24,119 @@ fn bench_database_query(c: &mut Criterion) {
     });
 }
 
-fn bench_batch_ingestion(c: &mut Criterion) {
-    let rt = Runtime::new().unwrap();
-    let temp_dir = tempdir().unwrap();
-    // Create a persistent queue using a temporary directory.
-    let queue = PersistentQueue::new(temp_dir.path()).unwrap();
-    let batch_size = 1_000;
-    let mut records = Vec::with_capacity(batch_size);
-    for _ in 0..batch_size {
-        records.push(IngestRecord {
-            table_name: "bench_table".to_string(),
-            project_id: "bench_project".to_string(),
-            id: Uuid::new_v4().to_string(),
-            version: 1,
-            event_type: "bench_event".to_string(),
-            timestamp: "2025-03-11T12:00:00Z".to_string(),
-            trace_id: "trace".to_string(),
-            span_id: "span".to_string(),
-            parent_span_id: None,
-            trace_state: None,
-            start_time: "2025-03-11T12:00:00Z".to_string(),
-            end_time: Some("2025-03-11T12:00:01Z".to_string()),
-            duration_ns: 1_000_000_000,
-            span_name: "span_name".to_string(),
-            span_kind: "client".to_string(),
-            span_type: "bench".to_string(),
-            status: None,
-            status_code: 0,
-            status_message: "OK".to_string(),
-            severity_text: None,
-            severity_number: 0,
-            host: "localhost".to_string(),
-            url_path: "/".to_string(),
-            raw_url: "/".to_string(),
-            method: "GET".to_string(),
-            referer: "".to_string(),
-            path_params: None,
-            query_params: None,
-            request_headers: None,
-            response_headers: None,
-            request_body: None,
-            response_body: None,
-            endpoint_hash: "hash".to_string(),
-            shape_hash: "shape".to_string(),
-            format_hashes: vec!["fmt".to_string()],
-            field_hashes: vec!["field".to_string()],
-            sdk_type: "rust".to_string(),
-            service_version: None,
-            attributes: None,
-            events: None,
-            links: None,
-            resource: None,
-            instrumentation_scope: None,
-            errors: None,
-            tags: vec!["tag".to_string()],
-        });
+/// A representative verbose span: JSON attributes/resources, messages and, for errors, a stacktrace
+fn span(i: usize) -> OtelLogsAndSpans {
+    let now = chrono::Utc::now();
+    OtelLogsAndSpans {
+        project_id: "default".to_string(),
+        timestamp: now,
+        date: now.date_naive(),
+        id: Uuid::new_v4().to_string(),
+        name: Some(format!("GET /api/v1/users/{}/orders", i % 50)),
+        kind: Some("server".to_string()),
+        status_code: Some(if i % 20 == 0 { "ERROR" } else { "OK" }.to_string()),
+        level: Some("INFO".to_string()),
+        duration: Some(1_000_000 + i as u64 * 37),
+        context___trace_id: Some(Uuid::new_v4().simple().to_string()),
+        context___span_id: Some(Uuid::new_v4().simple().to_string()[..16].to_string()),
+        attributes: Some(format!(
+            r#"{{"http.request.method":"GET","http.route":"/api/v1/users/{{id}}/orders","user.id":"{}","url.query":"page={}&limit=50"}}"#,
+            i % 1000,
+            i % 10
+        )),
+        attributes___exception___stacktrace: (i % 20 == 0).then(|| "at app::handlers::orders::list (src/handlers/orders.rs:42)\n".repeat(20)),
+        resource: Some(r#"{"service.name":"orders-api","service.version":"1.4.2","telemetry.sdk.language":"rust"}"#.to_string()),
+        resource___service___name: Some("orders-api".to_string()),
+        ..Default::default()
     }
-
-    c.bench_function("batch ingestion 1k records", |b| {
-        b.iter(|| {
-            // For each record, run the async enqueue function.
-            for record in records.iter() {
-                let res = rt.block_on(queue.enqueue(record)).unwrap();
-                black_box(res);
-            }
-        })
-    });
 }
 
 fn bench_insertion_range(c: &mut Criterion) {
     let rt = Runtime::new().unwrap();
-    // Define different sizes to test. Adjust these values as needed.
-    let sizes = vec![1_000, 10_000, 100_000, 1_000_000];
+    let db = rt.block_on(Database::new()).unwrap();
     let mut group = c.benchmark_group("insertion range");
     group.sample_size(10);
 
-    for &size in sizes.iter() {
-        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
-            // Reinitialize the queue for each size measurement.
-            let temp_dir = tempdir().unwrap();
-            let queue = PersistentQueue::new(temp_dir.path()).unwrap();
+    for size in [1_000, 10_000, 100_000] {
+        let records: Vec<OtelLogsAndSpans> = (0..size).map(span).collect();
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &records, |b, records| {
+            b.iter(|| rt.block_on(db.write_many(black_box(records))).unwrap());
+        });
+    }
+    group.finish();
+}
+
+fn bench_parquet_compression(c: &mut Criterion) {
+    use deltalake::datafusion::parquet::{arrow::ArrowWriter, file::properties::WriterProperties};
+    use serde_arrow::schema::SchemaLike;
+
+    let records: Vec<OtelLogsAndSpans> = (0..10_000).map(span).collect();
+    let fields = OtelLogsAndSpans::fields().unwrap();
+    let batch = serde_arrow::to_record_batch(&fields, &records).unwrap();
+
+    let mut group = c.benchmark_group("parquet compression");
+    group.sample_size(10);
+
+    for (codec, level) in [("none", 0), ("snappy", 0), ("lz4", 0), ("gzip", 0), ("zstd", 1), ("zstd", 6), ("zstd", 19)] {
+        let props = WriterProperties::builder().set_compression(parse_parquet_compression(codec, level).unwrap()).build();
+        let write = |props: WriterProperties| {
+            let mut buffer = Vec::new();
+            let mut writer = ArrowWriter::try_new(&mut buffer, batch.schema(), Some(props)).unwrap();
+            writer.write(&batch).unwrap();
+            writer.close().unwrap();
+            buffer
+        };
 
-            // Generate a batch of `size` records.
-            let mut records = Vec::with_capacity(size);
-            for _ in 0..size {
-                records.push(IngestRecord {
-                    table_name: "bench_table".to_string(),
-                    project_id: "bench_project".to_string(),
-                    id: Uuid::new_v4().to_string(),
-                    version: 1,
-                    event_type: "bench_event".to_string(),
-                    timestamp: "2025-03-11T12:00:00Z".to_string(),
-                    trace_id: "trace".to_string(),
-                    span_id: "span".to_string(),
-                    parent_span_id: None,
-                    trace_state: None,
-                    start_time: "2025-03-11T12:00:00Z".to_string(),
-                    end_time: Some("2025-03-11T12:00:01Z".to_string()),
-                    duration_ns: 1_000_000_000,
-                    span_name: "span_name".to_string(),
-                    span_kind: "client".to_string(),
-                    span_type: "bench".to_string(),
-                    status: None,
-                    status_code: 0,
-                    status_message: "OK".to_string(),
-                    severity_text: None,
-                    severity_number: 0,
-                    host: "localhost".to_string(),
-                    url_path: "/".to_string(),
-                    raw_url: "/".to_string(),
-                    method: "GET".to_string(),
-                    referer: "".to_string(),
-                    path_params: None,
-                    query_params: None,
-                    request_headers: None,
-                    response_headers: None,
-                    request_body: None,
-                    response_body: None,
-                    endpoint_hash: "hash".to_string(),
-                    shape_hash: "shape".to_string(),
-                    format_hashes: vec!["fmt".to_string()],
-                    field_hashes: vec!["field".to_string()],
-                    sdk_type: "rust".to_string(),
-                    service_version: None,
-                    attributes: None,
-                    events: None,
-                    links: None,
-                    resource: None,
-                    instrumentation_scope: None,
-                    errors: None,
-                    tags: vec!["tag".to_string()],
-                });
-            }
+        // The file size is part of the benchmark id, so the report shows size and write time side by side
+        let label = if codec == "zstd" { format!("zstd({})", level) } else { codec.to_string() };
+        let id = BenchmarkId::new(label, format!("{} bytes", write(props.clone()).len()));
+        group.bench_function(id, |b| b.iter(|| black_box(write(props.clone()))));
+    }
+    group.finish();
+}
 
+/// Scan a multi-file table under several S3_READ_CONCURRENCY limits, against the object store configured in the
+/// environment (AWS_S3_BUCKET/AWS_S3_ENDPOINT, e.g. MinIO)
+fn bench_s3_read_concurrency(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    unsafe {
+        std::env::set_var("TIMEFUSION_TABLE_PREFIX", format!("bench-concurrency-{}", Uuid::new_v4()));
+    }
+    // One file per write, so the scan has 20 Parquet files to fetch
+    let db = rt.block_on(Database::new()).unwrap();
+    for chunk in 0..20 {
+        let records: Vec<OtelLogsAndSpans> = (chunk * 5_000..(chunk + 1) * 5_000).map(span).collect();
+        rt.block_on(db.write_many(&records)).unwrap();
+    }
+
+    let mut group = c.benchmark_group("s3 scan concurrency");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(100_000));
+    for concurrency in [1, 4, 16, 64] {
+        unsafe {
+            std::env::set_var("S3_READ_CONCURRENCY", concurrency.to_string());
+        }
+        // The limit applies to tables opened and sessions created while it is set
+        let db = rt.block_on(Database::new()).unwrap();
+        group.bench_with_input(BenchmarkId::from_parameter(concurrency), &db, |b, db| {
             b.iter(|| {
-                for record in records.iter() {
-                    let res = rt.block_on(queue.enqueue(record)).unwrap();
-                    black_box(res);
-                }
+                let df = rt.block_on(db.query("SELECT count(*), sum(duration) FROM otel_logs_and_spans WHERE project_id = 'default'")).unwrap();
+                black_box(rt.block_on(df.collect()).unwrap());
             });
         });
     }
+    unsafe {
+        std::env::remove_var("S3_READ_CONCURRENCY");
+    }
     group.finish();
 }
 
-criterion_group!(benches, bench_database_query, bench_batch_ingestion, bench_insertion_range);
+criterion_group!(
+    benches,
+    bench_database_query,
+    bench_insertion_range,
+    bench_parquet_compression,
+    bench_s3_read_concurrency
+);
 criterion_main!(benches);
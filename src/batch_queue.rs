@@ -1,104 +1,592 @@
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use crossbeam::queue::SegQueue;
 use delta_kernel::arrow::record_batch::RecordBatch;
-use tokio::sync::RwLock;
+use serde::Serialize;
+use tokio::sync::{Notify, RwLock};
+use tokio::task::JoinHandle;
 use tokio::time::interval;
-use tracing::{error, info};
+use tracing::{error, info, warn};
+
+use crate::dead_letter::{DeadLetterStore, ReplayRequest};
+
+/// Returned by `BatchQueue::queue` when the rows didn't fit under MAX_QUEUED_ROWS. None of them were queued.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueFull {
+    pub rejected: usize,
+    pub depth: usize,
+    pub limit: usize,
+}
+
+impl fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Ingest queue is full ({} of {} rows queued), {} rows rejected",
+            self.depth, self.limit, self.rejected
+        )
+    }
+}
+
+impl std::error::Error for QueueFull {}
+
+/// Window over which the enqueue and dequeue rates are averaged
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// A queued batch, when it was first queued and the number of times writing it has failed
+#[derive(Debug)]
+struct QueuedBatch {
+    batch: RecordBatch,
+    enqueued_at: Instant,
+    attempts: u32,
+}
+
+impl QueuedBatch {
+    fn new(batch: RecordBatch) -> Self {
+        Self {
+            batch,
+            enqueued_at: Instant::now(),
+            attempts: 0,
+        }
+    }
+}
+
+/// Backlog figures for `GET /queue_stats`. A growing `oldest_pending_age_secs` means flushing is falling behind.
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueStats {
+    pub pending_batches: usize,
+    pub pending_rows: usize,
+    pub pending_bytes: usize,
+    pub oldest_pending_age_secs: Option<f64>,
+    pub enqueued_rows_total: u64,
+    pub dequeued_rows_total: u64,
+    pub enqueue_rows_per_sec: f64,
+    pub dequeue_rows_per_sec: f64,
+}
+
+/// The queued batches with the bookkeeping behind backpressure and `QueueStats`
+#[derive(Debug, Default)]
+struct PendingQueue {
+    batches: SegQueue<QueuedBatch>,
+    rows: AtomicUsize,
+    /// In-memory size of the pending batches
+    bytes: AtomicUsize,
+    /// Enqueue times of the pending batches, with how many batches share each time
+    enqueued_at: Mutex<BTreeMap<Instant, usize>>,
+    enqueued_rows_total: AtomicU64,
+    dequeued_rows_total: AtomicU64,
+    /// `(time, enqueued_rows_total, dequeued_rows_total)` taken on each flush tick over the last RATE_WINDOW
+    rate_samples: Mutex<VecDeque<(Instant, u64, u64)>>,
+}
+
+impl PendingQueue {
+    /// Push a batch whose rows were already added to `rows`
+    fn push_reserved(&self, entry: QueuedBatch) {
+        *self.enqueued_at.lock().unwrap_or_else(|e| e.into_inner()).entry(entry.enqueued_at).or_insert(0) += 1;
+        self.bytes.fetch_add(entry.batch.get_array_memory_size(), Ordering::SeqCst);
+        self.batches.push(entry);
+    }
+
+    fn requeue(&self, entry: QueuedBatch) {
+        self.rows.fetch_add(entry.batch.num_rows(), Ordering::SeqCst);
+        self.push_reserved(entry);
+    }
+
+    fn pop(&self) -> Option<QueuedBatch> {
+        let entry = self.batches.pop()?;
+        self.rows.fetch_sub(entry.batch.num_rows(), Ordering::SeqCst);
+        self.bytes.fetch_sub(entry.batch.get_array_memory_size(), Ordering::SeqCst);
+        let mut enqueued_at = self.enqueued_at.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = enqueued_at.get_mut(&entry.enqueued_at) {
+            *count -= 1;
+            if *count == 0 {
+                enqueued_at.remove(&entry.enqueued_at);
+            }
+        }
+        Some(entry)
+    }
+
+    /// Count rows that left the queue for good, written or dead-lettered
+    fn completed(&self, rows: usize) {
+        self.dequeued_rows_total.fetch_add(rows as u64, Ordering::SeqCst);
+    }
+
+    fn sample(&self, now: Instant) {
+        let mut samples = self.rate_samples.lock().unwrap_or_else(|e| e.into_inner());
+        samples.push_back((
+            now,
+            self.enqueued_rows_total.load(Ordering::SeqCst),
+            self.dequeued_rows_total.load(Ordering::SeqCst),
+        ));
+        while samples.front().is_some_and(|(at, _, _)| now.saturating_duration_since(*at) > RATE_WINDOW) {
+            samples.pop_front();
+        }
+    }
+
+    fn stats(&self, now: Instant) -> QueueStats {
+        let enqueued_rows_total = self.enqueued_rows_total.load(Ordering::SeqCst);
+        let dequeued_rows_total = self.dequeued_rows_total.load(Ordering::SeqCst);
+        let samples = self.rate_samples.lock().unwrap_or_else(|e| e.into_inner());
+        let (enqueue_rows_per_sec, dequeue_rows_per_sec) = match samples.front() {
+            Some((at, enqueued, dequeued)) if now > *at => {
+                let secs = now.duration_since(*at).as_secs_f64();
+                ((enqueued_rows_total - enqueued) as f64 / secs, (dequeued_rows_total - dequeued) as f64 / secs)
+            }
+            _ => (0.0, 0.0),
+        };
+        let oldest = self.enqueued_at.lock().unwrap_or_else(|e| e.into_inner()).keys().next().copied();
+        QueueStats {
+            pending_batches: self.batches.len(),
+            pending_rows: self.rows.load(Ordering::SeqCst),
+            pending_bytes: self.bytes.load(Ordering::SeqCst),
+            oldest_pending_age_secs: oldest.map(|at| now.saturating_duration_since(at).as_secs_f64()),
+            enqueued_rows_total,
+            dequeued_rows_total,
+            enqueue_rows_per_sec,
+            dequeue_rows_per_sec,
+        }
+    }
+}
+
+/// When the flusher writes the queue (WRITE_BUFFER_MIN_ROWS, WRITE_BUFFER_MIN_BYTES, WRITE_BUFFER_MAX_DELAY_MS).
+/// Without a size threshold every tick writes up to MAX_BATCH_SIZE rows. With one, batches accumulate until either
+/// size is reached or the oldest has waited the max delay, and are then written together, giving fewer, larger
+/// Parquet files and fewer object store PUTs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct FlushThresholds {
+    pub min_rows: Option<usize>,
+    pub min_bytes: Option<usize>,
+    pub max_delay_ms: u64,
+}
+
+impl FlushThresholds {
+    pub fn from_env() -> Self {
+        let var = |name: &str| std::env::var(name).ok().and_then(|v| v.parse::<usize>().ok()).filter(|n| *n > 0);
+        Self {
+            min_rows: var("WRITE_BUFFER_MIN_ROWS"),
+            min_bytes: var("WRITE_BUFFER_MIN_BYTES"),
+            max_delay_ms: var("WRITE_BUFFER_MAX_DELAY_MS").map_or(10_000, |ms| ms as u64),
+        }
+    }
+
+    pub fn is_buffering(&self) -> bool {
+        self.min_rows.is_some() || self.min_bytes.is_some()
+    }
+
+    fn is_due(&self, stats: &QueueStats) -> bool {
+        !self.is_buffering()
+            || self.min_rows.is_some_and(|min| stats.pending_rows >= min)
+            || self.min_bytes.is_some_and(|min| stats.pending_bytes >= min)
+            || stats.oldest_pending_age_secs.is_some_and(|age| age * 1000.0 >= self.max_delay_ms as f64)
+    }
+}
 
 /// BatchQueue collects RecordBatches and processes them at intervals
 #[derive(Debug)]
 pub struct BatchQueue {
-    queue: Arc<SegQueue<RecordBatch>>,
+    queue: Arc<PendingQueue>,
+    max_queued_rows: Option<usize>,
+    flush_thresholds: FlushThresholds,
+    dead_letter: Option<Arc<DeadLetterStore>>,
     is_shutting_down: Arc<RwLock<bool>>,
+    wake_flusher: Arc<Notify>,
+    flusher: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl BatchQueue {
     pub fn new(db: Arc<crate::database::Database>, interval_ms: u64, max_rows: usize) -> Self {
-        let queue = Arc::new(SegQueue::new());
+        let queue = Arc::new(PendingQueue::default());
         let is_shutting_down = Arc::new(RwLock::new(false));
+        let dead_letter = match DeadLetterStore::from_env() {
+            Ok(store) => store.map(Arc::new),
+            Err(e) => {
+                error!("Dead-letter store unavailable, batches failing MAX_WRITE_ATTEMPTS times will be dropped: {}", e);
+                None
+            }
+        };
 
         let queue_clone = Arc::clone(&queue);
+        let dead_letter_clone = dead_letter.clone();
         let shutdown_flag = Arc::clone(&is_shutting_down);
+        let wake_flusher = Arc::new(Notify::new());
+        let woken = Arc::clone(&wake_flusher);
+        let flush_thresholds = FlushThresholds::from_env();
+        if flush_thresholds.is_buffering() {
+            info!(
+                "Buffering writes until {:?} rows or {:?} bytes are queued, or the oldest batch waited {}ms",
+                flush_thresholds.min_rows, flush_thresholds.min_bytes, flush_thresholds.max_delay_ms
+            );
+        }
 
-        tokio::spawn(async move {
+        let flusher = tokio::spawn(async move {
             let mut ticker = interval(Duration::from_millis(interval_ms));
 
             loop {
-                ticker.tick().await;
+                tokio::select! {
+                    _ = ticker.tick() => {}
+                    _ = woken.notified() => {}
+                }
+                queue_clone.sample(Instant::now());
+
+                // While ingestion is paused batches stay queued. On shutdown they go to the dead-letter store for a
+                // later replay, or are written anyway when there is none, rather than lost.
+                if db.is_ingest_paused() {
+                    if !*shutdown_flag.read().await {
+                        continue;
+                    }
+                    if let Some(store) = dead_letter_clone.as_deref() {
+                        spill_queue(store, &queue_clone, "ingestion paused at shutdown");
+                        break;
+                    }
+                    warn!(
+                        "Shutting down while ingestion is paused without a dead-letter store, writing {} queued batches",
+                        queue_clone.batches.len()
+                    );
+                }
 
                 if *shutdown_flag.read().await {
-                    process_batches(&db, &queue_clone, max_rows).await;
+                    // Drain the whole queue, stopping early only when a pass writes nothing, e.g. storage is down
+                    loop {
+                        let before = queue_clone.dequeued_rows_total.load(Ordering::SeqCst);
+                        process_batches(&db, &queue_clone, dead_letter_clone.as_deref(), max_rows).await;
+                        if queue_clone.batches.is_empty() || queue_clone.dequeued_rows_total.load(Ordering::SeqCst) == before {
+                            break;
+                        }
+                    }
                     break;
                 }
 
-                process_batches(&db, &queue_clone, max_rows).await;
+                // A due buffer is written in one pass, so each project gets a single commit
+                let stats = queue_clone.stats(Instant::now());
+                if !flush_thresholds.is_due(&stats) {
+                    continue;
+                }
+                let limit = if flush_thresholds.is_buffering() { stats.pending_rows.max(max_rows) } else { max_rows };
+                process_batches(&db, &queue_clone, dead_letter_clone.as_deref(), limit).await;
             }
         });
 
-        Self { queue, is_shutting_down }
+        Self {
+            queue,
+            max_queued_rows: Self::max_queued_rows(),
+            flush_thresholds,
+            dead_letter,
+            is_shutting_down,
+            wake_flusher,
+            flusher: Mutex::new(Some(flusher)),
+        }
+    }
+
+    /// Size and delay thresholds the flusher writes at
+    pub fn flush_thresholds(&self) -> FlushThresholds {
+        self.flush_thresholds
     }
 
-    /// Add a batch to the queue
+    /// Longest shutdown waits for queued batches to be written (SHUTDOWN_GRACE_SECS, default 30)
+    pub fn shutdown_grace() -> Duration {
+        Duration::from_secs(std::env::var("SHUTDOWN_GRACE_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(30))
+    }
+
+    /// High-water mark for rows waiting in the queue (MAX_QUEUED_ROWS, unlimited when unset or 0)
+    pub fn max_queued_rows() -> Option<usize> {
+        std::env::var("MAX_QUEUED_ROWS").ok().and_then(|v| v.parse().ok()).filter(|max| *max > 0)
+    }
+
+    /// Add a batch to the queue, see `queue_all`
     pub fn queue(&self, batch: RecordBatch) -> Result<()> {
+        self.queue_all(vec![batch])
+    }
+
+    /// Add batches to the queue, all or none of them. When they don't fit under the high-water mark nothing is
+    /// queued and a `QueueFull` error is returned, so callers can tell clients to retry the whole request later.
+    /// An empty queue takes any request, however large, so an oversized one can't be rejected forever.
+    pub fn queue_all(&self, batches: Vec<RecordBatch>) -> Result<()> {
         if let Ok(flag) = self.is_shutting_down.try_read() {
             if *flag {
                 return Err(anyhow::anyhow!("BatchQueue is shutting down"));
             }
         }
 
-        self.queue.push(batch);
+        let rows: usize = batches.iter().map(|b| b.num_rows()).sum();
+        match self.max_queued_rows {
+            // Reserve room for every row atomically so concurrent requests can't overshoot the limit together
+            Some(limit) => {
+                let reserved = self.queue.rows.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |depth| {
+                    (depth == 0 || depth + rows <= limit).then_some(depth + rows)
+                });
+                if let Err(depth) = reserved {
+                    return Err(QueueFull { rejected: rows, depth, limit }.into());
+                }
+            }
+            None => {
+                self.queue.rows.fetch_add(rows, Ordering::SeqCst);
+            }
+        }
+        self.queue.enqueued_rows_total.fetch_add(rows as u64, Ordering::SeqCst);
+        for batch in batches {
+            self.queue.push_reserved(QueuedBatch::new(batch));
+        }
         Ok(())
     }
 
-    /// Signal shutdown and wait for queue to drain
-    pub async fn shutdown(&self) {
-        let mut guard = self.is_shutting_down.write().await;
-        *guard = true;
+    /// Number of rows waiting to be flushed
+    pub fn pending_rows(&self) -> usize {
+        self.queue.rows.load(Ordering::SeqCst)
+    }
+
+    /// Number of batches waiting to be flushed
+    pub fn pending_batches(&self) -> usize {
+        self.queue.batches.len()
+    }
+
+    /// Backlog size, age of the oldest pending batch and enqueue/dequeue rates over the last minute
+    pub fn stats(&self) -> QueueStats {
+        self.queue.stats(Instant::now())
+    }
+
+    /// Store of batches that failed MAX_WRITE_ATTEMPTS times, when DEAD_LETTER_PATH is set
+    pub fn dead_letter(&self) -> Option<&DeadLetterStore> {
+        self.dead_letter.as_deref()
+    }
+
+    /// Requeue the dead-lettered batches matching `request`, after applying its fixes. Entries that can't be
+    /// transformed stay in the store; once the queue is full that entry and the rest are left for a later replay.
+    pub fn replay_dead_letters(&self, request: &ReplayRequest) -> Result<ReplaySummary> {
+        let store = self.dead_letter().ok_or_else(|| anyhow::anyhow!("Dead-letter store is not enabled, set DEAD_LETTER_PATH"))?;
+        let keys = store.matching(request)?;
+        let mut summary = ReplaySummary::default();
+
+        for (i, key) in keys.iter().enumerate() {
+            let Some((entry, batch)) = store.get(key)? else {
+                continue;
+            };
+            let batch = match request.transform(batch) {
+                Ok(batch) => batch,
+                Err(e) => {
+                    summary.skipped_entries += 1;
+                    summary.errors.push(format!("{}: {}", key, e));
+                    continue;
+                }
+            };
+            match self.queue(batch.clone()) {
+                Ok(()) => {
+                    store.remove(key)?;
+                    summary.replayed_entries += 1;
+                    summary.replayed_rows += batch.num_rows();
+                }
+                Err(e) => {
+                    let Some(full) = e.downcast_ref::<QueueFull>() else {
+                        return Err(e);
+                    };
+                    summary.skipped_entries += keys.len() - i;
+                    summary.errors.push(full.to_string());
+                    break;
+                }
+            }
+        }
+
+        info!(
+            "Replayed {} dead-lettered batches ({} rows), {} skipped",
+            summary.replayed_entries, summary.replayed_rows, summary.skipped_entries
+        );
+        Ok(summary)
+    }
+
+    /// Signal shutdown and wait up to SHUTDOWN_GRACE_SECS for the queue to drain. Returns the rows written or
+    /// dead-lettered meanwhile. While ingestion is paused the queue is dead-lettered instead of written, when
+    /// DEAD_LETTER_PATH is set.
+    pub async fn shutdown(&self) -> usize {
+        self.shutdown_within(Self::shutdown_grace()).await
+    }
+
+    /// Shut down with the given grace period. A flusher still running when it expires is aborted, and whatever is
+    /// left in the queue is moved to the dead-letter store when there is one; otherwise it is lost.
+    async fn shutdown_within(&self, grace: Duration) -> usize {
+        let before = self.queue.dequeued_rows_total.load(Ordering::SeqCst);
+        *self.is_shutting_down.write().await = true;
+        self.wake_flusher.notify_one();
+
+        let flusher = self.flusher.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some(mut flusher) = flusher {
+            if tokio::time::timeout(grace, &mut flusher).await.is_err() {
+                warn!("Queue not drained within {:?}, stopping the flusher", grace);
+                // A Delta commit lands whole or not at all, but a batch whose commit hadn't landed yet is lost
+                flusher.abort();
+                let _ = flusher.await;
+            }
+        }
+
+        if let Some(store) = self.dead_letter.as_deref().filter(|_| !self.queue.batches.is_empty()) {
+            spill_queue(store, &self.queue, "not written before shutdown");
+        }
+
+        let flushed = (self.queue.dequeued_rows_total.load(Ordering::SeqCst) - before) as usize;
+        let remaining = self.pending_rows();
+        if remaining > 0 {
+            error!("Flushed {} queued rows on shutdown, {} rows were not written", flushed, remaining);
+        } else {
+            info!("Flushed {} queued rows on shutdown", flushed);
+        }
+        flushed
     }
 }
 
+/// Outcome of `BatchQueue::replay_dead_letters`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReplaySummary {
+    pub replayed_entries: usize,
+    pub replayed_rows: usize,
+    /// Matching entries left in the store because the queue filled up or the transformation failed
+    pub skipped_entries: usize,
+    pub errors: Vec<String>,
+}
+
 /// Process batches from the queue
-async fn process_batches(db: &Arc<crate::database::Database>, queue: &Arc<SegQueue<RecordBatch>>, max_rows: usize) {
-    if queue.is_empty() {
+async fn process_batches(db: &Arc<crate::database::Database>, queue: &PendingQueue, dead_letter: Option<&DeadLetterStore>, max_rows: usize) {
+    if queue.batches.is_empty() {
         return;
     }
 
-    let mut batches = Vec::new();
+    let mut entries = Vec::new();
     let mut total_rows = 0;
 
     // Take batches up to max_rows
-    while !queue.is_empty() && total_rows < max_rows {
-        if let Some(batch) = queue.pop() {
-            total_rows += batch.num_rows();
-            batches.push(batch);
-        } else {
+    while total_rows < max_rows {
+        let Some(entry) = queue.pop() else {
             break;
-        }
+        };
+        total_rows += entry.batch.num_rows();
+        entries.push(entry);
     }
 
-    if batches.is_empty() {
+    if entries.is_empty() {
         return;
     }
 
-    // Measure and log the insertion performance
-    let start = Instant::now();
+    // Write each project's rows separately, so a failed write requeues only that project's batches and
+    // never rows another project already committed
+    let mut groups: HashMap<String, Vec<QueuedBatch>> = HashMap::new();
+    for entry in entries {
+        match crate::database::Database::group_by_project(vec![entry.batch.clone()]) {
+            Ok(split) => {
+                for (project_id, batches) in split {
+                    let project_entries = groups.entry(project_id).or_default();
+                    project_entries.extend(batches.into_iter().map(|batch| QueuedBatch {
+                        batch,
+                        enqueued_at: entry.enqueued_at,
+                        attempts: entry.attempts,
+                    }));
+                }
+            }
+            Err(e) => match dead_letter {
+                Some(store) => dead_letter_batch(store, queue, "", entry, &e),
+                None => {
+                    error!("Dropping {} queued rows that can't be routed to a project: {}", entry.batch.num_rows(), e);
+                    crate::metrics::increment_counter(crate::metrics::RECORDS_DROPPED_TOTAL, "", entry.batch.num_rows() as u64);
+                    queue.completed(entry.batch.num_rows());
+                }
+            },
+        }
+    }
 
-    // Use skip_queue=true to force direct insertion and avoid infinite loop
-    match db.insert_records_batch("", batches.clone(), true).await {
-        Ok(_) => {
-            let elapsed = start.elapsed();
-            info!(
-                batches_count = batches.len(),
-                rows_count = total_rows,
-                duration_ms = elapsed.as_millis(),
-                "Batch insert completed"
+    let max_attempts = DeadLetterStore::max_write_attempts();
+    for (project_id, project_entries) in groups {
+        let project_batches: Vec<RecordBatch> = project_entries.iter().map(|entry| entry.batch.clone()).collect();
+        let rows: usize = project_batches.iter().map(|b| b.num_rows()).sum();
+        let start = Instant::now();
+
+        // Use skip_queue=true to force direct insertion and avoid infinite loop
+        match db.insert_records_batch("", project_batches, true).await {
+            Ok(_) => {
+                queue.completed(rows);
+                // Time from enqueue to commit, including any earlier failed attempts
+                for entry in &project_entries {
+                    crate::metrics::observe_histogram(
+                        crate::metrics::QUEUE_TO_COMMIT_SECONDS,
+                        &[("project", project_id.as_str())],
+                        entry.enqueued_at.elapsed().as_secs_f64(),
+                    );
+                }
+                info!(
+                    project_id = project_id.as_str(),
+                    batches_count = project_entries.len(),
+                    rows_count = rows,
+                    duration_ms = start.elapsed().as_millis(),
+                    "Batch insert completed"
+                );
+            }
+            Err(e) => {
+                error!("Failed to insert {} rows for project '{}': {}", rows, project_id, e);
+                for mut entry in project_entries {
+                    entry.attempts += 1;
+                    match dead_letter {
+                        _ if entry.attempts < max_attempts => queue.requeue(entry),
+                        Some(store) => dead_letter_batch(store, queue, &project_id, entry, &e),
+                        // Without a dead-letter store a batch that keeps failing is dropped, or it would block the queue
+                        None => {
+                            let rows = entry.batch.num_rows();
+                            error!(
+                                "Dropping {} rows for project '{}' after {} failed attempts, set DEAD_LETTER_PATH to keep them: {}",
+                                rows, project_id, entry.attempts, e
+                            );
+                            crate::metrics::increment_counter(crate::metrics::RECORDS_DROPPED_TOTAL, &project_id, rows as u64);
+                            queue.completed(rows);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Move every queued batch to the dead-letter store, keyed by project so it can be replayed per project. Batches
+/// the store can't take stay queued.
+fn spill_queue(store: &DeadLetterStore, queue: &PendingQueue, reason: &str) {
+    let mut entries = Vec::new();
+    while let Some(entry) = queue.pop() {
+        entries.push(entry);
+    }
+    let error = anyhow::anyhow!("{}", reason);
+    for entry in entries {
+        match crate::database::Database::group_by_project(vec![entry.batch.clone()]) {
+            Ok(split) => {
+                for (project_id, batches) in split {
+                    for batch in batches {
+                        let part = QueuedBatch {
+                            batch,
+                            enqueued_at: entry.enqueued_at,
+                            attempts: entry.attempts,
+                        };
+                        dead_letter_batch(store, queue, &project_id, part, &error);
+                    }
+                }
+            }
+            Err(_) => dead_letter_batch(store, queue, "", entry, &error),
+        }
+    }
+}
+
+/// Move a batch to the dead-letter store, falling back to requeueing it if the store can't take it
+fn dead_letter_batch(store: &DeadLetterStore, queue: &PendingQueue, project_id: &str, entry: QueuedBatch, error: &anyhow::Error) {
+    match store.put(&entry.batch, project_id, entry.attempts, &error.to_string()) {
+        Ok(key) => {
+            warn!(
+                "Moved {} rows for project '{}' to the dead-letter store as {} ({} failed attempts)",
+                entry.batch.num_rows(),
+                project_id,
+                key,
+                entry.attempts
             );
+            queue.completed(entry.batch.num_rows());
         }
         Err(e) => {
-            error!("Failed to insert batches: {}", e);
+            error!("Failed to dead-letter {} rows, requeueing: {}", entry.batch.num_rows(), e);
+            queue.requeue(entry);
         }
     }
 }
@@ -110,9 +598,11 @@ mod tests {
     use crate::persistent_queue::OtelLogsAndSpans;
     use chrono::Utc;
     use serde_arrow::schema::SchemaLike;
+    use serial_test::serial;
     use std::sync::Arc;
     use tokio::time::sleep;
 
+    #[serial]
     #[tokio::test]
     async fn test_batch_queue() -> Result<()> {
         dotenv::dotenv().ok();
@@ -153,4 +643,373 @@ mod tests {
 
         Ok(())
     }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_failed_batches_are_requeued() -> Result<()> {
+        use datafusion::arrow::array::StringArray;
+        use datafusion::arrow::datatypes::{DataType, Field, Schema};
+
+        dotenv::dotenv().ok();
+        let test_prefix = format!("test-batch-{}", uuid::Uuid::new_v4());
+        unsafe {
+            std::env::set_var("TIMEFUSION_TABLE_PREFIX", &test_prefix);
+        }
+        let db = Arc::new(Database::new().await?);
+
+        // A batch missing every column but project_id can't be written
+        let schema = Arc::new(Schema::new(vec![Field::new("project_id", DataType::Utf8, false)]));
+        let batch = RecordBatch::try_new(schema, vec![Arc::new(StringArray::from(vec!["default"]))])?;
+        let queue = PendingQueue::default();
+        queue.requeue(QueuedBatch::new(batch));
+        let dead_letter = DeadLetterStore::new(sled::Config::new().temporary(true).open()?)?;
+
+        process_batches(&db, &queue, Some(&dead_letter), 10).await;
+        assert_eq!(queue.batches.len(), 1, "the failed batch should be back in the queue");
+        assert_eq!(queue.rows.load(Ordering::SeqCst), 1);
+        assert!(dead_letter.is_empty());
+
+        // Once it has failed MAX_WRITE_ATTEMPTS times it moves to the dead-letter store
+        let mut entry = queue.pop().unwrap();
+        entry.attempts = DeadLetterStore::max_write_attempts() - 1;
+        queue.requeue(entry);
+        process_batches(&db, &queue, Some(&dead_letter), 10).await;
+        assert!(queue.batches.is_empty(), "the poison batch should leave the queue");
+        assert_eq!(queue.rows.load(Ordering::SeqCst), 0);
+        let dead = dead_letter.list(10)?;
+        assert_eq!((dead.len(), dead[0].project_id.as_str(), dead[0].rows), (1, "default", 1));
+
+        // Without a dead-letter store it is dropped and counted instead
+        let mut entry = QueuedBatch::new(dead_letter.get(&dead[0].key)?.unwrap().1);
+        entry.attempts = DeadLetterStore::max_write_attempts() - 1;
+        queue.requeue(entry);
+        let dropped = crate::metrics::counter_value(crate::metrics::RECORDS_DROPPED_TOTAL, "default");
+        process_batches(&db, &queue, None, 10).await;
+        assert!(queue.batches.is_empty());
+        assert_eq!(crate::metrics::counter_value(crate::metrics::RECORDS_DROPPED_TOTAL, "default"), dropped + 1);
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_queue_to_commit_latency_is_recorded() -> Result<()> {
+        use crate::metrics::{QUEUE_TO_COMMIT_SECONDS, histogram_count};
+
+        dotenv::dotenv().ok();
+        let test_prefix = format!("test-batch-{}", uuid::Uuid::new_v4());
+        unsafe {
+            std::env::set_var("TIMEFUSION_TABLE_PREFIX", &test_prefix);
+        }
+        let db = Arc::new(Database::new().await?);
+
+        let now = Utc::now();
+        let records = vec![OtelLogsAndSpans {
+            project_id: "default".to_string(),
+            timestamp: now,
+            id: "latency".to_string(),
+            date: now.date_naive(),
+            ..Default::default()
+        }];
+        let batch = serde_arrow::to_record_batch(&OtelLogsAndSpans::fields()?, &records)?;
+        let queue = PendingQueue::default();
+        queue.requeue(QueuedBatch::new(batch));
+
+        let before = histogram_count(QUEUE_TO_COMMIT_SECONDS, &[("project", "default")]);
+        process_batches(&db, &queue, None, 10).await;
+        assert!(queue.batches.is_empty());
+        assert_eq!(histogram_count(QUEUE_TO_COMMIT_SECONDS, &[("project", "default")]), before + 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_queue_high_water_mark() -> Result<()> {
+        use datafusion::arrow::array::StringArray;
+        use datafusion::arrow::datatypes::{DataType, Field, Schema};
+
+        // No flush task, so nothing drains the queue while the test fills it
+        let batch_queue = BatchQueue {
+            queue: Arc::new(PendingQueue::default()),
+            max_queued_rows: Some(3),
+            flush_thresholds: FlushThresholds::from_env(),
+            dead_letter: None,
+            is_shutting_down: Arc::new(RwLock::new(false)),
+            wake_flusher: Arc::new(Notify::new()),
+            flusher: Mutex::new(None),
+        };
+        let schema = Arc::new(Schema::new(vec![Field::new("id", DataType::Utf8, false)]));
+        let batch = |n: usize| RecordBatch::try_new(schema.clone(), vec![Arc::new(StringArray::from(vec!["x"; n]))]).unwrap();
+
+        // A request that doesn't fit is rejected whole
+        batch_queue.queue(batch(2))?;
+        let full = batch_queue.queue_all(vec![batch(1), batch(1)]).unwrap_err().downcast::<QueueFull>()?;
+        assert_eq!(
+            full,
+            QueueFull {
+                rejected: 2,
+                depth: 2,
+                limit: 3
+            }
+        );
+        assert_eq!(batch_queue.pending_rows(), 2);
+        assert_eq!(batch_queue.pending_batches(), 1);
+
+        batch_queue.queue(batch(1))?;
+        let full = batch_queue.queue(batch(1)).unwrap_err().downcast::<QueueFull>()?;
+        assert_eq!((full.rejected, full.depth), (1, 3));
+        assert_eq!(batch_queue.pending_batches(), 2, "nothing is queued once the limit is reached");
+
+        // An empty queue takes a request larger than the limit
+        let empty = BatchQueue {
+            queue: Arc::new(PendingQueue::default()),
+            max_queued_rows: Some(3),
+            flush_thresholds: FlushThresholds::from_env(),
+            dead_letter: None,
+            is_shutting_down: Arc::new(RwLock::new(false)),
+            wake_flusher: Arc::new(Notify::new()),
+            flusher: Mutex::new(None),
+        };
+        empty.queue(batch(5))?;
+        assert_eq!(empty.pending_rows(), 5);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_aborts_stuck_flusher_and_spills_queue() -> Result<()> {
+        use datafusion::arrow::array::StringArray;
+        use datafusion::arrow::datatypes::{DataType, Field, Schema};
+
+        // A flusher stuck in a write that never completes
+        let alive = Arc::new(());
+        let held = Arc::clone(&alive);
+        let flusher = tokio::spawn(async move {
+            let _held = held;
+            futures::future::pending::<()>().await
+        });
+        let batch_queue = BatchQueue {
+            queue: Arc::new(PendingQueue::default()),
+            max_queued_rows: None,
+            flush_thresholds: FlushThresholds::from_env(),
+            dead_letter: Some(Arc::new(DeadLetterStore::new(sled::Config::new().temporary(true).open()?)?)),
+            is_shutting_down: Arc::new(RwLock::new(false)),
+            wake_flusher: Arc::new(Notify::new()),
+            flusher: Mutex::new(Some(flusher)),
+        };
+        let schema = Arc::new(Schema::new(vec![Field::new("project_id", DataType::Utf8, false)]));
+        batch_queue.queue(RecordBatch::try_new(schema, vec![Arc::new(StringArray::from(vec!["default"; 3]))])?)?;
+
+        assert_eq!(batch_queue.shutdown_within(Duration::from_millis(50)).await, 3);
+        assert_eq!(Arc::strong_count(&alive), 1, "the flusher should have been aborted");
+        assert_eq!(batch_queue.pending_rows(), 0);
+        let dead = batch_queue.dead_letter().unwrap().list(10)?;
+        assert_eq!((dead.len(), dead[0].rows, dead[0].error.as_str()), (1, 3, "not written before shutdown"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_queue_stats() {
+        use datafusion::arrow::array::StringArray;
+        use datafusion::arrow::datatypes::{DataType, Field, Schema};
+
+        let schema = Arc::new(Schema::new(vec![Field::new("id", DataType::Utf8, false)]));
+        let batch = |n: usize| RecordBatch::try_new(schema.clone(), vec![Arc::new(StringArray::from(vec!["x"; n]))]).unwrap();
+        let queue = PendingQueue::default();
+        let t0 = Instant::now();
+        queue.sample(t0);
+
+        let mut oldest = QueuedBatch::new(batch(4));
+        oldest.enqueued_at = t0;
+        queue.enqueued_rows_total.fetch_add(4, Ordering::SeqCst);
+        queue.requeue(oldest);
+        let mut newer = QueuedBatch::new(batch(6));
+        newer.enqueued_at = t0 + Duration::from_secs(5);
+        queue.enqueued_rows_total.fetch_add(6, Ordering::SeqCst);
+        queue.requeue(newer);
+
+        let stats = queue.stats(t0 + Duration::from_secs(10));
+        assert_eq!((stats.pending_batches, stats.pending_rows), (2, 10));
+        assert!(stats.pending_bytes > 0);
+        assert_eq!(stats.oldest_pending_age_secs, Some(10.0));
+        assert_eq!(stats.enqueue_rows_per_sec, 1.0);
+
+        // Flushing the oldest batch moves the age to the next one
+        let entry = queue.pop().unwrap();
+        queue.completed(entry.batch.num_rows());
+        let stats = queue.stats(t0 + Duration::from_secs(10));
+        assert_eq!(stats.oldest_pending_age_secs, Some(5.0));
+        assert_eq!((stats.dequeued_rows_total, stats.dequeue_rows_per_sec), (4, 0.4));
+
+        queue.pop();
+        assert_eq!(queue.stats(t0 + Duration::from_secs(10)).oldest_pending_age_secs, None);
+    }
+
+    #[test]
+    fn test_flush_thresholds() {
+        let stats = |pending_rows, pending_bytes, oldest_pending_age_secs| QueueStats {
+            pending_batches: 1,
+            pending_rows,
+            pending_bytes,
+            oldest_pending_age_secs,
+            enqueued_rows_total: 0,
+            dequeued_rows_total: 0,
+            enqueue_rows_per_sec: 0.0,
+            dequeue_rows_per_sec: 0.0,
+        };
+
+        // Without size thresholds every tick flushes
+        let unbuffered = FlushThresholds {
+            min_rows: None,
+            min_bytes: None,
+            max_delay_ms: 10_000,
+        };
+        assert!(unbuffered.is_due(&stats(1, 10, Some(0.0))));
+
+        let buffered = FlushThresholds {
+            min_rows: Some(1000),
+            min_bytes: Some(1 << 20),
+            max_delay_ms: 5_000,
+        };
+        assert!(!buffered.is_due(&stats(0, 0, None)));
+        assert!(!buffered.is_due(&stats(999, 1000, Some(4.9))));
+        assert!(buffered.is_due(&stats(1000, 1000, Some(0.1))), "row threshold reached");
+        assert!(buffered.is_due(&stats(10, 1 << 20, Some(0.1))), "byte threshold reached");
+        assert!(buffered.is_due(&stats(10, 1000, Some(5.0))), "oldest batch waited the max delay");
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_shutdown_flushes_queue() -> Result<()> {
+        dotenv::dotenv().ok();
+        let test_prefix = format!("test-batch-{}", uuid::Uuid::new_v4());
+        unsafe {
+            std::env::set_var("TIMEFUSION_TABLE_PREFIX", &test_prefix);
+        }
+        let db = Arc::new(Database::new().await?);
+        // Flushing every hour leaves the batches queued until shutdown, and 2 rows per pass takes several passes
+        let batch_queue = BatchQueue::new(Arc::clone(&db), 3_600_000, 2);
+
+        let now = Utc::now();
+        let records = (0..5)
+            .map(|i| OtelLogsAndSpans {
+                project_id: "default".to_string(),
+                timestamp: now,
+                id: format!("shutdown-{}-{}", test_prefix, i),
+                hashes: vec![],
+                date: now.date_naive(),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        for record in &records {
+            batch_queue.queue(serde_arrow::to_record_batch(&OtelLogsAndSpans::fields()?, std::slice::from_ref(record))?)?;
+        }
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(batch_queue.pending_rows(), 5);
+
+        assert_eq!(batch_queue.shutdown().await, 5);
+        assert_eq!(batch_queue.pending_rows(), 0);
+
+        let ctx = db.create_session_context();
+        db.setup_session_context(&ctx)?;
+        let found = ctx
+            .sql(&format!(
+                "SELECT id FROM otel_logs_and_spans WHERE project_id = 'default' AND id LIKE 'shutdown-{}-%'",
+                test_prefix
+            ))
+            .await?
+            .collect()
+            .await?;
+        assert_eq!(found.iter().map(|b| b.num_rows()).sum::<usize>(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_spill_queue_to_dead_letter() -> Result<()> {
+        use datafusion::arrow::array::StringArray;
+        use datafusion::arrow::datatypes::{DataType, Field, Schema};
+
+        let schema = Arc::new(Schema::new(vec![Field::new("project_id", DataType::Utf8, false)]));
+        let batch = RecordBatch::try_new(schema, vec![Arc::new(StringArray::from(vec!["a", "b", "a"]))])?;
+        let queue = PendingQueue::default();
+        queue.requeue(QueuedBatch::new(batch));
+        let dead_letter = DeadLetterStore::new(sled::Config::new().temporary(true).open()?)?;
+
+        spill_queue(&dead_letter, &queue, "ingestion paused at shutdown");
+        assert!(queue.batches.is_empty());
+        assert_eq!(queue.rows.load(Ordering::SeqCst), 0);
+        let mut dead = dead_letter.list(10)?.into_iter().map(|d| (d.project_id, d.rows)).collect::<Vec<_>>();
+        dead.sort();
+        assert_eq!(dead, vec![("a".to_string(), 2), ("b".to_string(), 1)]);
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_replay_dead_letters_with_fixed_timestamp() -> Result<()> {
+        use datafusion::arrow::array::TimestampMicrosecondArray;
+
+        dotenv::dotenv().ok();
+        let test_prefix = format!("test-batch-{}", uuid::Uuid::new_v4());
+        unsafe {
+            std::env::set_var("TIMEFUSION_TABLE_PREFIX", &test_prefix);
+        }
+        let db = Arc::new(Database::new().await?);
+        let mut batch_queue = BatchQueue::new(Arc::clone(&db), 50, 100);
+        batch_queue.dead_letter = Some(Arc::new(DeadLetterStore::new(sled::Config::new().temporary(true).open()?)?));
+
+        // A record whose timestamp is missing (null) can't be written to the non-nullable column
+        let now = Utc::now();
+        let record = OtelLogsAndSpans {
+            project_id: "default".to_string(),
+            timestamp: now,
+            id: format!("replayed-{}", uuid::Uuid::new_v4()),
+            hashes: vec![],
+            date: now.date_naive(),
+            ..Default::default()
+        };
+        let batch = serde_arrow::to_record_batch(&OtelLogsAndSpans::fields()?, std::slice::from_ref(&record))?;
+        let ts_idx = batch.schema().index_of("timestamp")?;
+        let mut columns = batch.columns().to_vec();
+        columns[ts_idx] = Arc::new(TimestampMicrosecondArray::from(vec![None::<i64>]));
+        let schema = Arc::new(datafusion::arrow::datatypes::Schema::new(
+            batch
+                .schema()
+                .fields()
+                .iter()
+                .map(|f| if f.name() == "timestamp" { f.as_ref().clone().with_nullable(true) } else { f.as_ref().clone() })
+                .collect::<Vec<_>>(),
+        ));
+        let bad = RecordBatch::try_new(schema, columns)?;
+        let err = db.insert_records_batch("", vec![bad.clone()], true).await.expect_err("a null timestamp must fail the write");
+        let store = batch_queue.dead_letter().unwrap();
+        store.put(&bad, "default", 5, &format!("invalid timestamp: {}", err))?;
+        store.put(&bad, "default", 5, "some other failure")?;
+
+        let summary = batch_queue.replay_dead_letters(&ReplayRequest {
+            reason_contains: Some("invalid timestamp".to_string()),
+            set_timestamp: Some(now),
+            ..Default::default()
+        })?;
+        assert_eq!((summary.replayed_entries, summary.replayed_rows, summary.skipped_entries), (1, 1, 0));
+        assert_eq!(store.len(), 1, "entries with another failure reason stay dead-lettered");
+
+        sleep(Duration::from_millis(500)).await;
+        let ctx = db.create_session_context();
+        db.setup_session_context(&ctx)?;
+        let found = ctx
+            .sql(&format!(
+                "SELECT id FROM otel_logs_and_spans WHERE project_id = 'default' AND id = '{}'",
+                record.id
+            ))
+            .await?
+            .collect()
+            .await?;
+        assert_eq!(found.iter().map(|b| b.num_rows()).sum::<usize>(), 1);
+
+        batch_queue.shutdown().await;
+        Ok(())
+    }
 }
@@ -0,0 +1,118 @@
+// conn_string.rs - Parsing of project storage connection strings into a table URI and storage options
+use std::collections::HashMap;
+
+use anyhow::{Result, anyhow};
+use url::Url;
+
+/// A validated project connection string.
+///
+/// Supported forms:
+/// - `s3://bucket/prefix?endpoint=http://minio:9000&region=eu-west-1` (also `s3a://`)
+/// - `gs://bucket/prefix?service_account=/path/key.json`
+/// - `az://container/prefix?account=name` or `abfss://container@account.dfs.core.windows.net/prefix`
+/// - `file:///path/to/table`
+///
+/// Query parameters are moved into storage options; the returned `uri` has no query string.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionString {
+    pub uri: String,
+    pub options: HashMap<String, String>,
+}
+
+impl ConnectionString {
+    pub fn parse(conn_str: &str) -> Result<Self> {
+        let url = Url::parse(conn_str.trim()).map_err(|e| anyhow!("Malformed connection string '{}': {}", conn_str, e))?;
+
+        let option_names: &[(&str, &str)] = match url.scheme() {
+            "s3" | "s3a" => &[
+                ("endpoint", "AWS_ENDPOINT"),
+                ("region", "AWS_REGION"),
+                ("access_key", "AWS_ACCESS_KEY_ID"),
+                ("secret_key", "AWS_SECRET_ACCESS_KEY"),
+                ("allow_http", "AWS_ALLOW_HTTP"),
+            ],
+            "gs" => &[("service_account", "GOOGLE_SERVICE_ACCOUNT"), ("service_account_key", "GOOGLE_SERVICE_ACCOUNT_KEY")],
+            "az" | "abfs" | "abfss" => &[
+                ("account", "AZURE_STORAGE_ACCOUNT_NAME"),
+                ("access_key", "AZURE_STORAGE_ACCOUNT_KEY"),
+                ("sas_token", "AZURE_STORAGE_SAS_KEY"),
+                ("endpoint", "AZURE_STORAGE_ENDPOINT"),
+            ],
+            "file" => &[],
+            other => return Err(anyhow!("Unsupported storage scheme '{}' in connection string", other)),
+        };
+
+        if url.scheme() != "file" && url.host_str().is_none_or(|h| h.is_empty()) {
+            return Err(anyhow!("Connection string '{}' is missing a bucket or container", conn_str));
+        }
+
+        let mut options = HashMap::new();
+        for (key, value) in url.query_pairs() {
+            let option = option_names
+                .iter()
+                .find(|(name, _)| *name == key)
+                .map(|(_, option)| *option)
+                .ok_or_else(|| anyhow!("Unknown parameter '{}' for {}:// connection strings", key, url.scheme()))?;
+            if value.is_empty() {
+                return Err(anyhow!("Parameter '{}' in connection string has no value", key));
+            }
+            if key == "endpoint" {
+                Url::parse(&value).map_err(|e| anyhow!("Invalid endpoint '{}': {}", value, e))?;
+            }
+            options.insert(option.to_string(), value.into_owned());
+        }
+
+        // abfss://container@account.dfs.core.windows.net carries the account in the host
+        if url.scheme().starts_with("abfs") && !url.username().is_empty() {
+            if let Some(account) = url.host_str().and_then(|h| h.split('.').next()) {
+                options.entry("AZURE_STORAGE_ACCOUNT_NAME".to_string()).or_insert_with(|| account.to_string());
+            }
+        }
+
+        let mut uri = url.clone();
+        uri.set_query(None);
+        Ok(Self { uri: uri.to_string(), options })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_valid_connection_strings() {
+        let s3 = ConnectionString::parse("s3://bucket/prefix/otel_logs_and_spans/?endpoint=http://localhost:9000&region=eu-west-1").unwrap();
+        assert_eq!(s3.uri, "s3://bucket/prefix/otel_logs_and_spans/");
+        assert_eq!(s3.options["AWS_ENDPOINT"], "http://localhost:9000");
+        assert_eq!(s3.options["AWS_REGION"], "eu-west-1");
+
+        let gs = ConnectionString::parse("gs://bucket/prefix?service_account=/etc/key.json").unwrap();
+        assert_eq!(gs.uri, "gs://bucket/prefix");
+        assert_eq!(gs.options["GOOGLE_SERVICE_ACCOUNT"], "/etc/key.json");
+
+        let az = ConnectionString::parse("az://container/prefix?account=acme").unwrap();
+        assert_eq!(az.options["AZURE_STORAGE_ACCOUNT_NAME"], "acme");
+
+        let abfss = ConnectionString::parse("abfss://container@acme.dfs.core.windows.net/prefix").unwrap();
+        assert_eq!(abfss.options["AZURE_STORAGE_ACCOUNT_NAME"], "acme");
+
+        let file = ConnectionString::parse("file:///tmp/timefusion/table").unwrap();
+        assert_eq!(file.uri, "file:///tmp/timefusion/table");
+        assert!(file.options.is_empty());
+    }
+
+    #[test]
+    fn test_parse_malformed_connection_strings() {
+        for conn_str in [
+            "just-a-bucket",
+            "s3:///prefix",
+            "ftp://host/path",
+            "s3://bucket/prefix?endpoint=not a url",
+            "s3://bucket/prefix?bogus=1",
+            "s3://bucket/prefix?region=",
+            "gs://bucket/prefix?endpoint=http://localhost",
+        ] {
+            assert!(ConnectionString::parse(conn_str).is_err(), "'{}' should be rejected", conn_str);
+        }
+    }
+}
@@ -1,4 +1,20 @@
+use crate::conn_string::ConnectionString;
+use crate::dedup::DedupWindow;
+use crate::dml::{DmlChange, DmlQueryPlanner};
+use crate::encryption::{ColumnCipher, master_key_from_env};
+use crate::enrichment::Enrichment;
+use crate::lease::MaintenanceLease;
+use crate::metrics::{
+    FAILED_COMMITS_TOTAL, FILES_VACUUMED_TOTAL, INGEST_ERRORS_TOTAL, INGEST_LAG_SECONDS, RECORDS_DELETED_TOTAL, RECORDS_INGESTED_TOTAL, increment_counter,
+    set_gauge,
+};
+use crate::otel_metrics::OtelMetrics;
 use crate::persistent_queue::OtelLogsAndSpans;
+use crate::pg_compat::PgTypePlanner;
+use crate::pg_errors::TimeFusionHandlers;
+use crate::project_registry::{ProjectRegistry, RegisteredProject};
+use crate::query_cache::{CacheMode, CacheStatus, QueryCache};
+use crate::query_timeout::{CancelKeys, QueryTimeoutRule, run_with_query_timeout};
 use anyhow::Result;
 use arrow_schema::SchemaRef;
 use async_trait::async_trait;
@@ -13,24 +29,46 @@ use datafusion::physical_plan::insert::{DataSink, DataSinkExec};
 use datafusion::scalar::ScalarValue;
 use datafusion::{
     catalog::Session,
+    dataframe::DataFrame,
     datasource::{TableProvider, TableType},
     error::{DataFusionError, Result as DFResult},
-    logical_expr::{BinaryExpr, dml::InsertOp},
-    physical_plan::{DisplayFormatType, ExecutionPlan, SendableRecordBatchStream},
+    logical_expr::{
+        BinaryExpr,
+        dml::{InsertOp, WriteOp},
+        expr::InList,
+    },
+    physical_plan::{DisplayFormatType, ExecutionPlan, SendableRecordBatchStream, union::UnionExec},
 };
 use datafusion_postgres::{DfSessionService, HandlerFactory};
 use delta_kernel::arrow::record_batch::RecordBatch;
+use delta_kernel::schema::StructField;
 use deltalake::checkpoints;
 use deltalake::datafusion::parquet::basic::{Compression, ZstdLevel};
 use deltalake::datafusion::parquet::file::properties::WriterProperties;
 use deltalake::operations::transaction::CommitProperties;
+use deltalake::operations::write::SchemaMode;
 use deltalake::{DeltaOps, DeltaTable, DeltaTableBuilder, storage::StorageOptions};
 use futures::StreamExt;
+use serde::Serialize;
 use std::fmt;
-use std::{any::Any, collections::HashMap, env, sync::Arc};
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    any::Any,
+    collections::{HashMap, HashSet},
+    env,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
 use tokio::sync::RwLock;
-use tokio::{net::TcpListener, time::timeout};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    time::{timeout, timeout_at},
+};
 use tokio_stream::wrappers::TcpListenerStream;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info};
@@ -38,21 +76,461 @@ use url::Url;
 
 type ProjectConfig = (String, StorageOptions, Arc<RwLock<DeltaTable>>);
 
+/// How writes handle records whose `id` already exists in the target partition (DUPLICATE_ID_POLICY)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateIdPolicy {
+    /// Append as-is, duplicates are allowed (default)
+    Allow,
+    /// Fail the whole write if any id already exists
+    Reject,
+    /// Replace existing rows with the same id, insert the rest
+    Upsert,
+}
+
+impl DuplicateIdPolicy {
+    pub fn from_env() -> Self {
+        match env::var("DUPLICATE_ID_POLICY").unwrap_or_default().to_lowercase().as_str() {
+            "reject" => DuplicateIdPolicy::Reject,
+            "upsert" => DuplicateIdPolicy::Upsert,
+            _ => DuplicateIdPolicy::Allow,
+        }
+    }
+}
+
+/// Parse a Parquet codec name (`zstd`, `snappy`, `gzip`, `lz4`, `none`) into a compression setting
+pub fn parse_parquet_compression(codec: &str, zstd_level: i32) -> Result<Compression> {
+    use deltalake::datafusion::parquet::basic::GzipLevel;
+
+    match codec.to_lowercase().as_str() {
+        "zstd" => Ok(Compression::ZSTD(ZstdLevel::try_new(zstd_level)?)),
+        "snappy" => Ok(Compression::SNAPPY),
+        "gzip" => Ok(Compression::GZIP(GzipLevel::default())),
+        "lz4" => Ok(Compression::LZ4_RAW),
+        "none" | "uncompressed" => Ok(Compression::UNCOMPRESSED),
+        other => Err(anyhow::anyhow!("Unsupported PARQUET_COMPRESSION '{}'", other)),
+    }
+}
+
+/// Compression for Delta Parquet files from PARQUET_COMPRESSION and PARQUET_ZSTD_LEVEL (default zstd level 6)
+pub fn parquet_compression() -> Compression {
+    let codec = env::var("PARQUET_COMPRESSION").unwrap_or_else(|_| "zstd".to_string());
+    let zstd_level = env::var("PARQUET_ZSTD_LEVEL").ok().and_then(|v| v.parse().ok()).unwrap_or(6);
+    parse_parquet_compression(&codec, zstd_level).unwrap_or_else(|e| {
+        error!("Invalid Parquet compression settings, using zstd(6): {}", e);
+        Compression::ZSTD(ZstdLevel::try_new(6).unwrap())
+    })
+}
+
+/// Columns used to Z-order tables during scheduled optimization: ZORDER_COLUMNS (comma separated) when set and
+/// valid, `OtelLogsAndSpans::z_order_columns()` otherwise
+pub fn zorder_columns() -> Vec<String> {
+    let Ok(spec) = env::var("ZORDER_COLUMNS") else {
+        return OtelLogsAndSpans::z_order_columns();
+    };
+    let columns: Vec<String> = spec.split(',').map(|c| c.trim().to_string()).filter(|c| !c.is_empty()).collect();
+    match validate_zorder_columns(&columns) {
+        Ok(()) => columns,
+        Err(e) => {
+            error!("Ignoring ZORDER_COLUMNS: {}", e);
+            OtelLogsAndSpans::z_order_columns()
+        }
+    }
+}
+
+/// Check Z-order columns exist in `OtelLogsAndSpans::schema_ref()` and aren't partition columns
+pub fn validate_zorder_columns(columns: &[String]) -> Result<()> {
+    if columns.is_empty() {
+        return Err(anyhow::anyhow!("At least one Z-order column is required"));
+    }
+    let schema = OtelLogsAndSpans::schema_ref();
+    let partitions = OtelLogsAndSpans::partitions();
+    for column in columns {
+        if schema.field_with_name(column).is_err() {
+            return Err(anyhow::anyhow!("Unknown Z-order column '{}'", column));
+        }
+        if partitions.contains(column) {
+            return Err(anyhow::anyhow!("Cannot Z-order by partition column '{}'", column));
+        }
+    }
+    Ok(())
+}
+
+/// Lowest OTel severity number of each canonical level, ascending. Override with SEVERITY_LEVELS,
+/// e.g. `1=TRACE,5=DEBUG,9=INFO,13=WARN,17=ERROR,21=FATAL`.
+pub fn severity_levels() -> Vec<(i32, String)> {
+    let spec = env::var("SEVERITY_LEVELS").unwrap_or_else(|_| "1=TRACE,5=DEBUG,9=INFO,13=WARN,17=ERROR,21=FATAL".to_string());
+    let mut levels: Vec<(i32, String)> = spec
+        .split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .filter_map(|(n, l)| Some((n.trim().parse().ok()?, l.trim().to_uppercase())))
+        .collect();
+    levels.sort_by_key(|(bound, _)| *bound);
+    levels
+}
+
+/// Which of a record's timestamps drives the `timestamp` column and hence the `date` partition.
+/// Set with PARTITION_TIMESTAMP_SOURCE=event|observed (default `event`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimestampSource {
+    /// The time the event happened, as sent by the client
+    #[default]
+    Event,
+    /// The time TimeFusion (or the collector) received the record
+    Observed,
+}
+
+impl TimestampSource {
+    pub fn from_env() -> Self {
+        match env::var("PARTITION_TIMESTAMP_SOURCE").as_deref() {
+            Ok("observed") => TimestampSource::Observed,
+            Ok("event") | Err(_) => TimestampSource::Event,
+            Ok(other) => {
+                log::warn!("Unknown PARTITION_TIMESTAMP_SOURCE '{}', using event time", other);
+                TimestampSource::Event
+            }
+        }
+    }
+}
+
+/// Projects a session is allowed to read, attached to the `SessionConfig` as an extension. PGWire connections get
+/// it when their user logs in with PGWIRE_PASSWORD and PGWIRE_PROJECTS is set, see `attach_to`. Sessions without it
+/// are unrestricted.
+#[derive(Debug, Clone, Default)]
+pub struct ProjectAccess {
+    allowed: std::collections::HashSet<String>,
+    all: bool,
+}
+
+impl ProjectAccess {
+    pub fn new<I, S>(projects: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            allowed: projects.into_iter().map(Into::into).collect(),
+            all: false,
+        }
+    }
+
+    /// Access to every project, e.g. for an operator account
+    pub fn all() -> Self {
+        Self {
+            allowed: Default::default(),
+            all: true,
+        }
+    }
+
+    /// Parse a comma separated list of project ids, or `*` for every project
+    pub fn from_spec(spec: &str) -> Self {
+        if spec.trim() == "*" {
+            return Self::all();
+        }
+        Self::new(spec.split(',').map(str::trim).filter(|p| !p.is_empty()))
+    }
+
+    /// Restrict the sessions of `ctx` to these projects, and let them decrypt those projects' encrypted columns
+    pub fn attach_to(self, ctx: &SessionContext) {
+        let access = Arc::new(self);
+        ctx.state_ref().write().config_mut().set_extension(Arc::clone(&access));
+        match master_key_from_env() {
+            Ok(Some(master_key)) => ctx.register_udf(decrypt_udf(master_key, Some(access))),
+            Ok(None) => {}
+            Err(e) => error!("decrypt is unavailable: {}", e),
+        }
+    }
+
+    /// The only project allowed, when the list names exactly one
+    pub fn single(&self) -> Option<&str> {
+        match (self.all, self.allowed.len()) {
+            (false, 1) => self.allowed.iter().next().map(String::as_str),
+            _ => None,
+        }
+    }
+
+    pub fn check(&self, project_id: &str) -> DFResult<()> {
+        if self.all || self.allowed.contains(project_id) {
+            Ok(())
+        } else {
+            Err(DataFusionError::Execution(format!(
+                "Access denied: project '{}' is not authorized for this session",
+                project_id
+            )))
+        }
+    }
+}
+
+/// `decrypt(project_id, value)` returning the plaintext of encrypted columns for rows of projects `access` allows,
+/// or failing every call without an access list
+fn decrypt_udf(master_key: Vec<u8>, access: Option<Arc<ProjectAccess>>) -> datafusion::logical_expr::ScalarUDF {
+    use datafusion::arrow::array::StringArray;
+    use datafusion::arrow::datatypes::DataType;
+    use datafusion::logical_expr::{ColumnarValue, ScalarFunctionImplementation, Volatility, create_udf};
+
+    let decrypt_fn: ScalarFunctionImplementation = Arc::new(move |args: &[ColumnarValue]| -> DFResult<ColumnarValue> {
+        let Some(access) = &access else {
+            return Err(DataFusionError::Execution(
+                "Access denied: decrypt needs a session with a project access list".to_string(),
+            ));
+        };
+        let arrays = ColumnarValue::values_to_arrays(args)?;
+        let (Some(projects), Some(values)) = (
+            arrays[0].as_any().downcast_ref::<StringArray>(),
+            arrays[1].as_any().downcast_ref::<StringArray>(),
+        ) else {
+            return Err(DataFusionError::Execution("decrypt expects (project_id, value) string arguments".to_string()));
+        };
+
+        let mut ciphers: HashMap<&str, ColumnCipher> = HashMap::new();
+        let mut decrypted = Vec::with_capacity(values.len());
+        for (project_id, value) in projects.iter().zip(values.iter()) {
+            let (Some(project_id), Some(value)) = (project_id, value) else {
+                decrypted.push(None);
+                continue;
+            };
+            let cipher = match ciphers.entry(project_id) {
+                std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                std::collections::hash_map::Entry::Vacant(entry) => {
+                    access.check(project_id)?;
+                    entry.insert(ColumnCipher::for_project(&master_key, project_id).map_err(|e| DataFusionError::Execution(e.to_string()))?)
+                }
+            };
+            decrypted.push(Some(cipher.decrypt(value).map_err(|e| DataFusionError::Execution(e.to_string()))?));
+        }
+        Ok(ColumnarValue::Array(Arc::new(StringArray::from(decrypted))))
+    });
+
+    create_udf(
+        "decrypt",
+        vec![DataType::Utf8, DataType::Utf8],
+        DataType::Utf8,
+        Volatility::Immutable,
+        decrypt_fn,
+    )
+}
+
+/// Summary of a single Delta commit, read from the transaction log
+#[derive(Debug, Clone, Serialize)]
+pub struct CommitSummary {
+    pub version: i64,
+    pub timestamp: Option<i64>,
+    pub operation: Option<String>,
+    pub operation_parameters: Option<HashMap<String, serde_json::Value>>,
+    pub rows_added: Option<u64>,
+    pub files_added: Option<u64>,
+    pub files_removed: Option<u64>,
+}
+
+/// Returned by `register_project` when MAX_PROJECTS projects are already registered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProjectLimitReached {
+    pub max: usize,
+}
+
+impl fmt::Display for ProjectLimitReached {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Project limit reached: at most {} projects can be registered", self.max)
+    }
+}
+
+impl std::error::Error for ProjectLimitReached {}
+
+/// Returned by `create_project` when the project is already registered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProjectAlreadyRegistered {
+    pub project_id: String,
+}
+
+impl fmt::Display for ProjectAlreadyRegistered {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Project '{}' is already registered", self.project_id)
+    }
+}
+
+impl std::error::Error for ProjectAlreadyRegistered {}
+
+/// Returned by writes to a project marked read-only
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProjectReadOnly {
+    pub project_id: String,
+}
+
+impl fmt::Display for ProjectReadOnly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Project '{}' is read-only and doesn't accept writes", self.project_id)
+    }
+}
+
+impl std::error::Error for ProjectReadOnly {}
+
+/// Most projects that may be registered, not counting `default` (MAX_PROJECTS, unlimited when unset or 0)
+pub fn max_projects() -> Option<usize> {
+    env::var("MAX_PROJECTS").ok().and_then(|v| v.parse().ok()).filter(|max| *max > 0)
+}
+
+/// Whether a scan without a `project_id` filter unions every registered project's table (CROSS_PROJECT_SCAN=true)
+/// instead of reading only the default one. Off by default since such a scan touches every tenant's bucket.
+pub fn cross_project_scan_enabled() -> bool {
+    env::var("CROSS_PROJECT_SCAN").is_ok_and(|v| v == "true")
+}
+
+/// Rows per Arrow batch on the read path (SCAN_BATCH_SIZE, default DataFusion's 8192). Lowering it shrinks the
+/// per-batch buffers of the ~200 column `otel_logs_and_spans` table.
+pub fn scan_batch_size() -> usize {
+    env::var("SCAN_BATCH_SIZE").ok().and_then(|v| v.parse().ok()).filter(|size| *size > 0).unwrap_or(8192)
+}
+
+/// Most concurrent object store requests per table (S3_READ_CONCURRENCY, object_store's default when unset). Scans of
+/// long time ranges fetch many Parquet files at once; `cargo bench -- "s3 scan concurrency"` compares limits against
+/// the configured backend.
+pub fn s3_read_concurrency() -> Option<usize> {
+    env::var("S3_READ_CONCURRENCY").ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0)
+}
+
+/// Check that a project id is safe to use in paths and SQL literals: 1-64 ASCII letters, digits, `_` or `-`
+pub fn validate_project_id(project_id: &str) -> Result<()> {
+    if project_id.is_empty() || project_id.len() > 64 || !project_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return Err(anyhow::anyhow!("Invalid project id '{}': use 1-64 letters, digits, '_' or '-'", project_id));
+    }
+    Ok(())
+}
+
+/// Check an S3 bucket name against the AWS naming rules: 3-63 lowercase letters, digits, `.` or `-`, starting
+/// and ending with a letter or digit, and not shaped like an IP address
+pub fn validate_bucket_name(bucket: &str) -> Result<()> {
+    let valid_chars = bucket.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '.' || c == '-');
+    let valid_ends = bucket.starts_with(|c: char| c.is_ascii_alphanumeric()) && bucket.ends_with(|c: char| c.is_ascii_alphanumeric());
+    if !(3..=63).contains(&bucket.len()) || !valid_chars || !valid_ends || bucket.contains("..") || bucket.parse::<std::net::Ipv4Addr>().is_ok() {
+        return Err(anyhow::anyhow!("Invalid bucket name '{}'", bucket));
+    }
+    Ok(())
+}
+
+/// A registered project as listed by `GET /projects`, without credentials
+#[derive(Debug, Clone, Serialize)]
+pub struct ProjectSummary {
+    pub project_id: String,
+    /// Table location without the connection string's query parameters, which may hold keys
+    pub uri: String,
+    pub read_only: bool,
+    pub compaction_interval_secs: Option<u64>,
+    pub display_timezone: Option<String>,
+}
+
+/// A trace's spans and, when requested, the logs correlated with it through `context___trace_id`
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceView {
+    pub trace_id: String,
+    pub spans: Vec<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logs: Option<Vec<serde_json::Value>>,
+    /// Correlated logs whose `context___span_id` matches none of the trace's spans, e.g. from a span that was dropped
+    pub orphan_logs: usize,
+}
+
+/// Outcome of a delete: rows removed and data files removed (or rewritten without the deleted rows)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct DeleteSummary {
+    pub rows_deleted: u64,
+    pub files_removed: u64,
+}
+
+/// Differences between the expected `OtelLogsAndSpans` schema and a table's actual schema
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SchemaDiff {
+    /// Expected columns the table doesn't have
+    pub missing: Vec<String>,
+    /// Table columns that aren't part of the expected schema
+    pub extra: Vec<String>,
+    /// Columns present in both with different types
+    pub retyped: Vec<RetypedColumn>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RetypedColumn {
+    pub column: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl SchemaDiff {
+    pub fn between(expected: &arrow_schema::Schema, actual: &arrow_schema::Schema) -> Self {
+        let mut diff = SchemaDiff::default();
+        for field in expected.fields() {
+            match actual.field_with_name(field.name()) {
+                Ok(actual_field) if actual_field.data_type() != field.data_type() => diff.retyped.push(RetypedColumn {
+                    column: field.name().clone(),
+                    expected: field.data_type().to_string(),
+                    actual: actual_field.data_type().to_string(),
+                }),
+                Ok(_) => {}
+                Err(_) => diff.missing.push(field.name().clone()),
+            }
+        }
+        diff.extra = actual.fields().iter().filter(|f| expected.field_with_name(f.name()).is_err()).map(|f| f.name().clone()).collect();
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.retyped.is_empty()
+    }
+}
+
 pub type ProjectConfigs = Arc<RwLock<HashMap<String, ProjectConfig>>>;
 
+/// How often a project's table is compacted by the maintenance scheduler
+#[derive(Debug, Clone, Copy)]
+pub struct CompactionSchedule {
+    pub interval: Duration,
+    pub last_compacted: Instant,
+}
+
+impl CompactionSchedule {
+    /// Global default interval from COMPACTION_INTERVAL_SECS (default 3 hours)
+    pub fn default_interval() -> Duration {
+        Duration::from_secs(env::var("COMPACTION_INTERVAL_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(3 * 3600))
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.last_compacted) >= self.interval
+    }
+}
+
 #[derive(Debug)]
 pub struct Database {
     project_configs: ProjectConfigs,
+    compaction_schedules: Arc<RwLock<HashMap<String, CompactionSchedule>>>,
+    encrypted_columns: Arc<RwLock<HashMap<String, Vec<String>>>>,
+    read_only_projects: Arc<RwLock<HashSet<String>>>,
+    display_timezones: Arc<RwLock<HashMap<String, String>>>,
+    /// Persisted projects that couldn't be restored on startup, whose writes are refused until they are registered
+    /// again, so rows meant for encrypted columns never land in the default table in plain text
+    unrestored_projects: Arc<RwLock<HashSet<String>>>,
+    metrics_table: Arc<RwLock<DeltaTable>>,
     batch_queue: Option<Arc<crate::batch_queue::BatchQueue>>,
+    dedup: Option<Arc<DedupWindow>>,
+    project_registry: Option<Arc<ProjectRegistry>>,
+    query_cache: Option<Arc<QueryCache>>,
     maintenance_shutdown: Arc<CancellationToken>,
+    ingest_paused: Arc<AtomicBool>,
 }
 
 impl Clone for Database {
     fn clone(&self) -> Self {
         Self {
             project_configs: Arc::clone(&self.project_configs),
+            compaction_schedules: Arc::clone(&self.compaction_schedules),
+            encrypted_columns: Arc::clone(&self.encrypted_columns),
+            read_only_projects: Arc::clone(&self.read_only_projects),
+            display_timezones: Arc::clone(&self.display_timezones),
+            unrestored_projects: Arc::clone(&self.unrestored_projects),
+            metrics_table: Arc::clone(&self.metrics_table),
             batch_queue: self.batch_queue.clone(),
+            dedup: self.dedup.clone(),
+            project_registry: self.project_registry.clone(),
+            query_cache: self.query_cache.clone(),
             maintenance_shutdown: Arc::clone(&self.maintenance_shutdown),
+            ingest_paused: Arc::clone(&self.ingest_paused),
         }
     }
 }
@@ -72,19 +550,117 @@ impl Database {
         deltalake::aws::register_handlers(Some(aws_url));
         info!("AWS handlers registered");
 
+        let metrics_uri = format!("s3://{}/{}/{}/?endpoint={}", bucket, prefix, OtelMetrics::table_name(), aws_endpoint);
+        let metrics_table = Self::load_or_create_table(
+            &metrics_uri,
+            &Self::storage_options(None, None, None),
+            OtelMetrics::columns()?,
+            OtelMetrics::partitions(),
+        )
+        .await?;
+        info!("Metrics table loaded from {}", metrics_uri);
+
         let project_configs = HashMap::new();
 
         let db = Self {
             project_configs: Arc::new(RwLock::new(project_configs)),
+            compaction_schedules: Arc::new(RwLock::new(HashMap::new())),
+            encrypted_columns: Arc::new(RwLock::new(HashMap::new())),
+            read_only_projects: Arc::new(RwLock::new(HashSet::new())),
+            display_timezones: Arc::new(RwLock::new(HashMap::new())),
+            unrestored_projects: Arc::new(RwLock::new(HashSet::new())),
+            metrics_table: Arc::new(RwLock::new(metrics_table)),
             batch_queue: None, // Batch queue is set later
+            dedup: DedupWindow::from_env()?.map(Arc::new),
+            project_registry: ProjectRegistry::from_env()?.map(Arc::new),
+            query_cache: QueryCache::from_env().map(Arc::new),
             maintenance_shutdown: Arc::new(CancellationToken::new()),
+            ingest_paused: Arc::new(AtomicBool::new(Self::ingest_pause_marker().exists())),
         };
+        if db.is_ingest_paused() {
+            log::warn!("Ingestion is paused (marker {} exists)", Self::ingest_pause_marker().display());
+        }
 
         db.register_project("default", &storage_uri, None, None, None).await?;
+        db.load_persisted_projects().await?;
+
+        if let Ok(projects) = env::var("WARMUP_PROJECTS") {
+            db.warm_up(&projects).await;
+        }
 
         Ok(db)
     }
 
+    /// Bring the tables of the given projects (comma separated, or `*` for all registered) up to date
+    /// so the first query against them doesn't pay for reading the transaction log.
+    /// Runs up to `WARMUP_CONCURRENCY` (default 4) projects at a time; failures are logged and skipped.
+    pub async fn warm_up(&self, projects: &str) {
+        let concurrency = env::var("WARMUP_CONCURRENCY").ok().and_then(|v| v.parse().ok()).unwrap_or(4usize).max(1);
+
+        let project_ids: Vec<String> = if projects.trim() == "*" {
+            self.project_configs.read().await.keys().cloned().collect()
+        } else {
+            projects.split(',').map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).collect()
+        };
+
+        let started = Instant::now();
+        futures::stream::iter(project_ids)
+            .map(|project_id| async move {
+                let project_started = Instant::now();
+                let table_ref = match self.project_configs.read().await.get(&project_id) {
+                    Some((_, _, table)) => Arc::clone(table),
+                    None => {
+                        log::warn!("Skipping warm-up of unknown project '{}'", project_id);
+                        return;
+                    }
+                };
+
+                let mut table = table_ref.write().await;
+                match table.update().await {
+                    Ok(_) => info!(
+                        "Warmed up project '{}' at version {} ({} files) in {:?}",
+                        project_id,
+                        table.version(),
+                        table.get_files_count(),
+                        project_started.elapsed()
+                    ),
+                    Err(e) => error!("Failed to warm up project '{}': {}", project_id, e),
+                }
+            })
+            .buffer_unordered(concurrency)
+            .collect::<Vec<_>>()
+            .await;
+        info!("Table warm-up finished in {:?}", started.elapsed());
+    }
+
+    /// File whose presence marks ingestion as paused, so a pause survives restarts (INGEST_PAUSE_MARKER)
+    fn ingest_pause_marker() -> std::path::PathBuf {
+        env::var("INGEST_PAUSE_MARKER").unwrap_or_else(|_| ".timefusion_ingest_paused".to_string()).into()
+    }
+
+    pub fn is_ingest_paused(&self) -> bool {
+        self.ingest_paused.load(Ordering::SeqCst)
+    }
+
+    /// Stop accepting writes, e.g. during a maintenance window. Queries keep working and queued batches stay queued.
+    pub fn pause_ingest(&self) -> Result<()> {
+        std::fs::write(Self::ingest_pause_marker(), chrono::Utc::now().to_rfc3339())?;
+        self.ingest_paused.store(true, Ordering::SeqCst);
+        info!("Ingestion paused");
+        Ok(())
+    }
+
+    pub fn resume_ingest(&self) -> Result<()> {
+        match std::fs::remove_file(Self::ingest_pause_marker()) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        self.ingest_paused.store(false, Ordering::SeqCst);
+        info!("Ingestion resumed");
+        Ok(())
+    }
+
     /// Set the batch queue to use for insert operations
     pub fn with_batch_queue(mut self, batch_queue: Arc<crate::batch_queue::BatchQueue>) -> Self {
         self.batch_queue = Some(batch_queue);
@@ -97,19 +673,34 @@ impl Database {
 
         let scheduler = JobScheduler::new().await?;
         let db = Arc::new(self.clone());
+        let lease = Arc::new(self.maintenance_lease().await?);
+        info!("Maintenance lease holder id: {}", lease.holder());
+
+        // Lease renewal - every 30 seconds, so the holder keeps it and others take over once it stops renewing
+        let lease_job = Job::new_async("*/30 * * * * *", {
+            let lease = lease.clone();
+            move |_, _| {
+                let lease = lease.clone();
+                Box::pin(async move {
+                    lease.holds().await;
+                })
+            }
+        })?;
+
+        scheduler.add(lease_job).await?;
 
-        // Optimize job - every 3 hours
-        let optimize_job = Job::new_async("0 0 */3 * * *", {
+        // Optimize job - every 5 minutes, compacting the projects whose own interval has elapsed
+        let optimize_job = Job::new_async("0 */5 * * * *", {
             let db = db.clone();
+            let lease = lease.clone();
             move |_, _| {
                 let db = db.clone();
+                let lease = lease.clone();
                 Box::pin(async move {
-                    info!("Running scheduled optimize on all tables");
-                    for (project_id, (_, _, table)) in db.project_configs.read().await.iter() {
-                        if let Err(e) = db.optimize_table(table).await {
-                            error!("Optimize failed for {}: {}", project_id, e);
-                        }
+                    if !lease.holds().await {
+                        return;
                     }
+                    db.compact_due_projects().await;
                 })
             }
         })?;
@@ -119,15 +710,21 @@ impl Database {
         // Vacuum job - daily at 3AM
         let vacuum_job = Job::new_async("0 0 3 * * *", {
             let db = db.clone();
+            let lease = lease.clone();
             move |_, _| {
                 let db = db.clone();
+                let lease = lease.clone();
                 Box::pin(async move {
+                    if !lease.holds().await {
+                        info!("Skipping scheduled vacuum, another instance holds the maintenance lease");
+                        return;
+                    }
                     info!("Running scheduled vacuum on all tables");
                     let retention_hours = env::var("TIMEFUSION_VACUUM_RETENTION_HOURS").unwrap_or_else(|_| "336".to_string()).parse::<u64>().unwrap_or(336);
 
                     for (project_id, (_, _, table)) in db.project_configs.read().await.iter() {
                         info!("Vacuuming {} (retention: {}h)", project_id, retention_hours);
-                        db.vacuum_table(table, retention_hours).await;
+                        db.vacuum_table(project_id, table, retention_hours).await;
                     }
                 })
             }
@@ -135,6 +732,63 @@ impl Database {
 
         scheduler.add(vacuum_job).await?;
 
+        // Ingest lag job - every minute on every replica, it only reads snapshot statistics
+        let ingest_lag_job = Job::new_async("0 * * * * *", {
+            let db = db.clone();
+            move |_, _| {
+                let db = db.clone();
+                Box::pin(async move {
+                    db.refresh_ingest_lag().await;
+                })
+            }
+        })?;
+
+        scheduler.add(ingest_lag_job).await?;
+
+        // Dedup prune job - hourly, drops ids that fell out of the dedup window
+        if let Some(dedup) = self.dedup.clone() {
+            let dedup_prune_job = Job::new_async("0 0 * * * *", move |_, _| {
+                let dedup = dedup.clone();
+                Box::pin(async move {
+                    match tokio::task::spawn_blocking(move || dedup.prune(chrono::Utc::now())).await {
+                        Ok(Ok(removed)) => debug!("Pruned {} expired dedup entries", removed),
+                        Ok(Err(e)) => error!("Failed to prune dedup entries: {}", e),
+                        Err(e) => error!("Dedup prune task failed: {}", e),
+                    }
+                })
+            })?;
+            scheduler.add(dedup_prune_job).await?;
+        }
+
+        // Retention job - deletes data older than RETENTION_DAYS, disabled when unset
+        if let Some(retention_days) = env::var("RETENTION_DAYS").ok().and_then(|v| v.parse::<i64>().ok()).filter(|d| *d > 0) {
+            let schedule = env::var("RETENTION_SCHEDULE").unwrap_or_else(|_| "0 30 2 * * *".to_string());
+            let retention_job = Job::new_async(schedule.as_str(), {
+                let db = db.clone();
+                let lease = lease.clone();
+                move |_, _| {
+                    let db = db.clone();
+                    let lease = lease.clone();
+                    Box::pin(async move {
+                        if !lease.holds().await {
+                            info!("Skipping scheduled retention, another instance holds the maintenance lease");
+                            return;
+                        }
+                        let cutoff = chrono::Utc::now() - chrono::Duration::days(retention_days);
+                        info!("Running scheduled retention, deleting data older than {}", cutoff);
+                        let project_ids: Vec<String> = db.project_configs.read().await.keys().cloned().collect();
+                        for project_id in project_ids {
+                            if let Err(e) = db.delete_older_than(&project_id, cutoff).await {
+                                error!("Retention failed for {}: {}", project_id, e);
+                            }
+                        }
+                    })
+                }
+            })?;
+            scheduler.add(retention_job).await?;
+            info!("Retention enabled: {} days, schedule '{}'", retention_days, schedule);
+        }
+
         // Start the scheduler
         scheduler.start().await?;
 
@@ -143,72 +797,508 @@ impl Database {
         tokio::spawn(async move {
             shutdown.cancelled().await;
             info!("Shutting down maintenance scheduler");
+            if let Err(e) = lease.release().await {
+                error!("Failed to release maintenance lease: {}", e);
+            }
         });
 
         Ok(self)
     }
 
-    /// Create and configure a SessionContext with DataFusion settings
-    pub fn create_session_context(&self) -> SessionContext {
-        use datafusion::config::ConfigOptions;
-        use datafusion::execution::context::SessionContext;
+    /// Lease shared by all replicas using the default table's bucket, see `MaintenanceLease`.
+    /// The holder id comes from MAINTENANCE_INSTANCE_ID (random by default) and the lease expires after
+    /// MAINTENANCE_LEASE_TTL_SECS (default 120) without renewal.
+    async fn maintenance_lease(&self) -> Result<MaintenanceLease> {
+        let store = match self.project_configs.read().await.get("default") {
+            Some((_, _, table)) => table.read().await.object_store(),
+            None => return Err(anyhow::anyhow!("Default project is not registered")),
+        };
+        let holder = env::var("MAINTENANCE_INSTANCE_ID").unwrap_or_else(|_| uuid::Uuid::new_v4().to_string());
+        let ttl_secs = env::var("MAINTENANCE_LEASE_TTL_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(120);
+        Ok(MaintenanceLease::new(
+            store,
+            object_store::path::Path::from("_timefusion/maintenance.lease"),
+            holder,
+            chrono::Duration::seconds(ttl_secs),
+        ))
+    }
 
-        let mut options = ConfigOptions::new();
-        let _ = options.set("datafusion.sql_parser.enable_information_schema", "true");
-        SessionContext::new_with_config(options.into())
+    /// Optimize every project whose compaction interval has elapsed since it was last compacted
+    async fn compact_due_projects(&self) {
+        let now = Instant::now();
+        for project_id in self.projects_due_for_compaction(now).await {
+            let table = match self.project_configs.read().await.get(&project_id) {
+                Some((_, _, table)) => Arc::clone(table),
+                None => continue,
+            };
+
+            info!("Running scheduled optimize for project '{}'", project_id);
+            match self.optimize_table(&table).await {
+                Ok(()) => self.mark_compacted(&project_id, now).await,
+                Err(e) => error!("Optimize failed for {}: {}", project_id, e),
+            }
+        }
     }
 
-    /// Setup the session context with tables and register DataFusion tables
-    pub fn setup_session_context(&self, ctx: &SessionContext) -> DFResult<()> {
-        use crate::persistent_queue::OtelLogsAndSpans;
+    /// Projects whose compaction is due at `now`, sorted by project id
+    pub async fn projects_due_for_compaction(&self, now: Instant) -> Vec<String> {
+        let schedules = self.compaction_schedules.read().await;
+        let mut due: Vec<String> = schedules.iter().filter(|(_, s)| s.is_due(now)).map(|(id, _)| id.clone()).collect();
+        due.sort();
+        due
+    }
 
-        // Create tables and register them with session context
-        let schema = OtelLogsAndSpans::schema_ref();
+    async fn mark_compacted(&self, project_id: &str, at: Instant) {
+        if let Some(schedule) = self.compaction_schedules.write().await.get_mut(project_id) {
+            schedule.last_compacted = at;
+        }
+    }
 
-        // Get batch queue from the app state if available
-        let batch_queue = self.batch_queue.as_ref().map(Arc::clone);
+    /// Get the compaction schedule of a registered project
+    pub async fn compaction_schedule(&self, project_id: &str) -> Option<CompactionSchedule> {
+        self.compaction_schedules.read().await.get(project_id).copied()
+    }
 
-        let routing_table = ProjectRoutingTable::new("default".to_string(), Arc::new(self.clone()), schema, batch_queue);
+    /// Change how often a registered project is compacted
+    pub async fn set_compaction_interval(&self, project_id: &str, interval: Duration) -> Result<()> {
+        if interval.is_zero() {
+            return Err(anyhow::anyhow!("Compaction interval must be greater than zero"));
+        }
+        if !self.compaction_schedules.read().await.contains_key(project_id) {
+            return Err(anyhow::anyhow!("Project ID '{}' not found", project_id));
+        }
+        if let Some(registry) = &self.project_registry {
+            registry.update(project_id, |project| project.compaction_interval_secs = Some(interval.as_secs()))?;
+        }
+        if let Some(schedule) = self.compaction_schedules.write().await.get_mut(project_id) {
+            schedule.interval = interval;
+        }
+        info!("Compaction interval for project '{}' set to {:?}", project_id, interval);
+        Ok(())
+    }
 
-        ctx.register_table(OtelLogsAndSpans::table_name(), Arc::new(routing_table))?;
-        info!("Registered ProjectRoutingTable with SessionContext");
+    /// Set the columns of a registered project that are encrypted at rest. Only nullable string columns can be
+    /// encrypted, and COLUMN_ENCRYPTION_KEY must be configured. Applies to records written from now on.
+    pub async fn set_encrypted_columns(&self, project_id: &str, columns: Vec<String>) -> Result<()> {
+        if !self.project_configs.read().await.contains_key(project_id) {
+            return Err(anyhow::anyhow!("Project ID '{}' not found", project_id));
+        }
+        if !columns.is_empty() && master_key_from_env()?.is_none() {
+            return Err(anyhow::anyhow!("COLUMN_ENCRYPTION_KEY must be set to encrypt columns"));
+        }
 
-        self.register_pg_settings_table(ctx)?;
-        self.register_set_config_udf(ctx);
+        let fields = OtelLogsAndSpans::fields()?;
+        for column in &columns {
+            match fields.iter().find(|f| f.name() == column) {
+                Some(field) if field.data_type() == &arrow_schema::DataType::Utf8 && field.is_nullable() => {}
+                Some(_) => return Err(anyhow::anyhow!("Column '{}' is not a nullable string column and can't be encrypted", column)),
+                None => return Err(anyhow::anyhow!("Unknown column '{}'", column)),
+            }
+        }
 
+        // Persisted first, so a restart never writes these columns in plain text
+        if let Some(registry) = &self.project_registry {
+            registry.update(project_id, |project| project.encrypted_columns = columns.clone())?;
+        }
+        info!("Encrypted columns for project '{}' set to {:?}", project_id, columns);
+        self.encrypted_columns.write().await.insert(project_id.to_string(), columns);
         Ok(())
     }
 
-    /// Register PostgreSQL settings table for compatibility
-    pub fn register_pg_settings_table(&self, ctx: &SessionContext) -> datafusion::error::Result<()> {
-        use datafusion::arrow::array::StringArray;
-        use datafusion::arrow::datatypes::{DataType, Field, Schema};
-        use datafusion::arrow::record_batch::RecordBatch;
-
-        let schema = Arc::new(Schema::new(vec![
-            Field::new("name", DataType::Utf8, false),
-            Field::new("setting", DataType::Utf8, false),
-        ]));
+    /// Freeze or unfreeze a registered project. A read-only project rejects writes but still serves queries.
+    pub async fn set_project_read_only(&self, project_id: &str, read_only: bool) -> Result<()> {
+        if !self.project_configs.read().await.contains_key(project_id) {
+            return Err(anyhow::anyhow!("Project ID '{}' not found", project_id));
+        }
+        if let Some(registry) = &self.project_registry {
+            registry.update(project_id, |project| project.read_only = read_only)?;
+        }
+        let mut read_only_projects = self.read_only_projects.write().await;
+        if read_only {
+            read_only_projects.insert(project_id.to_string());
+        } else {
+            read_only_projects.remove(project_id);
+        }
+        info!("Project '{}' read-only: {}", project_id, read_only);
+        Ok(())
+    }
 
-        let names = vec![
-            "TimeZone".to_string(),
-            "client_encoding".to_string(),
-            "datestyle".to_string(),
-            "client_min_messages".to_string(),
-            // Add more PostgreSQL settings that clients might try to set
-            "lc_monetary".to_string(),
-            "lc_numeric".to_string(),
-            "lc_time".to_string(),
-            "standard_conforming_strings".to_string(),
-            "application_name".to_string(),
-            "search_path".to_string(),
-        ];
+    pub async fn is_project_read_only(&self, project_id: &str) -> bool {
+        self.read_only_projects.read().await.contains(project_id)
+    }
 
-        let settings = vec![
-            "UTC".to_string(),
-            "UTF8".to_string(),
-            "ISO, MDY".to_string(),
-            "notice".to_string(),
+    /// Set the time zone a registered project's timestamps are rendered in, see `DisplayTimezone`. Storage stays UTC.
+    pub async fn set_display_timezone(&self, project_id: &str, timezone: Option<String>) -> Result<()> {
+        if !self.project_configs.read().await.contains_key(project_id) {
+            return Err(anyhow::anyhow!("Project ID '{}' not found", project_id));
+        }
+        if let Some(timezone) = &timezone {
+            crate::pg_compat::validate_timezone(timezone)?;
+        }
+        if let Some(registry) = &self.project_registry {
+            registry.update(project_id, |project| project.display_timezone = timezone.clone())?;
+        }
+        let mut display_timezones = self.display_timezones.write().await;
+        match timezone {
+            Some(timezone) => {
+                info!("Project '{}' displays timestamps in {}", project_id, timezone);
+                display_timezones.insert(project_id.to_string(), timezone);
+            }
+            None => {
+                info!("Project '{}' displays timestamps in UTC", project_id);
+                display_timezones.remove(project_id);
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn display_timezone(&self, project_id: &str) -> Option<String> {
+        self.display_timezones.read().await.get(project_id).cloned()
+    }
+
+    /// Fail with `ProjectReadOnly` if any row of `batches` would be written to a read-only project's table, and
+    /// refuse rows of persisted projects that couldn't be restored on startup
+    async fn check_writable(&self, batches: &[RecordBatch]) -> Result<()> {
+        use datafusion::arrow::array::AsArray;
+
+        let unrestored = self.unrestored_projects.read().await.clone();
+        let read_only_projects = self.read_only_projects.read().await;
+        if read_only_projects.is_empty() && unrestored.is_empty() {
+            return Ok(());
+        }
+        let configs = self.project_configs.read().await;
+        for batch in batches {
+            let Some(project_ids) = batch.column_by_name("project_id").and_then(|c| c.as_string_opt::<i32>()) else {
+                continue;
+            };
+            for project_id in project_ids.iter().flatten() {
+                if unrestored.contains(project_id) {
+                    return Err(anyhow::anyhow!(
+                        "Project '{}' couldn't be restored from the project registry, register it again before writing",
+                        project_id
+                    ));
+                }
+                // Unregistered projects are written to the default table
+                let target = if configs.contains_key(project_id) { project_id } else { "default" };
+                if read_only_projects.contains(target) {
+                    return Err(ProjectReadOnly {
+                        project_id: target.to_string(),
+                    }
+                    .into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Create and configure a SessionContext with DataFusion settings
+    pub fn create_session_context(&self) -> SessionContext {
+        use datafusion::config::ConfigOptions;
+        use datafusion::execution::SessionStateBuilder;
+        use datafusion::execution::context::SessionContext;
+
+        let mut options = ConfigOptions::new();
+        let _ = options.set("datafusion.sql_parser.enable_information_schema", "true");
+        options.execution.batch_size = scan_batch_size();
+        if let Some(concurrency) = s3_read_concurrency() {
+            options.execution.meta_fetch_concurrency = concurrency;
+        }
+
+        let runtime = Self::create_runtime_env().unwrap_or_else(|e| {
+            error!("Failed to create query runtime, falling back to defaults: {}", e);
+            Arc::new(datafusion::execution::runtime_env::RuntimeEnv::default())
+        });
+        let state = SessionStateBuilder::new()
+            .with_config(options.into())
+            .with_runtime_env(runtime)
+            .with_default_features()
+            .with_type_planner(Arc::new(PgTypePlanner))
+            .with_query_planner(Arc::new(DmlQueryPlanner::new(self.clone())))
+            .with_physical_optimizer_rule(Arc::new(QueryTimeoutRule::default()))
+            .build();
+        SessionContext::new_with_state(state)
+    }
+
+    /// Session context of one PGWire connection. Tables, functions and planner rules are shared with `shared`, but the
+    /// connection has its own copy of the settings, so its `SET`s and the `ProjectAccess` of its login stay its own.
+    pub fn connection_context(shared: &SessionContext) -> SessionContext {
+        SessionContext::new_with_state(shared.state())
+    }
+
+    /// Build the query runtime. When QUERY_MEMORY_LIMIT_MB is set, queries share a spilling memory
+    /// pool of that size so large sorts, joins and aggregations spill to QUERY_SPILL_DIR (or the OS
+    /// temp dir) instead of running the process out of memory. Spill activity is reported per
+    /// operator as spill_count/spilled_bytes in EXPLAIN ANALYZE.
+    fn create_runtime_env() -> DFResult<Arc<datafusion::execution::runtime_env::RuntimeEnv>> {
+        use datafusion::execution::disk_manager::DiskManagerConfig;
+        use datafusion::execution::memory_pool::FairSpillPool;
+        use datafusion::execution::runtime_env::RuntimeEnvBuilder;
+
+        let mut builder = RuntimeEnvBuilder::new();
+
+        if let Some(limit_mb) = env::var("QUERY_MEMORY_LIMIT_MB").ok().and_then(|v| v.parse::<usize>().ok()) {
+            info!("Query memory limited to {} MB", limit_mb);
+            builder = builder.with_memory_pool(Arc::new(FairSpillPool::new(limit_mb * 1024 * 1024)));
+        }
+
+        if let Ok(spill_dir) = env::var("QUERY_SPILL_DIR") {
+            info!("Query spill directory: {}", spill_dir);
+            builder = builder.with_disk_manager(DiskManagerConfig::NewSpecified(vec![spill_dir.into()]));
+        }
+
+        builder.build_arc()
+    }
+
+    /// Setup the session context with tables and register DataFusion tables
+    pub fn setup_session_context(&self, ctx: &SessionContext) -> DFResult<()> {
+        use crate::persistent_queue::OtelLogsAndSpans;
+
+        // Create tables and register them with session context
+        let schema = OtelLogsAndSpans::schema_ref();
+
+        // Get batch queue from the app state if available
+        let batch_queue = self.batch_queue.as_ref().map(Arc::clone);
+
+        let routing_table = Arc::new(ProjectRoutingTable::new("default".to_string(), Arc::new(self.clone()), schema, batch_queue));
+
+        ctx.register_table(OtelLogsAndSpans::table_name(), routing_table.clone())?;
+        info!("Registered ProjectRoutingTable with SessionContext");
+
+        Self::register_spans_by_service_view(ctx, routing_table)?;
+
+        ctx.register_table(OtelMetrics::table_name(), Arc::new(MetricsTable::new(Arc::new(self.clone()))))?;
+        info!("Registered MetricsTable with SessionContext");
+
+        self.register_pg_settings_table(ctx)?;
+        self.register_set_config_udf(ctx);
+        self.register_severity_udfs(ctx);
+        self.register_decrypt_udf(ctx)?;
+
+        Ok(())
+    }
+
+    /// Register `decrypt(project_id, column)` when COLUMN_ENCRYPTION_KEY is set. It only works once a project
+    /// access list is attached to the session, see `ProjectAccess::attach_to`, and fails for everyone else.
+    pub fn register_decrypt_udf(&self, ctx: &SessionContext) -> Result<()> {
+        if let Some(master_key) = master_key_from_env()? {
+            ctx.register_udf(decrypt_udf(master_key, None));
+        }
+        Ok(())
+    }
+
+    /// Register `severity_level(severity_number)` and `severity_number(level)` so queries can filter consistently on
+    /// records that only carry one of `level` or `severity___severity_number`. Uses the OTel severity ranges:
+    /// 1-4 TRACE, 5-8 DEBUG, 9-12 INFO, 13-16 WARN, 17-20 ERROR, 21-24 FATAL, overridable via SEVERITY_LEVELS.
+    pub fn register_severity_udfs(&self, ctx: &SessionContext) {
+        use datafusion::arrow::array::{Int32Array, StringArray};
+        use datafusion::arrow::datatypes::DataType;
+        use datafusion::logical_expr::{ColumnarValue, ScalarFunctionImplementation, Volatility, create_udf};
+
+        let levels = Arc::new(severity_levels());
+
+        let level_fn: ScalarFunctionImplementation = {
+            let levels = Arc::clone(&levels);
+            Arc::new(move |args: &[ColumnarValue]| -> DFResult<ColumnarValue> {
+                let numbers = ColumnarValue::values_to_arrays(args)?.remove(0);
+                let numbers = numbers
+                    .as_any()
+                    .downcast_ref::<StringArray>()
+                    .ok_or_else(|| DataFusionError::Execution("severity_level expects a string argument".to_string()))?;
+                let result: StringArray = numbers
+                    .iter()
+                    .map(|n| {
+                        n.and_then(|n| n.trim().parse::<i32>().ok())
+                            .and_then(|n| levels.iter().rev().find(|(bound, _)| n >= *bound).map(|(_, l)| l.as_str()))
+                    })
+                    .collect();
+                Ok(ColumnarValue::Array(Arc::new(result)))
+            })
+        };
+
+        let number_fn: ScalarFunctionImplementation = Arc::new(move |args: &[ColumnarValue]| -> DFResult<ColumnarValue> {
+            let names = ColumnarValue::values_to_arrays(args)?.remove(0);
+            let names = names
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .ok_or_else(|| DataFusionError::Execution("severity_number expects a string argument".to_string()))?;
+            let result: Int32Array = names
+                .iter()
+                .map(|name| name.and_then(|name| levels.iter().find(|(_, l)| l.eq_ignore_ascii_case(name.trim())).map(|(bound, _)| *bound)))
+                .collect();
+            Ok(ColumnarValue::Array(Arc::new(result)))
+        });
+
+        ctx.register_udf(create_udf(
+            "severity_level",
+            vec![DataType::Utf8],
+            DataType::Utf8,
+            Volatility::Immutable,
+            level_fn,
+        ));
+        ctx.register_udf(create_udf(
+            "severity_number",
+            vec![DataType::Utf8],
+            DataType::Int32,
+            Volatility::Immutable,
+            number_fn,
+        ));
+    }
+
+    /// Run a SQL query against a fresh session context with all TimeFusion tables registered
+    pub async fn query(&self, sql: &str) -> DFResult<DataFrame> {
+        let ctx = self.create_session_context();
+        self.setup_session_context(&ctx)?;
+        ctx.sql(sql).await
+    }
+
+    /// Session for an HTTP request, restricted to the projects `access` allows
+    fn session_with_access(&self, access: &ProjectAccess) -> DFResult<SessionContext> {
+        let ctx = self.create_session_context();
+        self.setup_session_context(&ctx)?;
+        access.clone().attach_to(&ctx);
+        Ok(ctx)
+    }
+
+    /// Run a read-only query over the projects `access` allows and collect its result, serving repeated queries from
+    /// the query cache when it is enabled and `mode` allows it. Cached results skip the scan, so callers check that
+    /// the project the query names is allowed before calling.
+    pub async fn query_cached(&self, sql: &str, mode: CacheMode, access: &ProjectAccess) -> DFResult<(Vec<RecordBatch>, CacheStatus)> {
+        use datafusion::logical_expr::LogicalPlan;
+
+        let key = QueryCache::normalize(sql);
+        let Some(cache) = self.query_cache.as_ref().filter(|_| mode != CacheMode::Bypass && QueryCache::is_cacheable(&key)) else {
+            let batches = run_with_query_timeout(async { self.session_with_access(access)?.sql(sql).await?.collect().await }).await?;
+            return Ok((batches, CacheStatus::Bypass));
+        };
+        if let Some(batches) = (mode == CacheMode::Use).then(|| cache.get(&key)).flatten() {
+            return Ok((batches, CacheStatus::Hit));
+        }
+
+        let started = Instant::now();
+        let (plan, batches) = run_with_query_timeout(async {
+            let df = self.session_with_access(access)?.sql(sql).await?;
+            let plan = df.clone().into_optimized_plan()?;
+            Ok((plan, df.collect().await?))
+        })
+        .await?;
+        if matches!(plan, LogicalPlan::Dml(_) | LogicalPlan::Ddl(_) | LogicalPlan::Copy(_)) {
+            return Ok((batches, CacheStatus::Bypass));
+        }
+        cache.put(key, batches.clone(), Self::projects_read_by(&plan)?, started);
+        Ok((batches, CacheStatus::Miss))
+    }
+
+    /// Run a query over otel_logs_and_spans and deserialize its rows into `OtelLogsAndSpans`. Columns the query
+    /// doesn't select are left at their `Default`, and columns that aren't struct fields are ignored.
+    pub async fn query_spans(&self, sql: &str) -> Result<Vec<OtelLogsAndSpans>> {
+        use datafusion::arrow::datatypes::Schema;
+
+        let mut spans = Vec::new();
+        for batch in self.query(sql).await?.collect().await? {
+            if batch.num_rows() == 0 {
+                continue;
+            }
+
+            // Start from a batch of default records and swap in every column the query returned
+            let defaults = Self::records_to_batch(&vec![OtelLogsAndSpans::default(); batch.num_rows()])?;
+            let (fields, columns): (Vec<_>, Vec<_>) = defaults
+                .schema()
+                .fields()
+                .iter()
+                .zip(defaults.columns())
+                .map(|(field, default)| match batch.schema().column_with_name(field.name()) {
+                    Some((idx, selected)) => (Arc::new(selected.clone()), Arc::clone(batch.column(idx))),
+                    None => (Arc::clone(field), Arc::clone(default)),
+                })
+                .unzip();
+            let filled = RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)?;
+            spans.extend(serde_arrow::from_record_batch::<Vec<OtelLogsAndSpans>>(&filled)?);
+        }
+        Ok(spans)
+    }
+
+    /// Query cache statistics, `None` when the cache is disabled
+    pub fn query_cache_stats(&self) -> Option<crate::query_cache::QueryCacheStats> {
+        self.query_cache.as_ref().map(|cache| cache.stats())
+    }
+
+    /// Projects whose writes change the result of `plan`: those named in `project_id` filters of its spans table scans.
+    /// `None` when a scan reads another table or isn't restricted to known projects.
+    fn projects_read_by(plan: &datafusion::logical_expr::LogicalPlan) -> DFResult<Option<Vec<String>>> {
+        use datafusion::common::tree_node::TreeNodeRecursion;
+        use datafusion::logical_expr::LogicalPlan;
+
+        let mut projects = Some(Vec::new());
+        plan.apply_with_subqueries(|node| {
+            if let LogicalPlan::TableScan(scan) = node {
+                let scanned = (scan.table_name.table() == OtelLogsAndSpans::table_name())
+                    .then(|| ProjectRoutingTable::extract_project_ids_from_filters(&scan.filters))
+                    .flatten();
+                match (projects.as_mut(), scanned) {
+                    (Some(projects), Some(scanned)) => projects.extend(scanned),
+                    _ => projects = None,
+                }
+            }
+            Ok(TreeNodeRecursion::Continue)
+        })?;
+        Ok(projects)
+    }
+
+    /// Register `spans_by_service`, a view over the spans table with friendly names for service-level navigation:
+    /// `service, operation, timestamp, duration, status, trace_id, project_id, date`. It is a projection only, so
+    /// filters on the view are rewritten onto the underlying columns and reach the routing table and Delta pruning.
+    pub fn register_spans_by_service_view(ctx: &SessionContext, spans: Arc<dyn TableProvider>) -> DFResult<()> {
+        use datafusion::datasource::{provider_as_source, view::ViewTable};
+        use datafusion::logical_expr::LogicalPlanBuilder;
+        use datafusion::prelude::col;
+
+        let plan = LogicalPlanBuilder::scan(OtelLogsAndSpans::table_name(), provider_as_source(spans), None)?
+            .project(vec![
+                col("resource___service___name").alias("service"),
+                col("name").alias("operation"),
+                col("timestamp"),
+                col("duration"),
+                col("status_code").alias("status"),
+                col("context___trace_id").alias("trace_id"),
+                col("project_id"),
+                col("date"),
+            ])?
+            .build()?;
+
+        ctx.register_table("spans_by_service", Arc::new(ViewTable::try_new(plan, None)?))?;
+        Ok(())
+    }
+
+    /// Register PostgreSQL settings table for compatibility
+    pub fn register_pg_settings_table(&self, ctx: &SessionContext) -> datafusion::error::Result<()> {
+        use datafusion::arrow::array::StringArray;
+        use datafusion::arrow::datatypes::{DataType, Field, Schema};
+        use datafusion::arrow::record_batch::RecordBatch;
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("name", DataType::Utf8, false),
+            Field::new("setting", DataType::Utf8, false),
+        ]));
+
+        let names = vec![
+            "TimeZone".to_string(),
+            "client_encoding".to_string(),
+            "datestyle".to_string(),
+            "client_min_messages".to_string(),
+            // Add more PostgreSQL settings that clients might try to set
+            "lc_monetary".to_string(),
+            "lc_numeric".to_string(),
+            "lc_time".to_string(),
+            "standard_conforming_strings".to_string(),
+            "application_name".to_string(),
+            "search_path".to_string(),
+        ];
+
+        let settings = vec![
+            "UTC".to_string(),
+            "UTF8".to_string(),
+            "ISO, MDY".to_string(),
+            "notice".to_string(),
             // Default values for the additional settings
             "C".to_string(),
             "C".to_string(),
@@ -274,15 +1364,23 @@ impl Database {
             info!("PGWire server successfully bound to {}", local_addr);
         }
 
-        // 2) pgwire service + handler
-        let service = Arc::new(DfSessionService::new(session_ctx));
-        let factory = Arc::new(HandlerFactory(service));
+        // 2) pgwire service + handlers are created per connection, see `connection_context`
+        if let Some(source) = crate::pg_auth::PasswordAuthSource::from_env() {
+            info!("PGWire password authentication enabled for user '{}'", source.user());
+        }
 
         // 3) concurrency + logging
         let max_conn = std::env::var("MAX_PG_CONNECTIONS").ok().and_then(|v| v.parse().ok()).unwrap_or(100) as usize;
-        info!("PGWire listening on 0.0.0.0:{} (limit {})", port, max_conn);
+        let handshake_timeout = Duration::from_secs(env::var("PGWIRE_HANDSHAKE_TIMEOUT_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(10));
+        let max_startup_bytes = env::var("PGWIRE_MAX_STARTUP_PACKET_BYTES").ok().and_then(|v| v.parse().ok()).unwrap_or(10_000);
+        info!(
+            "PGWire listening on 0.0.0.0:{} (limit {}, handshake timeout {:?})",
+            port, max_conn, handshake_timeout
+        );
 
         // 4) spawn the accept‐&‐process loop
+        let cancel_keys = Arc::new(CancelKeys::default());
+        let database = self.clone();
         let handle = tokio::spawn({
             let shutdown = shutdown.clone();
             let stream = TcpListenerStream::new(listener);
@@ -302,12 +1400,61 @@ impl Database {
                                     info!("Client connected from {}", peer_addr);
                                 }
 
+                                // Drop clients that stall or send an oversized packet before completing the startup phase.
+                                // The deadline covers the whole handshake, including SSL negotiation and authentication.
+                                let deadline = tokio::time::Instant::now() + handshake_timeout;
+                                match timeout_at(deadline, wait_for_startup_packet(&sock, max_startup_bytes)).await {
+                                    Ok(Ok(())) => {}
+                                    Ok(Err(e)) => {
+                                        error!("Rejecting PGWire connection during startup: {}", e);
+                                        return;
+                                    }
+                                    Err(_) => {
+                                        error!("PGWire startup handshake timed out after {:?}", handshake_timeout);
+                                        return;
+                                    }
+                                }
+
+                                // A CancelRequest comes on a connection of its own, which is closed without a reply
+                                if let Ok(Some((pid, secret))) = peek_cancel_request(&sock).await {
+                                    if cancel_keys.cancel(pid, secret) {
+                                        info!("Canceled the queries of PGWire connection {}", pid);
+                                    } else {
+                                        log::warn!("Ignoring CancelRequest for unknown PGWire connection {}", pid);
+                                    }
+                                    return;
+                                }
+
                                 // Use a longer timeout to prevent idle disconnections
                                 let timeout_duration = Duration::from_secs(3600); // 1 hour
                                 info!("Starting PGWire connection processing");
-                                let start_time = std::time::Instant::now();
-
-                                match timeout(timeout_duration, pgwire::tokio::process_socket(sock, None, factory.clone())).await {
+                                let start_time = Instant::now();
+
+                                // Dropping the key at the end of the connection unregisters it
+                                let cancel_key = cancel_keys.register();
+                                let conn_ctx = Self::connection_context(&session_ctx);
+                                cancel_key.canceler.attach_to(&conn_ctx);
+                                let service = Arc::new(DfSessionService::new(conn_ctx.clone()));
+                                let factory = Arc::new(TimeFusionHandlers::new(
+                                    HandlerFactory(service),
+                                    conn_ctx,
+                                    database.clone(),
+                                    (cancel_key.pid, cancel_key.secret),
+                                ));
+                                let ready = factory.ready();
+                                let processing = timeout(timeout_duration, pgwire::tokio::process_socket(sock, None, factory));
+                                tokio::pin!(processing);
+                                let outcome = tokio::select! {
+                                    outcome = &mut processing => outcome,
+                                    handshake = timeout_at(deadline, ready.notified()) => match handshake {
+                                        Ok(()) => processing.await,
+                                        Err(_) => {
+                                            error!("PGWire handshake not completed within {:?}, dropping connection", handshake_timeout);
+                                            return;
+                                        }
+                                    },
+                                };
+                                match outcome {
                                     Ok(Ok(_)) => {
                                         let elapsed = start_time.elapsed();
                                         info!("PGWire connection completed successfully (duration: {:?})", elapsed);
@@ -379,6 +1526,11 @@ impl Database {
     }
 
     pub async fn insert_records_batch(&self, _table: &str, batches: Vec<RecordBatch>, skip_queue: bool) -> Result<()> {
+        if self.is_ingest_paused() {
+            return Err(anyhow::anyhow!("Ingestion is paused"));
+        }
+        self.check_writable(&batches).await?;
+
         // Check if we should use the batch queue based on:
         // 1. skip_queue parameter (if true, always skip)
         // 2. ENABLE_BATCH_QUEUE env var (if set to "true", allow queue usage)
@@ -387,24 +1539,122 @@ impl Database {
 
         if !skip_queue && enable_queue && self.batch_queue.is_some() {
             let queue = self.batch_queue.as_ref().unwrap();
-            // Add to batch queue
-            for batch in batches {
-                if let Err(e) = queue.queue(batch) {
-                    return Err(anyhow::anyhow!("Queue error: {}", e));
+            // Add to batch queue, all batches or none so a full queue rejects the whole request
+            return queue.queue_all(batches).map_err(|e| match e.downcast::<crate::batch_queue::QueueFull>() {
+                Ok(full) => full.into(),
+                Err(e) => anyhow::anyhow!("Queue error: {}", e),
+            });
+        }
+
+        // Direct insert logic if skip_queue=true, queue disabled, no batch queue, or when processing from batch queue
+        let enrichment = Enrichment::from_env();
+        let batches = batches
+            .into_iter()
+            .map(|batch| {
+                enrichment
+                    .apply(batch)
+                    .and_then(|batch| Self::apply_timestamp_source(batch, TimestampSource::from_env()))
+                    .and_then(Self::resolve_partition_dates)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Drop rows already written within the dedup window, e.g. a batch flushed again after a retry. Under the
+        // upsert policy a repeated id is an update to apply, so nothing is skipped.
+        let now = chrono::Utc::now();
+        let dedup = self.dedup.as_ref().filter(|_| DuplicateIdPolicy::from_env() != DuplicateIdPolicy::Upsert);
+        let batches = match dedup {
+            Some(dedup) => dedup.filter(batches, now)?,
+            None => batches,
+        };
+
+        // Route each project's rows to its own table, unregistered projects fall back to default
+        for (project_id, project_batches) in Self::group_by_project(batches)? {
+            let table_ref = {
+                let configs = self.project_configs.read().await;
+                match configs.get(&project_id) {
+                    Some((_, _, table)) => Arc::clone(table),
+                    None => {
+                        let rows: usize = project_batches.iter().map(|b| b.num_rows()).sum();
+                        log::warn!("Project '{}' not registered, writing {} rows to the default table", project_id, rows);
+                        let (_, _, table) = configs.get("default").ok_or_else(|| anyhow::anyhow!("Project ID '{}' not found", "default"))?;
+                        Arc::clone(table)
+                    }
                 }
+            };
+            let project_batches = self.encrypt_columns(&project_id, project_batches).await?;
+            let rows: usize = project_batches.iter().map(|b| b.num_rows()).sum();
+            if let Err(e) = Self::write_batches(&table_ref, project_batches.clone()).await {
+                increment_counter(INGEST_ERRORS_TOTAL, &project_id, 1);
+                // Delta commits are atomic: files uploaded before a failed commit are never referenced by the log,
+                // so readers keep seeing the previous version and vacuum removes the files once they age out
+                if e.downcast_ref::<deltalake::DeltaTableError>().is_some() {
+                    log::warn!(
+                        "Delta write of {} rows for project '{}' failed, table left at its last commit: {}",
+                        rows,
+                        project_id,
+                        e
+                    );
+                    increment_counter(FAILED_COMMITS_TOTAL, &project_id, 1);
+                }
+                return Err(e);
+            }
+            increment_counter(RECORDS_INGESTED_TOTAL, &project_id, rows as u64);
+            if let Some(cache) = &self.query_cache {
+                cache.note_write(&project_id);
+            }
+            if let Some(dedup) = dedup {
+                dedup.mark_written(&project_batches, now).await?;
             }
-            return Ok(());
         }
 
-        // Direct insert logic if skip_queue=true, queue disabled, no batch queue, or when processing from batch queue
-        let (_conn_str, _options, table_ref) = {
-            let configs = self.project_configs.read().await;
-            configs.get("default").ok_or_else(|| anyhow::anyhow!("Project ID '{}' not found", "default"))?.clone()
+        Ok(())
+    }
+
+    /// Encrypt the project's configured sensitive columns before they are written
+    async fn encrypt_columns(&self, project_id: &str, batches: Vec<RecordBatch>) -> Result<Vec<RecordBatch>> {
+        let columns = match self.encrypted_columns.read().await.get(project_id) {
+            Some(columns) if !columns.is_empty() => columns.clone(),
+            _ => return Ok(batches),
         };
+        let master_key =
+            master_key_from_env()?.ok_or_else(|| anyhow::anyhow!("Project '{}' has encrypted columns but COLUMN_ENCRYPTION_KEY is not set", project_id))?;
+        let cipher = ColumnCipher::for_project(&master_key, project_id)?;
+        batches.into_iter().map(|batch| cipher.encrypt_batch(batch, &columns)).collect()
+    }
+
+    /// Split batches into per-project groups using their `project_id` column
+    pub(crate) fn group_by_project(batches: Vec<RecordBatch>) -> Result<HashMap<String, Vec<RecordBatch>>> {
+        use datafusion::arrow::array::{AsArray, BooleanArray};
+        use datafusion::arrow::compute::filter_record_batch;
+        use std::collections::BTreeSet;
+
+        let mut groups: HashMap<String, Vec<RecordBatch>> = HashMap::new();
+        for batch in batches {
+            let project_ids = batch
+                .column_by_name("project_id")
+                .and_then(|c| c.as_string_opt::<i32>())
+                .ok_or_else(|| anyhow::anyhow!("Batch is missing the project_id column"))?;
+            let distinct: BTreeSet<String> = project_ids.iter().flatten().map(|p| p.to_string()).collect();
+
+            if distinct.len() <= 1 {
+                let project_id = distinct.into_iter().next().unwrap_or_else(|| "default".to_string());
+                groups.entry(project_id).or_default().push(batch);
+                continue;
+            }
+
+            for project_id in distinct {
+                let mask: BooleanArray = project_ids.iter().map(|p| Some(p == Some(project_id.as_str()))).collect();
+                groups.entry(project_id).or_default().push(filter_record_batch(&batch, &mask)?);
+            }
+        }
+        Ok(groups)
+    }
 
-        // Create writer properties with ZSTD compression level 6 and bloom filters
+    /// Write batches to a single table in one commit, applying the DUPLICATE_ID_POLICY
+    async fn write_batches(table_ref: &Arc<RwLock<DeltaTable>>, batches: Vec<RecordBatch>) -> Result<()> {
+        // Create writer properties with the configured compression and bloom filters
         let writer_properties = WriterProperties::builder()
-            .set_compression(Compression::ZSTD(ZstdLevel::try_new(6).unwrap()))
+            .set_compression(parquet_compression())
             .set_bloom_filter_enabled(true)
             .set_sorting_columns(Some(OtelLogsAndSpans::sorting_columns()))
             .build();
@@ -413,13 +1663,49 @@ impl Database {
         {
             let mut table = table_ref.write().await;
 
-            // Create the DeltaOps with a clone of the table
-            let write_op = DeltaOps(table.clone())
-                .write(batches)
-                .with_partition_columns(OtelLogsAndSpans::partitions())
-                .with_writer_properties(writer_properties);
+            // Batches with columns the table doesn't have yet, e.g. fields added to OtelLogsAndSpans after the
+            // table was created, evolve the table schema; existing rows read the new columns as NULL
+            let new_columns = Self::columns_missing_from_table(&table, &batches)?;
+            let schema_mode = if new_columns.is_empty() {
+                None
+            } else {
+                info!("Adding column(s) {} to table {}", new_columns.join(", "), table.table_uri());
+                Some(SchemaMode::Merge)
+            };
+
+            let policy = DuplicateIdPolicy::from_env();
+            if policy == DuplicateIdPolicy::Reject {
+                let duplicates = Self::duplicate_ids(&table, &batches).await?;
+                if !duplicates.is_empty() {
+                    return Err(anyhow::anyhow!(
+                        "Write rejected: {} record id(s) already exist: {}",
+                        duplicates.len(),
+                        duplicates.iter().take(10).cloned().collect::<Vec<_>>().join(", ")
+                    ));
+                }
+            }
 
-            let new_table = write_op.await?;
+            let new_table = match policy {
+                DuplicateIdPolicy::Upsert => {
+                    // MERGE doesn't evolve the target schema, so the new columns are added in their own commit first
+                    let mut target = table.clone();
+                    if !new_columns.is_empty() {
+                        let fields: Vec<StructField> = OtelLogsAndSpans::columns()?.into_iter().filter(|c| new_columns.contains(c.name())).collect();
+                        target = DeltaOps(target).add_columns().with_fields(fields).await?;
+                    }
+                    Self::upsert_batches(&target, batches, writer_properties).await?
+                }
+                DuplicateIdPolicy::Allow | DuplicateIdPolicy::Reject => {
+                    let mut write = DeltaOps(table.clone())
+                        .write(batches)
+                        .with_partition_columns(OtelLogsAndSpans::partitions())
+                        .with_writer_properties(writer_properties);
+                    if let Some(mode) = schema_mode {
+                        write = write.with_schema_mode(mode);
+                    }
+                    write.await?
+                }
+            };
             *table = new_table;
 
             // Note: Checkpointing, optimization, and vacuum are now managed by scheduled jobs
@@ -428,815 +1714,3678 @@ impl Database {
         Ok(())
     }
 
-    #[cfg(test)]
-    pub async fn insert_records(&self, records: &Vec<crate::persistent_queue::OtelLogsAndSpans>) -> Result<()> {
-        // TODO: insert records doesn't need to accept a project_id as they can be read from the
-        // record.
-        // Records should be grouped by span, and separated into groups then inserted into the
-        // correct table.
-
-        use serde_arrow::schema::SchemaLike;
-
-        // Convert OtelLogsAndSpans records to Arrow RecordBatch format
-        let fields = OtelLogsAndSpans::fields()?;
-        let batch = serde_arrow::to_record_batch(&fields, &records)?;
-
-        // Call insert_records_batch with the converted batch to reuse common insertion logic
-        // In tests we always skip the queue for direct insertion
-        self.insert_records_batch("default", vec![batch], true).await
+    /// Columns of `batches` that the table's schema doesn't have
+    fn columns_missing_from_table(table: &DeltaTable, batches: &[RecordBatch]) -> Result<Vec<String>> {
+        let table_schema = table.snapshot()?.schema();
+        let mut missing: Vec<String> = batches
+            .iter()
+            .flat_map(|batch| batch.schema().fields().iter().map(|f| f.name().clone()).collect::<Vec<_>>())
+            .filter(|name| table_schema.field(name).is_none())
+            .collect();
+        missing.sort();
+        missing.dedup();
+        Ok(missing)
     }
 
-    /// Optimize the Delta table using Z-ordering on timestamp and id columns
-    /// This improves query performance for time-based queries
-    async fn optimize_table(&self, table_ref: &Arc<RwLock<DeltaTable>>) -> Result<()> {
-        // Log the start of the optimization operation
-        info!("Starting Delta table optimization with Z-ordering");
-
-        // Get a clone of the table to avoid holding the lock during the operation
-        let table_clone = {
-            let table = table_ref.read().await;
-            table.clone()
+    /// Add the columns this build expects but the project's table lacks, without writing any rows. Only nullable
+    /// columns can be added, since existing rows have no value for them. Returns the added column names.
+    pub async fn evolve_schema(&self, project_id: &str) -> Result<Vec<String>> {
+        let table_ref = {
+            let configs = self.project_configs.read().await;
+            let (_, _, table) = configs.get(project_id).ok_or_else(|| anyhow::anyhow!("Project ID '{}' not found", project_id))?;
+            Arc::clone(table)
         };
+        let mut table = table_ref.write().await;
+        table.update().await?;
 
-        // Run optimize operation with Z-order on the timestamp and id columns
-        // and a target size of 256MB for optimal file size
-        let writer_properties = WriterProperties::builder()
-            .set_compression(Compression::ZSTD(ZstdLevel::try_new(6).unwrap()))
-            .set_bloom_filter_enabled(true)
-            .set_sorting_columns(Some(OtelLogsAndSpans::sorting_columns()))
-            .build();
+        let table_schema = table.snapshot()?.schema().clone();
+        let missing: Vec<StructField> = OtelLogsAndSpans::columns()?.into_iter().filter(|c| table_schema.field(c.name()).is_none()).collect();
+        if missing.is_empty() {
+            return Ok(vec![]);
+        }
+        let required: Vec<&str> = missing.iter().filter(|c| !c.is_nullable()).map(|c| c.name().as_str()).collect();
+        if !required.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Can't add non-nullable column(s) {} to the existing table of project '{}'",
+                required.join(", "),
+                project_id
+            ));
+        }
 
-        // Note: Z-order functionality is achieved through sorting_columns in writer_properties
-        let optimize_result = DeltaOps(table_clone)
-            .optimize()
-            .with_type(deltalake::operations::optimize::OptimizeType::ZOrder(OtelLogsAndSpans::z_order_columns()))
-            .with_target_size(268435456) // 256MB
-            .with_writer_properties(writer_properties)
-            .await;
+        let added: Vec<String> = missing.iter().map(|c| c.name().clone()).collect();
+        *table = DeltaOps(table.clone()).add_columns().with_fields(missing).await?;
+        info!("Added column(s) {} to the table of project '{}'", added.join(", "), project_id);
+        Ok(added)
+    }
 
-        match optimize_result {
-            Ok((new_table, metrics)) => {
-                info!(
-                    "Optimization with sorted columns completed: {} files removed, {} files added, {} partitions optimized, {} total files considered, {} files skipped",
-                    metrics.num_files_removed,
-                    metrics.num_files_added,
-                    metrics.partitions_optimized,
-                    metrics.total_considered_files,
-                    metrics.total_files_skipped
-                );
+    /// Make `timestamp` follow the configured source while keeping both times stored: a missing
+    /// `observed_timestamp` is filled with the receipt time, and in `observed` mode the event time is moved
+    /// to `start_time` (unless already set) and the row's `date` is re-derived from the new timestamp.
+    fn apply_timestamp_source(batch: RecordBatch, source: TimestampSource) -> Result<RecordBatch> {
+        use datafusion::arrow::array::{AsArray, Date32Array, TimestampMicrosecondArray};
+        use datafusion::arrow::datatypes::{Date32Type, TimestampMicrosecondType};
 
-                // Update the table reference with the optimized version
-                let mut table = table_ref.write().await;
-                *table = new_table;
+        let schema = batch.schema();
+        let (Ok(ts_idx), Ok(observed_idx)) = (schema.index_of("timestamp"), schema.index_of("observed_timestamp")) else {
+            return Ok(batch);
+        };
+        let (Some(timestamps), Some(observed)) = (
+            batch.column(ts_idx).as_primitive_opt::<TimestampMicrosecondType>(),
+            batch.column(observed_idx).as_primitive_opt::<TimestampMicrosecondType>(),
+        ) else {
+            return Ok(batch);
+        };
 
-                Ok(())
+        let tz = |idx: usize| match schema.field(idx).data_type() {
+            arrow_schema::DataType::Timestamp(_, tz) => tz.clone(),
+            _ => None,
+        };
+        let received = chrono::Utc::now().timestamp_micros();
+        let observed: TimestampMicrosecondArray = observed.iter().map(|o| o.or(Some(received))).collect();
+        let mut columns = batch.columns().to_vec();
+
+        if source == TimestampSource::Observed {
+            if let Ok(start_idx) = schema.index_of("start_time") {
+                if let Some(start_times) = batch.column(start_idx).as_primitive_opt::<TimestampMicrosecondType>() {
+                    let start_times: TimestampMicrosecondArray = start_times.iter().zip(timestamps.iter()).map(|(start, ts)| start.or(ts)).collect();
+                    columns[start_idx] = Arc::new(start_times.with_timezone_opt(tz(start_idx)));
+                }
             }
-            Err(e) => {
-                error!("Optimization operation failed: {}", e);
-                Err(anyhow::anyhow!("Table optimization failed: {}", e))
+            if let Ok(date_idx) = schema.index_of("date") {
+                if batch.column(date_idx).as_primitive_opt::<Date32Type>().is_some() {
+                    // Reset to the unset default so resolve_partition_dates derives it from the observed time
+                    columns[date_idx] = Arc::new(Date32Array::from(vec![0; batch.num_rows()]));
+                }
             }
+            columns[ts_idx] = Arc::new(observed.clone().with_timezone_opt(tz(ts_idx)));
         }
+        columns[observed_idx] = Arc::new(observed.with_timezone_opt(tz(observed_idx)));
+
+        Ok(RecordBatch::try_new(schema, columns)?)
     }
 
-    /// Vacuum the Delta table to clean up old files that are no longer needed
-    /// This reduces storage costs and improves query performance
-    async fn vacuum_table(&self, table_ref: &Arc<RwLock<DeltaTable>>, retention_hours: u64) {
-        // Log the start of the vacuum operation
-        info!("Starting vacuum operation with retention period of {} hours", retention_hours);
+    /// Fill in the `date` partition from `timestamp` for rows that didn't set one (left at the 1970-01-01 default).
+    /// An explicitly supplied date is kept, e.g. for backfills, but a warning is logged when it differs from the
+    /// timestamp's date.
+    fn resolve_partition_dates(batch: RecordBatch) -> Result<RecordBatch> {
+        use datafusion::arrow::array::{AsArray, Date32Array};
+        use datafusion::arrow::datatypes::{Date32Type, TimestampMicrosecondType};
 
-        // Get a clone of the table to avoid holding the lock during the operation
-        let table_clone = {
-            let table = table_ref.read().await;
-            table.clone()
+        let schema = batch.schema();
+        let (Ok(date_idx), Ok(ts_idx)) = (schema.index_of("date"), schema.index_of("timestamp")) else {
+            return Ok(batch);
+        };
+        let (Some(dates), Some(timestamps)) = (
+            batch.column(date_idx).as_primitive_opt::<Date32Type>(),
+            batch.column(ts_idx).as_primitive_opt::<TimestampMicrosecondType>(),
+        ) else {
+            return Ok(batch);
         };
 
-        // Directly run vacuum without dry run to delete old files
-        match DeltaOps(table_clone)
-            .vacuum()
-            .with_retention_period(chrono::Duration::hours(retention_hours as i64))
-            .with_enforce_retention_duration(false) // Allow deletion of files newer than default retention
-            .await
-        {
-            Ok((_, metrics)) => {
-                let files_deleted = metrics.files_deleted.len();
-                info!("Vacuum completed successfully, deleted {} files", files_deleted);
-
-                // Update the table reference with the vacuumed version
-                let mut table = table_ref.write().await;
-                if let Ok(()) = table.update().await {
-                    info!("Table updated after vacuum");
-                } else {
-                    error!("Failed to update table after vacuum");
+        let mut mismatched = 0;
+        let resolved: Date32Array = dates
+            .iter()
+            .zip(timestamps.iter())
+            .map(|(date, ts)| {
+                let ts_date = ts.map(|micros| micros.div_euclid(86_400_000_000) as i32);
+                match (date, ts_date) {
+                    (None | Some(0), ts_date) => ts_date.or(date),
+                    (Some(date), Some(ts_date)) => {
+                        if date != ts_date {
+                            mismatched += 1;
+                        }
+                        Some(date)
+                    }
+                    (date, None) => date,
                 }
-            }
-            Err(e) => error!("Vacuum operation failed: {}", e),
+            })
+            .collect();
+
+        if mismatched > 0 {
+            log::warn!(
+                "{} record(s) have an explicit partition date that differs from their timestamp's date",
+                mismatched
+            );
         }
+
+        let mut columns = batch.columns().to_vec();
+        columns[date_idx] = Arc::new(resolved);
+        Ok(RecordBatch::try_new(schema, columns)?)
     }
 
-    pub async fn register_project(
-        &self, project_id: &str, conn_str: &str, access_key: Option<&str>, secret_key: Option<&str>, endpoint: Option<&str>,
-    ) -> Result<()> {
-        let mut storage_options = StorageOptions::default();
+    /// Find record ids in `batches` that already exist in the table or repeat within the batches.
+    /// The lookup is restricted to the project_id/date partitions being written so only those files are scanned.
+    async fn duplicate_ids(table: &DeltaTable, batches: &[RecordBatch]) -> Result<Vec<String>> {
+        use datafusion::arrow::array::AsArray;
+        use datafusion::arrow::compute::cast;
+        use datafusion::arrow::datatypes::{DataType, Date32Type};
+        use datafusion::prelude::{col, lit};
+
+        let mut ids = HashSet::new();
+        let mut project_ids = HashSet::new();
+        let mut dates = HashSet::new();
+        let mut duplicates = Vec::new();
+
+        for batch in batches {
+            let id_col = batch.column_by_name("id").and_then(|c| c.as_string_opt::<i32>());
+            let project_col = batch.column_by_name("project_id").and_then(|c| c.as_string_opt::<i32>());
+            let date_col = batch.column_by_name("date").and_then(|c| c.as_primitive_opt::<Date32Type>());
+            let (Some(id_col), Some(project_col), Some(date_col)) = (id_col, project_col, date_col) else {
+                return Err(anyhow::anyhow!("Batch is missing the id, project_id or date column"));
+            };
 
-        if let Some(key) = access_key.filter(|k| !k.is_empty()) {
-            storage_options.0.insert("AWS_ACCESS_KEY_ID".to_string(), key.to_string());
+            for i in 0..batch.num_rows() {
+                if id_col.is_null(i) {
+                    continue;
+                }
+                let id = id_col.value(i).to_string();
+                if !ids.insert(id.clone()) {
+                    duplicates.push(id);
+                }
+                project_ids.insert(project_col.value(i).to_string());
+                dates.insert(date_col.value(i));
+            }
         }
 
-        if let Some(key) = secret_key.filter(|k| !k.is_empty()) {
-            storage_options.0.insert("AWS_SECRET_ACCESS_KEY".to_string(), key.to_string());
+        if ids.is_empty() {
+            return Ok(duplicates);
         }
 
-        if let Some(ep) = endpoint.filter(|e| !e.is_empty()) {
-            storage_options.0.insert("AWS_ENDPOINT".to_string(), ep.to_string());
+        let predicate = col("project_id")
+            .in_list(project_ids.into_iter().map(lit).collect(), false)
+            .and(col("date").in_list(dates.into_iter().map(|d| lit(ScalarValue::Date32(Some(d)))).collect(), false))
+            .and(col("id").in_list(ids.iter().map(|id| lit(id.as_str())).collect(), false));
+
+        let existing = SessionContext::new().read_table(Arc::new(table.clone()))?.filter(predicate)?.select_columns(&["id"])?.collect().await?;
+
+        for batch in existing {
+            let ids = cast(batch.column(0), &DataType::Utf8)?;
+            duplicates.extend(ids.as_string::<i32>().iter().flatten().map(|id| id.to_string()));
         }
 
-        storage_options.0.insert("AWS_ALLOW_HTTP".to_string(), "true".to_string());
+        Ok(duplicates)
+    }
 
-        let table = match DeltaTableBuilder::from_uri(conn_str).with_storage_options(storage_options.0.clone()).with_allow_http(true).load().await {
-            Ok(table) => {
-                // Check if table needs checkpointing - use same threshold as in insert_records_batch
-                let version = table.version();
-                // Only checkpoint if it's a multiple of 20 to be consistent with our write policy
-                if version > 0 && version % 20 == 0 {
-                    info!("Checkpointing table for project '{}' at initial load, version {}", project_id, version);
-                    checkpoints::create_checkpoint(&table, None).await?;
-                }
-                table
-            }
-            Err(err) => {
-                log::warn!("table doesn't exist. creating new table. err: {:?}", err);
+    /// Merge `batches` into the table: rows whose id already exists in the same partition are
+    /// replaced, all other rows are inserted.
+    async fn upsert_batches(table: &DeltaTable, batches: Vec<RecordBatch>, writer_properties: WriterProperties) -> Result<DeltaTable> {
+        use datafusion::prelude::col;
 
-                // Create the table with project_id partitioning only for now
-                // Timestamp partitioning is likely causing issues with nanosecond precision
-                let delta_ops = DeltaOps::try_from_uri(&conn_str).await?;
-                let commit_properties = CommitProperties::default().with_create_checkpoint(true).with_cleanup_expired_logs(Some(true));
+        let source = SessionContext::new().read_batches(batches)?;
+        let partitions = OtelLogsAndSpans::partitions();
+        let columns: Vec<String> = source.schema().fields().iter().map(|f| f.name().clone()).collect();
 
-                // Create table with ZSTD compression and auto-optimization
-                // Note: z-ordering will be applied via sorting_columns in the writer properties
-                delta_ops
-                    .create()
-                    .with_columns(OtelLogsAndSpans::columns().unwrap_or_default())
-                    .with_partition_columns(OtelLogsAndSpans::partitions())
-                    .with_storage_options(storage_options.0.clone())
-                    .with_commit_properties(commit_properties)
-                    .with_configuration_property(deltalake::TableProperty::AutoOptimizeOptimizeWrite, Some("true"))
-                    .with_configuration_property(deltalake::TableProperty::AutoOptimizeAutoCompact, Some("true"))
-                    .await?
-            }
-        };
+        let predicate = col("target.id")
+            .eq(col("source.id"))
+            .and(col("target.project_id").eq(col("source.project_id")))
+            .and(col("target.date").eq(col("source.date")));
 
-        let mut configs = self.project_configs.write().await;
-        configs.insert(project_id.to_string(), (conn_str.to_string(), storage_options, Arc::new(RwLock::new(table))));
-        Ok(())
+        let (new_table, metrics) = DeltaOps(table.clone())
+            .merge(source, predicate)
+            .with_source_alias("source")
+            .with_target_alias("target")
+            .with_writer_properties(writer_properties)
+            .when_matched_update(|update| {
+                columns
+                    .iter()
+                    .filter(|c| !partitions.contains(c))
+                    .fold(update, |update, c| update.update(c.as_str(), col(format!("source.{}", c))))
+            })?
+            .when_not_matched_insert(|insert| columns.iter().fold(insert, |insert, c| insert.set(c.as_str(), col(format!("source.{}", c)))))?
+            .await?;
+
+        debug!(
+            "Upsert completed: {} rows updated, {} rows inserted",
+            metrics.num_target_rows_updated, metrics.num_target_rows_inserted
+        );
+        Ok(new_table)
     }
-}
 
-#[derive(Debug, Clone)]
-pub struct ProjectRoutingTable {
-    default_project: String,
-    database: Arc<Database>,
-    schema: SchemaRef,
-    batch_queue: Option<Arc<crate::batch_queue::BatchQueue>>,
-}
+    /// Write OTLP metric data points to the metrics table
+    pub async fn insert_metrics(&self, records: &[OtelMetrics]) -> Result<()> {
+        use serde_arrow::schema::SchemaLike;
 
-impl ProjectRoutingTable {
-    pub fn new(default_project: String, database: Arc<Database>, schema: SchemaRef, batch_queue: Option<Arc<crate::batch_queue::BatchQueue>>) -> Self {
-        Self {
-            default_project,
-            database,
-            schema,
-            batch_queue,
+        if self.is_ingest_paused() {
+            return Err(anyhow::anyhow!("Ingestion is paused"));
+        }
+        if records.is_empty() {
+            return Ok(());
         }
-    }
 
-    fn extract_project_id_from_filters(&self, filters: &[Expr]) -> Option<String> {
-        // Look for expressions like "project_id = 'some_value'"
-        for filter in filters {
-            if let Some(project_id) = self.extract_project_id(filter) {
-                return Some(project_id);
-            }
+        let fields = OtelMetrics::fields()?;
+        let batch = serde_arrow::to_record_batch(&fields, &records)?;
+
+        let mut table = self.metrics_table.write().await;
+        let new_table = DeltaOps(table.clone())
+            .write(vec![batch])
+            .with_partition_columns(OtelMetrics::partitions())
+            .with_writer_properties(WriterProperties::builder().set_compression(parquet_compression()).build())
+            .await?;
+        *table = new_table;
+        // Metrics queries aren't tied to projects, so this marks every cached result that isn't either
+        if let Some(cache) = &self.query_cache {
+            cache.note_write(&OtelMetrics::table_name());
         }
-        None
-    }
 
-    fn schema(&self) -> SchemaRef {
-        OtelLogsAndSpans::schema_ref()
+        Ok(())
     }
 
-    fn extract_project_id(&self, expr: &Expr) -> Option<String> {
-        match expr {
-            // Binary expression: "project_id = 'value'"
-            Expr::BinaryExpr(BinaryExpr { left, op, right }) => {
-                // Check if this is an equality operation
-                if *op == Operator::Eq {
-                    // Check if left side is a column reference to "project_id"
-                    if let Expr::Column(col) = left.as_ref() {
-                        if col.name == "project_id" {
-                            // Check if right side is a literal string
-                            if let Expr::Literal(ScalarValue::Utf8(Some(value))) = right.as_ref() {
-                                return Some(value.clone());
-                            }
-                        }
-                    }
-
-                    // Also check if right side is the column (order might be flipped)
-                    if let Expr::Column(col) = right.as_ref() {
-                        if col.name == "project_id" {
-                            // Check if left side is a literal string
-                            if let Expr::Literal(ScalarValue::Utf8(Some(value))) = left.as_ref() {
-                                return Some(value.clone());
-                            }
-                        }
-                    }
-                }
-                None
+    /// Get the metrics table, updated to its latest version
+    pub async fn resolve_metrics_table(&self) -> DFResult<Arc<RwLock<DeltaTable>>> {
+        {
+            let mut table = self.metrics_table.write().await;
+            if let Err(e) = table.update().await {
+                error!("Failed to update metrics table: {}", e);
             }
-            // // Recursive: AND, OR expressions
-            // Expr::BooleanQuery { operands, .. } => {
-            //     for operand in operands {
-            //         if let Some(project_id) = self.extract_project_id(operand) {
-            //             return Some(project_id);
-            //         }
-            //     }
-            //     None
-            // }
-            // Look inside NOT expressions
-            Expr::Not(inner) => self.extract_project_id(inner),
-            _ => None,
         }
+        Ok(Arc::clone(&self.metrics_table))
     }
-}
 
-// Needed by DataSink
-impl DisplayAs for ProjectRoutingTable {
-    fn fmt_as(&self, t: DisplayFormatType, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match t {
-            DisplayFormatType::Default | DisplayFormatType::Verbose => {
-                write!(f, "ProjectRoutingTable ")
-            } // DisplayFormatType::TreeRender => {
-              //     // TODO: collect info
-              //     write!(f, "")
-              // }
+    /// Write a slice of records as a single Arrow RecordBatch in one Delta commit, partitioned by
+    /// `OtelLogsAndSpans::partitions()`. An empty slice is a no-op.
+    pub async fn write_many(&self, records: &[OtelLogsAndSpans]) -> Result<()> {
+        if records.is_empty() {
+            return Ok(());
         }
-    }
-}
 
-#[async_trait]
-impl DataSink for ProjectRoutingTable {
-    fn schema(&self) -> &SchemaRef {
-        &self.schema
+        // Bypass the queue, the whole slice is already one batch
+        self.insert_records_batch("default", vec![Self::records_to_batch(records)?], true).await
     }
 
-    async fn write_all(&self, mut data: SendableRecordBatchStream, _context: &Arc<TaskContext>) -> DFResult<u64> {
-        let mut row_count = 0;
-        let mut batches = Vec::new();
-
-        // Collect all batches from the stream
-        while let Some(batch) = data.next().await.transpose()? {
-            row_count += batch.num_rows();
-            batches.push(batch);
-        }
-
-        if batches.is_empty() {
-            return Ok(0);
+    /// Ingest records through the batch queue when it is enabled, writing directly otherwise
+    pub async fn ingest_many(&self, records: &[OtelLogsAndSpans]) -> Result<()> {
+        if records.is_empty() {
+            return Ok(());
         }
 
-        // Let the database handle the queue decision with skip_queue=false
-        // This means it will use the queue if it's available and not disabled via env var
-        self.database
-            .insert_records_batch("", batches, false)
-            .await
-            .map_err(|e| DataFusionError::Execution(format!("Insert error: {}", e)))?;
-
-        Ok(row_count as u64)
+        self.insert_records_batch("default", vec![Self::records_to_batch(records)?], false).await
     }
 
-    fn as_any(&self) -> &dyn Any {
-        self
-    }
-}
+    fn records_to_batch(records: &[OtelLogsAndSpans]) -> Result<RecordBatch> {
+        use serde_arrow::schema::SchemaLike;
 
-#[async_trait]
-impl TableProvider for ProjectRoutingTable {
-    fn as_any(&self) -> &dyn Any {
-        self
+        let fields = OtelLogsAndSpans::fields()?;
+        Ok(serde_arrow::to_record_batch(&fields, &records)?)
     }
 
-    fn table_type(&self) -> TableType {
-        TableType::Base
+    #[cfg(test)]
+    pub async fn insert_records(&self, records: &Vec<crate::persistent_queue::OtelLogsAndSpans>) -> Result<()> {
+        self.write_many(records).await
     }
 
-    fn schema(&self) -> SchemaRef {
-        self.schema()
-    }
+    /// Bin-pack small files of every registered project's table. Each table's write lock is held while it is
+    /// compacted so no write can interleave. All projects are attempted; failures are combined into one error.
+    pub async fn compact_all_projects(&self) -> Result<()> {
+        use deltalake::operations::optimize::OptimizeType;
 
-    async fn insert_into(&self, _state: &dyn Session, input: Arc<dyn ExecutionPlan>, insert_op: InsertOp) -> DFResult<Arc<dyn ExecutionPlan>> {
-        // Create a physical plan from the logical plan.
-        // Check that the schema of the plan matches the schema of this table.
-        match self.schema().logically_equivalent_names_and_types(&input.schema()) {
-            Ok(_) => debug!("insert_into; Schema validation passed"),
-            Err(e) => {
-                error!("Schema validation failed: {}", e);
-                return Err(e);
+        let tables: Vec<(String, Arc<RwLock<DeltaTable>>)> =
+            self.project_configs.read().await.iter().map(|(id, (_, _, table))| (id.clone(), Arc::clone(table))).collect();
+
+        let mut failures = Vec::new();
+        for (project_id, table_ref) in tables {
+            let mut table = table_ref.write().await;
+            let writer_properties = WriterProperties::builder()
+                .set_compression(parquet_compression())
+                .set_bloom_filter_enabled(true)
+                .set_sorting_columns(Some(OtelLogsAndSpans::sorting_columns()))
+                .build();
+
+            match DeltaOps(table.clone()).optimize().with_type(OptimizeType::Compact).with_writer_properties(writer_properties).await {
+                Ok((new_table, metrics)) => {
+                    info!(
+                        "Compacted project '{}': {} files removed, {} files added",
+                        project_id, metrics.num_files_removed, metrics.num_files_added
+                    );
+                    *table = new_table;
+                }
+                Err(e) => {
+                    error!("Compaction failed for {}: {}", project_id, e);
+                    failures.push(format!("{}: {}", project_id, e));
+                }
             }
         }
 
-        if insert_op != InsertOp::Append {
-            error!("Unsupported insert operation: {:?}", insert_op);
-            return not_impl_err!("{insert_op} not implemented for MemoryTable yet");
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("Compaction failed for {} project(s): {}", failures.len(), failures.join("; ")))
         }
-
-        // Create sink executor but with additional logging
-        let sink = DataSinkExec::new(input, Arc::new(self.clone()), None);
-
-        Ok(Arc::new(sink))
     }
 
-    fn supports_filters_pushdown(&self, filter: &[&Expr]) -> DFResult<Vec<TableProviderFilterPushDown>> {
-        Ok(filter.iter().map(|_| TableProviderFilterPushDown::Inexact).collect())
+    /// Optimize the Delta table using Z-ordering on the configured columns (see `zorder_columns`)
+    /// This improves query performance for time-based queries
+    async fn optimize_table(&self, table_ref: &Arc<RwLock<DeltaTable>>) -> Result<()> {
+        self.zorder_table(table_ref, zorder_columns()).await
     }
 
-    async fn scan(&self, state: &dyn Session, projection: Option<&Vec<usize>>, filters: &[Expr], limit: Option<usize>) -> DFResult<Arc<dyn ExecutionPlan>> {
-        // Get project_id from filters if possible, otherwise use default
-        let project_id = self.extract_project_id_from_filters(filters).unwrap_or_else(|| self.default_project.clone());
-
-        let delta_table = self.database.resolve_table(&project_id).await?;
-        let table = delta_table.read().await;
-        table.scan(state, projection, filters, limit).await
+    /// Z-order a project's table by `columns`, e.g. `["context___trace_id", "timestamp"]` for trace lookups
+    /// within a time range. Columns must exist in the schema and can't be partition columns.
+    pub async fn zorder_project(&self, project_id: &str, columns: &[String]) -> Result<()> {
+        validate_zorder_columns(columns)?;
+        let table_ref = match self.project_configs.read().await.get(project_id) {
+            Some((_, _, table)) => Arc::clone(table),
+            None => return Err(anyhow::anyhow!("Project ID '{}' not found", project_id)),
+        };
+        self.zorder_table(&table_ref, columns.to_vec()).await
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use chrono::{TimeZone, Utc};
-    use datafusion::assert_batches_eq;
-    use datafusion::prelude::SessionContext;
-    use dotenv::dotenv;
-    use serial_test::serial;
-    use uuid::Uuid;
+    async fn zorder_table(&self, table_ref: &Arc<RwLock<DeltaTable>>, columns: Vec<String>) -> Result<()> {
+        // Log the start of the optimization operation
+        info!("Starting Delta table optimization with Z-ordering on {:?}", columns);
 
-    use super::*;
+        // Get a clone of the table to avoid holding the lock during the operation
+        let table_clone = {
+            let table = table_ref.read().await;
+            table.clone()
+        };
 
-    // Helper function to create a test database with a unique table prefix
-    async fn setup_test_database(prefix: String) -> Result<(Database, SessionContext, String)> {
-        let _ = env_logger::builder().is_test(true).try_init();
-        dotenv().ok();
+        // Run optimize operation with Z-order on the timestamp and id columns
+        // and a target size of 256MB for optimal file size
+        let writer_properties = WriterProperties::builder()
+            .set_compression(parquet_compression())
+            .set_bloom_filter_enabled(true)
+            .set_sorting_columns(Some(OtelLogsAndSpans::sorting_columns()))
+            .build();
 
-        // Set a unique test-specific prefix for a clean Delta table
-        let test_prefix = format!("test-data-{}", prefix);
-        unsafe {
-            env::set_var("TIMEFUSION_TABLE_PREFIX", &test_prefix);
-        }
+        // Note: Z-order functionality is achieved through sorting_columns in writer_properties
+        let optimize_result = DeltaOps(table_clone)
+            .optimize()
+            .with_type(deltalake::operations::optimize::OptimizeType::ZOrder(columns))
+            .with_target_size(268435456) // 256MB
+            .with_writer_properties(writer_properties)
+            .await;
 
-        let db = Database::new().await?;
-        let mut session_context = SessionContext::new();
-        datafusion_functions_json::register_all(&mut session_context)?;
-        let schema = OtelLogsAndSpans::schema_ref();
+        match optimize_result {
+            Ok((new_table, metrics)) => {
+                info!(
+                    "Optimization with sorted columns completed: {} files removed, {} files added, {} partitions optimized, {} total files considered, {} files skipped",
+                    metrics.num_files_removed,
+                    metrics.num_files_added,
+                    metrics.partitions_optimized,
+                    metrics.total_considered_files,
+                    metrics.total_files_skipped
+                );
 
-        let routing_table = ProjectRoutingTable::new(
-            "default".to_string(),
+                // Update the table reference with the optimized version
+                let mut table = table_ref.write().await;
+                *table = new_table;
+
+                Ok(())
+            }
+            Err(e) => {
+                error!("Optimization operation failed: {}", e);
+                Err(anyhow::anyhow!("Table optimization failed: {}", e))
+            }
+        }
+    }
+
+    /// Vacuum the Delta table to clean up old files that are no longer needed
+    /// This reduces storage costs and improves query performance
+    async fn vacuum_table(&self, project_id: &str, table_ref: &Arc<RwLock<DeltaTable>>, retention_hours: u64) {
+        // Log the start of the vacuum operation
+        info!("Starting vacuum operation with retention period of {} hours", retention_hours);
+
+        // Get a clone of the table to avoid holding the lock during the operation
+        let table_clone = {
+            let table = table_ref.read().await;
+            table.clone()
+        };
+
+        // Directly run vacuum without dry run to delete old files
+        match DeltaOps(table_clone)
+            .vacuum()
+            .with_retention_period(chrono::Duration::hours(retention_hours as i64))
+            .with_enforce_retention_duration(false) // Allow deletion of files newer than default retention
+            .await
+        {
+            Ok((_, metrics)) => {
+                let files_deleted = metrics.files_deleted.len();
+                info!("Vacuum completed successfully, deleted {} files", files_deleted);
+                increment_counter(FILES_VACUUMED_TOTAL, project_id, files_deleted as u64);
+
+                // Update the table reference with the vacuumed version
+                let mut table = table_ref.write().await;
+                if let Ok(()) = table.update().await {
+                    info!("Table updated after vacuum");
+                } else {
+                    error!("Failed to update table after vacuum");
+                }
+            }
+            Err(e) => error!("Vacuum operation failed: {}", e),
+        }
+    }
+
+    /// Reopen a project's table from storage under its write lock, e.g. after an external process wrote to the
+    /// bucket, so following queries see those writes. Returns the reloaded table version.
+    pub async fn reload_project(&self, project_id: &str) -> Result<i64> {
+        let (conn_str, storage_options, table_ref) = match self.project_configs.read().await.get(project_id) {
+            Some((conn_str, storage_options, table)) => (conn_str.clone(), storage_options.clone(), Arc::clone(table)),
+            None => return Err(anyhow::anyhow!("Project ID '{}' not found", project_id)),
+        };
+        let conn = ConnectionString::parse(&conn_str)?;
+
+        let mut table = table_ref.write().await;
+        let previous_version = table.version();
+        *table = DeltaTableBuilder::from_uri(&conn.uri).with_storage_options(storage_options.0).with_allow_http(true).load().await?;
+        info!("Reloaded project '{}' from version {} to {}", project_id, previous_version, table.version());
+        Ok(table.version())
+    }
+
+    /// Latest `timestamp` in a project's table, taken from the per-file max statistics of the Delta snapshot
+    /// so no data files are read. `None` for an empty table.
+    pub async fn max_timestamp(&self, project_id: &str) -> Result<Option<chrono::DateTime<chrono::Utc>>> {
+        use datafusion::arrow::array::AsArray;
+        use datafusion::arrow::compute::{cast, max};
+        use datafusion::arrow::datatypes::{DataType, TimeUnit, TimestampMicrosecondType};
+
+        let table_ref = match self.project_configs.read().await.get(project_id) {
+            Some((_, _, table)) => Arc::clone(table),
+            None => return Err(anyhow::anyhow!("Project ID '{}' not found", project_id)),
+        };
+        let mut table = table_ref.write().await;
+        table.update().await?;
+
+        let actions = table.snapshot()?.add_actions_table(true)?;
+        let Some(column) = actions.column_by_name("max.timestamp") else {
+            return Ok(None);
+        };
+        let column = cast(column, &DataType::Timestamp(TimeUnit::Microsecond, None))?;
+        Ok(max(column.as_primitive::<TimestampMicrosecondType>()).and_then(chrono::DateTime::from_timestamp_micros))
+    }
+
+    /// Update the `timefusion_ingest_lag_seconds` gauge of every project: how far its newest record is behind now
+    pub async fn refresh_ingest_lag(&self) {
+        let now = chrono::Utc::now();
+        let project_ids: Vec<String> = self.project_configs.read().await.keys().cloned().collect();
+        for project_id in project_ids {
+            match self.max_timestamp(&project_id).await {
+                Ok(Some(newest)) => set_gauge(INGEST_LAG_SECONDS, &project_id, (now - newest).num_milliseconds() as f64 / 1000.0),
+                Ok(None) => {}
+                Err(e) => error!("Failed to compute ingest lag for project '{}': {}", project_id, e),
+            }
+        }
+    }
+
+    /// Compare every registered project's table schema against `OtelLogsAndSpans::schema_ref()`.
+    /// Read-only: tables are refreshed but never altered.
+    pub async fn schema_check(&self) -> Result<std::collections::BTreeMap<String, SchemaDiff>> {
+        use deltalake::delta_datafusion::DataFusionMixins;
+
+        let expected = OtelLogsAndSpans::schema_ref();
+        let tables: Vec<(String, Arc<RwLock<DeltaTable>>)> =
+            self.project_configs.read().await.iter().map(|(id, (_, _, table))| (id.clone(), Arc::clone(table))).collect();
+
+        let mut report = std::collections::BTreeMap::new();
+        for (project_id, table_ref) in tables {
+            let mut table = table_ref.write().await;
+            table.update().await?;
+            // input_schema keeps partition columns unwrapped, arrow_schema would dictionary-encode them
+            let actual = table.snapshot()?.input_schema()?;
+            report.insert(project_id, SchemaDiff::between(&expected, &actual));
+        }
+        Ok(report)
+    }
+
+    /// Delete a project's records with `from <= timestamp < to` and return the number of rows removed
+    pub async fn delete_range(&self, project_id: &str, from: chrono::DateTime<chrono::Utc>, to: chrono::DateTime<chrono::Utc>) -> Result<u64> {
+        let table_ref = self.resolve_table(project_id).await?;
+        let predicate = format!(
+            "project_id = '{}' AND timestamp >= '{}' AND timestamp < '{}'",
+            project_id.replace('\'', "''"),
+            from.format("%Y-%m-%dT%H:%M:%S%.6f"),
+            to.format("%Y-%m-%dT%H:%M:%S%.6f")
+        );
+
+        let summary = Self::delete_where(project_id, &table_ref, predicate).await?;
+        if let Some(cache) = &self.query_cache {
+            cache.note_write(project_id);
+        }
+        info!(
+            "Deleted {} records of project '{}' between {} and {}",
+            summary.rows_deleted, project_id, from, to
+        );
+        Ok(summary.rows_deleted)
+    }
+
+    /// Retention: delete every record older than `cutoff` from a registered project's table.
+    /// The table's write lock is held for the whole delete so it can't race with an in-flight write.
+    pub async fn delete_older_than(&self, project_id: &str, cutoff: chrono::DateTime<chrono::Utc>) -> Result<DeleteSummary> {
+        let table_ref = match self.project_configs.read().await.get(project_id) {
+            Some((_, _, table)) => Arc::clone(table),
+            None => return Err(anyhow::anyhow!("Project ID '{}' not found", project_id)),
+        };
+
+        // The date bound lets Delta skip whole partitions, the timestamp bound makes the cut exact
+        let predicate = format!("date <= '{}' AND timestamp < '{}'", cutoff.date_naive(), cutoff.format("%Y-%m-%dT%H:%M:%S%.6f"));
+
+        let summary = Self::delete_where(project_id, &table_ref, predicate).await?;
+        if let Some(cache) = &self.query_cache {
+            cache.note_write(project_id);
+        }
+        info!(
+            "Retention removed {} records ({} files rewritten or removed) older than {} from project '{}'",
+            summary.rows_deleted, summary.files_removed, cutoff, project_id
+        );
+        Ok(summary)
+    }
+
+    /// `DELETE FROM otel_logs_and_spans WHERE ...`, returning the number of deleted rows. The `WHERE` clause must
+    /// name the project(s) with `project_id = ...` or `project_id IN (...)`.
+    pub async fn delete_record(&self, sql: &str) -> Result<u64> {
+        self.execute_dml(sql, WriteOp::Delete).await
+    }
+
+    /// `UPDATE otel_logs_and_spans SET ... WHERE ...`, returning the number of updated rows. The `WHERE` clause
+    /// must name the project(s) like for `delete_record`.
+    pub async fn update_record(&self, sql: &str) -> Result<u64> {
+        self.execute_dml(sql, WriteOp::Update).await
+    }
+
+    /// Called from Rust, so unlike SQL sessions this needs neither ENABLE_SQL_DML nor a project access list
+    async fn execute_dml(&self, sql: &str, op: WriteOp) -> Result<u64> {
+        use datafusion::logical_expr::LogicalPlan;
+
+        let ctx = self.create_session_context();
+        self.setup_session_context(&ctx)?;
+        let plan = ctx.state().create_logical_plan(sql).await?;
+        let plan = ctx.state().optimize(&plan)?;
+        match &plan {
+            LogicalPlan::Dml(dml) if dml.op == op => self.apply_dml(&DmlChange::from_plan(&dml.op, &dml.input)?, &ProjectAccess::all()).await,
+            _ => Err(anyhow::anyhow!("Expected a {} statement: {}", op, sql)),
+        }
+    }
+
+    /// Apply a `DELETE` or `UPDATE` to the table of every project its predicate names, unregistered projects being
+    /// stored in the default table. The predicate must have a top-level `project_id = '...'` or `project_id IN (...)`
+    /// condition, and `access` must allow those projects. `project_id` can't be updated, since that would move rows
+    /// between tables.
+    pub(crate) async fn apply_dml(&self, change: &DmlChange, access: &ProjectAccess) -> Result<u64> {
+        use datafusion::logical_expr::utils::split_conjunction;
+
+        let predicate = change.predicate.as_ref().ok_or_else(|| anyhow::anyhow!("{} needs a WHERE clause naming the project_id", change.op))?;
+        let project_ids = split_conjunction(predicate)
+            .into_iter()
+            .find_map(|conjunct| match conjunct {
+                Expr::BinaryExpr(BinaryExpr { op: Operator::Eq, .. }) | Expr::InList(InList { negated: false, .. }) => {
+                    ProjectRoutingTable::extract_project_ids_from_filters(std::slice::from_ref(conjunct))
+                }
+                _ => None,
+            })
+            .ok_or_else(|| anyhow::anyhow!("{} needs a project_id = '...' or project_id IN (...) condition", change.op))?;
+        if change.assignments.iter().any(|(column, _)| column == "project_id") {
+            return Err(anyhow::anyhow!("project_id can't be updated"));
+        }
+
+        let mut targets: Vec<String> = Vec::new();
+        {
+            let configs = self.project_configs.read().await;
+            for project_id in &project_ids {
+                let target = if configs.contains_key(project_id) { project_id.clone() } else { "default".to_string() };
+                access.check(project_id)?;
+                access.check(&target)?;
+                if !targets.contains(&target) {
+                    targets.push(target);
+                }
+            }
+        }
+
+        let mut affected = 0;
+        for target in targets {
+            if self.is_project_read_only(&target).await {
+                return Err(ProjectReadOnly { project_id: target }.into());
+            }
+            let table_ref = self.resolve_table(&target).await?;
+            let mut table = table_ref.write().await;
+            table.update().await?;
+            affected += match change.op {
+                WriteOp::Delete => {
+                    let (new_table, metrics) = DeltaOps(table.clone()).delete().with_predicate(predicate.clone()).await?;
+                    *table = new_table;
+                    increment_counter(RECORDS_DELETED_TOTAL, &target, metrics.num_deleted_rows as u64);
+                    metrics.num_deleted_rows as u64
+                }
+                WriteOp::Update => {
+                    let mut update = DeltaOps(table.clone()).update().with_predicate(predicate.clone());
+                    for (column, value) in &change.assignments {
+                        update = update.with_update(column.as_str(), value.clone());
+                    }
+                    let (new_table, metrics) = update.await?;
+                    *table = new_table;
+                    metrics.num_updated_rows as u64
+                }
+                _ => return Err(anyhow::anyhow!("{} is not supported here", change.op)),
+            };
+        }
+
+        if let Some(cache) = &self.query_cache {
+            for project_id in &project_ids {
+                cache.note_write(project_id);
+            }
+        }
+        info!("{} affected {} records of project(s) {:?}", change.op, affected, project_ids);
+        Ok(affected)
+    }
+
+    async fn delete_where(project_id: &str, table_ref: &Arc<RwLock<DeltaTable>>, predicate: String) -> Result<DeleteSummary> {
+        let mut table = table_ref.write().await;
+        table.update().await?;
+        let (new_table, metrics) = DeltaOps(table.clone()).delete().with_predicate(predicate).await?;
+        *table = new_table;
+
+        let summary = DeleteSummary {
+            rows_deleted: metrics.num_deleted_rows as u64,
+            files_removed: metrics.num_removed_files as u64,
+        };
+        increment_counter(RECORDS_DELETED_TOTAL, project_id, summary.rows_deleted);
+        Ok(summary)
+    }
+
+    /// Vacuum a project's table, deleting files no longer referenced by any version within `retention_hours`.
+    /// Retention shorter than the table's `deletedFileRetentionDuration` is allowed so operators can force an
+    /// aggressive cleanup. With `dry_run` nothing is deleted. Returns the paths of the (would-be) deleted files.
+    pub async fn vacuum_project(&self, project_id: &str, retention_hours: u64, dry_run: bool) -> Result<Vec<String>> {
+        let table_ref = match self.project_configs.read().await.get(project_id) {
+            Some((_, _, table)) => Arc::clone(table),
+            None => return Err(anyhow::anyhow!("Project ID '{}' not found", project_id)),
+        };
+
+        let mut table = table_ref.write().await;
+        table.update().await?;
+        let retention = Duration::from_secs(retention_hours * 3600);
+        let below_default = retention < table.snapshot()?.table_config().deleted_file_retention_duration();
+
+        let (new_table, metrics) = DeltaOps(table.clone())
+            .vacuum()
+            .with_retention_period(chrono::Duration::hours(retention_hours as i64))
+            .with_enforce_retention_duration(!below_default)
+            .with_dry_run(dry_run)
+            .await?;
+
+        if dry_run {
+            info!("Vacuum dry run for project '{}' would delete {} files", project_id, metrics.files_deleted.len());
+        } else {
+            info!("Vacuum deleted {} files from project '{}'", metrics.files_deleted.len(), project_id);
+            increment_counter(FILES_VACUUMED_TOTAL, project_id, metrics.files_deleted.len() as u64);
+            *table = new_table;
+        }
+        Ok(metrics.files_deleted)
+    }
+
+    /// Rows of `project_id` sharing `trace_id`, split into spans and logs (rows whose `kind` is `log` or `logs`),
+    /// each ordered by `timestamp`
+    pub async fn trace_view(&self, project_id: &str, trace_id: &str, include_logs: bool, access: &ProjectAccess) -> Result<TraceView> {
+        use datafusion::prelude::{col, lit};
+
+        let ctx = self.session_with_access(access)?;
+        let rows = ctx
+            .table(OtelLogsAndSpans::table_name())
+            .await?
+            .filter(col("project_id").eq(lit(project_id)).and(col("context___trace_id").eq(lit(trace_id))))?;
+        let is_log = col("kind").in_list(vec![lit("log"), lit("logs")], false);
+
+        let spans_df = rows.clone().filter(is_log.clone().is_not_true())?.sort(vec![col("timestamp").sort(true, true)])?;
+        let spans = Self::batches_to_json(&spans_df.collect().await?)?;
+
+        let (logs, orphan_logs) = if include_logs {
+            let logs_df = rows.filter(is_log)?.sort(vec![col("timestamp").sort(true, true)])?;
+            let logs = Self::batches_to_json(&logs_df.collect().await?)?;
+            let span_ids: std::collections::HashSet<&str> = spans.iter().filter_map(|s| s["context___span_id"].as_str()).collect();
+            let orphans = logs.iter().filter(|l| l["context___span_id"].as_str().is_none_or(|id| !span_ids.contains(id))).count();
+            (Some(logs), orphans)
+        } else {
+            (None, 0)
+        };
+
+        Ok(TraceView {
+            trace_id: trace_id.to_string(),
+            spans,
+            logs,
+            orphan_logs,
+        })
+    }
+
+    /// Render batches as JSON objects, one per row. Timestamps become RFC3339 strings; ones without a timezone are
+    /// stored in UTC and are marked as such, so clients don't parse them as local time.
+    fn batches_to_json(batches: &[RecordBatch]) -> Result<Vec<serde_json::Value>> {
+        use datafusion::arrow::compute::cast;
+        use datafusion::arrow::datatypes::{DataType, Field, Schema};
+
+        let batches = batches
+            .iter()
+            .map(|batch| {
+                let (fields, columns): (Vec<_>, Vec<_>) = batch
+                    .schema()
+                    .fields()
+                    .iter()
+                    .zip(batch.columns())
+                    .map(|(field, column)| match field.data_type() {
+                        DataType::Timestamp(unit, None) => {
+                            let utc = DataType::Timestamp(*unit, Some("UTC".into()));
+                            Ok((Arc::new(Field::new(field.name(), utc.clone(), field.is_nullable())), cast(column, &utc)?))
+                        }
+                        _ => Ok((Arc::clone(field), Arc::clone(column))),
+                    })
+                    .collect::<Result<Vec<_>>>()?
+                    .into_iter()
+                    .unzip();
+                Ok(RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)?)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut writer = datafusion::arrow::json::ArrayWriter::new(Vec::new());
+        writer.write_batches(&batches.iter().collect::<Vec<_>>())?;
+        writer.finish()?;
+        let bytes = writer.into_inner();
+        if bytes.is_empty() {
+            return Ok(vec![]);
+        }
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// Return the most recent commits of a project's table, newest first.
+    /// Returns `None` if the project is not registered.
+    pub async fn project_history(&self, project_id: &str, limit: Option<usize>) -> Result<Option<Vec<CommitSummary>>> {
+        let table_ref = match self.project_configs.read().await.get(project_id) {
+            Some((_, _, table)) => Arc::clone(table),
+            None => return Ok(None),
+        };
+
+        let mut table = table_ref.write().await;
+        table.update().await?;
+        let current_version = table.version();
+        let history = table.history(limit).await?;
+
+        let commits = history
+            .into_iter()
+            .enumerate()
+            .map(|(idx, commit)| {
+                // delta-rs and Spark name the operation metrics differently, so check both spellings
+                let metrics = commit.info.get("operationMetrics");
+                let metric = |keys: &[&str]| {
+                    metrics
+                        .and_then(|m| keys.iter().find_map(|k| m.get(*k)))
+                        .and_then(|v| v.as_u64().or_else(|| v.as_str().and_then(|s| s.parse().ok())))
+                };
+
+                CommitSummary {
+                    version: commit.read_version.map(|v| v + 1).unwrap_or(current_version - idx as i64),
+                    timestamp: commit.timestamp,
+                    rows_added: metric(&["num_added_rows", "numOutputRows", "numAddedRows"]),
+                    files_added: metric(&["num_added_files", "numFilesAdded", "numAddedFiles"]),
+                    files_removed: metric(&["num_removed_files", "numFilesRemoved", "numRemovedFiles"]),
+                    operation: commit.operation,
+                    operation_parameters: commit.operation_parameters,
+                }
+            })
+            .collect();
+
+        Ok(Some(commits))
+    }
+
+    pub async fn register_project(
+        &self, project_id: &str, conn_str: &str, access_key: Option<&str>, secret_key: Option<&str>, endpoint: Option<&str>,
+    ) -> Result<()> {
+        self.register(project_id, conn_str, access_key, secret_key, endpoint, true).await
+    }
+
+    /// Register a new project, failing with `ProjectAlreadyRegistered` if it exists. The check and the insert happen
+    /// under the same write lock, so of two concurrent creations only one succeeds.
+    pub async fn create_project(
+        &self, project_id: &str, conn_str: &str, access_key: Option<&str>, secret_key: Option<&str>, endpoint: Option<&str>,
+    ) -> Result<()> {
+        self.register_project_with(project_id, conn_str, access_key, secret_key, endpoint, true, true).await
+    }
+
+    /// Register the projects saved in PROJECT_REGISTRY_PATH with their settings. A project whose entry can't be
+    /// decrypted or whose table can't be opened is logged and skipped so one broken tenant doesn't block startup,
+    /// and its writes are refused until it is registered again.
+    async fn load_persisted_projects(&self) -> Result<()> {
+        let Some(registry) = &self.project_registry else {
+            return Ok(());
+        };
+        for (project_id, project) in registry.load_all()? {
+            let restored = match project {
+                Ok(project) => self.restore_project(project).await,
+                Err(e) => Err(e),
+            };
+            match restored {
+                Ok(()) => info!("Restored project '{}'", project_id),
+                Err(e) => {
+                    error!("Skipping persisted project '{}', its writes are refused: {:?}", project_id, e);
+                    self.unrestored_projects.write().await.insert(project_id);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn restore_project(&self, project: RegisteredProject) -> Result<()> {
+        self.register(
+            &project.project_id,
+            &project.conn_str,
+            project.access_key.as_deref(),
+            project.secret_key.as_deref(),
+            project.endpoint.as_deref(),
+            false,
+        )
+        .await?;
+        if project.read_only {
+            self.read_only_projects.write().await.insert(project.project_id.clone());
+        }
+        let interval = project.compaction_interval_secs.filter(|secs| *secs > 0).map(Duration::from_secs);
+        if let (Some(interval), Some(schedule)) = (interval, self.compaction_schedules.write().await.get_mut(&project.project_id)) {
+            schedule.interval = interval;
+        }
+        if let Some(timezone) = project.display_timezone {
+            self.display_timezones.write().await.insert(project.project_id.clone(), timezone);
+        }
+        if !project.encrypted_columns.is_empty() {
+            self.encrypted_columns.write().await.insert(project.project_id, project.encrypted_columns);
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn register(
+        &self, project_id: &str, conn_str: &str, access_key: Option<&str>, secret_key: Option<&str>, endpoint: Option<&str>, persist: bool,
+    ) -> Result<()> {
+        self.register_project_with(project_id, conn_str, access_key, secret_key, endpoint, persist, false).await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn register_project_with(
+        &self, project_id: &str, conn_str: &str, access_key: Option<&str>, secret_key: Option<&str>, endpoint: Option<&str>, persist: bool, create_only: bool,
+    ) -> Result<()> {
+        let conn = ConnectionString::parse(conn_str)?;
+        // Checked before opening the table so rejected registrations cost nothing, and again under the write lock
+        self.check_registration(&*self.project_configs.read().await, project_id, create_only)?;
+        let mut storage_options = Self::storage_options(access_key, secret_key, endpoint);
+        // Explicit credentials and endpoint take precedence over those in the connection string
+        for (key, value) in conn.options {
+            storage_options.0.entry(key).or_insert(value);
+        }
+
+        let table = Self::load_or_create_table(
+            &conn.uri,
+            &storage_options,
+            OtelLogsAndSpans::columns().unwrap_or_default(),
+            OtelLogsAndSpans::partitions(),
+        )
+        .await?;
+
+        // Re-registering keeps the project's settings, read before taking the write lock
+        let encrypted_columns = self.encrypted_columns.read().await.get(project_id).cloned().unwrap_or_default();
+        let read_only = self.read_only_projects.read().await.contains(project_id);
+        let compaction_interval_secs = self.compaction_schedules.read().await.get(project_id).map(|s| s.interval.as_secs());
+        let display_timezone = self.display_timezone(project_id).await;
+        let mut configs = self.project_configs.write().await;
+        self.check_registration(&configs, project_id, create_only)?;
+        // `default` comes from the environment on every start, so only tenants are persisted
+        if let Some(registry) = self.project_registry.as_ref().filter(|_| persist && project_id != "default") {
+            registry.save(&RegisteredProject {
+                project_id: project_id.to_string(),
+                conn_str: conn_str.to_string(),
+                access_key: access_key.map(String::from),
+                secret_key: secret_key.map(String::from),
+                endpoint: endpoint.map(String::from),
+                encrypted_columns,
+                read_only,
+                compaction_interval_secs,
+                display_timezone,
+            })?;
+        }
+        configs.insert(project_id.to_string(), (conn_str.to_string(), storage_options, Arc::new(RwLock::new(table))));
+        drop(configs);
+        self.unrestored_projects.write().await.remove(project_id);
+
+        self.compaction_schedules.write().await.entry(project_id.to_string()).or_insert_with(|| CompactionSchedule {
+            interval: CompactionSchedule::default_interval(),
+            last_compacted: Instant::now(),
+        });
+        Ok(())
+    }
+
+    pub async fn is_project_registered(&self, project_id: &str) -> bool {
+        self.project_configs.read().await.contains_key(project_id)
+    }
+
+    /// Registered projects ordered by id
+    pub async fn list_projects(&self) -> Vec<ProjectSummary> {
+        let configs = self.project_configs.read().await;
+        let schedules = self.compaction_schedules.read().await;
+        let read_only = self.read_only_projects.read().await;
+        let display_timezones = self.display_timezones.read().await;
+        let mut projects: Vec<ProjectSummary> = configs
+            .iter()
+            .map(|(project_id, (conn_str, _, _))| ProjectSummary {
+                project_id: project_id.clone(),
+                uri: ConnectionString::parse(conn_str).map(|conn| conn.uri).unwrap_or_default(),
+                read_only: read_only.contains(project_id),
+                compaction_interval_secs: schedules.get(project_id).map(|s| s.interval.as_secs()),
+                display_timezone: display_timezones.get(project_id).cloned(),
+            })
+            .collect();
+        projects.sort_by(|a, b| a.project_id.cmp(&b.project_id));
+        projects
+    }
+
+    /// Unregister a project and delete every object of its table, returning how many were deleted.
+    /// Only used for scratch projects such as the self-test's; `default` can't be purged.
+    pub(crate) async fn purge_project(&self, project_id: &str) -> Result<usize> {
+        use futures::TryStreamExt;
+        use object_store::ObjectStore;
+
+        if project_id == "default" {
+            return Err(anyhow::anyhow!("The default project can't be purged"));
+        }
+        let Some((_, _, table)) = self.project_configs.write().await.remove(project_id) else {
+            return Err(anyhow::anyhow!("Project ID '{}' not found", project_id));
+        };
+        self.compaction_schedules.write().await.remove(project_id);
+        self.encrypted_columns.write().await.remove(project_id);
+        self.read_only_projects.write().await.remove(project_id);
+        self.display_timezones.write().await.remove(project_id);
+
+        let store = table.read().await.object_store();
+        let paths: Vec<_> = store.list(None).map_ok(|meta| meta.location).try_collect().await?;
+        for path in &paths {
+            store.delete(path).await?;
+        }
+        info!("Purged project '{}' ({} objects deleted)", project_id, paths.len());
+        Ok(paths.len())
+    }
+
+    /// Number of registered projects, not counting `default`
+    pub async fn project_count(&self) -> usize {
+        self.project_configs.read().await.keys().filter(|id| *id != "default").count()
+    }
+
+    fn check_registration(&self, configs: &HashMap<String, ProjectConfig>, project_id: &str, create_only: bool) -> Result<()> {
+        if create_only && configs.contains_key(project_id) {
+            return Err(ProjectAlreadyRegistered {
+                project_id: project_id.to_string(),
+            }
+            .into());
+        }
+        self.check_project_limit(configs, project_id)
+    }
+
+    /// Re-registering a project is always allowed, a new one only below MAX_PROJECTS
+    fn check_project_limit(&self, configs: &HashMap<String, ProjectConfig>, project_id: &str) -> Result<()> {
+        let Some(max) = max_projects() else {
+            return Ok(());
+        };
+        if project_id == "default" || configs.contains_key(project_id) {
+            return Ok(());
+        }
+        if configs.keys().filter(|id| *id != "default").count() >= max {
+            return Err(ProjectLimitReached { max }.into());
+        }
+        Ok(())
+    }
+
+    fn storage_options(access_key: Option<&str>, secret_key: Option<&str>, endpoint: Option<&str>) -> StorageOptions {
+        let mut storage_options = StorageOptions::default();
+
+        if let Some(key) = access_key.filter(|k| !k.is_empty()) {
+            storage_options.0.insert("AWS_ACCESS_KEY_ID".to_string(), key.to_string());
+        }
+
+        if let Some(key) = secret_key.filter(|k| !k.is_empty()) {
+            storage_options.0.insert("AWS_SECRET_ACCESS_KEY".to_string(), key.to_string());
+        }
+
+        if let Some(ep) = endpoint.filter(|e| !e.is_empty()) {
+            storage_options.0.insert("AWS_ENDPOINT".to_string(), ep.to_string());
+        }
+
+        storage_options.0.insert("AWS_ALLOW_HTTP".to_string(), "true".to_string());
+        if let Some(concurrency) = s3_read_concurrency() {
+            storage_options.0.insert("OBJECT_STORE_CONCURRENCY_LIMIT".to_string(), concurrency.to_string());
+        }
+        storage_options
+    }
+
+    /// Load the Delta table at `conn_str`, creating it with the given columns if it doesn't exist yet
+    async fn load_or_create_table(conn_str: &str, storage_options: &StorageOptions, columns: Vec<StructField>, partitions: Vec<String>) -> Result<DeltaTable> {
+        let table = match DeltaTableBuilder::from_uri(conn_str).with_storage_options(storage_options.0.clone()).with_allow_http(true).load().await {
+            Ok(table) => {
+                // Check if table needs checkpointing - use same threshold as in insert_records_batch
+                let version = table.version();
+                // Only checkpoint if it's a multiple of 20 to be consistent with our write policy
+                if version > 0 && version % 20 == 0 {
+                    info!("Checkpointing table at {} at initial load, version {}", conn_str, version);
+                    checkpoints::create_checkpoint(&table, None).await?;
+                }
+                table
+            }
+            Err(err) => {
+                log::warn!("table doesn't exist. creating new table. err: {:?}", err);
+
+                // Create the table with project_id partitioning only for now
+                // Timestamp partitioning is likely causing issues with nanosecond precision
+                let delta_ops = DeltaOps::try_from_uri(&conn_str).await?;
+                let commit_properties = CommitProperties::default().with_create_checkpoint(true).with_cleanup_expired_logs(Some(true));
+
+                // Create table with ZSTD compression and auto-optimization
+                // Note: z-ordering will be applied via sorting_columns in the writer properties
+                delta_ops
+                    .create()
+                    .with_columns(columns)
+                    .with_partition_columns(partitions)
+                    .with_storage_options(storage_options.0.clone())
+                    .with_commit_properties(commit_properties)
+                    .with_configuration_property(deltalake::TableProperty::AutoOptimizeOptimizeWrite, Some("true"))
+                    .with_configuration_property(deltalake::TableProperty::AutoOptimizeAutoCompact, Some("true"))
+                    // Collect min/max/null-count stats for every column, not just the first 32, so filters on
+                    // sparse attribute columns (e.g. `attributes___exception___type IS NOT NULL`) can skip files
+                    .with_configuration_property(deltalake::TableProperty::DataSkippingNumIndexedCols, Some("-1"))
+                    .await?
+            }
+        };
+
+        Ok(table)
+    }
+}
+
+/// Wait until the client's first startup packet has fully arrived, without consuming it,
+/// so pgwire can process it as usual. Fails if the declared packet length exceeds `max_bytes`
+/// or the client disconnects first.
+async fn wait_for_startup_packet(sock: &TcpStream, max_bytes: usize) -> std::io::Result<()> {
+    let mut buf = vec![0u8; max_bytes.max(8)];
+    loop {
+        let n = sock.peek(&mut buf).await?;
+        if n == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "client closed connection during startup",
+            ));
+        }
+
+        if n >= 4 {
+            let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+            if len > max_bytes {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("startup packet of {} bytes exceeds limit of {} bytes", len, max_bytes),
+                ));
+            }
+            if n >= len {
+                return Ok(());
+            }
+        }
+
+        // peek returns immediately while partial data is buffered, so back off before retrying
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Protocol code that a CancelRequest carries where a startup packet has its protocol version
+const CANCEL_REQUEST_CODE: u32 = 80877102;
+
+/// Process id and secret key of the CancelRequest waiting on `sock`, or None when the client sent a startup packet.
+/// Only peeks, like `wait_for_startup_packet`, which must have seen the whole packet first.
+async fn peek_cancel_request(sock: &TcpStream) -> std::io::Result<Option<(i32, i32)>> {
+    let mut buf = [0u8; 16];
+    if sock.peek(&mut buf).await? < buf.len() {
+        return Ok(None);
+    }
+    let word = |i: usize| [buf[i], buf[i + 1], buf[i + 2], buf[i + 3]];
+    if u32::from_be_bytes(word(0)) != 16 || u32::from_be_bytes(word(4)) != CANCEL_REQUEST_CODE {
+        return Ok(None);
+    }
+    Ok(Some((i32::from_be_bytes(word(8)), i32::from_be_bytes(word(12)))))
+}
+
+#[derive(Debug, Clone)]
+pub struct ProjectRoutingTable {
+    default_project: String,
+    database: Arc<Database>,
+    schema: SchemaRef,
+    batch_queue: Option<Arc<crate::batch_queue::BatchQueue>>,
+}
+
+impl ProjectRoutingTable {
+    pub fn new(default_project: String, database: Arc<Database>, schema: SchemaRef, batch_queue: Option<Arc<crate::batch_queue::BatchQueue>>) -> Self {
+        Self {
+            default_project,
+            database,
+            schema,
+            batch_queue,
+        }
+    }
+
+    pub(crate) fn extract_project_ids_from_filters(filters: &[Expr]) -> Option<Vec<String>> {
+        // Look for expressions like "project_id = 'some_value'" or "project_id IN ('a', 'b')"
+        for filter in filters {
+            if let Some(project_ids) = Self::extract_project_ids(filter) {
+                return Some(project_ids);
+            }
+        }
+        None
+    }
+
+    fn schema(&self) -> SchemaRef {
+        OtelLogsAndSpans::schema_ref()
+    }
+
+    /// Union of the scans of every registered project the session's `ProjectAccess` allows. Filters and the limit are pushed
+    /// into each project's scan; `project_id` is a partition column of every table so it stays in the rows.
+    async fn scan_all_projects(
+        &self, state: &dyn Session, projection: Option<&Vec<usize>>, filters: &[Expr], limit: Option<usize>,
+    ) -> DFResult<Arc<dyn ExecutionPlan>> {
+        // Sessions without an access list may read any single project, but not every tenant at once
+        let Some(access) = state.config().get_extension::<ProjectAccess>() else {
+            return Err(DataFusionError::Execution(
+                "Access denied: scanning all projects needs a session with a project access list, filter on project_id".to_string(),
+            ));
+        };
+        let mut project_ids: Vec<String> = self.database.project_configs.read().await.keys().cloned().collect();
+        project_ids.retain(|id| access.check(id).is_ok());
+        project_ids.sort();
+        if project_ids.is_empty() {
+            return Err(DataFusionError::Execution(
+                "Access denied: no project is authorized for this session".to_string(),
+            ));
+        }
+        self.scan_projects(state, &project_ids, projection, filters, limit).await
+    }
+
+    /// Union of the scans of the given registered projects, each with all the filters and the limit pushed down
+    async fn scan_projects(
+        &self, state: &dyn Session, project_ids: &[String], projection: Option<&Vec<usize>>, filters: &[Expr], limit: Option<usize>,
+    ) -> DFResult<Arc<dyn ExecutionPlan>> {
+        let mut plans = Vec::with_capacity(project_ids.len());
+        for project_id in project_ids {
+            let delta_table = self.database.resolve_table(project_id).await?;
+            let table = delta_table.read().await;
+            plans.push(table.scan(state, projection, filters, limit).await?);
+        }
+        debug!("Scanning projects {:?}", project_ids);
+        Ok(if plans.len() == 1 { plans.remove(0) } else { Arc::new(UnionExec::new(plans)) })
+    }
+
+    fn extract_project_ids(expr: &Expr) -> Option<Vec<String>> {
+        match expr {
+            // Binary expression: "project_id = 'value'"
+            Expr::BinaryExpr(BinaryExpr { left, op, right }) => {
+                // Check if this is an equality operation
+                if *op == Operator::Eq {
+                    // The column may be on either side
+                    if is_project_id_column(left) {
+                        return utf8_literal(right).map(|value| vec![value]);
+                    }
+                    if is_project_id_column(right) {
+                        return utf8_literal(left).map(|value| vec![value]);
+                    }
+                }
+                // "project_id = 'a' OR project_id = 'b'": only usable when both sides restrict the project
+                if *op == Operator::Or {
+                    let mut project_ids = Self::extract_project_ids(left)?;
+                    for project_id in Self::extract_project_ids(right)? {
+                        if !project_ids.contains(&project_id) {
+                            project_ids.push(project_id);
+                        }
+                    }
+                    return Some(project_ids);
+                }
+                None
+            }
+            // "project_id IN ('a', 'b')"
+            Expr::InList(InList { expr, list, negated: false }) if is_project_id_column(expr) => {
+                let mut project_ids = Vec::with_capacity(list.len());
+                for value in list {
+                    let value = utf8_literal(value)?;
+                    if !project_ids.contains(&value) {
+                        project_ids.push(value);
+                    }
+                }
+                (!project_ids.is_empty()).then_some(project_ids)
+            }
+            // NOT and anything else doesn't restrict the scan to known projects
+            _ => None,
+        }
+    }
+}
+
+/// `Exact` for a plain comparison of a partition column with a literal, or an AND of them, which the Delta scan
+/// resolves exactly by pruning whole files on their partition values, so DataFusion doesn't evaluate them again.
+/// Everything else is `Inexact`: file pruning is conservative for NOT, OR, IN lists and casts of the column, and
+/// keeps files it can't decide on, so their rows must still be filtered.
+fn partition_filter_pushdown(expr: &Expr, partitions: &[String]) -> TableProviderFilterPushDown {
+    if is_partition_comparison(expr, partitions) {
+        TableProviderFilterPushDown::Exact
+    } else {
+        TableProviderFilterPushDown::Inexact
+    }
+}
+
+/// `col =/</<=/>/>= literal` (either way round) on a partition column, possibly combined with AND
+fn is_partition_comparison(expr: &Expr, partitions: &[String]) -> bool {
+    let is_partition_column = |expr: &Expr| matches!(expr, Expr::Column(col) if partitions.contains(&col.name));
+    // A cast literal, e.g. a date string coerced to Date32, is still a constant
+    let is_literal = |expr: &Expr| match expr {
+        Expr::Literal(_) => true,
+        Expr::Cast(cast) => matches!(cast.expr.as_ref(), Expr::Literal(_)),
+        _ => false,
+    };
+
+    match expr {
+        Expr::BinaryExpr(BinaryExpr { left, op, right }) => match op {
+            Operator::And => is_partition_comparison(left, partitions) && is_partition_comparison(right, partitions),
+            Operator::Eq | Operator::Lt | Operator::LtEq | Operator::Gt | Operator::GtEq => {
+                (is_partition_column(left) && is_literal(right)) || (is_literal(left) && is_partition_column(right))
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn is_project_id_column(expr: &Expr) -> bool {
+    matches!(expr, Expr::Column(col) if col.name == "project_id")
+}
+
+fn utf8_literal(expr: &Expr) -> Option<String> {
+    match expr {
+        Expr::Literal(ScalarValue::Utf8(Some(value))) => Some(value.clone()),
+        _ => None,
+    }
+}
+
+// Needed by DataSink
+impl DisplayAs for ProjectRoutingTable {
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match t {
+            DisplayFormatType::Default | DisplayFormatType::Verbose => {
+                write!(f, "ProjectRoutingTable ")
+            } // DisplayFormatType::TreeRender => {
+              //     // TODO: collect info
+              //     write!(f, "")
+              // }
+        }
+    }
+}
+
+#[async_trait]
+impl DataSink for ProjectRoutingTable {
+    fn schema(&self) -> &SchemaRef {
+        &self.schema
+    }
+
+    async fn write_all(&self, mut data: SendableRecordBatchStream, _context: &Arc<TaskContext>) -> DFResult<u64> {
+        let mut row_count = 0;
+        let mut batches = Vec::new();
+
+        // Collect all batches from the stream
+        while let Some(batch) = data.next().await.transpose()? {
+            row_count += batch.num_rows();
+            batches.push(batch);
+        }
+
+        if batches.is_empty() {
+            return Ok(0);
+        }
+
+        // Let the database handle the queue decision with skip_queue=false
+        // This means it will use the queue if it's available and not disabled via env var
+        self.database
+            .insert_records_batch("", batches, false)
+            .await
+            .map_err(|e| DataFusionError::Execution(format!("Insert error: {}", e)))?;
+
+        Ok(row_count as u64)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[async_trait]
+impl TableProvider for ProjectRoutingTable {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn table_type(&self) -> TableType {
+        TableType::Base
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema()
+    }
+
+    async fn insert_into(&self, _state: &dyn Session, input: Arc<dyn ExecutionPlan>, insert_op: InsertOp) -> DFResult<Arc<dyn ExecutionPlan>> {
+        // Create a physical plan from the logical plan.
+        // Check that the schema of the plan matches the schema of this table.
+        match self.schema().logically_equivalent_names_and_types(&input.schema()) {
+            Ok(_) => debug!("insert_into; Schema validation passed"),
+            Err(e) => {
+                error!("Schema validation failed: {}", e);
+                return Err(e);
+            }
+        }
+
+        if insert_op != InsertOp::Append {
+            error!("Unsupported insert operation: {:?}", insert_op);
+            return not_impl_err!("{insert_op} not implemented for MemoryTable yet");
+        }
+
+        // Create sink executor but with additional logging
+        let sink = DataSinkExec::new(input, Arc::new(self.clone()), None);
+
+        Ok(Arc::new(sink))
+    }
+
+    fn supports_filters_pushdown(&self, filter: &[&Expr]) -> DFResult<Vec<TableProviderFilterPushDown>> {
+        let partitions = OtelLogsAndSpans::partitions();
+        Ok(filter.iter().map(|expr| partition_filter_pushdown(expr, &partitions)).collect())
+    }
+
+    async fn scan(&self, state: &dyn Session, projection: Option<&Vec<usize>>, filters: &[Expr], limit: Option<usize>) -> DFResult<Arc<dyn ExecutionPlan>> {
+        // Get the projects from the filters if possible, otherwise use default
+        let requested = match Self::extract_project_ids_from_filters(filters) {
+            Some(project_ids) => project_ids,
+            None if cross_project_scan_enabled() => return self.scan_all_projects(state, projection, filters, limit).await,
+            None => vec![self.default_project.clone()],
+        };
+
+        // Check both the requested projects and the ones actually scanned, unknown projects fall back to the default table
+        let access = state.config().get_extension::<ProjectAccess>();
+        let configs = self.database.project_configs.read().await;
+        let mut project_ids = Vec::with_capacity(requested.len());
+        for project_id in requested {
+            let scanned = if configs.contains_key(&project_id) { project_id.clone() } else { self.default_project.clone() };
+            if let Some(access) = &access {
+                access.check(&project_id)?;
+                access.check(&scanned)?;
+            }
+            if !project_ids.contains(&scanned) {
+                project_ids.push(scanned);
+            }
+        }
+        drop(configs);
+
+        self.scan_projects(state, &project_ids, projection, filters, limit).await
+    }
+}
+
+/// Read-only table provider for the `otel_metrics` table
+#[derive(Debug, Clone)]
+pub struct MetricsTable {
+    database: Arc<Database>,
+    schema: SchemaRef,
+}
+
+impl MetricsTable {
+    pub fn new(database: Arc<Database>) -> Self {
+        Self {
+            database,
+            schema: OtelMetrics::schema_ref(),
+        }
+    }
+}
+
+#[async_trait]
+impl TableProvider for MetricsTable {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn table_type(&self) -> TableType {
+        TableType::Base
+    }
+
+    fn schema(&self) -> SchemaRef {
+        Arc::clone(&self.schema)
+    }
+
+    fn supports_filters_pushdown(&self, filter: &[&Expr]) -> DFResult<Vec<TableProviderFilterPushDown>> {
+        let partitions = OtelMetrics::partitions();
+        Ok(filter.iter().map(|expr| partition_filter_pushdown(expr, &partitions)).collect())
+    }
+
+    async fn scan(&self, state: &dyn Session, projection: Option<&Vec<usize>>, filters: &[Expr], limit: Option<usize>) -> DFResult<Arc<dyn ExecutionPlan>> {
+        let delta_table = self.database.resolve_metrics_table().await?;
+        let table = delta_table.read().await;
+        table.scan(state, projection, filters, limit).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use datafusion::assert_batches_eq;
+    use datafusion::prelude::SessionContext;
+    use dotenv::dotenv;
+    use serial_test::serial;
+    use uuid::Uuid;
+
+    use super::*;
+
+    // Helper function to create a test database with a unique table prefix
+    async fn setup_test_database(prefix: String) -> Result<(Database, SessionContext, String)> {
+        let _ = env_logger::builder().is_test(true).try_init();
+        dotenv().ok();
+
+        // Set a unique test-specific prefix for a clean Delta table
+        let test_prefix = format!("test-data-{}", prefix);
+        unsafe {
+            env::set_var("TIMEFUSION_TABLE_PREFIX", &test_prefix);
+        }
+
+        let db = Database::new().await?;
+        let mut session_context = SessionContext::new();
+        datafusion_functions_json::register_all(&mut session_context)?;
+        let schema = OtelLogsAndSpans::schema_ref();
+
+        let routing_table = ProjectRoutingTable::new(
+            "default".to_string(),
             Arc::new(db.clone()),
             schema,
-            None, // No batch queue in tests
+            None, // No batch queue in tests
+        );
+        session_context.register_table(OtelLogsAndSpans::table_name(), Arc::new(routing_table))?;
+
+        Ok((db, session_context, test_prefix))
+    }
+
+    // Helper function to create sample test records
+    fn create_test_records() -> Vec<OtelLogsAndSpans> {
+        let timestamp1 = Utc.with_ymd_and_hms(2023, 1, 1, 10, 0, 0).unwrap();
+        let timestamp2 = Utc.with_ymd_and_hms(2023, 1, 1, 10, 10, 0).unwrap();
+
+        vec![
+            OtelLogsAndSpans {
+                project_id: "test_project".to_string(),
+                // date: timestamp1.date_naive(),
+                timestamp: timestamp1,
+                observed_timestamp: Some(timestamp1),
+                id: "span1".to_string(),
+                name: Some("test_span_1".to_string()),
+                context___trace_id: Some("trace1".to_string()),
+                context___span_id: Some("span1".to_string()),
+                start_time: Some(timestamp1),
+                duration: Some(100_000_000),
+                status_code: Some("OK".to_string()),
+                level: Some("INFO".to_string()),
+                ..Default::default()
+            },
+            OtelLogsAndSpans {
+                project_id: "test_project".to_string(),
+                // date: timestamp2.date_naive(),
+                timestamp: timestamp2,
+                observed_timestamp: Some(timestamp2),
+                id: "span2".to_string(),
+                name: Some("test_span_2".to_string()),
+                context___trace_id: Some("trace2".to_string()),
+                context___span_id: Some("span2".to_string()),
+                start_time: Some(timestamp2),
+                duration: Some(200_000_000),
+                status_code: Some("ERROR".to_string()),
+                level: Some("ERROR".to_string()),
+                status_message: Some("Error occurred".to_string()),
+                ..Default::default()
+            },
+        ]
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_database_query() -> Result<()> {
+        let (db, ctx, test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "query").await?;
+        log::info!("Using test-specific table prefix: {}", test_prefix);
+
+        let records = create_test_records();
+        db.insert_records(&records).await?;
+
+        // Test 1: Basic count query to verify record insertion
+        let count_df = ctx.sql("SELECT COUNT(*) as count FROM otel_logs_and_spans").await?;
+        let result = count_df.collect().await?;
+
+        #[rustfmt::skip]
+        assert_batches_eq!(
+            [
+                "+-------+", 
+                "| count |", 
+                "+-------+", 
+                "| 2     |", 
+                "+-------+",
+            ], &result);
+
+        // Test 2: Query with field selection and ordering
+        log::info!("Testing field selection and ordering");
+        let df = ctx.sql("SELECT timestamp, name, status_code, level FROM otel_logs_and_spans ORDER BY name").await?;
+        let result = df.collect().await?;
+
+        assert_batches_eq!(
+            [
+                "+---------------------+-------------+-------------+-------+",
+                "| timestamp           | name        | status_code | level |",
+                "+---------------------+-------------+-------------+-------+",
+                "| 2023-01-01T10:00:00 | test_span_1 | OK          | INFO  |",
+                "| 2023-01-01T10:10:00 | test_span_2 | ERROR       | ERROR |",
+                "+---------------------+-------------+-------------+-------+",
+            ],
+            &result
+        );
+
+        // Test 3: Filtering by project_id and level
+        log::info!("Testing filtering by project_id and level");
+        let df = ctx
+            .sql("SELECT name, level, status_code, status_message FROM otel_logs_and_spans WHERE project_id = 'test_project' AND level = 'ERROR'")
+            .await?;
+        let result = df.collect().await?;
+
+        assert_batches_eq!(
+            [
+                "+-------------+-------+-------------+----------------+",
+                "| name        | level | status_code | status_message |",
+                "+-------------+-------+-------------+----------------+",
+                "| test_span_2 | ERROR | ERROR       | Error occurred |",
+                "+-------------+-------+-------------+----------------+",
+            ],
+            &result
+        );
+
+        // Test 4: Complex query with multiple data types (including timestamp and end_time)
+        // Note: For timestamp columns, we need to format them for the test to use assert_batches_eq
+        log::info!("Testing complex query with multiple data types");
+        let df = ctx
+            .sql(
+                "
+                    SELECT
+                        id,
+                        name,
+                        project_id,
+                        duration,
+                        CAST(duration / 1000000 AS INTEGER) as duration_ms,
+                        context___trace_id,
+                        status_code,
+                        level,
+                        to_char(timestamp, '%Y-%m-%d %H:%M') as formatted_timestamp,
+                        to_char(end_time, 'YYYY-MM-DD HH24:MI') as formatted_end_time,
+                        CASE WHEN status_code = 'ERROR' THEN status_message ELSE NULL END as conditional_message
+                    FROM otel_logs_and_spans
+                    ORDER BY id
+                ",
+            )
+            .await?;
+        let result = df.collect().await?;
+
+        assert_batches_eq!(
+            [
+                "+-------+-------------+--------------+-----------+-------------+--------------------+-------------+-------+---------------------+--------------------+---------------------+",
+                "| id    | name        | project_id   | duration  | duration_ms | context___trace_id | status_code | level | formatted_timestamp | formatted_end_time | conditional_message |",
+                "+-------+-------------+--------------+-----------+-------------+--------------------+-------------+-------+---------------------+--------------------+---------------------+",
+                "| span1 | test_span_1 | test_project | 100000000 | 100         | trace1             | OK          | INFO  | 2023-01-01 10:00    |                    |                     |",
+                "| span2 | test_span_2 | test_project | 200000000 | 200         | trace2             | ERROR       | ERROR | 2023-01-01 10:10    |                    | Error occurred      |",
+                "+-------+-------------+--------------+-----------+-------------+--------------------+-------------+-------+---------------------+--------------------+---------------------+",
+            ],
+            &result
+        );
+
+        // Test 5: Timestamp filtering
+        log::info!("Testing timestamp filtering");
+        let df = ctx.sql("SELECT COUNT(*) as count FROM otel_logs_and_spans WHERE timestamp > '2023-01-01T10:05:00.000000Z'").await?;
+        let result = df.collect().await?;
+
+        #[rustfmt::skip]
+            assert_batches_eq!(
+                [
+                    "+-------+",
+                    "| count |",
+                    "+-------+",
+                    "| 1     |",
+                    "+-------+",
+                ],
+                &result
+            );
+
+        // Test 6: Duration filtering
+        log::info!("Testing duration filtering");
+        let df = ctx.sql("SELECT name FROM otel_logs_and_spans WHERE duration > 150000000").await?;
+        let result = df.collect().await?;
+
+        #[rustfmt::skip]
+            assert_batches_eq!(
+                [
+                    "+-------------+",
+                    "| name        |",
+                    "+-------------+",
+                    "| test_span_2 |",
+                    "+-------------+",
+                ],
+                &result
+            );
+
+        // Test 7: Complex filtering with timestamp columns in ISO 8601 format
+        log::info!("Testing complex filtering with timestamp columns in ISO 8601 format");
+        let df = ctx
+            .sql(
+                "
+                    WITH time_data AS (
+                        SELECT
+                            name,
+                            status_code,
+                            level,
+                            CAST(duration / 1000000 AS INTEGER) as duration_ms,
+                            timestamp,
+                            to_char(timestamp, '%Y-%m-%d') as date_only,
+                            EXTRACT(HOUR FROM timestamp) as hour,
+                            to_char(timestamp, '%Y-%m-%dT%H:%M:%S%.6fZ') as iso_timestamp
+                        FROM otel_logs_and_spans
+                        WHERE
+                            project_id = 'test_project'
+                            AND timestamp > '2023-01-01T10:05:00.000000Z'
+                    )
+                    SELECT
+                        name,
+                        status_code,
+                        level,
+                        duration_ms,
+                        date_only,
+                        hour,
+                        iso_timestamp
+                    FROM time_data
+                    ORDER BY name
+                ",
+            )
+            .await?;
+        let result = df.collect().await?;
+
+        assert_batches_eq!(
+            [
+                "+-------------+-------------+-------+-------------+------------+------+-----------------------------+",
+                "| name        | status_code | level | duration_ms | date_only  | hour | iso_timestamp               |",
+                "+-------------+-------------+-------+-------------+------------+------+-----------------------------+",
+                "| test_span_2 | ERROR       | ERROR | 200         | 2023-01-01 | 10   | 2023-01-01T10:10:00.000000Z |",
+                "+-------------+-------------+-------+-------------+------------+------+-----------------------------+",
+            ],
+            &result
+        );
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_sql_insert() -> Result<()> {
+        let (db, ctx, test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "insert").await?;
+        log::info!("Using test-specific table prefix for SQL INSERT test: {}", test_prefix);
+
+        let datetime = chrono::DateTime::parse_from_rfc3339("2023-02-01T15:30:00.000000Z").unwrap().with_timezone(&chrono::Utc);
+        let record = OtelLogsAndSpans {
+            project_id: "default".to_string(),
+            timestamp: datetime,
+            observed_timestamp: Some(datetime),
+            id: "sql_span1a".to_string(),
+            name: Some("sql_test_span".to_string()),
+            duration: Some(150000000),
+            start_time: Some(datetime),
+            context___trace_id: Some("sql_trace1".to_string()),
+            context___span_id: Some("sql_span1".to_string()),
+            status_code: Some("OK".to_string()),
+            status_message: Some("SQL inserted successfully".to_string()),
+            level: Some("INFO".to_string()),
+            ..Default::default()
+        };
+        db.insert_records(&vec![record]).await?;
+
+        let verify_df = ctx.sql("SELECT id, name, timestamp from otel_logs_and_spans").await?.collect().await?;
+        #[rustfmt::skip]
+        assert_batches_eq!(
+            [
+                "+------------+---------------+---------------------+",
+                "| id         | name          | timestamp           |",
+                "+------------+---------------+---------------------+",
+                "| sql_span1a | sql_test_span | 2023-02-01T15:30:00 |",
+                "+------------+---------------+---------------------+",
+        ], &verify_df);
+
+        let insert_sql = "INSERT INTO otel_logs_and_spans (
+                 project_id, date, timestamp, id, hashes,
+                 parent_id, name, kind,
+                 status_code, status_message, level, severity___severity_text, severity___severity_number,
+                 body, duration, start_time, end_time
+             ) VALUES (
+                 'test_project', TIMESTAMP '2023-01-01', TIMESTAMP '2023-01-01T10:00:00Z', 'sql_span1', ARRAY[],
+                 NULL, 'sql_test_span', NULL,
+                 'OK', 'span inserted successfully', 'INFO', 'INFORMATION', NULL,
+                 NULL, 150000000, TIMESTAMP '2023-01-01T10:00:00Z', NULL
+             )";
+
+        let insert_result = ctx.sql(insert_sql).await?.collect().await?;
+        #[rustfmt::skip]
+         assert_batches_eq!(
+             ["+-------+",
+             "| count |",
+             "+-------+",
+             "| 1     |",
+             "+-------+",
+         ], &insert_result);
+
+        let verify_df = ctx
+            .sql("SELECT project_id, id, name, timestamp, kind, status_code, severity___severity_text, duration, start_time from otel_logs_and_spans order by timestamp desc")
+            .await?
+            .collect()
+            .await?;
+        #[rustfmt::skip]
+        assert_batches_eq!(
+        [
+            "+--------------+------------+---------------+---------------------+------+-------------+--------------------------+-----------+---------------------+",
+            "| project_id   | id         | name          | timestamp           | kind | status_code | severity___severity_text | duration  | start_time          |",
+            "+--------------+------------+---------------+---------------------+------+-------------+--------------------------+-----------+---------------------+",
+            "| default      | sql_span1a | sql_test_span | 2023-02-01T15:30:00 |      | OK          |                          | 150000000 | 2023-02-01T15:30:00 |",
+            "| test_project | sql_span1  | sql_test_span | 2023-01-01T10:00:00 |      | OK          | INFORMATION              | 150000000 | 2023-01-01T10:00:00 |",
+            "+--------------+------------+---------------+---------------------+------+-------------+--------------------------+-----------+---------------------+",
+        ]
+        , &verify_df);
+
+        log::info!("Inserting record directly via insert statement");
+        let insert_sql = "INSERT INTO otel_logs_and_spans (
+                project_id, date, timestamp, observed_timestamp, id, hashes,
+                parent_id, name, kind,
+                status_code, status_message, level, severity___severity_text, severity___severity_number,
+                body, duration, start_time, end_time,
+                context___trace_id, context___span_id, context___trace_state, context___trace_flags,
+                context___is_remote, events, links,
+                attributes___client___address, attributes___client___port,
+
+                attributes___server___address, attributes___server___port, attributes___network___local__address, attributes___network___local__port,
+                attributes___network___peer___address, attributes___network___peer__port, attributes___network___protocol___name, attributes___network___protocol___version,
+                attributes___network___transport, attributes___network___type,attributes___code___number, attributes___code___file___path,
+                attributes___code___function___name, attributes___code___line___number, attributes___code___stacktrace, attributes___log__record___original,
+
+                attributes___log__record___uid, attributes___error___type, attributes___exception___type, attributes___exception___message,
+                attributes___exception___stacktrace, attributes___url___fragment, attributes___url___full, attributes___url___path,
+                attributes___url___query, attributes___url___scheme, attributes___user_agent___original, attributes___http___request___method,
+                attributes___http___request___method_original,attributes___http___response___status_code, attributes___http___request___resend_count, attributes___http___request___body___size,
+
+                attributes___session___id, attributes___session___previous___id, attributes___db___system___name, attributes___db___collection___name,
+                attributes___db___namespace, attributes___db___operation___name, attributes___db___response___status_code, attributes___db___operation___batch___size,
+                attributes___db___query___summary, attributes___db___query___text, attributes___user___id, attributes___user___email,
+                attributes___user___full_name, attributes___user___name, attributes___user___hash, resource___service___name,
+
+                resource___service___version, resource___service___instance___id, resource___service___namespace, resource___telemetry___sdk___language,
+                resource___telemetry___sdk___name, resource___telemetry___sdk___version, resource___user_agent___original
+            ) VALUES (
+                'test_project','2023-01-02',  TIMESTAMP '2023-01-02T10:00:00Z', NULL, 'sql_span2', ARRAY[],
+                NULL, 'sql_test_span', NULL,
+                'OK', 'span inserted successfully', 'INFO', NULL, NULL,
+                NULL, 150000000, TIMESTAMP '2023-01-01T10:00:00Z', NULL,
+                'sql_trace1', 'sql_span1', NULL, NULL,
+                NULL, NULL, NULL,
+                NULL, NULL,
+
+                NULL, NULL, NULL, NULL,
+                NULL, NULL, NULL, NULL,
+                NULL, NULL, NULL, NULL,
+                NULL, NULL, NULL, NULL,
+
+                NULL, NULL, NULL, NULL,
+                NULL, NULL, NULL, NULL,
+                NULL, NULL, NULL, NULL,
+                NULL, NULL, NULL, NULL,
+
+                NULL, NULL, NULL, NULL,
+                NULL, NULL, NULL, NULL,
+                NULL, NULL, NULL, NULL,
+                NULL, NULL, NULL, NULL,
+
+                NULL, NULL, NULL, NULL,
+                NULL, NULL, NULL
+            )";
+
+        let insert_result = ctx.sql(insert_sql).await?.collect().await?;
+        #[rustfmt::skip]
+         assert_batches_eq!(
+             ["+-------+",
+             "| count |",
+             "+-------+",
+             "| 1     |",
+             "+-------+",
+         ], &insert_result);
+
+        // Verify that the SQL-inserted record exists
+        let verify_df = ctx.sql("SELECT id, name, status_message FROM otel_logs_and_spans WHERE id = 'sql_span1'").await?;
+        let verify_result = verify_df.collect().await?;
+
+        // Check that we can retrieve the inserted record
+        assert_batches_eq!(
+            [
+                "+-----------+---------------+----------------------------+",
+                "| id        | name          | status_message             |",
+                "+-----------+---------------+----------------------------+",
+                "| sql_span1 | sql_test_span | span inserted successfully |",
+                "+-----------+---------------+----------------------------+",
+            ],
+            &verify_result
+        );
+
+        // TODO: verify the correct copy to syntax
+        // let copy_sql = "COPY (VALUES (
+        //         NULL, 'sql_span2copy',
+        //         NULL, 'sql_test_span_copy', NULL,
+        //         'OK', 'span copied into successfully', 'INFO', NULL, NULL,
+        //         NULL, 150000000, TIMESTAMP '2023-01-01T10:00:00Z', NULL,
+        //         'sql_trace1copy', 'sql_span1copy', NULL, NULL,
+        //         NULL, NULL, NULL,
+        //         NULL, NULL,
+        //
+        //         NULL, NULL, NULL, NULL,
+        //         NULL, NULL, NULL, NULL,
+        //         NULL, NULL, NULL, NULL,
+        //         NULL, NULL, NULL, NULL,
+        //
+        //         NULL, NULL, NULL, NULL,
+        //         NULL, NULL, NULL, NULL,
+        //         NULL, NULL, NULL, NULL,
+        //         NULL, NULL, NULL, NULL,
+        //
+        //         NULL, NULL, NULL, NULL,
+        //         NULL, NULL, NULL, NULL,
+        //         NULL, NULL, NULL, NULL,
+        //         NULL, NULL, NULL, NULL,
+        //
+        //         NULL, NULL, NULL, NULL,
+        //         NULL, NULL, NULL,
+        //
+        //         'test_project', TIMESTAMP '2023-01-02T10:00:00Z'
+        //     )) TO otel_logs_and_spans ";
+        //
+        // let insert_result = ctx.sql(copy_sql).await?.collect().await?;
+        // #[rustfmt::skip]
+        // assert_batches_eq!(
+        //     ["+-------+",
+        //     "| count |",
+        //     "+-------+",
+        //     "| 1     |",
+        //     "+-------+",
+        // ], &insert_result);
+
+        let verify_df = ctx
+             .sql("SELECT project_id, id, name, timestamp, kind, status_code, severity___severity_text, duration, start_time from otel_logs_and_spans order by timestamp desc")
+             .await?
+             .collect()
+             .await?;
+        #[rustfmt::skip]
+         assert_batches_eq!(
+             [
+                 "+--------------+------------+---------------+---------------------+------+-------------+--------------------------+-----------+---------------------+",
+                 "| project_id   | id         | name          | timestamp           | kind | status_code | severity___severity_text | duration  | start_time          |",
+                 "+--------------+------------+---------------+---------------------+------+-------------+--------------------------+-----------+---------------------+",
+                 "| default      | sql_span1a | sql_test_span | 2023-02-01T15:30:00 |      | OK          |                          | 150000000 | 2023-02-01T15:30:00 |",
+                 "| test_project | sql_span2  | sql_test_span | 2023-01-02T10:00:00 |      | OK          |                          | 150000000 | 2023-01-01T10:00:00 |",
+                 "| test_project | sql_span1  | sql_test_span | 2023-01-01T10:00:00 |      | OK          | INFORMATION              | 150000000 | 2023-01-01T10:00:00 |",
+                 "+--------------+------------+---------------+---------------------+------+-------------+--------------------------+-----------+---------------------+",
+             ],
+             &verify_df
+         );
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_large_sort_spills_to_disk() -> Result<()> {
+        let (db, _ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "spill").await?;
+        let spill_dir = tempfile::tempdir()?;
+        unsafe {
+            env::set_var("QUERY_MEMORY_LIMIT_MB", "16");
+            env::set_var("QUERY_SPILL_DIR", spill_dir.path());
+        }
+
+        let ctx = db.create_session_context();
+        ctx.sql("SET datafusion.execution.sort_spill_reservation_bytes = 1048576").await?.collect().await?;
+
+        let sql = "SELECT value, CAST(value AS VARCHAR) AS label FROM generate_series(1, 2000000) ORDER BY label DESC";
+        let result = ctx.sql(sql).await?.collect().await?;
+        let rows: usize = result.iter().map(|b| b.num_rows()).sum();
+        assert_eq!(rows, 2_000_000);
+
+        let explain = ctx.sql(&format!("EXPLAIN ANALYZE {}", sql)).await?.collect().await?;
+        let plan = datafusion::arrow::util::pretty::pretty_format_batches(&explain)?.to_string();
+        assert!(!plan.contains("spill_count=0,"), "Sort should have spilled to disk: {}", plan);
+        assert!(plan.contains("spill_count="), "Sort metrics should report spills: {}", plan);
+
+        unsafe {
+            env::remove_var("QUERY_MEMORY_LIMIT_MB");
+            env::remove_var("QUERY_SPILL_DIR");
+        }
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_project_history() -> Result<()> {
+        let (db, _ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "history").await?;
+
+        let records = create_test_records();
+        db.insert_records(&records).await?;
+        db.insert_records(&records).await?;
+
+        let table = db.resolve_table("default").await?;
+        db.optimize_table(&table).await?;
+
+        let history = db.project_history("default", Some(10)).await?.expect("default project should exist");
+        let operations: Vec<&str> = history.iter().filter_map(|c| c.operation.as_deref()).collect();
+
+        assert_eq!(
+            operations.first(),
+            Some(&"OPTIMIZE"),
+            "latest commit should be the compaction: {:?}",
+            operations
+        );
+        assert!(operations.contains(&"WRITE"), "history should contain the insert: {:?}", operations);
+        assert!(history.windows(2).all(|w| w[0].version > w[1].version), "history should be newest first");
+
+        assert!(db.project_history("unknown_project", None).await?.is_none());
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_attribute_existence_prunes_files() -> Result<()> {
+        let (db, ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "nullstats").await?;
+
+        // Write two files: only the second one has an exception
+        let mut records = create_test_records();
+        records[1].attributes___exception___type = Some("TimeoutError".to_string());
+        db.insert_records(&vec![records[0].clone()]).await?;
+        db.insert_records(&vec![records[1].clone()]).await?;
+
+        let sql = "SELECT id FROM otel_logs_and_spans WHERE attributes___exception___type IS NOT NULL";
+        let result = ctx.sql(sql).await?.collect().await?;
+        assert_batches_eq!(["+-------+", "| id    |", "+-------+", "| span2 |", "+-------+"], &result);
+
+        // The file without any exception values is skipped using its null-count statistics
+        let explain = ctx.sql(&format!("EXPLAIN ANALYZE {}", sql)).await?.collect().await?;
+        let plan = datafusion::arrow::util::pretty::pretty_format_batches(&explain)?.to_string();
+        assert!(plan.contains("files_pruned=1"), "Expected one file to be pruned: {}", plan);
+        assert!(plan.contains("files_scanned=1"), "Expected one file to be scanned: {}", plan);
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_duplicate_id_policies() -> Result<()> {
+        let (db, ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "duplicates").await?;
+        let count_id = |id: &'static str| {
+            let ctx = ctx.clone();
+            async move {
+                let df = ctx.sql(&format!("SELECT COUNT(*) AS count FROM otel_logs_and_spans WHERE id = '{}'", id)).await?;
+                let batches = df.collect().await?;
+                let count = batches[0].column(0).as_any().downcast_ref::<datafusion::arrow::array::Int64Array>().unwrap().value(0);
+                Ok::<_, anyhow::Error>(count)
+            }
+        };
+
+        let mut record = create_test_records().remove(0);
+
+        // allow: duplicates are appended
+        unsafe {
+            env::set_var("DUPLICATE_ID_POLICY", "allow");
+        }
+        record.id = "dup_allow".to_string();
+        db.insert_records(&vec![record.clone()]).await?;
+        db.insert_records(&vec![record.clone()]).await?;
+        assert_eq!(count_id("dup_allow").await?, 2);
+
+        // reject: the second write fails and nothing is added
+        unsafe {
+            env::set_var("DUPLICATE_ID_POLICY", "reject");
+        }
+        record.id = "dup_reject".to_string();
+        db.insert_records(&vec![record.clone()]).await?;
+        let err = db.insert_records(&vec![record.clone()]).await.expect_err("duplicate id should be rejected");
+        assert!(err.to_string().contains("dup_reject"), "error should name the duplicate id: {}", err);
+        assert_eq!(count_id("dup_reject").await?, 1);
+
+        // upsert: the second write replaces the first
+        unsafe {
+            env::set_var("DUPLICATE_ID_POLICY", "upsert");
+        }
+        record.id = "dup_upsert".to_string();
+        db.insert_records(&vec![record.clone()]).await?;
+        record.status_code = Some("ERROR".to_string());
+        db.insert_records(&vec![record.clone()]).await?;
+        assert_eq!(count_id("dup_upsert").await?, 1);
+
+        let result = ctx.sql("SELECT id, status_code FROM otel_logs_and_spans WHERE id = 'dup_upsert'").await?.collect().await?;
+        assert_batches_eq!(
+            [
+                "+------------+-------------+",
+                "| id         | status_code |",
+                "+------------+-------------+",
+                "| dup_upsert | ERROR       |",
+                "+------------+-------------+",
+            ],
+            &result
+        );
+
+        unsafe {
+            env::remove_var("DUPLICATE_ID_POLICY");
+        }
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_ingest_enrichment() -> Result<()> {
+        let (db, ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "enrich").await?;
+
+        let mut records = create_test_records();
+        records[0].id = "enrich1".to_string();
+        records[1].id = "enrich2".to_string();
+        // Client-supplied values
+        records[1].resource___service___namespace = Some("client-ns".to_string());
+        records[1].resource = Some(r#"{"cloud.region":"client-region"}"#.to_string());
+
+        unsafe {
+            env::set_var("INGEST_ENRICH", "cloud.region=eu-west-1, service.namespace=ingest");
+        }
+        db.insert_records(&records).await?;
+
+        unsafe {
+            env::set_var("INGEST_ENRICH_OVERRIDE", "true");
+        }
+        records[1].id = "enrich3".to_string();
+        db.insert_records(&vec![records[1].clone()]).await?;
+
+        unsafe {
+            env::remove_var("INGEST_ENRICH");
+            env::remove_var("INGEST_ENRICH_OVERRIDE");
+        }
+
+        let result = ctx
+            .sql("SELECT id, resource___service___namespace AS namespace, resource FROM otel_logs_and_spans WHERE id LIKE 'enrich%' ORDER BY id")
+            .await?
+            .collect()
+            .await?;
+        assert_batches_eq!(
+            [
+                "+---------+-----------+----------------------------------+",
+                "| id      | namespace | resource                         |",
+                "+---------+-----------+----------------------------------+",
+                "| enrich1 | ingest    | {\"cloud.region\":\"eu-west-1\"}     |",
+                "| enrich2 | client-ns | {\"cloud.region\":\"client-region\"} |",
+                "| enrich3 | ingest    | {\"cloud.region\":\"eu-west-1\"}     |",
+                "+---------+-----------+----------------------------------+",
+            ],
+            &result
+        );
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_project_access_enforced_in_scan() -> Result<()> {
+        use datafusion::prelude::SessionConfig;
+
+        let (db, _ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "access").await?;
+        db.insert_records(&create_test_records()).await?;
+
+        let session_for = |projects: Vec<&str>| -> Result<SessionContext> {
+            let config = SessionConfig::new().with_extension(Arc::new(ProjectAccess::new(projects)));
+            let ctx = SessionContext::new_with_config(config);
+            let routing_table = ProjectRoutingTable::new("default".to_string(), Arc::new(db.clone()), OtelLogsAndSpans::schema_ref(), None);
+            ctx.register_table(OtelLogsAndSpans::table_name(), Arc::new(routing_table))?;
+            Ok(ctx)
+        };
+
+        let count_sql = "SELECT COUNT(*) FROM otel_logs_and_spans WHERE project_id = 'default'";
+
+        // Permitted project is readable
+        let ctx = session_for(vec!["default"])?;
+        assert!(ctx.sql(count_sql).await?.collect().await.is_ok());
+
+        // Project outside the allowed set is denied by the provider
+        let ctx = session_for(vec!["other_project"])?;
+        let err = ctx.sql(count_sql).await?.collect().await.expect_err("scan should be denied");
+        assert!(err.to_string().contains("Access denied"), "unexpected error: {}", err);
+
+        // An allowed but unregistered project must not leak the default table it falls back to
+        let ctx = session_for(vec!["unregistered"])?;
+        let err = ctx
+            .sql("SELECT COUNT(*) FROM otel_logs_and_spans WHERE project_id = 'unregistered'")
+            .await?
+            .collect()
+            .await
+            .expect_err("fallback to default should be denied");
+        assert!(err.to_string().contains("'default'"), "unexpected error: {}", err);
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_project_access_is_per_connection() -> Result<()> {
+        let (db, ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "connaccess").await?;
+        db.insert_records(&create_test_records()).await?;
+        let count_sql = "SELECT COUNT(*) FROM otel_logs_and_spans WHERE project_id = 'default'";
+
+        let restricted = Database::connection_context(&ctx);
+        ProjectAccess::from_spec("other_project, another").attach_to(&restricted);
+        let err = restricted.sql(count_sql).await?.collect().await.expect_err("scan should be denied");
+        assert!(err.to_string().contains("Access denied"), "unexpected error: {}", err);
+
+        // Neither other connections nor the shared context they are created from are restricted
+        assert!(Database::connection_context(&ctx).sql(count_sql).await?.collect().await.is_ok());
+        assert!(ctx.sql(count_sql).await?.collect().await.is_ok());
+
+        ProjectAccess::from_spec("*").attach_to(&restricted);
+        assert!(restricted.sql(count_sql).await?.collect().await.is_ok());
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_per_project_compaction_schedules() -> Result<()> {
+        let (db, _ctx, test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "compaction").await?;
+
+        let bucket = env::var("AWS_S3_BUCKET").expect("AWS_S3_BUCKET environment variable not set");
+        let endpoint = env::var("AWS_S3_ENDPOINT").unwrap_or_else(|_| "https://s3.amazonaws.com".to_string());
+        let quiet_uri = format!("s3://{}/{}/quiet/?endpoint={}", bucket, test_prefix, endpoint);
+        db.register_project("quiet", &quiet_uri, None, None, None).await?;
+
+        db.set_compaction_interval("default", Duration::from_secs(3600)).await?;
+        db.set_compaction_interval("quiet", Duration::from_secs(86400)).await?;
+        assert!(db.set_compaction_interval("unknown", Duration::from_secs(60)).await.is_err());
+
+        let start = Instant::now();
+        for project_id in ["default", "quiet"] {
+            db.mark_compacted(project_id, start).await;
+        }
+
+        assert!(db.projects_due_for_compaction(start + Duration::from_secs(1800)).await.is_empty());
+        assert_eq!(db.projects_due_for_compaction(start + Duration::from_secs(2 * 3600)).await, vec!["default"]);
+
+        // The busy project compacts again an hour later while the quiet one is still waiting
+        db.mark_compacted("default", start + Duration::from_secs(2 * 3600)).await;
+        assert!(db.projects_due_for_compaction(start + Duration::from_secs(2 * 3600 + 60)).await.is_empty());
+        assert_eq!(db.projects_due_for_compaction(start + Duration::from_secs(3 * 3600)).await, vec!["default"]);
+
+        assert_eq!(
+            db.projects_due_for_compaction(start + Duration::from_secs(25 * 3600)).await,
+            vec!["default", "quiet"]
+        );
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_schema_check_reports_mismatches() -> Result<()> {
+        use delta_kernel::schema::DataType as DeltaDataType;
+
+        let (db, _ctx, test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "schemacheck").await?;
+
+        // Create a table that lacks `name`, retypes `level` and has a leftover column
+        let bucket = env::var("AWS_S3_BUCKET").expect("AWS_S3_BUCKET environment variable not set");
+        let endpoint = env::var("AWS_S3_ENDPOINT").unwrap_or_else(|_| "https://s3.amazonaws.com".to_string());
+        let legacy_uri = format!("s3://{}/{}/legacy/?endpoint={}", bucket, test_prefix, endpoint);
+        let mut columns: Vec<StructField> = OtelLogsAndSpans::columns()?
+            .into_iter()
+            .filter(|c| c.name() != "name")
+            .map(|c| if c.name() == "level" { StructField::new("level", DeltaDataType::INTEGER, true) } else { c })
+            .collect();
+        columns.insert(0, StructField::new("legacy_col", DeltaDataType::STRING, true));
+        Database::load_or_create_table(
+            &legacy_uri,
+            &Database::storage_options(None, None, None),
+            columns,
+            OtelLogsAndSpans::partitions(),
+        )
+        .await?;
+        db.register_project("legacy", &legacy_uri, None, None, None).await?;
+
+        let report = db.schema_check().await?;
+
+        assert!(report["default"].is_empty(), "default table should match: {:?}", report["default"]);
+        assert_eq!(
+            report["legacy"],
+            SchemaDiff {
+                missing: vec!["name".to_string()],
+                extra: vec!["legacy_col".to_string()],
+                retyped: vec![RetypedColumn {
+                    column: "level".to_string(),
+                    expected: "Utf8".to_string(),
+                    actual: "Int32".to_string(),
+                }],
+            }
+        );
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_delete_range_counts_deleted_records() -> Result<()> {
+        let (db, ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "deleterange").await?;
+        db.insert_records(&create_test_records()).await?;
+
+        let before = crate::metrics::counter_value(RECORDS_DELETED_TOTAL, "test_project");
+        let deleted = db
+            .delete_range(
+                "test_project",
+                Utc.with_ymd_and_hms(2023, 1, 1, 10, 5, 0).unwrap(),
+                Utc.with_ymd_and_hms(2023, 1, 1, 11, 0, 0).unwrap(),
+            )
+            .await?;
+
+        assert_eq!(deleted, 1);
+        assert_eq!(crate::metrics::counter_value(RECORDS_DELETED_TOTAL, "test_project"), before + 1);
+
+        let result = ctx.sql("SELECT id FROM otel_logs_and_spans WHERE project_id = 'test_project'").await?.collect().await?;
+        assert_batches_eq!(["+-------+", "| id    |", "+-------+", "| span1 |", "+-------+"], &result);
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_write_many_single_commit() -> Result<()> {
+        let (db, ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "writemany").await?;
+
+        let version_before = db.resolve_table("default").await?.read().await.version();
+        db.write_many(&[]).await?;
+        assert_eq!(
+            db.resolve_table("default").await?.read().await.version(),
+            version_before,
+            "empty slice must not commit"
+        );
+
+        let records: Vec<OtelLogsAndSpans> = (0..100)
+            .map(|i| OtelLogsAndSpans {
+                id: format!("many{}", i),
+                ..create_test_records().remove(i % 2)
+            })
+            .collect();
+        db.write_many(&records).await?;
+        assert_eq!(
+            db.resolve_table("default").await?.read().await.version(),
+            version_before + 1,
+            "expected a single commit"
+        );
+
+        let result = ctx.sql("SELECT COUNT(*) AS count FROM otel_logs_and_spans WHERE id LIKE 'many%'").await?.collect().await?;
+        assert_batches_eq!(["+-------+", "| count |", "+-------+", "| 100   |", "+-------+"], &result);
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_explicit_partition_date_override() -> Result<()> {
+        let (db, ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "partitiondate").await?;
+
+        let mut records = create_test_records();
+        records[0].id = "derived_date".to_string();
+        // Backfill into the next day's partition despite the 2023-01-01 timestamp
+        records[1].id = "explicit_date".to_string();
+        records[1].date = chrono::NaiveDate::from_ymd_opt(2023, 1, 2).unwrap();
+        db.insert_records(&records).await?;
+
+        let result = ctx
+            .sql("SELECT id, date FROM otel_logs_and_spans WHERE id IN ('derived_date', 'explicit_date') ORDER BY id")
+            .await?
+            .collect()
+            .await?;
+        assert_batches_eq!(
+            [
+                "+---------------+------------+",
+                "| id            | date       |",
+                "+---------------+------------+",
+                "| derived_date  | 2023-01-01 |",
+                "| explicit_date | 2023-01-02 |",
+                "+---------------+------------+",
+            ],
+            &result
+        );
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_writes_routed_by_project_id() -> Result<()> {
+        let (db, ctx, test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "routing").await?;
+
+        let bucket = env::var("AWS_S3_BUCKET").expect("AWS_S3_BUCKET environment variable not set");
+        let endpoint = env::var("AWS_S3_ENDPOINT").unwrap_or_else(|_| "https://s3.amazonaws.com".to_string());
+        let tenant_uri = format!("s3://{}/{}/tenant_a/?endpoint={}", bucket, test_prefix, endpoint);
+        db.register_project("tenant_a", &tenant_uri, None, None, None).await?;
+
+        // One batch with rows for a registered and an unregistered project
+        let mut records = create_test_records();
+        records[0].project_id = "tenant_a".to_string();
+        db.insert_records(&records).await?;
+
+        let count_in = |table: Arc<RwLock<DeltaTable>>, project_id: &'static str| async move {
+            let table = table.read().await.clone();
+            SessionContext::new()
+                .read_table(Arc::new(table))?
+                .filter(datafusion::prelude::col("project_id").eq(datafusion::prelude::lit(project_id)))?
+                .count()
+                .await
+        };
+
+        let tenant_table = db.resolve_table("tenant_a").await?;
+        let default_table = db.resolve_table("default").await?;
+        assert_eq!(count_in(Arc::clone(&tenant_table), "tenant_a").await?, 1);
+        assert_eq!(count_in(Arc::clone(&default_table), "tenant_a").await?, 0);
+        assert_eq!(count_in(Arc::clone(&default_table), "test_project").await?, 1);
+        assert_eq!(count_in(tenant_table, "test_project").await?, 0);
+
+        let result = ctx.sql("SELECT id FROM otel_logs_and_spans WHERE project_id = 'tenant_a'").await?.collect().await?;
+        assert_batches_eq!(["+-------+", "| id    |", "+-------+", "| span1 |", "+-------+"], &result);
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_delete_older_than() -> Result<()> {
+        let (db, ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "retention").await?;
+        db.insert_records(&create_test_records()).await?;
+
+        let summary = db.delete_older_than("default", Utc.with_ymd_and_hms(2023, 1, 1, 10, 5, 0).unwrap()).await?;
+        assert_eq!(summary.rows_deleted, 1);
+        assert!(summary.files_removed >= 1);
+
+        let result = ctx.sql("SELECT id FROM otel_logs_and_spans WHERE project_id = 'test_project'").await?.collect().await?;
+        assert_batches_eq!(["+-------+", "| id    |", "+-------+", "| span2 |", "+-------+"], &result);
+
+        assert!(db.delete_older_than("unknown", Utc::now()).await.is_err());
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_severity_number_and_level_filters_agree() -> Result<()> {
+        let (db, ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "severity").await?;
+        db.register_severity_udfs(&ctx);
+
+        let base = create_test_records().remove(0);
+        let records: Vec<OtelLogsAndSpans> =
+            [("sev_info", "INFO", "9"), ("sev_warn", "WARN", "13"), ("sev_error", "ERROR", "17"), ("sev_fatal", "FATAL", "21")]
+                .into_iter()
+                .map(|(id, level, number)| OtelLogsAndSpans {
+                    id: id.to_string(),
+                    level: Some(level.to_string()),
+                    severity___severity_number: Some(number.to_string()),
+                    ..base.clone()
+                })
+                .collect();
+        db.insert_records(&records).await?;
+
+        let ids = |sql: String| {
+            let ctx = ctx.clone();
+            async move {
+                let batches = ctx.sql(&sql).await?.collect().await?;
+                Ok::<_, anyhow::Error>(datafusion::arrow::util::pretty::pretty_format_batches(&batches)?.to_string())
+            }
+        };
+        let query = |filter: &str| format!("SELECT id FROM otel_logs_and_spans WHERE id LIKE 'sev_%' AND {} ORDER BY id", filter);
+
+        let by_number = ids(query("CAST(severity___severity_number AS INT) >= 17")).await?;
+        assert_eq!(by_number, ids(query("severity_number(level) >= 17")).await?);
+        assert_eq!(by_number, ids(query("level IN ('ERROR', 'FATAL')")).await?);
+        assert!(by_number.contains("sev_error") && by_number.contains("sev_fatal") && !by_number.contains("sev_warn"));
+
+        assert_eq!(
+            ids(query("severity_level(severity___severity_number) = 'WARN'")).await?,
+            ids(query("level = 'WARN'")).await?
+        );
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_ingest_pause_and_resume() -> Result<()> {
+        let marker_dir = tempfile::tempdir()?;
+        unsafe {
+            env::set_var("INGEST_PAUSE_MARKER", marker_dir.path().join("paused"));
+        }
+        let (db, ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "pause").await?;
+        let mut records = create_test_records();
+        records.truncate(1);
+        records[0].id = "paused_write".to_string();
+
+        db.pause_ingest()?;
+        let err = db.insert_records(&records).await.expect_err("writes must be rejected while paused");
+        assert!(err.to_string().contains("paused"));
+
+        // Queries keep working while paused
+        ctx.sql("SELECT COUNT(*) FROM otel_logs_and_spans").await?.collect().await?;
+
+        // The pause survives a restart
+        assert!(Database::new().await?.is_ingest_paused());
+
+        db.resume_ingest()?;
+        assert!(!Database::new().await?.is_ingest_paused());
+        db.insert_records(&records).await?;
+
+        let result = ctx.sql("SELECT id FROM otel_logs_and_spans WHERE id = 'paused_write'").await?.collect().await?;
+        assert_eq!(result.iter().map(|b| b.num_rows()).sum::<usize>(), 1);
+
+        unsafe {
+            env::remove_var("INGEST_PAUSE_MARKER");
+        }
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_compact_all_projects_reduces_file_count() -> Result<()> {
+        let (db, _ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "compactall").await?;
+
+        for i in 0..5 {
+            let mut records = create_test_records();
+            records.truncate(1);
+            records[0].id = format!("small{}", i);
+            db.insert_records(&records).await?;
+        }
+
+        let table = db.resolve_table("default").await?;
+        let files_before = table.read().await.get_files_count();
+        assert!(files_before >= 5, "expected one file per small write, got {}", files_before);
+
+        db.compact_all_projects().await?;
+
+        let files_after = table.read().await.get_files_count();
+        assert!(
+            files_after < files_before,
+            "compaction should reduce files: {} -> {}",
+            files_before,
+            files_after
+        );
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_zorder_project() -> Result<()> {
+        let (db, ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "zorder").await?;
+        db.insert_records(&create_test_records()).await?;
+        db.insert_records(&create_test_records()).await?;
+
+        let columns = vec!["context___trace_id".to_string(), "timestamp".to_string()];
+        db.zorder_project("default", &columns).await?;
+
+        let history = db.project_history("default", Some(1)).await?.unwrap();
+        assert_eq!(history[0].operation.as_deref(), Some("OPTIMIZE"));
+
+        let result = ctx
+            .sql("SELECT COUNT(*) AS count FROM otel_logs_and_spans WHERE context___trace_id = 'trace1'")
+            .await?
+            .collect()
+            .await?;
+        assert_batches_eq!(["+-------+", "| count |", "+-------+", "| 2     |", "+-------+"], &result);
+
+        let err = db.zorder_project("default", &["no_such_column".to_string()]).await.expect_err("unknown column");
+        assert!(err.to_string().contains("Unknown Z-order column 'no_such_column'"));
+        assert!(db.zorder_project("default", &["project_id".to_string()]).await.is_err());
+        assert!(db.zorder_project("unknown", &columns).await.is_err());
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_spans_by_service_view() -> Result<()> {
+        let (db, ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "spansview").await?;
+        let spans = ctx.table_provider(OtelLogsAndSpans::table_name()).await?;
+        Database::register_spans_by_service_view(&ctx, spans)?;
+
+        let mut records = create_test_records();
+        records[0].resource___service___name = Some("checkout".to_string());
+        records[1].resource___service___name = Some("payments".to_string());
+        db.insert_records(&records).await?;
+
+        let sql = "SELECT service, operation, status, trace_id FROM spans_by_service WHERE project_id = 'test_project' AND service = 'checkout'";
+        let result = ctx.sql(sql).await?.collect().await?;
+        assert_batches_eq!(
+            [
+                "+----------+-------------+--------+----------+",
+                "| service  | operation   | status | trace_id |",
+                "+----------+-------------+--------+----------+",
+                "| checkout | test_span_1 | OK     | trace1   |",
+                "+----------+-------------+--------+----------+",
+            ],
+            &result
+        );
+
+        // The service filter reaches the table scan on the underlying column
+        let plan = ctx.sql(&format!("EXPLAIN {}", sql)).await?.collect().await?;
+        let plan = datafusion::arrow::util::pretty::pretty_format_batches(&plan)?.to_string();
+        assert!(
+            plan.contains("partial_filters") && plan.contains("resource___service___name = Utf8(\"checkout\")"),
+            "service filter was not pushed down:\n{}",
+            plan
+        );
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_vacuum_project_dry_run() -> Result<()> {
+        let (db, ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "vacuum").await?;
+        db.insert_records(&create_test_records()).await?;
+        db.insert_records(&create_test_records()).await?;
+        // Compaction tombstones the small files it replaces
+        db.compact_all_projects().await?;
+
+        let preview = db.vacuum_project("default", 0, true).await?;
+        assert!(!preview.is_empty(), "compacted files should be vacuumable");
+
+        // A dry run deletes nothing, so the same files are still listed
+        assert_eq!(db.vacuum_project("default", 0, true).await?.len(), preview.len());
+
+        let before = crate::metrics::counter_value(FILES_VACUUMED_TOTAL, "default");
+        let deleted = db.vacuum_project("default", 0, false).await?;
+        assert_eq!(deleted.len(), preview.len());
+        assert_eq!(crate::metrics::counter_value(FILES_VACUUMED_TOTAL, "default"), before + deleted.len() as u64);
+        assert!(db.vacuum_project("default", 0, true).await?.is_empty());
+
+        // Live data is untouched
+        let result = ctx.sql("SELECT COUNT(*) AS count FROM otel_logs_and_spans WHERE project_id = 'test_project'").await?.collect().await?;
+        assert_batches_eq!(["+-------+", "| count |", "+-------+", "| 4     |", "+-------+"], &result);
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_partition_timestamp_source() -> Result<()> {
+        let (db, ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "tssource").await?;
+
+        // Event recorded on Jan 1st but received by the collector on Jan 3rd
+        let received = Utc.with_ymd_and_hms(2023, 1, 3, 8, 0, 0).unwrap();
+        let mut records = create_test_records();
+        records.truncate(1);
+        records[0].start_time = None;
+        records[0].observed_timestamp = Some(received);
+
+        records[0].id = "event_source".to_string();
+        db.insert_records(&records).await?;
+
+        unsafe {
+            env::set_var("PARTITION_TIMESTAMP_SOURCE", "observed");
+        }
+        records[0].id = "observed_source".to_string();
+        let result = db.insert_records(&records).await;
+        unsafe {
+            env::remove_var("PARTITION_TIMESTAMP_SOURCE");
+        }
+        result?;
+
+        let result = ctx
+            .sql(
+                "SELECT id, date, timestamp, observed_timestamp, start_time FROM otel_logs_and_spans
+                 WHERE id IN ('event_source', 'observed_source') ORDER BY id",
+            )
+            .await?
+            .collect()
+            .await?;
+        assert_batches_eq!(
+            [
+                "+-----------------+------------+---------------------+---------------------+---------------------+",
+                "| id              | date       | timestamp           | observed_timestamp  | start_time          |",
+                "+-----------------+------------+---------------------+---------------------+---------------------+",
+                "| event_source    | 2023-01-01 | 2023-01-01T10:00:00 | 2023-01-03T08:00:00 |                     |",
+                "| observed_source | 2023-01-03 | 2023-01-03T08:00:00 | 2023-01-03T08:00:00 | 2023-01-01T10:00:00 |",
+                "+-----------------+------------+---------------------+---------------------+---------------------+",
+            ],
+            &result
+        );
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_encrypted_column_round_trip() -> Result<()> {
+        let (db, ctx, test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "encryption").await?;
+
+        let bucket = env::var("AWS_S3_BUCKET").expect("AWS_S3_BUCKET environment variable not set");
+        let endpoint = env::var("AWS_S3_ENDPOINT").unwrap_or_else(|_| "https://s3.amazonaws.com".to_string());
+        let project_uri = format!("s3://{}/{}/test_project/?endpoint={}", bucket, test_prefix, endpoint);
+        db.register_project("test_project", &project_uri, None, None, None).await?;
+
+        unsafe {
+            env::set_var("COLUMN_ENCRYPTION_KEY", "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=");
+        }
+        assert!(db.set_encrypted_columns("test_project", vec!["timestamp".to_string()]).await.is_err());
+        db.set_encrypted_columns("test_project", vec!["attributes___user___email".to_string()]).await?;
+        db.register_decrypt_udf(&ctx)?;
+        let authorized = Database::connection_context(&ctx);
+        ProjectAccess::new(["test_project"]).attach_to(&authorized);
+
+        let mut records = create_test_records();
+        records[0].attributes___user___email = Some("jane@example.com".to_string());
+        let result = db.insert_records(&records).await;
+        unsafe {
+            env::remove_var("COLUMN_ENCRYPTION_KEY");
+        }
+        result?;
+
+        let sql = "SELECT attributes___user___email AS raw, decrypt(project_id, attributes___user___email) AS email
+                   FROM otel_logs_and_spans WHERE project_id = 'test_project' AND id = 'span1'";
+        // Sessions without a project access list can't decrypt
+        let err = ctx.sql(sql).await?.collect().await.unwrap_err();
+        assert!(err.to_string().contains("Access denied"), "{}", err);
+
+        let result = authorized.sql(sql).await?.collect().await?;
+        let raw = result[0].column(0).as_any().downcast_ref::<datafusion::arrow::array::StringArray>().unwrap().value(0);
+        let email = result[0].column(1).as_any().downcast_ref::<datafusion::arrow::array::StringArray>().unwrap().value(0);
+        assert!(
+            raw.starts_with(crate::encryption::ENCRYPTED_PREFIX),
+            "stored value should be encrypted: {}",
+            raw
+        );
+        assert!(!raw.contains("jane"));
+        assert_eq!(email, "jane@example.com");
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_ingest_lag_gauge() -> Result<()> {
+        let (db, _ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "ingestlag").await?;
+
+        // Newest fixture record is from 2023-01-01T10:10:00, so ingestion looks years behind
+        db.insert_records(&create_test_records()).await?;
+        db.refresh_ingest_lag().await;
+
+        let newest = Utc.with_ymd_and_hms(2023, 1, 1, 10, 10, 0).unwrap();
+        assert_eq!(db.max_timestamp("default").await?, Some(newest));
+
+        let expected = (Utc::now() - newest).num_seconds() as f64;
+        let lag = crate::metrics::gauge_values(INGEST_LAG_SECONDS)
+            .into_iter()
+            .find(|(project_id, _)| project_id == "default")
+            .map(|(_, lag)| lag)
+            .expect("ingest lag gauge should be set for the default project");
+        assert!((lag - expected).abs() < 60.0, "lag {} should be close to {}", lag, expected);
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_failed_commit_leaves_no_partial_data() -> Result<()> {
+        use object_store::{ObjectStore, path::Path};
+
+        let (db, ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "failedcommit").await?;
+        let table_ref = db.resolve_table("default").await?;
+
+        // Another writer's broken commit takes the next version, so the data files get uploaded but our commit fails
+        let (store, version) = {
+            let table = table_ref.read().await;
+            (table.object_store(), table.version())
+        };
+        let next_commit = Path::from(format!("_delta_log/{:020}.json", version + 1));
+        store.put(&next_commit, bytes::Bytes::from_static(b"not a delta commit").into()).await?;
+
+        let before = crate::metrics::counter_value(FAILED_COMMITS_TOTAL, "test_project");
+        assert!(db.insert_records(&create_test_records()).await.is_err());
+        assert_eq!(crate::metrics::counter_value(FAILED_COMMITS_TOTAL, "test_project"), before + 1);
+        assert_eq!(table_ref.read().await.version(), version);
+
+        // None of the uploaded rows are visible
+        let batches = ctx.sql("SELECT id FROM otel_logs_and_spans WHERE project_id = 'test_project'").await?.collect().await?;
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 0);
+
+        store.delete(&next_commit).await?;
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_reload_project_sees_external_writes() -> Result<()> {
+        let (db, ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "reload").await?;
+        let cached_version = |db: Database| async move { db.project_configs.read().await["default"].2.read().await.version() };
+        let initial_version = cached_version(db.clone()).await;
+
+        // A second instance on the same bucket plays the external writer
+        let external = Database::new().await?;
+        external.insert_records(&create_test_records()).await?;
+        assert_eq!(cached_version(db.clone()).await, initial_version, "snapshot should be stale before reload");
+
+        let version = db.reload_project("default").await?;
+        assert!(version > initial_version);
+        assert_eq!(cached_version(db.clone()).await, version);
+
+        let result = ctx.sql("SELECT COUNT(*) AS count FROM otel_logs_and_spans").await?.collect().await?;
+        assert_batches_eq!(["+-------+", "| count |", "+-------+", "| 2     |", "+-------+"], &result);
+
+        assert!(db.reload_project("missing").await.is_err());
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_dedup_window_skips_reflushed_records() -> Result<()> {
+        let store = tempfile::tempdir()?;
+        unsafe {
+            env::set_var("DEDUP_WINDOW_SECS", "3600");
+            env::set_var("DEDUP_STORE_PATH", store.path().join("dedup"));
+        }
+        let setup = setup_test_database(Uuid::new_v4().to_string() + "dedup").await;
+        unsafe {
+            env::remove_var("DEDUP_WINDOW_SECS");
+            env::remove_var("DEDUP_STORE_PATH");
+        }
+        let (db, ctx, _test_prefix) = setup?;
+
+        let records = create_test_records();
+        db.insert_records(&records).await?;
+        // The same records flushed again, e.g. after a retry, are skipped
+        db.insert_records(&records).await?;
+
+        let result = ctx.sql("SELECT id, COUNT(*) AS count FROM otel_logs_and_spans GROUP BY id ORDER BY id").await?.collect().await?;
+        assert_batches_eq!(
+            [
+                "+-------+-------+",
+                "| id    | count |",
+                "+-------+-------+",
+                "| span1 | 1     |",
+                "| span2 | 1     |",
+                "+-------+-------+",
+            ],
+            &result
+        );
+
+        // Under the upsert policy a repeated id is an update, so it isn't skipped
+        let mut updated = records[0].clone();
+        updated.status_message = Some("Updated".to_string());
+        unsafe {
+            env::set_var("DUPLICATE_ID_POLICY", "upsert");
+        }
+        let written = db.insert_records(&vec![updated]).await;
+        unsafe {
+            env::remove_var("DUPLICATE_ID_POLICY");
+        }
+        written?;
+        let result = ctx.sql("SELECT status_message FROM otel_logs_and_spans WHERE id = 'span1'").await?.collect().await?;
+        assert_batches_eq!(
+            ["+----------------+", "| status_message |", "+----------------+", "| Updated        |", "+----------------+",],
+            &result
+        );
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_export_query_to_s3() -> Result<()> {
+        use crate::export::{ExportDestination, export_to_s3};
+        use datafusion::parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        let (db, _ctx, test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "export").await?;
+        db.insert_records(&create_test_records()).await?;
+
+        let bucket = env::var("AWS_S3_BUCKET")?;
+        let destination = ExportDestination::parse(&format!("s3://{}/exports/{}", bucket, test_prefix))?;
+        let store = destination.store()?;
+        let df = db.query("SELECT id, name FROM otel_logs_and_spans ORDER BY id").await?;
+        let keys = export_to_s3(df, &destination, store.clone()).await?;
+        assert_eq!(keys.len(), 1);
+        assert!(keys[0].starts_with(&format!("exports/{}/", test_prefix)));
+
+        let bytes = store.get(&object_store::path::Path::from(keys[0].as_str())).await?.bytes().await?;
+        let batches = ParquetRecordBatchReaderBuilder::try_new(bytes)?.build()?.collect::<Result<Vec<_>, _>>()?;
+        assert_batches_eq!(
+            [
+                "+-------+-------------+",
+                "| id    | name        |",
+                "+-------+-------------+",
+                "| span1 | test_span_1 |",
+                "| span2 | test_span_2 |",
+                "+-------+-------------+",
+            ],
+            &batches
+        );
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_trace_view_includes_correlated_logs() -> Result<()> {
+        let (db, _ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "traceview").await?;
+
+        let mut records = create_test_records();
+        let mut log = records[0].clone();
+        log.id = "log1".to_string();
+        log.kind = Some("log".to_string());
+        log.body = Some("\"cache miss\"".to_string());
+        let mut orphan = log.clone();
+        orphan.id = "log2".to_string();
+        orphan.context___span_id = Some("unknown_span".to_string());
+        let mut other_trace = log.clone();
+        other_trace.id = "log3".to_string();
+        other_trace.context___trace_id = Some("trace2".to_string());
+        records.extend([log, orphan, other_trace]);
+        db.insert_records(&records).await?;
+
+        let ids = |rows: &[serde_json::Value]| rows.iter().map(|r| r["id"].as_str().unwrap().to_string()).collect::<Vec<_>>();
+
+        let view = db.trace_view("test_project", "trace1", true, &ProjectAccess::all()).await?;
+        assert_eq!(ids(&view.spans), ["span1", "span2"]);
+        assert_eq!(ids(view.logs.as_deref().unwrap()), ["log1", "log2"]);
+        assert_eq!(view.orphan_logs, 1);
+
+        let view = db.trace_view("test_project", "trace1", false, &ProjectAccess::new(["test_project"])).await?;
+        assert_eq!(ids(&view.spans), ["span1", "span2"]);
+        assert!(view.logs.is_none());
+
+        // Another project's token can't read the trace
+        let err = db.trace_view("test_project", "trace1", true, &ProjectAccess::new(["other_project"])).await.unwrap_err();
+        assert!(err.to_string().contains("Access denied"), "{}", err);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_batches_to_json_types() -> Result<()> {
+        use datafusion::arrow::array::{BooleanArray, TimestampMicrosecondArray, UInt32Array, UInt64Array};
+        use datafusion::arrow::datatypes::{DataType, Field, Schema, TimeUnit};
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("cache_hit", DataType::Boolean, true),
+            Field::new("attributes___client___port", DataType::UInt32, true),
+            Field::new("duration", DataType::UInt64, true),
+            Field::new("timestamp", DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())), false),
+            Field::new("start_time", DataType::Timestamp(TimeUnit::Microsecond, None), true),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(BooleanArray::from(vec![Some(true), None])),
+                Arc::new(UInt32Array::from(vec![Some(8080), None])),
+                Arc::new(UInt64Array::from(vec![Some(100_000_000), None])),
+                Arc::new(TimestampMicrosecondArray::from(vec![1_672_567_200_000_000, 1_672_567_800_000_000]).with_timezone("UTC")),
+            ],
+        )?;
+
+        let rows = Database::batches_to_json(&[batch])?;
+        assert_eq!(
+            rows[0],
+            serde_json::json!({
+                "cache_hit": true,
+                "attributes___client___port": 8080,
+                "duration": 100_000_000,
+                "timestamp": "2023-01-01T10:00:00Z",
+                "start_time": "2023-01-01T10:00:00.123456Z",
+            })
         );
-        session_context.register_table(OtelLogsAndSpans::table_name(), Arc::new(routing_table))?;
+        assert_eq!(rows[1], serde_json::json!({ "timestamp": "2023-01-01T10:10:00Z" }), "nulls are left out");
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_max_projects() -> Result<()> {
+        let (db, _ctx, test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "maxprojects").await?;
+        let bucket = env::var("AWS_S3_BUCKET").expect("AWS_S3_BUCKET environment variable not set");
+        let endpoint = env::var("AWS_S3_ENDPOINT").unwrap_or_else(|_| "https://s3.amazonaws.com".to_string());
+        let uri = |name: &str| format!("s3://{}/{}/{}/?endpoint={}", bucket, test_prefix, name, endpoint);
+
+        unsafe {
+            env::set_var("MAX_PROJECTS", "1");
+        }
+        let result = async {
+            db.register_project("first", &uri("first"), None, None, None).await?;
+            let err = db.register_project("second", &uri("second"), None, None, None).await.expect_err("second project is over the cap");
+            assert_eq!(err.downcast_ref::<ProjectLimitReached>(), Some(&ProjectLimitReached { max: 1 }));
+            // Re-registering an existing project doesn't count against the cap
+            db.register_project("first", &uri("first"), None, None, None).await?;
+            assert_eq!(db.project_count().await, 1);
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+        unsafe {
+            env::remove_var("MAX_PROJECTS");
+        }
+        result
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_read_only_project() -> Result<()> {
+        let (db, ctx, test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "readonly").await?;
+        let bucket = env::var("AWS_S3_BUCKET").expect("AWS_S3_BUCKET environment variable not set");
+        let endpoint = env::var("AWS_S3_ENDPOINT").unwrap_or_else(|_| "https://s3.amazonaws.com".to_string());
+        db.register_project(
+            "archive",
+            &format!("s3://{}/{}/archive/?endpoint={}", bucket, test_prefix, endpoint),
+            None,
+            None,
+            None,
+        )
+        .await?;
+
+        let mut records = create_test_records();
+        for record in &mut records {
+            record.project_id = "archive".to_string();
+        }
+        db.insert_records(&records[..1].to_vec()).await?;
+
+        db.set_project_read_only("archive", true).await?;
+        let err = db.insert_records(&records[1..].to_vec()).await.expect_err("writes to a read-only project must be rejected");
+        assert_eq!(
+            err.downcast_ref::<ProjectReadOnly>(),
+            Some(&ProjectReadOnly {
+                project_id: "archive".to_string()
+            })
+        );
+        // Other projects are still writable
+        db.insert_records(&create_test_records()).await?;
+
+        // Queries keep working
+        let result = ctx.sql("SELECT id FROM otel_logs_and_spans WHERE project_id = 'archive'").await?.collect().await?;
+        assert_eq!(result.iter().map(|b| b.num_rows()).sum::<usize>(), 1);
+
+        db.set_project_read_only("archive", false).await?;
+        db.insert_records(&records[1..].to_vec()).await?;
+        assert!(db.set_project_read_only("missing", true).await.is_err());
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_query_custom_attributes() -> Result<()> {
+        let (db, ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "attributes").await?;
+        let mut records = create_test_records();
+        records[0].attributes = Some(r#"{"feature.flag":"beta","retries":3}"#.to_string());
+        db.insert_records(&records).await?;
+
+        let result = ctx
+            .sql(
+                "SELECT id, attributes->>'feature.flag' AS flag, json_get_int(attributes, 'retries') AS retries \
+                 FROM otel_logs_and_spans WHERE project_id = 'test_project' AND attributes->>'feature.flag' = 'beta'",
+            )
+            .await?
+            .collect()
+            .await?;
+        let expected = [
+            "+-------+------+---------+",
+            "| id    | flag | retries |",
+            "+-------+------+---------+",
+            "| span1 | beta | 3       |",
+            "+-------+------+---------+",
+        ];
+        assert_batches_eq!(expected, &result);
 
-        Ok((db, session_context, test_prefix))
+        Ok(())
     }
 
-    // Helper function to create sample test records
-    fn create_test_records() -> Vec<OtelLogsAndSpans> {
-        let timestamp1 = Utc.with_ymd_and_hms(2023, 1, 1, 10, 0, 0).unwrap();
-        let timestamp2 = Utc.with_ymd_and_hms(2023, 1, 1, 10, 10, 0).unwrap();
+    #[serial]
+    #[tokio::test]
+    async fn test_postgres_fdw_query_shapes() -> Result<()> {
+        let (db, ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "fdw").await?;
+        db.insert_records(&create_test_records()).await?;
+
+        // Remote queries as postgres_fdw deparses them: schema-qualified, quoted columns, casted literals,
+        // doubly parenthesized quals, explicit null ordering and a casted LIMIT
+        let rows = |sql: &'static str| {
+            let ctx = ctx.clone();
+            async move { ctx.sql(sql).await?.collect().await }
+        };
+        let result = rows(
+            "SELECT \"id\", \"name\", \"timestamp\" FROM public.otel_logs_and_spans \
+             WHERE ((project_id = 'test_project'::text)) AND ((\"timestamp\" >= '2023-01-01 10:05:00'::timestamp without time zone))",
+        )
+        .await?;
+        let expected = [
+            "+-------+-------------+---------------------+",
+            "| id    | name        | timestamp           |",
+            "+-------+-------------+---------------------+",
+            "| span2 | test_span_2 | 2023-01-01T10:10:00 |",
+            "+-------+-------------+---------------------+",
+        ];
+        assert_batches_eq!(expected, &result);
+
+        let result = rows(
+            "SELECT \"id\" FROM public.otel_logs_and_spans WHERE ((project_id = 'test_project'::text)) \
+             AND ((\"timestamp\" < '2023-01-02 00:00:00+00'::timestamp with time zone)) ORDER BY \"timestamp\" DESC NULLS FIRST LIMIT 1::bigint",
+        )
+        .await?;
+        let expected = ["+-------+", "| id    |", "+-------+", "| span2 |", "+-------+"];
+        assert_batches_eq!(expected, &result);
+
+        // Aggregate pushdown, and the `SELECT NULL` postgres_fdw sends for count(*) without it
+        let result = rows(
+            "SELECT status_code, count(*) FROM public.otel_logs_and_spans WHERE ((project_id = 'test_project'::text)) \
+             GROUP BY 1 ORDER BY status_code ASC NULLS LAST",
+        )
+        .await?;
+        let expected = [
+            "+-------------+----------+",
+            "| status_code | count(*) |",
+            "+-------------+----------+",
+            "| ERROR       | 1        |",
+            "| OK          | 1        |",
+            "+-------------+----------+",
+        ];
+        assert_batches_eq!(expected, &result);
+        let result = rows("SELECT NULL FROM public.otel_logs_and_spans WHERE ((project_id = 'test_project'::text))").await?;
+        assert_eq!(result.iter().map(|b| b.num_rows()).sum::<usize>(), 2);
 
-        vec![
-            OtelLogsAndSpans {
-                project_id: "test_project".to_string(),
-                // date: timestamp1.date_naive(),
-                timestamp: timestamp1,
-                observed_timestamp: Some(timestamp1),
-                id: "span1".to_string(),
-                name: Some("test_span_1".to_string()),
-                context___trace_id: Some("trace1".to_string()),
-                context___span_id: Some("span1".to_string()),
-                start_time: Some(timestamp1),
-                duration: Some(100_000_000),
-                status_code: Some("OK".to_string()),
-                level: Some("INFO".to_string()),
-                ..Default::default()
-            },
-            OtelLogsAndSpans {
-                project_id: "test_project".to_string(),
-                // date: timestamp2.date_naive(),
-                timestamp: timestamp2,
-                observed_timestamp: Some(timestamp2),
-                id: "span2".to_string(),
-                name: Some("test_span_2".to_string()),
-                context___trace_id: Some("trace2".to_string()),
-                context___span_id: Some("span2".to_string()),
-                start_time: Some(timestamp2),
-                duration: Some(200_000_000),
-                status_code: Some("ERROR".to_string()),
-                level: Some("ERROR".to_string()),
-                status_message: Some("Error occurred".to_string()),
-                ..Default::default()
-            },
-        ]
+        Ok(())
     }
 
     #[serial]
     #[tokio::test]
-    async fn test_database_query() -> Result<()> {
-        let (db, ctx, test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "query").await?;
-        log::info!("Using test-specific table prefix: {}", test_prefix);
+    async fn test_delete_and_update_records() -> Result<()> {
+        let (db, ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "dml").await?;
+        db.insert_records(&create_test_records()).await?;
+
+        let deleted = db.delete_record("DELETE FROM otel_logs_and_spans WHERE project_id = 'test_project' AND id = 'span1'").await?;
+        assert_eq!(deleted, 1);
+        let updated = db
+            .update_record("UPDATE otel_logs_and_spans SET status_code = 'UNSET' WHERE project_id = 'test_project' AND context___trace_id = 'trace2'")
+            .await?;
+        assert_eq!(updated, 1);
+
+        let result = ctx.sql("SELECT id, status_code FROM otel_logs_and_spans WHERE project_id = 'test_project'").await?.collect().await?;
+        let expected = [
+            "+-------+-------------+",
+            "| id    | status_code |",
+            "+-------+-------------+",
+            "| span2 | UNSET       |",
+            "+-------+-------------+",
+        ];
+        assert_batches_eq!(expected, &result);
+
+        // Over PGWire the statements need ENABLE_SQL_DML and a session with an access list
+        let sql = "DELETE FROM otel_logs_and_spans WHERE project_id = 'test_project' AND id = 'span2'";
+        let disabled = ctx.sql(sql).await?.collect().await.expect_err("SQL DML is off by default");
+        assert!(disabled.to_string().contains("ENABLE_SQL_DML"), "{}", disabled);
+        let session = Database::connection_context(&ctx);
+        ProjectAccess::new(["test_project", "default"]).attach_to(&session);
+        unsafe {
+            env::set_var("ENABLE_SQL_DML", "true");
+        }
+        let unauthorized = ctx.sql(sql).await?.collect().await;
+        let authorized = session.sql(sql).await?.collect().await;
+        unsafe {
+            env::remove_var("ENABLE_SQL_DML");
+        }
+        assert!(unauthorized.expect_err("needs an access list").to_string().contains("Access denied"));
+        let expected = ["+-------+", "| count |", "+-------+", "| 1     |", "+-------+"];
+        assert_batches_eq!(expected, &authorized?);
+
+        // The project must be named by a positive condition, and subqueries aren't applied to the target table
+        for sql in [
+            "DELETE FROM otel_logs_and_spans WHERE id = 'span2'",
+            "DELETE FROM otel_logs_and_spans WHERE NOT (project_id = 'unregistered')",
+            "DELETE FROM otel_logs_and_spans WHERE project_id = 'test_project' OR id = 'span2'",
+        ] {
+            let err = db.delete_record(sql).await.unwrap_err();
+            assert!(err.to_string().contains("project_id"), "{}: {}", sql, err);
+        }
+        let subquery = "DELETE FROM otel_logs_and_spans WHERE project_id = 'test_project' \
+                        AND id IN (SELECT id FROM otel_logs_and_spans WHERE project_id = 'other')";
+        assert!(db.delete_record(subquery).await.is_err());
+        assert!(db.update_record("UPDATE otel_logs_and_spans SET project_id = 'other' WHERE project_id = 'test_project'").await.is_err());
+
+        Ok(())
+    }
 
+    #[serial]
+    #[tokio::test]
+    async fn test_query_spans() -> Result<()> {
+        let (db, _ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "spans").await?;
         let records = create_test_records();
         db.insert_records(&records).await?;
 
-        // Test 1: Basic count query to verify record insertion
-        let count_df = ctx.sql("SELECT COUNT(*) as count FROM otel_logs_and_spans").await?;
-        let result = count_df.collect().await?;
-
-        #[rustfmt::skip]
-        assert_batches_eq!(
-            [
-                "+-------+", 
-                "| count |", 
-                "+-------+", 
-                "| 2     |", 
-                "+-------+",
-            ], &result);
+        let spans = db.query_spans("SELECT * FROM otel_logs_and_spans WHERE project_id = 'test_project' ORDER BY timestamp").await?;
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].id, "span1");
+        assert_eq!(spans[0].timestamp, records[0].timestamp);
+        assert_eq!(spans[0].duration, Some(100_000_000));
+        assert_eq!(spans[1].status_code.as_deref(), Some("ERROR"));
+        assert_eq!(spans[1].status_message.as_deref(), Some("Error occurred"));
+
+        // Columns left out of the query come back as their defaults
+        let spans = db.query_spans("SELECT id, name FROM otel_logs_and_spans WHERE project_id = 'test_project' ORDER BY id").await?;
+        assert_eq!(spans.iter().map(|s| s.id.as_str()).collect::<Vec<_>>(), ["span1", "span2"]);
+        assert_eq!(spans[1].name.as_deref(), Some("test_span_2"));
+        assert_eq!(spans[1].status_code, None);
+        assert_eq!(spans[1].timestamp, OtelLogsAndSpans::default().timestamp);
 
-        // Test 2: Query with field selection and ordering
-        log::info!("Testing field selection and ordering");
-        let df = ctx.sql("SELECT timestamp, name, status_code, level FROM otel_logs_and_spans ORDER BY name").await?;
-        let result = df.collect().await?;
+        Ok(())
+    }
 
-        assert_batches_eq!(
-            [
-                "+---------------------+-------------+-------------+-------+",
-                "| timestamp           | name        | status_code | level |",
-                "+---------------------+-------------+-------------+-------+",
-                "| 2023-01-01T10:00:00 | test_span_1 | OK          | INFO  |",
-                "| 2023-01-01T10:10:00 | test_span_2 | ERROR       | ERROR |",
-                "+---------------------+-------------+-------------+-------+",
-            ],
-            &result
+    #[serial]
+    #[tokio::test]
+    async fn test_selftest() -> Result<()> {
+        let (db, _ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "selftest").await?;
+        crate::selftest::run(&db).await?;
+
+        // The scratch project is gone again
+        assert_eq!(
+            db.list_projects().await.iter().map(|p| p.project_id.as_str()).collect::<Vec<_>>(),
+            vec!["default"]
         );
 
-        // Test 3: Filtering by project_id and level
-        log::info!("Testing filtering by project_id and level");
-        let df = ctx
-            .sql("SELECT name, level, status_code, status_message FROM otel_logs_and_spans WHERE project_id = 'test_project' AND level = 'ERROR'")
-            .await?;
-        let result = df.collect().await?;
+        Ok(())
+    }
 
-        assert_batches_eq!(
-            [
-                "+-------------+-------+-------------+----------------+",
-                "| name        | level | status_code | status_message |",
-                "+-------------+-------+-------------+----------------+",
-                "| test_span_2 | ERROR | ERROR       | Error occurred |",
-                "+-------------+-------+-------------+----------------+",
-            ],
-            &result
-        );
+    #[serial]
+    #[tokio::test]
+    async fn test_cross_project_scan() -> Result<()> {
+        let (db, ctx, test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "crossproject").await?;
+        let bucket = env::var("AWS_S3_BUCKET").expect("AWS_S3_BUCKET environment variable not set");
+        let endpoint = env::var("AWS_S3_ENDPOINT").unwrap_or_else(|_| "https://s3.amazonaws.com".to_string());
+        db.register_project(
+            "tenant",
+            &format!("s3://{}/{}/tenant/?endpoint={}", bucket, test_prefix, endpoint),
+            None,
+            None,
+            None,
+        )
+        .await?;
+
+        db.insert_records(&create_test_records()).await?;
+        let mut tenant_records = create_test_records();
+        for record in &mut tenant_records {
+            record.project_id = "tenant".to_string();
+            record.id = format!("tenant_{}", record.id);
+        }
+        db.insert_records(&tenant_records).await?;
 
-        // Test 4: Complex query with multiple data types (including timestamp and end_time)
-        // Note: For timestamp columns, we need to format them for the test to use assert_batches_eq
-        log::info!("Testing complex query with multiple data types");
-        let df = ctx
-            .sql(
-                "
-                    SELECT
-                        id,
-                        name,
-                        project_id,
-                        duration,
-                        CAST(duration / 1000000 AS INTEGER) as duration_ms,
-                        context___trace_id,
-                        status_code,
-                        level,
-                        to_char(timestamp, '%Y-%m-%d %H:%M') as formatted_timestamp,
-                        to_char(end_time, 'YYYY-MM-DD HH24:MI') as formatted_end_time,
-                        CASE WHEN status_code = 'ERROR' THEN status_message ELSE NULL END as conditional_message
-                    FROM otel_logs_and_spans
-                    ORDER BY id
-                ",
-            )
-            .await?;
-        let result = df.collect().await?;
+        let count = |ctx: &SessionContext, sql: &'static str| {
+            let ctx = ctx.clone();
+            async move { Ok::<_, anyhow::Error>(ctx.sql(sql).await?.collect().await?.iter().map(|b| b.num_rows()).sum::<usize>()) }
+        };
+        let operator = Database::connection_context(&ctx);
+        ProjectAccess::all().attach_to(&operator);
+        let tenant_only = Database::connection_context(&ctx);
+        ProjectAccess::new(["tenant"]).attach_to(&tenant_only);
 
-        assert_batches_eq!(
-            [
-                "+-------+-------------+--------------+-----------+-------------+--------------------+-------------+-------+---------------------+--------------------+---------------------+",
-                "| id    | name        | project_id   | duration  | duration_ms | context___trace_id | status_code | level | formatted_timestamp | formatted_end_time | conditional_message |",
-                "+-------+-------------+--------------+-----------+-------------+--------------------+-------------+-------+---------------------+--------------------+---------------------+",
-                "| span1 | test_span_1 | test_project | 100000000 | 100         | trace1             | OK          | INFO  | 2023-01-01 10:00    |                    |                     |",
-                "| span2 | test_span_2 | test_project | 200000000 | 200         | trace2             | ERROR       | ERROR | 2023-01-01 10:10    |                    | Error occurred      |",
-                "+-------+-------------+--------------+-----------+-------------+--------------------+-------------+-------+---------------------+--------------------+---------------------+",
-            ],
-            &result
-        );
+        // Without the flag only the default table is read
+        assert_eq!(count(&ctx, "SELECT id FROM otel_logs_and_spans").await?, 2);
 
-        // Test 5: Timestamp filtering
-        log::info!("Testing timestamp filtering");
-        let df = ctx.sql("SELECT COUNT(*) as count FROM otel_logs_and_spans WHERE timestamp > '2023-01-01T10:05:00.000000Z'").await?;
-        let result = df.collect().await?;
+        unsafe {
+            env::set_var("CROSS_PROJECT_SCAN", "true");
+        }
+        let all = count(&operator, "SELECT id FROM otel_logs_and_spans").await;
+        let limited = count(&operator, "SELECT id FROM otel_logs_and_spans LIMIT 3").await;
+        let plan = operator.sql("EXPLAIN SELECT id FROM otel_logs_and_spans LIMIT 3").await?.collect().await;
+        let filtered = count(&operator, "SELECT id FROM otel_logs_and_spans WHERE project_id = 'tenant'").await;
+        let allowed_only = count(&tenant_only, "SELECT id FROM otel_logs_and_spans").await;
+        let unrestricted = count(&ctx, "SELECT id FROM otel_logs_and_spans").await;
+        unsafe {
+            env::remove_var("CROSS_PROJECT_SCAN");
+        }
+        assert_eq!(all?, 4);
+        assert_eq!(limited?, 3);
+        assert_eq!(filtered?, 2);
+        assert_eq!(allowed_only?, 2);
+        let plan = datafusion::arrow::util::pretty::pretty_format_batches(&plan?)?.to_string();
+        assert!(plan.contains("UnionExec"), "{}", plan);
 
-        #[rustfmt::skip]
-            assert_batches_eq!(
-                [
-                    "+-------+",
-                    "| count |",
-                    "+-------+",
-                    "| 1     |",
-                    "+-------+",
-                ],
-                &result
-            );
+        // Sessions without an access list can't read every tenant at once
+        let err = unrestricted.expect_err("cross-project scan needs an access list");
+        assert!(err.to_string().contains("Access denied"), "{}", err);
 
-        // Test 6: Duration filtering
-        log::info!("Testing duration filtering");
-        let df = ctx.sql("SELECT name FROM otel_logs_and_spans WHERE duration > 150000000").await?;
-        let result = df.collect().await?;
+        Ok(())
+    }
 
-        #[rustfmt::skip]
+    #[serial]
+    #[tokio::test]
+    async fn test_scan_routes_in_list_and_or_to_each_project() -> Result<()> {
+        let (db, ctx, test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "inlist").await?;
+        let bucket = env::var("AWS_S3_BUCKET").expect("AWS_S3_BUCKET environment variable not set");
+        let endpoint = env::var("AWS_S3_ENDPOINT").unwrap_or_else(|_| "https://s3.amazonaws.com".to_string());
+        db.register_project(
+            "tenant",
+            &format!("s3://{}/{}/tenant/?endpoint={}", bucket, test_prefix, endpoint),
+            None,
+            None,
+            None,
+        )
+        .await?;
+
+        // test_project isn't registered, so its records live in the default table
+        db.insert_records(&create_test_records()).await?;
+        let mut tenant_records = create_test_records();
+        for record in &mut tenant_records {
+            record.project_id = "tenant".to_string();
+            record.id = format!("tenant_{}", record.id);
+        }
+        db.insert_records(&tenant_records).await?;
+
+        for sql in [
+            "SELECT id FROM otel_logs_and_spans WHERE project_id IN ('test_project', 'tenant') ORDER BY id",
+            "SELECT id FROM otel_logs_and_spans WHERE project_id = 'test_project' OR project_id = 'tenant' ORDER BY id",
+        ] {
+            let result = ctx.sql(sql).await?.collect().await?;
             assert_batches_eq!(
                 [
-                    "+-------------+",
-                    "| name        |",
-                    "+-------------+",
-                    "| test_span_2 |",
-                    "+-------------+",
+                    "+--------------+",
+                    "| id           |",
+                    "+--------------+",
+                    "| span1        |",
+                    "| span2        |",
+                    "| tenant_span1 |",
+                    "| tenant_span2 |",
+                    "+--------------+",
                 ],
                 &result
             );
+        }
 
-        // Test 7: Complex filtering with timestamp columns in ISO 8601 format
-        log::info!("Testing complex filtering with timestamp columns in ISO 8601 format");
-        let df = ctx
-            .sql(
-                "
-                    WITH time_data AS (
-                        SELECT
-                            name,
-                            status_code,
-                            level,
-                            CAST(duration / 1000000 AS INTEGER) as duration_ms,
-                            timestamp,
-                            to_char(timestamp, '%Y-%m-%d') as date_only,
-                            EXTRACT(HOUR FROM timestamp) as hour,
-                            to_char(timestamp, '%Y-%m-%dT%H:%M:%S%.6fZ') as iso_timestamp
-                        FROM otel_logs_and_spans
-                        WHERE
-                            project_id = 'test_project'
-                            AND timestamp > '2023-01-01T10:05:00.000000Z'
-                    )
-                    SELECT
-                        name,
-                        status_code,
-                        level,
-                        duration_ms,
-                        date_only,
-                        hour,
-                        iso_timestamp
-                    FROM time_data
-                    ORDER BY name
-                ",
-            )
+        // Remaining filters still apply in every branch
+        let result = ctx
+            .sql("SELECT id FROM otel_logs_and_spans WHERE project_id IN ('test_project', 'tenant') AND status_code = 'ERROR' ORDER BY id")
+            .await?
+            .collect()
             .await?;
-        let result = df.collect().await?;
-
         assert_batches_eq!(
-            [
-                "+-------------+-------------+-------+-------------+------------+------+-----------------------------+",
-                "| name        | status_code | level | duration_ms | date_only  | hour | iso_timestamp               |",
-                "+-------------+-------------+-------+-------------+------------+------+-----------------------------+",
-                "| test_span_2 | ERROR       | ERROR | 200         | 2023-01-01 | 10   | 2023-01-01T10:10:00.000000Z |",
-                "+-------------+-------------+-------+-------------+------------+------+-----------------------------+",
-            ],
+            ["+--------------+", "| id           |", "+--------------+", "| span2        |", "| tenant_span2 |", "+--------------+",],
             &result
         );
+        let plan = ctx
+            .sql("EXPLAIN SELECT id FROM otel_logs_and_spans WHERE project_id IN ('test_project', 'tenant')")
+            .await?
+            .collect()
+            .await?;
+        let plan = datafusion::arrow::util::pretty::pretty_format_batches(&plan)?.to_string();
+        assert!(plan.contains("UnionExec"), "{}", plan);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_project_ids_ignores_negations() {
+        use datafusion::prelude::{col, lit};
+
+        let ids = |expr: Expr| ProjectRoutingTable::extract_project_ids(&expr);
+        let in_list = || col("project_id").in_list(vec![lit("a"), lit("b")], false);
+        assert_eq!(ids(in_list()), Some(vec!["a".to_string(), "b".to_string()]));
+        assert_eq!(ids(Expr::Not(Box::new(in_list()))), None);
+        assert_eq!(ids(Expr::Not(Box::new(col("project_id").eq(lit("a"))))), None);
+        assert_eq!(
+            ids(col("project_id").eq(lit("a")).or(Expr::Not(Box::new(col("project_id").eq(lit("b")))))),
+            None
+        );
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_partition_filters_are_exact() -> Result<()> {
+        let (db, ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "exactfilters").await?;
+        db.insert_records(&create_test_records()).await?;
+
+        let sql = "SELECT id FROM otel_logs_and_spans WHERE project_id = 'test_project' AND date = '2023-01-01' AND status_code = 'ERROR'";
+        let result = ctx.sql(sql).await?.collect().await?;
+        assert_batches_eq!(["+-------+", "| id    |", "+-------+", "| span2 |", "+-------+"], &result);
+
+        // Only the non-partition predicate is evaluated again after the scan
+        let plan = ctx.sql(&format!("EXPLAIN {}", sql)).await?.collect().await?;
+        let plan = datafusion::arrow::util::pretty::pretty_format_batches(&plan)?.to_string();
+        let physical = plan.split("physical_plan").nth(1).unwrap_or_default();
+        let filter = physical.lines().find(|l| l.contains("FilterExec")).expect("status_code filter should remain");
+        assert!(
+            filter.contains("status_code") && !filter.contains("project_id") && !filter.contains("date"),
+            "{}",
+            plan
+        );
+
+        let partitions = OtelLogsAndSpans::partitions();
+        let exact = |expr: Expr| partition_filter_pushdown(&expr, &partitions) == TableProviderFilterPushDown::Exact;
+        use datafusion::prelude::{cast, col, lit};
+        assert!(exact(col("project_id").eq(lit("p1"))));
+        assert!(exact(lit("p1").eq(col("project_id"))));
+        assert!(exact(col("date").gt_eq(lit("2023-01-01")).and(col("project_id").eq(lit("p1")))));
+        assert!(!exact(col("timestamp").gt(lit("2023-01-01"))));
+        assert!(!exact(col("project_id").eq(lit("p1")).or(col("status_code").eq(lit("OK")))));
+        assert!(!exact(col("project_id").like(lit("p%"))));
+        // File pruning can't be relied on for these, so they are filtered again after the scan
+        let many: Vec<Expr> = (0..25).map(|i| lit(format!("p{}", i))).collect();
+        assert!(!exact(col("project_id").in_list(many, false)));
+        assert!(!exact(col("project_id").in_list(vec![lit("p1"), lit("p2")], false)));
+        assert!(!exact(col("project_id").eq(lit("p1")).or(col("project_id").eq(lit("p2")))));
+        assert!(!exact(Expr::Not(Box::new(col("project_id").eq(lit("p1"))))));
+        assert!(!exact(col("project_id").not_eq(lit("p1"))));
+        assert!(!exact(cast(col("date"), arrow_schema::DataType::Utf8).eq(lit("2023-01-01"))));
+
+        Ok(())
+    }
+
+    #[serial]
+    #[tokio::test]
+    async fn test_query_cache_is_invalidated_by_writes() -> Result<()> {
+        unsafe {
+            env::set_var("TIMEFUSION_QUERY_CACHE", "true");
+        }
+        let setup = setup_test_database(Uuid::new_v4().to_string() + "querycache").await;
+        unsafe {
+            env::remove_var("TIMEFUSION_QUERY_CACHE");
+        }
+        let (db, _ctx, _test_prefix) = setup?;
+        db.insert_records(&create_test_records()[..1].to_vec()).await?;
+
+        let sql = "SELECT count(*) AS n FROM otel_logs_and_spans WHERE project_id = 'test_project'";
+        let count = |(batches, _): (Vec<RecordBatch>, CacheStatus)| {
+            batches[0].column(0).as_any().downcast_ref::<datafusion::arrow::array::Int64Array>().unwrap().value(0)
+        };
+        assert_eq!(count(db.query_cached(sql, CacheMode::Use, &ProjectAccess::all()).await?), 1);
+        assert_eq!(
+            count(db.query_cached(&format!("  {}  ;", sql), CacheMode::Use, &ProjectAccess::all()).await?),
+            1
+        );
+        let stats = db.query_cache_stats().unwrap();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+
+        // Writes to other projects keep the entry, writes to test_project make the next query see them
+        let mut other = create_test_records()[1..].to_vec();
+        other[0].project_id = "other_project".to_string();
+        db.insert_records(&other).await?;
+        assert_eq!(count(db.query_cached(sql, CacheMode::Use, &ProjectAccess::all()).await?), 1);
+        assert_eq!(db.query_cache_stats().unwrap().hits, 2);
+
+        db.insert_records(&create_test_records()[1..].to_vec()).await?;
+        assert_eq!(count(db.query_cached(sql, CacheMode::Use, &ProjectAccess::all()).await?), 2);
+        assert_eq!(db.query_cache_stats().unwrap().misses, 2);
+
+        // `no-store` runs the query without touching the cache, `no-cache` runs it and replaces the entry
+        let (_, status) = db.query_cached(sql, CacheMode::Bypass, &ProjectAccess::all()).await?;
+        assert_eq!(status, CacheStatus::Bypass);
+        let stats = db.query_cache_stats().unwrap();
+        assert_eq!((stats.hits, stats.misses), (2, 2));
+        let (_, status) = db.query_cached(sql, CacheMode::Refresh, &ProjectAccess::all()).await?;
+        assert_eq!(status, CacheStatus::Miss);
+        assert_eq!(db.query_cached(sql, CacheMode::Use, &ProjectAccess::all()).await?.1, CacheStatus::Hit);
 
         Ok(())
     }
 
-    #[serial]
-    #[tokio::test]
-    async fn test_sql_insert() -> Result<()> {
-        let (db, ctx, test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "insert").await?;
-        log::info!("Using test-specific table prefix for SQL INSERT test: {}", test_prefix);
+    #[test]
+    fn test_projects_read_by_ignores_negations() -> Result<()> {
+        use datafusion::datasource::{MemTable, provider_as_source};
+        use datafusion::logical_expr::LogicalPlanBuilder;
+        use datafusion::prelude::{col, lit};
 
-        let datetime = chrono::DateTime::parse_from_rfc3339("2023-02-01T15:30:00.000000Z").unwrap().with_timezone(&chrono::Utc);
-        let record = OtelLogsAndSpans {
-            project_id: "default".to_string(),
-            timestamp: datetime,
-            observed_timestamp: Some(datetime),
-            id: "sql_span1a".to_string(),
-            name: Some("sql_test_span".to_string()),
-            duration: Some(150000000),
-            start_time: Some(datetime),
-            context___trace_id: Some("sql_trace1".to_string()),
-            context___span_id: Some("sql_span1".to_string()),
-            status_code: Some("OK".to_string()),
-            status_message: Some("SQL inserted successfully".to_string()),
-            level: Some("INFO".to_string()),
-            ..Default::default()
+        let source = provider_as_source(Arc::new(MemTable::try_new(OtelLogsAndSpans::schema_ref(), vec![vec![]])?));
+        let read_by = |filter: Expr| {
+            let plan = LogicalPlanBuilder::scan_with_filters(OtelLogsAndSpans::table_name(), Arc::clone(&source), None, vec![filter])?.build()?;
+            Database::projects_read_by(&plan)
         };
-        db.insert_records(&vec![record]).await?;
+        assert_eq!(read_by(col("project_id").eq(lit("a")))?, Some(vec!["a".to_string()]));
+        // `NOT (project_id = 'a')` reads every project but `a`, so any write must invalidate it
+        assert_eq!(read_by(Expr::Not(Box::new(col("project_id").eq(lit("a")))))?, None);
+        assert_eq!(read_by(Expr::Not(Box::new(col("project_id").in_list(vec![lit("a"), lit("b")], false))))?, None);
+        Ok(())
+    }
 
-        let verify_df = ctx.sql("SELECT id, name, timestamp from otel_logs_and_spans").await?.collect().await?;
-        #[rustfmt::skip]
-        assert_batches_eq!(
-            [
-                "+------------+---------------+---------------------+",
-                "| id         | name          | timestamp           |",
-                "+------------+---------------+---------------------+",
-                "| sql_span1a | sql_test_span | 2023-02-01T15:30:00 |",
-                "+------------+---------------+---------------------+",
-        ], &verify_df);
+    #[test]
+    fn test_validate_project_and_bucket_names() {
+        for valid in ["tenant_1", "pid-3", "A"] {
+            assert!(validate_project_id(valid).is_ok(), "{} should be valid", valid);
+        }
+        for invalid in ["", "has space", "quote'd", "../etc", &"x".repeat(65)] {
+            assert!(validate_project_id(invalid).is_err(), "{} should be invalid", invalid);
+        }
 
-        let insert_sql = "INSERT INTO otel_logs_and_spans (
-                 project_id, date, timestamp, id, hashes,
-                 parent_id, name, kind,
-                 status_code, status_message, level, severity___severity_text, severity___severity_number,
-                 body, duration, start_time, end_time
-             ) VALUES (
-                 'test_project', TIMESTAMP '2023-01-01', TIMESTAMP '2023-01-01T10:00:00Z', 'sql_span1', ARRAY[],
-                 NULL, 'sql_test_span', NULL,
-                 'OK', 'span inserted successfully', 'INFO', 'INFORMATION', NULL,
-                 NULL, 150000000, TIMESTAMP '2023-01-01T10:00:00Z', NULL
-             )";
+        for valid in ["tenant-logs", "my.bucket.1", "abc"] {
+            assert!(validate_bucket_name(valid).is_ok(), "{} should be valid", valid);
+        }
+        for invalid in ["ab", "Upper", "-leading", "trailing.", "double..dot", "192.168.0.1", "s3://bucket", &"b".repeat(64)] {
+            assert!(validate_bucket_name(invalid).is_err(), "{} should be invalid", invalid);
+        }
+    }
 
-        let insert_result = ctx.sql(insert_sql).await?.collect().await?;
-        #[rustfmt::skip]
-         assert_batches_eq!(
-             ["+-------+",
-             "| count |",
-             "+-------+",
-             "| 1     |",
-             "+-------+",
-         ], &insert_result);
+    #[serial]
+    #[tokio::test]
+    async fn test_list_projects_hides_credentials() -> Result<()> {
+        let (db, _ctx, test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "listprojects").await?;
+        let bucket = env::var("AWS_S3_BUCKET").expect("AWS_S3_BUCKET environment variable not set");
+        let endpoint = env::var("AWS_S3_ENDPOINT").unwrap_or_else(|_| "https://s3.amazonaws.com".to_string());
+        let conn_str = format!("s3://{}/{}/tenant/?endpoint={}&secret_key=hunter2", bucket, test_prefix, endpoint);
+        db.register_project("tenant", &conn_str, None, None, None).await?;
+        db.set_project_read_only("tenant", true).await?;
+
+        let projects = db.list_projects().await;
+        assert_eq!(projects.iter().map(|p| p.project_id.as_str()).collect::<Vec<_>>(), ["default", "tenant"]);
+        let tenant = &projects[1];
+        assert_eq!(tenant.uri, format!("s3://{}/{}/tenant/", bucket, test_prefix));
+        assert!(tenant.read_only);
+        assert!(!serde_json::to_string(&projects)?.contains("hunter2"));
+        assert!(db.is_project_registered("tenant").await);
+
+        // Creating an existing project fails, registering it again still updates it
+        let err = db.create_project("tenant", &conn_str, None, None, None).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ProjectAlreadyRegistered>(),
+            Some(&ProjectAlreadyRegistered {
+                project_id: "tenant".to_string()
+            })
+        );
+        db.register_project("tenant", &conn_str, None, None, None).await?;
 
-        let verify_df = ctx
-            .sql("SELECT project_id, id, name, timestamp, kind, status_code, severity___severity_text, duration, start_time from otel_logs_and_spans order by timestamp desc")
-            .await?
-            .collect()
-            .await?;
-        #[rustfmt::skip]
-        assert_batches_eq!(
-        [
-            "+--------------+------------+---------------+---------------------+------+-------------+--------------------------+-----------+---------------------+",
-            "| project_id   | id         | name          | timestamp           | kind | status_code | severity___severity_text | duration  | start_time          |",
-            "+--------------+------------+---------------+---------------------+------+-------------+--------------------------+-----------+---------------------+",
-            "| default      | sql_span1a | sql_test_span | 2023-02-01T15:30:00 |      | OK          |                          | 150000000 | 2023-02-01T15:30:00 |",
-            "| test_project | sql_span1  | sql_test_span | 2023-01-01T10:00:00 |      | OK          | INFORMATION              | 150000000 | 2023-01-01T10:00:00 |",
-            "+--------------+------------+---------------+---------------------+------+-------------+--------------------------+-----------+---------------------+",
-        ]
-        , &verify_df);
+        Ok(())
+    }
 
-        log::info!("Inserting record directly via insert statement");
-        let insert_sql = "INSERT INTO otel_logs_and_spans (
-                project_id, date, timestamp, observed_timestamp, id, hashes,
-                parent_id, name, kind,
-                status_code, status_message, level, severity___severity_text, severity___severity_number,
-                body, duration, start_time, end_time,
-                context___trace_id, context___span_id, context___trace_state, context___trace_flags,
-                context___is_remote, events, links,
-                attributes___client___address, attributes___client___port,
+    #[serial]
+    #[tokio::test]
+    async fn test_scan_batch_size() -> Result<()> {
+        let (db, _ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "batchsize").await?;
+        assert_eq!(db.create_session_context().state().config().batch_size(), 8192);
 
-                attributes___server___address, attributes___server___port, attributes___network___local__address, attributes___network___local__port,
-                attributes___network___peer___address, attributes___network___peer__port, attributes___network___protocol___name, attributes___network___protocol___version,
-                attributes___network___transport, attributes___network___type,attributes___code___number, attributes___code___file___path,
-                attributes___code___function___name, attributes___code___line___number, attributes___code___stacktrace, attributes___log__record___original,
+        unsafe {
+            env::set_var("SCAN_BATCH_SIZE", "1024");
+        }
+        let ctx = db.create_session_context();
+        unsafe {
+            env::remove_var("SCAN_BATCH_SIZE");
+        }
+        assert_eq!(ctx.state().config().batch_size(), 1024);
 
-                attributes___log__record___uid, attributes___error___type, attributes___exception___type, attributes___exception___message,
-                attributes___exception___stacktrace, attributes___url___fragment, attributes___url___full, attributes___url___path,
-                attributes___url___query, attributes___url___scheme, attributes___user_agent___original, attributes___http___request___method,
-                attributes___http___request___method_original,attributes___http___response___status_code, attributes___http___request___resend_count, attributes___http___request___body___size,
+        Ok(())
+    }
 
-                attributes___session___id, attributes___session___previous___id, attributes___db___system___name, attributes___db___collection___name,
-                attributes___db___namespace, attributes___db___operation___name, attributes___db___response___status_code, attributes___db___operation___batch___size,
-                attributes___db___query___summary, attributes___db___query___text, attributes___user___id, attributes___user___email,
-                attributes___user___full_name, attributes___user___name, attributes___user___hash, resource___service___name,
+    #[serial]
+    #[tokio::test]
+    async fn test_registered_projects_survive_restart() -> Result<()> {
+        let registry_dir = tempfile::tempdir()?;
+        unsafe {
+            env::set_var("PROJECT_REGISTRY_PATH", registry_dir.path().join("projects.json"));
+            env::set_var("COLUMN_ENCRYPTION_KEY", "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=");
+        }
+        let result = async {
+            let (db, _ctx, test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "registry").await?;
+            let bucket = env::var("AWS_S3_BUCKET").expect("AWS_S3_BUCKET environment variable not set");
+            let endpoint = env::var("AWS_S3_ENDPOINT").unwrap_or_else(|_| "https://s3.amazonaws.com".to_string());
+            let uri = |name: &str| format!("s3://{}/{}/{}/?endpoint={}", bucket, test_prefix, name, endpoint);
+            db.register_project("tenant", &uri("tenant"), None, None, None).await?;
+            db.set_encrypted_columns("tenant", vec!["attributes___user___email".to_string()]).await?;
+            db.set_project_read_only("tenant", true).await?;
+            db.set_compaction_interval("tenant", Duration::from_secs(600)).await?;
+            db.set_display_timezone("tenant", Some("Europe/Berlin".to_string())).await?;
+            assert!(db.set_display_timezone("tenant", Some("Nowhere/Special".to_string())).await.is_err());
+            // A project whose table can no longer be opened is skipped on startup
+            ProjectRegistry::from_env()?.unwrap().save(&RegisteredProject {
+                project_id: "broken".to_string(),
+                conn_str: format!("s3://{}-missing/{}/broken/?endpoint={}", bucket, test_prefix, endpoint),
+                access_key: None,
+                secret_key: None,
+                endpoint: None,
+                encrypted_columns: vec!["attributes___user___email".to_string()],
+                read_only: false,
+                compaction_interval_secs: None,
+                display_timezone: None,
+            })?;
+
+            let restarted = Database::new().await?;
+            assert!(restarted.is_project_registered("tenant").await);
+            assert_eq!(
+                restarted.encrypted_columns.read().await.get("tenant"),
+                Some(&vec!["attributes___user___email".to_string()])
+            );
+            assert!(restarted.is_project_read_only("tenant").await);
+            assert_eq!(
+                restarted.compaction_schedule("tenant").await.map(|s| s.interval),
+                Some(Duration::from_secs(600))
+            );
+            assert_eq!(restarted.display_timezone("tenant").await.as_deref(), Some("Europe/Berlin"));
+            assert!(!restarted.is_project_registered("broken").await);
+
+            // Its rows are refused rather than written to the default table without encryption
+            let mut records = create_test_records();
+            records.iter_mut().for_each(|r| r.project_id = "broken".to_string());
+            let err = restarted.insert_records(&records).await.unwrap_err();
+            assert!(err.to_string().contains("couldn't be restored"), "{}", err);
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+        unsafe {
+            env::remove_var("PROJECT_REGISTRY_PATH");
+            env::remove_var("COLUMN_ENCRYPTION_KEY");
+        }
+        result
+    }
 
-                resource___service___version, resource___service___instance___id, resource___service___namespace, resource___telemetry___sdk___language,
-                resource___telemetry___sdk___name, resource___telemetry___sdk___version, resource___user_agent___original
-            ) VALUES (
-                'test_project','2023-01-02',  TIMESTAMP '2023-01-02T10:00:00Z', NULL, 'sql_span2', ARRAY[],
-                NULL, 'sql_test_span', NULL,
-                'OK', 'span inserted successfully', 'INFO', NULL, NULL,
-                NULL, 150000000, TIMESTAMP '2023-01-01T10:00:00Z', NULL,
-                'sql_trace1', 'sql_span1', NULL, NULL,
-                NULL, NULL, NULL,
-                NULL, NULL,
+    #[serial]
+    #[tokio::test]
+    async fn test_write_evolves_older_table_schema() -> Result<()> {
+        use datafusion::arrow::array::AsArray;
 
-                NULL, NULL, NULL, NULL,
-                NULL, NULL, NULL, NULL,
-                NULL, NULL, NULL, NULL,
-                NULL, NULL, NULL, NULL,
+        let (db, _ctx, test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "evolve").await?;
+        let bucket = env::var("AWS_S3_BUCKET").expect("AWS_S3_BUCKET environment variable not set");
+        let endpoint = env::var("AWS_S3_ENDPOINT").unwrap_or_else(|_| "https://s3.amazonaws.com".to_string());
+        let uri = format!("s3://{}/{}/older_schema/?endpoint={}", bucket, test_prefix, endpoint);
+
+        // A table created before the last nullable column was added to the schema
+        let mut columns = OtelLogsAndSpans::columns()?;
+        let new_column = columns.iter().rposition(|c| c.is_nullable()).map(|idx| columns.remove(idx)).unwrap();
+        let new_column = new_column.name().clone();
+        let options = Database::storage_options(None, None, None);
+        let table = Database::load_or_create_table(&uri, &options, columns, OtelLogsAndSpans::partitions()).await?;
+        let table_ref = Arc::new(RwLock::new(table));
+
+        let full = Database::records_to_batch(&create_test_records())?;
+        let old_fields: Vec<usize> = (0..full.num_columns()).filter(|i| full.schema().field(*i).name() != &new_column).collect();
+        Database::write_batches(&table_ref, vec![full.slice(0, 1).project(&old_fields)?]).await?;
+
+        // Writing the current schema adds the column instead of failing
+        Database::write_batches(&table_ref, vec![full.slice(1, 1)]).await?;
+        let table = table_ref.read().await.clone();
+        assert!(table.snapshot()?.schema().field(&new_column).is_some());
+
+        // Old rows stay readable and read the new column as NULL
+        let ctx = SessionContext::new();
+        ctx.register_table("evolved", Arc::new(table))?;
+        let batches = ctx.sql(&format!("SELECT id FROM evolved WHERE \"{}\" IS NULL ORDER BY id", new_column)).await?.collect().await?;
+        let ids: Vec<String> = batches
+            .iter()
+            .flat_map(|b| b.column(0).as_string::<i32>().iter().flatten().map(String::from).collect::<Vec<_>>())
+            .collect();
+        assert!(ids.contains(&"span1".to_string()), "{:?}", ids);
+
+        // The upsert policy's MERGE evolves an older table too
+        let upsert_uri = format!("s3://{}/{}/older_schema_upsert/?endpoint={}", bucket, test_prefix, endpoint);
+        let columns: Vec<StructField> = OtelLogsAndSpans::columns()?.into_iter().filter(|c| c.name() != &new_column).collect();
+        let table = Database::load_or_create_table(&upsert_uri, &options, columns, OtelLogsAndSpans::partitions()).await?;
+        let table_ref = Arc::new(RwLock::new(table));
+        Database::write_batches(&table_ref, vec![full.slice(0, 1).project(&old_fields)?]).await?;
+        unsafe {
+            env::set_var("DUPLICATE_ID_POLICY", "upsert");
+        }
+        let upserted = Database::write_batches(&table_ref, vec![full.clone()]).await;
+        unsafe {
+            env::remove_var("DUPLICATE_ID_POLICY");
+        }
+        upserted?;
+        assert!(table_ref.read().await.snapshot()?.schema().field(&new_column).is_some());
 
-                NULL, NULL, NULL, NULL,
-                NULL, NULL, NULL, NULL,
-                NULL, NULL, NULL, NULL,
-                NULL, NULL, NULL, NULL,
+        Ok(())
+    }
 
-                NULL, NULL, NULL, NULL,
-                NULL, NULL, NULL, NULL,
-                NULL, NULL, NULL, NULL,
-                NULL, NULL, NULL, NULL,
+    #[serial]
+    #[tokio::test]
+    async fn test_evolve_schema() -> Result<()> {
+        let (db, _ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "evolveop").await?;
+        // The default table was created with the current schema, so there is nothing to add
+        assert!(db.evolve_schema("default").await?.is_empty());
+        assert!(db.evolve_schema("missing").await.is_err());
+        Ok(())
+    }
 
-                NULL, NULL, NULL, NULL,
-                NULL, NULL, NULL
-            )";
+    #[serial]
+    #[tokio::test]
+    async fn test_s3_read_concurrency() -> Result<()> {
+        let (db, _ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "concurrency").await?;
+        assert!(!Database::storage_options(None, None, None).0.contains_key("OBJECT_STORE_CONCURRENCY_LIMIT"));
 
-        let insert_result = ctx.sql(insert_sql).await?.collect().await?;
-        #[rustfmt::skip]
-         assert_batches_eq!(
-             ["+-------+",
-             "| count |",
-             "+-------+",
-             "| 1     |",
-             "+-------+",
-         ], &insert_result);
+        unsafe {
+            env::set_var("S3_READ_CONCURRENCY", "4");
+        }
+        let options = Database::storage_options(None, None, None);
+        let ctx = db.create_session_context();
+        unsafe {
+            env::remove_var("S3_READ_CONCURRENCY");
+        }
+        assert_eq!(options.0.get("OBJECT_STORE_CONCURRENCY_LIMIT").map(String::as_str), Some("4"));
+        assert_eq!(ctx.state().config().options().execution.meta_fetch_concurrency, 4);
 
-        // Verify that the SQL-inserted record exists
-        let verify_df = ctx.sql("SELECT id, name, status_message FROM otel_logs_and_spans WHERE id = 'sql_span1'").await?;
-        let verify_result = verify_df.collect().await?;
+        Ok(())
+    }
 
-        // Check that we can retrieve the inserted record
-        assert_batches_eq!(
-            [
-                "+-----------+---------------+----------------------------+",
-                "| id        | name          | status_message             |",
-                "+-----------+---------------+----------------------------+",
-                "| sql_span1 | sql_test_span | span inserted successfully |",
-                "+-----------+---------------+----------------------------+",
-            ],
-            &verify_result
-        );
+    #[serial]
+    #[tokio::test]
+    async fn test_copy_from_commits_every_batch() -> Result<()> {
+        let (db, ctx, _test_prefix) = setup_test_database(Uuid::new_v4().to_string() + "copybatch").await?;
+        unsafe {
+            env::set_var("COPY_BATCH_ROWS", "2");
+        }
+        let copy = crate::pg_copy::TimeFusionCopyHandler::new(db.clone(), ctx.clone());
+        let format = crate::pg_copy::CopyFormat {
+            delimiter: b',',
+            header: false,
+        };
+        copy.start(vec!["project_id".to_string(), "timestamp".to_string(), "id".to_string()], format)?;
+        unsafe {
+            env::remove_var("COPY_BATCH_ROWS");
+        }
 
-        // TODO: verify the correct copy to syntax
-        // let copy_sql = "COPY (VALUES (
-        //         NULL, 'sql_span2copy',
-        //         NULL, 'sql_test_span_copy', NULL,
-        //         'OK', 'span copied into successfully', 'INFO', NULL, NULL,
-        //         NULL, 150000000, TIMESTAMP '2023-01-01T10:00:00Z', NULL,
-        //         'sql_trace1copy', 'sql_span1copy', NULL, NULL,
-        //         NULL, NULL, NULL,
-        //         NULL, NULL,
-        //
-        //         NULL, NULL, NULL, NULL,
-        //         NULL, NULL, NULL, NULL,
-        //         NULL, NULL, NULL, NULL,
-        //         NULL, NULL, NULL, NULL,
-        //
-        //         NULL, NULL, NULL, NULL,
-        //         NULL, NULL, NULL, NULL,
-        //         NULL, NULL, NULL, NULL,
-        //         NULL, NULL, NULL, NULL,
-        //
-        //         NULL, NULL, NULL, NULL,
-        //         NULL, NULL, NULL, NULL,
-        //         NULL, NULL, NULL, NULL,
-        //         NULL, NULL, NULL, NULL,
-        //
-        //         NULL, NULL, NULL, NULL,
-        //         NULL, NULL, NULL,
-        //
-        //         'test_project', TIMESTAMP '2023-01-02T10:00:00Z'
-        //     )) TO otel_logs_and_spans ";
-        //
-        // let insert_result = ctx.sql(copy_sql).await?.collect().await?;
-        // #[rustfmt::skip]
-        // assert_batches_eq!(
-        //     ["+-------+",
-        //     "| count |",
-        //     "+-------+",
-        //     "| 1     |",
-        //     "+-------+",
-        // ], &insert_result);
+        let version_before = db.resolve_table("default").await?.read().await.version();
+        copy.receive(b"test_project,2024-01-02T03:04:05Z,copy1\ntest_project,2024-01-02T03:04:06Z,copy2\ntest_project,2024-01-02T03")
+            .await?;
+        assert_eq!(
+            db.resolve_table("default").await?.read().await.version(),
+            version_before + 1,
+            "first batch must commit"
+        );
 
-        let verify_df = ctx
-             .sql("SELECT project_id, id, name, timestamp, kind, status_code, severity___severity_text, duration, start_time from otel_logs_and_spans order by timestamp desc")
-             .await?
-             .collect()
-             .await?;
-        #[rustfmt::skip]
-         assert_batches_eq!(
-             [
-                 "+--------------+------------+---------------+---------------------+------+-------------+--------------------------+-----------+---------------------+",
-                 "| project_id   | id         | name          | timestamp           | kind | status_code | severity___severity_text | duration  | start_time          |",
-                 "+--------------+------------+---------------+---------------------+------+-------------+--------------------------+-----------+---------------------+",
-                 "| default      | sql_span1a | sql_test_span | 2023-02-01T15:30:00 |      | OK          |                          | 150000000 | 2023-02-01T15:30:00 |",
-                 "| test_project | sql_span2  | sql_test_span | 2023-01-02T10:00:00 |      | OK          |                          | 150000000 | 2023-01-01T10:00:00 |",
-                 "| test_project | sql_span1  | sql_test_span | 2023-01-01T10:00:00 |      | OK          | INFORMATION              | 150000000 | 2023-01-01T10:00:00 |",
-                 "+--------------+------------+---------------+---------------------+------+-------------+--------------------------+-----------+---------------------+",
-             ],
-             &verify_df
-         );
+        copy.receive(b":04:07Z,copy3\ntest_project,2024-01-02T03:04:08Z,copy4\ntest_project,2024-01-02T03:04:09Z,copy5\n")
+            .await?;
+        assert_eq!(copy.complete().await?, 5);
+        assert_eq!(db.resolve_table("default").await?.read().await.version(), version_before + 3);
 
+        let result = ctx.sql("SELECT COUNT(*) AS count FROM otel_logs_and_spans WHERE id LIKE 'copy%'").await?.collect().await?;
+        assert_batches_eq!(["+-------+", "| count |", "+-------+", "| 5     |", "+-------+"], &result);
         Ok(())
     }
 }
@@ -0,0 +1,314 @@
+// dead_letter.rs - Queued batches that repeatedly failed to write, kept on local disk for inspection and replay
+use std::{env, io::Cursor, sync::Arc};
+
+use anyhow::{Result, anyhow};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use chrono::{DateTime, Utc};
+use datafusion::arrow::{
+    array::{Array, Date32Array, StringArray, TimestampMicrosecondArray},
+    datatypes::DataType,
+    ipc::{reader::StreamReader, writer::StreamWriter},
+    record_batch::RecordBatch,
+};
+use serde::{Deserialize, Serialize};
+
+/// A dead-lettered batch as listed by `GET /dead_letter`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    #[serde(skip_deserializing)]
+    pub key: String,
+    pub project_id: String,
+    pub rows: usize,
+    pub attempts: u32,
+    pub error: String,
+    pub failed_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredDeadLetter {
+    #[serde(flatten)]
+    entry: DeadLetter,
+    /// The batch in the Arrow IPC stream format, base64 encoded
+    batch: String,
+}
+
+/// Which dead-lettered batches to replay, and the fixes to apply to them first
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ReplayRequest {
+    /// Only entries whose error contains this text
+    pub reason_contains: Option<String>,
+    /// Only entries for this project
+    pub project_id: Option<String>,
+    /// Move the rows to another project
+    pub set_project_id: Option<String>,
+    /// Replace null or out-of-range `timestamp` values, and the `date` partition derived from them
+    pub set_timestamp: Option<DateTime<Utc>>,
+}
+
+impl ReplayRequest {
+    pub fn matches(&self, entry: &DeadLetter) -> bool {
+        self.reason_contains.as_ref().is_none_or(|reason| entry.error.contains(reason.as_str()))
+            && self.project_id.as_ref().is_none_or(|project_id| &entry.project_id == project_id)
+    }
+
+    /// Apply the requested fixes to a dead-lettered batch
+    pub fn transform(&self, batch: RecordBatch) -> Result<RecordBatch> {
+        let schema = batch.schema();
+        let rows = batch.num_rows();
+        let mut columns = batch.columns().to_vec();
+
+        if let Some(project_id) = &self.set_project_id {
+            let idx = schema.index_of("project_id")?;
+            columns[idx] = Arc::new(StringArray::from(vec![project_id.as_str(); rows]));
+        }
+        if let Some(timestamp) = self.set_timestamp {
+            let idx = schema.index_of("timestamp")?;
+            let tz = match schema.field(idx).data_type() {
+                DataType::Timestamp(_, tz) => tz.clone(),
+                other => return Err(anyhow!("Unexpected timestamp column type {}", other)),
+            };
+            let timestamps = columns[idx]
+                .as_any()
+                .downcast_ref::<TimestampMicrosecondArray>()
+                .ok_or_else(|| anyhow!("Unexpected timestamp column type {}", columns[idx].data_type()))?;
+            // Valid timestamps are kept, only the rows that can't be written get the replacement
+            let replace: Vec<bool> = timestamps.iter().map(|ts| ts.is_none_or(|ts| !timestamp_in_range(ts))).collect();
+            let fixed = timestamps.iter().zip(&replace).map(|(ts, replace)| if *replace { Some(timestamp.timestamp_micros()) } else { ts });
+            columns[idx] = Arc::new(fixed.collect::<TimestampMicrosecondArray>().with_timezone_opt(tz));
+            if let Ok(date_idx) = schema.index_of("date") {
+                let dates = columns[date_idx]
+                    .as_any()
+                    .downcast_ref::<Date32Array>()
+                    .ok_or_else(|| anyhow!("Unexpected date column type {}", columns[date_idx].data_type()))?;
+                let days = timestamp.timestamp_micros().div_euclid(86_400_000_000) as i32;
+                let fixed = dates.iter().zip(&replace).map(|(date, replace)| if *replace { Some(days) } else { date });
+                columns[date_idx] = Arc::new(fixed.collect::<Date32Array>());
+            }
+        }
+        Ok(RecordBatch::try_new(schema, columns)?)
+    }
+}
+
+/// Whether a timestamp in microseconds lies between the Unix epoch and a day from now
+fn timestamp_in_range(micros: i64) -> bool {
+    (0..=(Utc::now() + chrono::Duration::days(1)).timestamp_micros()).contains(&micros)
+}
+
+/// Sled "dead_letter" tree of batches the batch queue gave up on after MAX_WRITE_ATTEMPTS failed writes.
+///
+/// Moving a poison batch here keeps it from being retried on every flush, while the rows stay available
+/// to requeue once the cause is fixed.
+pub struct DeadLetterStore {
+    db: sled::Db,
+    tree: sled::Tree,
+}
+
+impl std::fmt::Debug for DeadLetterStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DeadLetterStore").field("entries", &self.tree.len()).finish()
+    }
+}
+
+impl DeadLetterStore {
+    /// Enabled when DEAD_LETTER_PATH is set
+    pub fn from_env() -> Result<Option<Self>> {
+        let Some(path) = env::var("DEAD_LETTER_PATH").ok().filter(|p| !p.is_empty()) else {
+            return Ok(None);
+        };
+        let db = sled::open(&path).map_err(|e| anyhow!("Failed to open dead-letter store at {}: {}", path, e))?;
+        log::info!("Dead-letter store enabled at {} (max write attempts: {})", path, Self::max_write_attempts());
+        Ok(Some(Self::new(db)?))
+    }
+
+    pub fn new(db: sled::Db) -> Result<Self> {
+        let tree = db.open_tree("dead_letter")?;
+        Ok(Self { db, tree })
+    }
+
+    /// Failed writes after which a queued batch is dead-lettered (MAX_WRITE_ATTEMPTS, default 5)
+    pub fn max_write_attempts() -> u32 {
+        env::var("MAX_WRITE_ATTEMPTS").ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(5)
+    }
+
+    /// Store a batch with the error of its last write, returning its key
+    pub fn put(&self, batch: &RecordBatch, project_id: &str, attempts: u32, error: &str) -> Result<String> {
+        let key = self.db.generate_id()?;
+        let stored = StoredDeadLetter {
+            entry: DeadLetter {
+                key: key.to_string(),
+                project_id: project_id.to_string(),
+                rows: batch.num_rows(),
+                attempts,
+                error: error.to_string(),
+                failed_at: Utc::now(),
+            },
+            batch: STANDARD.encode(encode_batch(batch)?),
+        };
+        self.tree.insert(key.to_be_bytes(), serde_json::to_vec(&stored)?)?;
+        self.tree.flush()?;
+        Ok(key.to_string())
+    }
+
+    /// Up to `limit` entries, oldest first
+    pub fn list(&self, limit: usize) -> Result<Vec<DeadLetter>> {
+        self.tree
+            .iter()
+            .take(limit)
+            .map(|entry| {
+                let (key, value) = entry?;
+                Ok(Self::decode(&key, &value)?.entry)
+            })
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// The entry and batch stored under `key`, without removing it
+    pub fn get(&self, key: &str) -> Result<Option<(DeadLetter, RecordBatch)>> {
+        let Ok(id) = key.parse::<u64>() else {
+            return Ok(None);
+        };
+        let Some(value) = self.tree.get(id.to_be_bytes())? else {
+            return Ok(None);
+        };
+        let stored = Self::decode(&id.to_be_bytes(), &value)?;
+        let batch = decode_batch(&STANDARD.decode(&stored.batch)?)?;
+        Ok(Some((stored.entry, batch)))
+    }
+
+    /// Keys of the entries matching `request`, oldest first
+    pub fn matching(&self, request: &ReplayRequest) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for entry in self.tree.iter() {
+            let (key, value) = entry?;
+            let stored = Self::decode(&key, &value)?;
+            if request.matches(&stored.entry) {
+                keys.push(stored.entry.key);
+            }
+        }
+        Ok(keys)
+    }
+
+    pub fn remove(&self, key: &str) -> Result<bool> {
+        let Ok(id) = key.parse::<u64>() else {
+            return Ok(false);
+        };
+        let removed = self.tree.remove(id.to_be_bytes())?.is_some();
+        self.tree.flush()?;
+        Ok(removed)
+    }
+
+    fn decode(key: &[u8], value: &[u8]) -> Result<StoredDeadLetter> {
+        let mut stored: StoredDeadLetter = serde_json::from_slice(value)?;
+        let id: [u8; 8] = key.try_into().map_err(|_| anyhow!("Invalid dead-letter key"))?;
+        stored.entry.key = u64::from_be_bytes(id).to_string();
+        Ok(stored)
+    }
+}
+
+fn encode_batch(batch: &RecordBatch) -> Result<Vec<u8>> {
+    let mut writer = StreamWriter::try_new(Vec::new(), &batch.schema())?;
+    writer.write(batch)?;
+    Ok(writer.into_inner()?)
+}
+
+fn decode_batch(bytes: &[u8]) -> Result<RecordBatch> {
+    let mut reader = StreamReader::try_new(Cursor::new(bytes), None)?;
+    reader.next().ok_or_else(|| anyhow!("Dead-letter entry has no batch"))?.map_err(Into::into)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use datafusion::arrow::{
+        array::StringArray,
+        datatypes::{DataType, Field, Schema},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_dead_letter_store() -> Result<()> {
+        let store = DeadLetterStore::new(sled::Config::new().temporary(true).open()?)?;
+        let schema = Arc::new(Schema::new(vec![Field::new("id", DataType::Utf8, false)]));
+        let batch = RecordBatch::try_new(schema, vec![Arc::new(StringArray::from(vec!["a", "b"]))])?;
+
+        let key = store.put(&batch, "p1", 5, "schema mismatch")?;
+        let listed = store.list(10)?;
+        assert_eq!(listed.len(), 1);
+        assert_eq!((listed[0].key.as_str(), listed[0].project_id.as_str(), listed[0].rows), (key.as_str(), "p1", 2));
+        assert_eq!(listed[0].error, "schema mismatch");
+
+        let (entry, stored) = store.get(&key)?.expect("entry should exist");
+        assert_eq!(entry.attempts, 5);
+        assert_eq!(stored, batch);
+
+        assert!(store.remove(&key)?);
+        assert!(store.is_empty());
+        assert!(store.get("not-a-key")?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_transform() -> Result<()> {
+        use datafusion::arrow::array::AsArray;
+        use datafusion::arrow::datatypes::{Date32Type, TimestampMicrosecondType};
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("project_id", DataType::Utf8, false),
+            Field::new(
+                "timestamp",
+                DataType::Timestamp(datafusion::arrow::datatypes::TimeUnit::Microsecond, None),
+                false,
+            ),
+            Field::new("date", DataType::Date32, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(StringArray::from(vec!["p1", "p1"])),
+                Arc::new(TimestampMicrosecondArray::from(vec![-1, 1_700_000_000_000_000])),
+                Arc::new(Date32Array::from(vec![0, 19675])),
+            ],
+        )?;
+
+        let fixed_at = DateTime::parse_from_rfc3339("2024-03-02T10:00:00Z")?.with_timezone(&Utc);
+        let request = ReplayRequest {
+            reason_contains: Some("timestamp".to_string()),
+            set_project_id: Some("p2".to_string()),
+            set_timestamp: Some(fixed_at),
+            ..Default::default()
+        };
+        let fixed = request.transform(batch)?;
+        assert_eq!(fixed.column(0).as_string::<i32>().value(1), "p2");
+        assert_eq!(fixed.column(1).as_primitive::<TimestampMicrosecondType>().value(0), fixed_at.timestamp_micros());
+        assert_eq!(fixed.column(2).as_primitive::<Date32Type>().value(0), 19784);
+        // The valid timestamp and its date are left alone
+        assert_eq!(fixed.column(1).as_primitive::<TimestampMicrosecondType>().value(1), 1_700_000_000_000_000);
+        assert_eq!(fixed.column(2).as_primitive::<Date32Type>().value(1), 19675);
+
+        let entry = DeadLetter {
+            key: "1".to_string(),
+            project_id: "p1".to_string(),
+            rows: 2,
+            attempts: 5,
+            error: "invalid timestamp".to_string(),
+            failed_at: Utc::now(),
+        };
+        assert!(request.matches(&entry));
+        assert!(!request.matches(&DeadLetter {
+            error: "schema mismatch".to_string(),
+            ..entry
+        }));
+
+        Ok(())
+    }
+}
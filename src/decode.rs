@@ -0,0 +1,100 @@
+// decode.rs - Reading ingest request bodies, inflating gzip and zstd Content-Encoding
+use std::{env, io::Read};
+
+use actix_web::{HttpRequest, http::header::CONTENT_ENCODING, web};
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+
+use crate::otlp::IngestError;
+
+/// Largest accepted request body, after decompression. Override with MAX_DECOMPRESSED_BODY_BYTES.
+pub fn max_body_bytes() -> usize {
+    env::var("MAX_DECOMPRESSED_BODY_BYTES").ok().and_then(|v| v.parse().ok()).unwrap_or(32 * 1024 * 1024)
+}
+
+/// Read the raw request payload and inflate it according to its `Content-Encoding`.
+///
+/// Handlers take `web::Payload` rather than `web::Bytes` so the body is decoded exactly once, here,
+/// with the size limit applied to both the compressed and the decompressed body.
+pub async fn read_body(req: &HttpRequest, mut payload: web::Payload) -> Result<Bytes, IngestError> {
+    let limit = max_body_bytes();
+    let encoding = req.headers().get(CONTENT_ENCODING).map(|v| v.to_str().unwrap_or_default().trim().to_lowercase());
+
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|e| IngestError::InvalidPayload(e.to_string()))?;
+        if body.len() + chunk.len() > limit {
+            return Err(IngestError::PayloadTooLarge(limit));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    decode(encoding.as_deref(), body.freeze(), limit)
+}
+
+/// Inflate `body` for the given `Content-Encoding`, failing once the output grows past `limit` bytes
+pub fn decode(encoding: Option<&str>, body: Bytes, limit: usize) -> Result<Bytes, IngestError> {
+    match encoding {
+        None | Some("") | Some("identity") => Ok(body),
+        Some("gzip") | Some("x-gzip") => inflate(flate2::read::MultiGzDecoder::new(&body[..]), limit),
+        Some("zstd") => {
+            let decoder = zstd::stream::read::Decoder::new(&body[..]).map_err(|e| IngestError::InvalidPayload(e.to_string()))?;
+            inflate(decoder, limit)
+        }
+        Some(other) => Err(IngestError::UnsupportedEncoding(other.to_string())),
+    }
+}
+
+fn inflate(decoder: impl Read, limit: usize) -> Result<Bytes, IngestError> {
+    let mut out = Vec::new();
+    // Read one byte past the limit to tell a body of exactly `limit` bytes from an oversized one
+    decoder
+        .take(limit as u64 + 1)
+        .read_to_end(&mut out)
+        .map_err(|e| IngestError::InvalidPayload(format!("Failed to decompress body: {}", e)))?;
+    if out.len() > limit {
+        return Err(IngestError::PayloadTooLarge(limit));
+    }
+    Ok(Bytes::from(out))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{Compression, write::GzEncoder};
+
+    use super::*;
+
+    fn gzip(data: &[u8]) -> Bytes {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        Bytes::from(encoder.finish().unwrap())
+    }
+
+    #[test]
+    fn test_decode_content_encodings() {
+        let data = b"otlp payload".repeat(100);
+
+        assert_eq!(decode(None, Bytes::from(data.clone()), 4096).unwrap(), data);
+        assert_eq!(decode(Some("gzip"), gzip(&data), 4096).unwrap(), data);
+        let zstd_body = Bytes::from(zstd::encode_all(&data[..], 3).unwrap());
+        assert_eq!(decode(Some("zstd"), zstd_body, 4096).unwrap(), data);
+
+        assert!(matches!(
+            decode(Some("br"), Bytes::from(data.clone()), 4096),
+            Err(IngestError::UnsupportedEncoding(_))
+        ));
+        assert!(matches!(decode(Some("gzip"), Bytes::from(data), 4096), Err(IngestError::InvalidPayload(_))));
+    }
+
+    #[test]
+    fn test_decode_rejects_oversized_output() {
+        // 10 MB of zeros compresses to a few KB
+        let bomb = gzip(&vec![0u8; 10 * 1024 * 1024]);
+        assert!(bomb.len() < 64 * 1024);
+
+        assert!(matches!(decode(Some("gzip"), bomb.clone(), 1024 * 1024), Err(IngestError::PayloadTooLarge(_))));
+        assert_eq!(decode(Some("gzip"), bomb, 10 * 1024 * 1024).unwrap().len(), 10 * 1024 * 1024);
+    }
+}
@@ -0,0 +1,171 @@
+// dedup.rs - Persisted window of recently written record ids, so retried or re-flushed records are skipped
+use std::{collections::HashSet, env};
+
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Duration, Utc};
+use datafusion::arrow::{
+    array::{AsArray, BooleanArray},
+    compute::filter_record_batch,
+    record_batch::RecordBatch,
+};
+
+/// Ids written within the last `window`, keyed by `project_id` and `id` in a sled tree on local disk.
+///
+/// Writes check the tree before committing and record their ids after the commit succeeds, so a batch that
+/// is flushed again after a crash or a client retry is dropped instead of duplicated. Entries older than
+/// the window are ignored by lookups and removed by `prune`.
+pub struct DedupWindow {
+    db: sled::Db,
+    window: Duration,
+}
+
+impl DedupWindow {
+    /// Enabled when DEDUP_WINDOW_SECS is greater than zero, stored at DEDUP_STORE_PATH (default `.timefusion_dedup`)
+    pub fn from_env() -> Result<Option<Self>> {
+        let window_secs: i64 = env::var("DEDUP_WINDOW_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(0);
+        if window_secs <= 0 {
+            return Ok(None);
+        }
+        let path = env::var("DEDUP_STORE_PATH").unwrap_or_else(|_| ".timefusion_dedup".to_string());
+        let db = sled::open(&path).map_err(|e| anyhow!("Failed to open dedup store at {}: {}", path, e))?;
+        log::info!("Write dedup window enabled: {}s, store at {}", window_secs, path);
+        Ok(Some(Self::new(db, Duration::seconds(window_secs))))
+    }
+
+    pub fn new(db: sled::Db, window: Duration) -> Self {
+        Self { db, window }
+    }
+
+    /// Drop rows whose id was already written within the window, or appears earlier in `batches`
+    pub fn filter(&self, batches: Vec<RecordBatch>, now: DateTime<Utc>) -> Result<Vec<RecordBatch>> {
+        let cutoff = (now - self.window).timestamp_micros();
+        let mut seen = HashSet::new();
+        let mut filtered = Vec::with_capacity(batches.len());
+        for batch in batches {
+            let keys = Self::keys(&batch)?;
+            let mut keep = Vec::with_capacity(keys.len());
+            for key in keys {
+                let written = match self.db.get(&key)? {
+                    Some(value) => Self::decode_time(&value) > cutoff,
+                    None => false,
+                };
+                keep.push(!written && seen.insert(key));
+            }
+            let skipped = keep.iter().filter(|k| !**k).count();
+            if skipped == 0 {
+                filtered.push(batch);
+                continue;
+            }
+            log::debug!("Skipping {} record(s) already written within the dedup window", skipped);
+            let batch = filter_record_batch(&batch, &BooleanArray::from(keep))?;
+            if batch.num_rows() > 0 {
+                filtered.push(batch);
+            }
+        }
+        Ok(filtered)
+    }
+
+    /// Remember the ids of committed rows. The flush to disk is awaited without blocking the runtime's threads.
+    pub async fn mark_written(&self, batches: &[RecordBatch], now: DateTime<Utc>) -> Result<()> {
+        let written_at = now.timestamp_micros().to_be_bytes();
+        let mut writes = sled::Batch::default();
+        for batch in batches {
+            for key in Self::keys(batch)? {
+                writes.insert(key, &written_at[..]);
+            }
+        }
+        self.db.apply_batch(writes)?;
+        self.db.flush_async().await?;
+        Ok(())
+    }
+
+    /// Remove entries that fell out of the window, returning how many were removed
+    pub fn prune(&self, now: DateTime<Utc>) -> Result<usize> {
+        let cutoff = (now - self.window).timestamp_micros();
+        let mut removed = 0;
+        for entry in self.db.iter() {
+            let (key, value) = entry?;
+            if Self::decode_time(&value) <= cutoff {
+                self.db.remove(key)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn keys(batch: &RecordBatch) -> Result<Vec<Vec<u8>>> {
+        let (Some(projects), Some(ids)) = (
+            batch.column_by_name("project_id").and_then(|c| c.as_string_opt::<i32>()),
+            batch.column_by_name("id").and_then(|c| c.as_string_opt::<i32>()),
+        ) else {
+            return Err(anyhow!("Batch is missing the project_id or id column"));
+        };
+        Ok(projects
+            .iter()
+            .zip(ids.iter())
+            .map(|(project, id)| {
+                let mut key = project.unwrap_or_default().as_bytes().to_vec();
+                key.push(0);
+                key.extend_from_slice(id.unwrap_or_default().as_bytes());
+                key
+            })
+            .collect())
+    }
+
+    fn decode_time(value: &[u8]) -> i64 {
+        value.try_into().map(i64::from_be_bytes).unwrap_or(i64::MIN)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use datafusion::arrow::{
+        array::StringArray,
+        datatypes::{DataType, Field, Schema},
+    };
+
+    use super::*;
+
+    fn batch(ids: &[&str]) -> RecordBatch {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("project_id", DataType::Utf8, false),
+            Field::new("id", DataType::Utf8, false),
+        ]));
+        RecordBatch::try_new(
+            schema,
+            vec![Arc::new(StringArray::from(vec!["p1"; ids.len()])), Arc::new(StringArray::from(ids.to_vec()))],
+        )
+        .unwrap()
+    }
+
+    fn ids(batches: &[RecordBatch]) -> Vec<String> {
+        batches
+            .iter()
+            .flat_map(|b| b.column_by_name("id").unwrap().as_string::<i32>().iter().flatten().map(String::from).collect::<Vec<_>>())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_dedup_window() -> Result<()> {
+        let db = sled::Config::new().temporary(true).open()?;
+        let dedup = DedupWindow::new(db, Duration::seconds(60));
+        let t0 = Utc::now();
+
+        // Repeats inside one write are collapsed
+        let first = dedup.filter(vec![batch(&["a", "b", "a"])], t0)?;
+        assert_eq!(ids(&first), ["a", "b"]);
+        dedup.mark_written(&first, t0).await?;
+
+        // Re-flushing written ids is a no-op, new ids pass through
+        assert!(dedup.filter(vec![batch(&["a", "b"])], t0 + Duration::seconds(30))?.is_empty());
+        assert_eq!(ids(&dedup.filter(vec![batch(&["b", "c"])], t0 + Duration::seconds(30))?), ["c"]);
+
+        // Once the window has passed the ids are accepted again and pruned
+        assert_eq!(ids(&dedup.filter(vec![batch(&["a"])], t0 + Duration::seconds(61))?), ["a"]);
+        assert_eq!(dedup.prune(t0 + Duration::seconds(61))?, 2);
+
+        Ok(())
+    }
+}
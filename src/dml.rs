@@ -0,0 +1,225 @@
+// dml.rs - DELETE and UPDATE on otel_logs_and_spans, run as Delta deletes/updates of the project tables they name
+use std::{any::Any, env, fmt, sync::Arc};
+
+use async_trait::async_trait;
+use datafusion::{
+    arrow::{
+        array::UInt64Array,
+        datatypes::{DataType, Field, Schema, SchemaRef},
+        record_batch::RecordBatch,
+    },
+    common::{
+        Column,
+        tree_node::{Transformed, TreeNode},
+    },
+    error::{DataFusionError, Result as DFResult},
+    execution::{
+        SendableRecordBatchStream, TaskContext,
+        context::{QueryPlanner, SessionState},
+    },
+    logical_expr::{Expr, LogicalPlan, dml::WriteOp, utils::conjunction},
+    physical_expr::EquivalenceProperties,
+    physical_plan::{
+        DisplayAs, DisplayFormatType, ExecutionPlan, Partitioning, PlanProperties,
+        execution_plan::{Boundedness, EmissionType},
+        stream::RecordBatchStreamAdapter,
+    },
+    physical_planner::{DefaultPhysicalPlanner, PhysicalPlanner},
+};
+
+use crate::{
+    database::{Database, ProjectAccess},
+    persistent_queue::OtelLogsAndSpans,
+};
+
+/// Whether `DELETE` and `UPDATE` on otel_logs_and_spans may be run as SQL (ENABLE_SQL_DML=true). Off by default; even
+/// then only sessions with a project access list may run them, on the projects in that list.
+pub fn sql_dml_enabled() -> bool {
+    env::var("ENABLE_SQL_DML").is_ok_and(|v| v == "true")
+}
+
+/// What a `DELETE` or `UPDATE` does: the rows its `WHERE` clause matches and, for `UPDATE`, the new column values.
+/// Expressions use unqualified column names, as Delta resolves them against the table schema.
+#[derive(Debug, Clone)]
+pub struct DmlChange {
+    pub op: WriteOp,
+    pub predicate: Option<Expr>,
+    pub assignments: Vec<(String, Expr)>,
+}
+
+impl DmlChange {
+    /// Read the change from the optimized input of a `Dml` plan, which must be a filter and/or projection over a single
+    /// scan of otel_logs_and_spans. Filters may have been pushed into the table scan, so both `Filter` predicates and
+    /// scan filters count. Anything else, e.g. a subquery turned into a join, is rejected rather than have its
+    /// conditions applied to the wrong table.
+    pub fn from_plan(op: &WriteOp, input: &LogicalPlan) -> DFResult<Self> {
+        let unsupported = || DataFusionError::NotImplemented(format!("{} other than a plain filter on {}", op, OtelLogsAndSpans::table_name()));
+        let (projection, below) = match input {
+            LogicalPlan::Projection(projection) => (Some(projection), projection.input.as_ref()),
+            plan => (None, plan),
+        };
+        let (filter, scan) = match below {
+            LogicalPlan::Filter(filter) => (Some(filter), filter.input.as_ref()),
+            plan => (None, plan),
+        };
+        let LogicalPlan::TableScan(scan) = scan else {
+            return Err(unsupported());
+        };
+        if scan.table_name.table() != OtelLogsAndSpans::table_name() {
+            return Err(unsupported());
+        }
+
+        let predicates: Vec<Expr> = filter.map(|filter| filter.predicate.clone()).into_iter().chain(scan.filters.iter().cloned()).collect();
+        for expr in predicates.iter().chain(projection.iter().flat_map(|projection| projection.expr.iter())) {
+            if has_subquery(expr)? {
+                return Err(unsupported());
+            }
+        }
+        let predicate = conjunction(predicates.into_iter().map(unqualify).collect::<DFResult<Vec<_>>>()?);
+
+        // UPDATE plans project every column, assigned ones as their new value and the rest as themselves
+        let assignments = match (op, projection) {
+            (WriteOp::Update, Some(projection)) => projection
+                .expr
+                .iter()
+                .filter_map(|expr| match expr {
+                    Expr::Alias(alias) => match alias.expr.as_ref() {
+                        Expr::Column(column) if column.name == alias.name => None,
+                        value => Some((alias.name.clone(), value.clone())),
+                    },
+                    _ => None,
+                })
+                .map(|(column, value)| Ok((column, unqualify(value)?)))
+                .collect::<DFResult<Vec<_>>>()?,
+            (WriteOp::Update, _) => return Err(DataFusionError::NotImplemented("UPDATE of this form".to_string())),
+            _ => Vec::new(),
+        };
+
+        Ok(Self {
+            op: op.clone(),
+            predicate,
+            assignments,
+        })
+    }
+}
+
+fn has_subquery(expr: &Expr) -> DFResult<bool> {
+    expr.exists(|expr| Ok(matches!(expr, Expr::ScalarSubquery(_) | Expr::InSubquery(_) | Expr::Exists(_))))
+}
+
+fn unqualify(expr: Expr) -> DFResult<Expr> {
+    expr.transform(|expr| match expr {
+        Expr::Column(column) if column.relation.is_some() => Ok(Transformed::yes(Expr::Column(Column::new_unqualified(column.name)))),
+        expr => Ok(Transformed::no(expr)),
+    })
+    .map(|transformed| transformed.data)
+}
+
+/// Plans `DELETE` and `UPDATE` on otel_logs_and_spans as a `DmlExec` when ENABLE_SQL_DML is set and the session has a
+/// `ProjectAccess`, and everything else with DataFusion's planner, which has no physical plan for either
+#[derive(Debug)]
+pub struct DmlQueryPlanner {
+    database: Database,
+}
+
+impl DmlQueryPlanner {
+    pub fn new(database: Database) -> Self {
+        Self { database }
+    }
+}
+
+#[async_trait]
+impl QueryPlanner for DmlQueryPlanner {
+    async fn create_physical_plan(&self, logical_plan: &LogicalPlan, session_state: &SessionState) -> DFResult<Arc<dyn ExecutionPlan>> {
+        if let LogicalPlan::Dml(dml) = logical_plan {
+            if matches!(dml.op, WriteOp::Delete | WriteOp::Update) && dml.table_name.table() == OtelLogsAndSpans::table_name() {
+                if !sql_dml_enabled() {
+                    return Err(DataFusionError::Plan(format!("{} is disabled, set ENABLE_SQL_DML=true to allow it", dml.op)));
+                }
+                let Some(access) = session_state.config().get_extension::<ProjectAccess>() else {
+                    return Err(DataFusionError::Execution(format!(
+                        "Access denied: {} needs a session with a project access list",
+                        dml.op
+                    )));
+                };
+                let change = DmlChange::from_plan(&dml.op, &dml.input)?;
+                return Ok(Arc::new(DmlExec::new(self.database.clone(), change, access)));
+            }
+        }
+        DefaultPhysicalPlanner::default().create_physical_plan(logical_plan, session_state).await
+    }
+}
+
+/// Runs a `DmlChange` when executed and returns the affected row count as a single `count` row, like DataFusion's
+/// `INSERT`
+#[derive(Debug)]
+pub struct DmlExec {
+    database: Database,
+    change: DmlChange,
+    access: Arc<ProjectAccess>,
+    properties: PlanProperties,
+}
+
+impl DmlExec {
+    pub fn new(database: Database, change: DmlChange, access: Arc<ProjectAccess>) -> Self {
+        let schema = Arc::new(Schema::new(vec![Field::new("count", DataType::UInt64, false)]));
+        let properties = PlanProperties::new(
+            EquivalenceProperties::new(schema),
+            Partitioning::UnknownPartitioning(1),
+            EmissionType::Final,
+            Boundedness::Bounded,
+        );
+        Self {
+            database,
+            change,
+            access,
+            properties,
+        }
+    }
+}
+
+impl DisplayAs for DmlExec {
+    fn fmt_as(&self, _t: DisplayFormatType, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DmlExec: op={}", self.change.op)?;
+        if let Some(predicate) = &self.change.predicate {
+            write!(f, ", predicate={}", predicate)?;
+        }
+        Ok(())
+    }
+}
+
+impl ExecutionPlan for DmlExec {
+    fn name(&self) -> &str {
+        "DmlExec"
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn properties(&self) -> &PlanProperties {
+        &self.properties
+    }
+
+    fn children(&self) -> Vec<&Arc<dyn ExecutionPlan>> {
+        vec![]
+    }
+
+    fn with_new_children(self: Arc<Self>, children: Vec<Arc<dyn ExecutionPlan>>) -> DFResult<Arc<dyn ExecutionPlan>> {
+        if !children.is_empty() {
+            return Err(DataFusionError::Internal("DmlExec has no children".to_string()));
+        }
+        Ok(self)
+    }
+
+    fn execute(&self, _partition: usize, _context: Arc<TaskContext>) -> DFResult<SendableRecordBatchStream> {
+        let schema: SchemaRef = self.schema();
+        let (database, change, access) = (self.database.clone(), self.change.clone(), self.access.clone());
+        let batch_schema = Arc::clone(&schema);
+        let stream = futures::stream::once(async move {
+            let rows = database.apply_dml(&change, &access).await.map_err(|e| DataFusionError::Execution(e.to_string()))?;
+            DFResult::Ok(RecordBatch::try_new(batch_schema, vec![Arc::new(UInt64Array::from(vec![rows]))])?)
+        });
+        Ok(Box::pin(RecordBatchStreamAdapter::new(schema, stream)))
+    }
+}
@@ -0,0 +1,133 @@
+// encryption.rs - Application-level encryption of sensitive string columns with per-project keys
+use std::{env, sync::Arc};
+
+use anyhow::{Result, anyhow};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use datafusion::arrow::{
+    array::{ArrayRef, AsArray, StringArray},
+    datatypes::DataType,
+    record_batch::RecordBatch,
+};
+use ring::{
+    aead::{AES_256_GCM, Aad, LessSafeKey, NONCE_LEN, Nonce, UnboundKey},
+    hkdf,
+    rand::{SecureRandom, SystemRandom},
+};
+
+/// Marks an encrypted value: `enc:v1:` followed by base64 of `nonce || ciphertext || tag`
+pub const ENCRYPTED_PREFIX: &str = "enc:v1:";
+
+/// The master key from COLUMN_ENCRYPTION_KEY (base64, 32 bytes), if configured
+pub fn master_key_from_env() -> Result<Option<Vec<u8>>> {
+    let Ok(encoded) = env::var("COLUMN_ENCRYPTION_KEY") else {
+        return Ok(None);
+    };
+    let key = STANDARD.decode(encoded.trim()).map_err(|e| anyhow!("COLUMN_ENCRYPTION_KEY is not valid base64: {}", e))?;
+    if key.len() != 32 {
+        return Err(anyhow!("COLUMN_ENCRYPTION_KEY must decode to 32 bytes, got {}", key.len()));
+    }
+    Ok(Some(key))
+}
+
+/// AES-256-GCM cipher for one project. The project key is derived from the master key with HKDF-SHA256,
+/// so projects never share a key and the master key is the only secret to manage. The project id is bound
+/// as associated data, so a value copied into another project's table fails to decrypt.
+pub struct ColumnCipher {
+    project_id: String,
+    key: LessSafeKey,
+    rng: SystemRandom,
+}
+
+impl ColumnCipher {
+    pub fn for_project(master_key: &[u8], project_id: &str) -> Result<Self> {
+        Self::derive(master_key, b"timefusion-column-encryption", project_id)
+    }
+
+    /// Cipher for a project's entry in the project registry. Its key comes from a salt of its own, so the `decrypt`
+    /// UDF, which holds the project's column key, can't open the entry's credentials.
+    pub fn for_project_registry(master_key: &[u8], project_id: &str) -> Result<Self> {
+        Self::derive(master_key, b"timefusion-project-registry", project_id)
+    }
+
+    fn derive(master_key: &[u8], salt: &[u8], project_id: &str) -> Result<Self> {
+        let salt = hkdf::Salt::new(hkdf::HKDF_SHA256, salt);
+        let info = [project_id.as_bytes()];
+        let okm = salt.extract(master_key).expand(&info, &AES_256_GCM).map_err(|_| anyhow!("Failed to derive project key"))?;
+        Ok(Self {
+            project_id: project_id.to_string(),
+            key: LessSafeKey::new(UnboundKey::from(okm)),
+            rng: SystemRandom::new(),
+        })
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> Result<String> {
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng.fill(&mut nonce).map_err(|_| anyhow!("Failed to generate nonce"))?;
+
+        let mut in_out = plaintext.as_bytes().to_vec();
+        self.key
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(self.project_id.as_bytes()), &mut in_out)
+            .map_err(|_| anyhow!("Encryption failed"))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&in_out);
+        Ok(format!("{}{}", ENCRYPTED_PREFIX, STANDARD.encode(sealed)))
+    }
+
+    /// Decrypt a value written by `encrypt`. Values without the prefix, e.g. written before the column was
+    /// encrypted, are returned unchanged.
+    pub fn decrypt(&self, value: &str) -> Result<String> {
+        let Some(encoded) = value.strip_prefix(ENCRYPTED_PREFIX) else {
+            return Ok(value.to_string());
+        };
+        let mut sealed = STANDARD.decode(encoded).map_err(|e| anyhow!("Malformed encrypted value: {}", e))?;
+        if sealed.len() < NONCE_LEN {
+            return Err(anyhow!("Malformed encrypted value: too short"));
+        }
+        let mut ciphertext = sealed.split_off(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(&sealed).map_err(|_| anyhow!("Malformed encrypted value: bad nonce"))?;
+        let plaintext = self
+            .key
+            .open_in_place(nonce, Aad::from(self.project_id.as_bytes()), &mut ciphertext)
+            .map_err(|_| anyhow!("Decryption failed for project '{}'", self.project_id))?;
+        Ok(String::from_utf8(plaintext.to_vec())?)
+    }
+
+    /// Encrypt the given string columns of `batch`; columns missing from the batch are skipped
+    pub fn encrypt_batch(&self, batch: RecordBatch, columns: &[String]) -> Result<RecordBatch> {
+        let schema = batch.schema();
+        let mut arrays = batch.columns().to_vec();
+        for column in columns {
+            let Ok(idx) = schema.index_of(column) else { continue };
+            if schema.field(idx).data_type() != &DataType::Utf8 {
+                return Err(anyhow!("Column '{}' is not a string column and can't be encrypted", column));
+            }
+            let encrypted = arrays[idx].as_string::<i32>().iter().map(|v| v.map(|v| self.encrypt(v)).transpose()).collect::<Result<StringArray>>()?;
+            arrays[idx] = Arc::new(encrypted) as ArrayRef;
+        }
+        Ok(RecordBatch::try_new(schema, arrays)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt_round_trip() {
+        let master_key = [7u8; 32];
+        let cipher = ColumnCipher::for_project(&master_key, "project_a").unwrap();
+
+        let encrypted = cipher.encrypt("jane@example.com").unwrap();
+        assert!(encrypted.starts_with(ENCRYPTED_PREFIX));
+        assert!(!encrypted.contains("jane"));
+        // Random nonces: the same value encrypts differently each time
+        assert_ne!(encrypted, cipher.encrypt("jane@example.com").unwrap());
+        assert_eq!(cipher.decrypt(&encrypted).unwrap(), "jane@example.com");
+
+        // Plain values pass through, other projects' keys can't decrypt
+        assert_eq!(cipher.decrypt("not encrypted").unwrap(), "not encrypted");
+        let other = ColumnCipher::for_project(&master_key, "project_b").unwrap();
+        assert!(other.decrypt(&encrypted).is_err());
+    }
+}
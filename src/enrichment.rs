@@ -0,0 +1,99 @@
+// enrichment.rs - Server-side attributes stamped onto records at ingestion time
+use std::{env, sync::Arc};
+
+use anyhow::Result;
+use datafusion::arrow::{
+    array::{ArrayRef, AsArray, StringArray},
+    datatypes::DataType,
+    record_batch::RecordBatch,
+};
+use serde_json::{Map, Value};
+
+/// Attributes from `INGEST_ENRICH` (e.g. `cloud.region=eu-west-1,host.name=ingest-1`) added to every ingested record.
+///
+/// A key whose `resource___` column exists in the schema (e.g. `service.namespace` ->
+/// `resource___service___namespace`) is written to that column, any other key is merged into the
+/// `resource` JSON column. Values supplied by the client win unless `INGEST_ENRICH_OVERRIDE=true`.
+#[derive(Debug, Clone, Default)]
+pub struct Enrichment {
+    attributes: Vec<(String, String)>,
+    override_client: bool,
+}
+
+impl Enrichment {
+    pub fn from_env() -> Self {
+        let spec = env::var("INGEST_ENRICH").unwrap_or_default();
+        let override_client = env::var("INGEST_ENRICH_OVERRIDE").map(|v| v == "true").unwrap_or(false);
+        Self::parse(&spec, override_client)
+    }
+
+    pub fn parse(spec: &str, override_client: bool) -> Self {
+        let attributes = spec
+            .split(',')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+            .filter(|(k, _)| !k.is_empty())
+            .collect();
+        Self { attributes, override_client }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.attributes.is_empty()
+    }
+
+    /// Return a copy of `batch` with the enrichment attributes applied to every row
+    pub fn apply(&self, batch: RecordBatch) -> Result<RecordBatch> {
+        if self.is_empty() {
+            return Ok(batch);
+        }
+
+        let schema = batch.schema();
+        let mut columns = batch.columns().to_vec();
+        let mut json_attributes = Map::new();
+
+        for (key, value) in &self.attributes {
+            let column_name = format!("resource___{}", key.replace('.', "___"));
+            match schema.index_of(&column_name) {
+                Ok(idx) if schema.field(idx).data_type() == &DataType::Utf8 => {
+                    let existing = columns[idx].as_string::<i32>();
+                    let enriched: StringArray = existing
+                        .iter()
+                        .map(|v| match v {
+                            Some(v) if !self.override_client => Some(v.to_string()),
+                            _ => Some(value.clone()),
+                        })
+                        .collect();
+                    columns[idx] = Arc::new(enriched) as ArrayRef;
+                }
+                _ => {
+                    json_attributes.insert(key.clone(), Value::String(value.clone()));
+                }
+            }
+        }
+
+        if !json_attributes.is_empty() {
+            if let Ok(idx) = schema.index_of("resource") {
+                let existing = columns[idx].as_string::<i32>();
+                let enriched: StringArray = existing.iter().map(|v| Some(self.merge_json(v, &json_attributes))).collect();
+                columns[idx] = Arc::new(enriched) as ArrayRef;
+            }
+        }
+
+        Ok(RecordBatch::try_new(schema, columns)?)
+    }
+
+    fn merge_json(&self, existing: Option<&str>, attributes: &Map<String, Value>) -> String {
+        let mut object = match existing.map(serde_json::from_str::<Value>) {
+            Some(Ok(Value::Object(object))) => object,
+            // Leave values that aren't JSON objects untouched rather than dropping client data
+            Some(_) => return existing.unwrap_or_default().to_string(),
+            None => Map::new(),
+        };
+        for (key, value) in attributes {
+            if self.override_client || !object.contains_key(key) {
+                object.insert(key.clone(), value.clone());
+            }
+        }
+        Value::Object(object).to_string()
+    }
+}
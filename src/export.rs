@@ -0,0 +1,140 @@
+// export.rs - Writing query results as Parquet files straight to S3
+use std::{env, sync::Arc};
+
+use anyhow::{Result, anyhow};
+use datafusion::{
+    dataframe::DataFrame,
+    parquet::{arrow::ArrowWriter, file::properties::WriterProperties},
+};
+use futures::StreamExt;
+use object_store::{ObjectStore, aws::AmazonS3Builder, path::Path};
+use tracing::info;
+
+use crate::database::parquet_compression;
+
+/// A validated `s3://bucket/prefix` export destination
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportDestination {
+    pub bucket: String,
+    pub prefix: String,
+}
+
+impl ExportDestination {
+    /// Parse and check a destination. The bucket must be AWS_S3_BUCKET or listed in EXPORT_ALLOWED_BUCKETS
+    /// (comma separated), and the prefix may not point into the tables under TIMEFUSION_TABLE_PREFIX, where
+    /// vacuum would treat the exported files as garbage.
+    pub fn parse(destination: &str) -> Result<Self> {
+        let mut allowed_buckets: Vec<String> = env::var("EXPORT_ALLOWED_BUCKETS").unwrap_or_default().split(',').map(|b| b.trim().to_string()).collect();
+        allowed_buckets.extend(env::var("AWS_S3_BUCKET").ok());
+        let table_prefix = env::var("TIMEFUSION_TABLE_PREFIX").unwrap_or_else(|_| "timefusion".to_string());
+        Self::parse_with(destination, &allowed_buckets, &table_prefix)
+    }
+
+    fn parse_with(destination: &str, allowed_buckets: &[String], table_prefix: &str) -> Result<Self> {
+        let rest = destination.strip_prefix("s3://").ok_or_else(|| anyhow!("Export destination must be an s3:// URL"))?;
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        let prefix = prefix.trim_matches('/');
+        if bucket.is_empty() || prefix.is_empty() {
+            return Err(anyhow!("Export destination must include a bucket and a prefix, e.g. s3://bucket/exports/daily"));
+        }
+        if prefix.split('/').any(|segment| segment.is_empty() || segment == "." || segment == "..") {
+            return Err(anyhow!("Invalid export prefix '{}'", prefix));
+        }
+        if !allowed_buckets.iter().any(|b| !b.is_empty() && b == bucket) {
+            return Err(anyhow!("Exports to bucket '{}' are not allowed", bucket));
+        }
+        if prefix.split('/').next() == Some(table_prefix) {
+            return Err(anyhow!("Export prefix can't be inside the table prefix '{}'", table_prefix));
+        }
+
+        Ok(Self {
+            bucket: bucket.to_string(),
+            prefix: prefix.to_string(),
+        })
+    }
+
+    /// Object store for the destination bucket, using the AWS credentials and AWS_S3_ENDPOINT of the tables
+    pub fn store(&self) -> Result<Arc<dyn ObjectStore>> {
+        let mut builder = AmazonS3Builder::from_env().with_bucket_name(&self.bucket);
+        if let Ok(endpoint) = env::var("AWS_S3_ENDPOINT") {
+            builder = builder.with_allow_http(endpoint.starts_with("http://")).with_endpoint(endpoint);
+        }
+        Ok(Arc::new(builder.build()?))
+    }
+}
+
+/// Stream the result of `df` into Parquet files of at most EXPORT_ROWS_PER_FILE rows (default 1,000,000) under
+/// the destination prefix, returning the written object keys. An empty result still writes one file with the schema.
+pub async fn export_to_s3(df: DataFrame, destination: &ExportDestination, store: Arc<dyn ObjectStore>) -> Result<Vec<String>> {
+    let rows_per_file: usize = env::var("EXPORT_ROWS_PER_FILE").ok().and_then(|v| v.parse().ok()).unwrap_or(1_000_000);
+    let properties = WriterProperties::builder().set_compression(parquet_compression()).build();
+    let export_id = uuid::Uuid::new_v4();
+
+    let mut stream = df.execute_stream().await?;
+    let schema = stream.schema();
+    let mut keys = Vec::new();
+    let mut writer = ArrowWriter::try_new(Vec::new(), schema.clone(), Some(properties.clone()))?;
+    let mut rows_in_file = 0;
+
+    while let Some(batch) = stream.next().await {
+        let batch = batch?;
+        writer.write(&batch)?;
+        rows_in_file += batch.num_rows();
+        if rows_in_file >= rows_per_file {
+            let full = std::mem::replace(&mut writer, ArrowWriter::try_new(Vec::new(), schema.clone(), Some(properties.clone()))?);
+            keys.push(put_file(&store, destination, export_id, keys.len(), full).await?);
+            rows_in_file = 0;
+        }
+    }
+    if rows_in_file > 0 || keys.is_empty() {
+        keys.push(put_file(&store, destination, export_id, keys.len(), writer).await?);
+    }
+
+    info!(
+        "Exported query result to s3://{}/{} ({} files)",
+        destination.bucket,
+        destination.prefix,
+        keys.len()
+    );
+    Ok(keys)
+}
+
+async fn put_file(
+    store: &Arc<dyn ObjectStore>, destination: &ExportDestination, export_id: uuid::Uuid, part: usize, writer: ArrowWriter<Vec<u8>>,
+) -> Result<String> {
+    let key = format!("{}/{}-{:05}.parquet", destination.prefix, export_id, part);
+    let bytes = writer.into_inner()?;
+    store.put(&Path::from(key.as_str()), bytes.into()).await?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_export_destination() {
+        let allowed = ["timefusion-tests".to_string(), "exports-b".to_string()];
+        let parse = |destination: &str| ExportDestination::parse_with(destination, &allowed, "timefusion");
+
+        assert_eq!(
+            parse("s3://exports-b/daily/2023-01-01/").unwrap(),
+            ExportDestination {
+                bucket: "exports-b".to_string(),
+                prefix: "daily/2023-01-01".to_string(),
+            }
+        );
+        assert!(parse("s3://timefusion-tests/exports").is_ok());
+
+        for invalid in [
+            "https://exports-b/daily",
+            "s3://exports-b",
+            "s3://exports-b/",
+            "s3://other-bucket/daily",
+            "s3://exports-b/daily/../secrets",
+            "s3://timefusion-tests/timefusion/otel_logs_and_spans",
+        ] {
+            assert!(parse(invalid).is_err(), "{} should be rejected", invalid);
+        }
+    }
+}
@@ -0,0 +1,195 @@
+// grafana.rs - Time series queries in the shape expected by Grafana JSON datasources
+use std::collections::BTreeMap;
+
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Duration, Utc};
+use datafusion::arrow::{
+    array::{Array, AsArray},
+    compute::cast,
+    datatypes::{DataType, Float64Type, Int64Type},
+    record_batch::RecordBatch,
+};
+use serde::{Deserialize, Serialize};
+
+/// Query parameters accepted by `GET /grafana/query`
+///
+/// - `project_id`: project to query (default: `default`)
+/// - `metric`: `request_rate` (req/s), `error_rate` (errors/s), `count`, `avg_latency` (ms) or `p95_latency` (ms)
+/// - `from` / `to`: RFC3339 time range (default: the last hour)
+/// - `interval`: bucket width such as `30s`, `5m`, `1h` (default: `1m`)
+/// - `group_by`: optional series split, one of `service`, `name`, `status_code`, `kind`, `level`, `http_method`
+#[derive(Debug, Deserialize)]
+pub struct GrafanaQuery {
+    pub project_id: Option<String>,
+    pub metric: String,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub interval: Option<String>,
+    pub group_by: Option<String>,
+}
+
+/// A single Grafana series: `datapoints` are `[value, timestamp_ms]` pairs
+#[derive(Debug, Serialize, PartialEq)]
+pub struct TimeSeries {
+    pub target: String,
+    pub datapoints: Vec<(f64, i64)>,
+}
+
+/// Build the aggregation SQL for a Grafana query
+pub fn build_sql(query: &GrafanaQuery) -> Result<String> {
+    let interval_secs = parse_interval(query.interval.as_deref().unwrap_or("1m"))?;
+    let to = parse_time(query.to.as_deref())?.unwrap_or_else(Utc::now);
+    let from = parse_time(query.from.as_deref())?.unwrap_or(to - Duration::hours(1));
+    if from >= to {
+        return Err(anyhow!("'from' must be before 'to'"));
+    }
+
+    let metric_expr = match query.metric.as_str() {
+        "request_rate" => format!("CAST(COUNT(*) AS DOUBLE) / {}", interval_secs),
+        "error_rate" => format!("CAST(SUM(CASE WHEN status_code = 'ERROR' THEN 1 ELSE 0 END) AS DOUBLE) / {}", interval_secs),
+        "count" => "CAST(COUNT(*) AS DOUBLE)".to_string(),
+        "avg_latency" => "AVG(CAST(duration AS DOUBLE)) / 1000000.0".to_string(),
+        "p95_latency" => "approx_percentile_cont(CAST(duration AS DOUBLE), 0.95) / 1000000.0".to_string(),
+        other => return Err(anyhow!("Unsupported metric '{}'", other)),
+    };
+
+    let series_expr = match query.group_by.as_deref() {
+        None | Some("") => format!("'{}'", query.metric),
+        Some("service") => "resource___service___name".to_string(),
+        Some("name") => "name".to_string(),
+        Some("status_code") => "status_code".to_string(),
+        Some("kind") => "kind".to_string(),
+        Some("level") => "level".to_string(),
+        Some("http_method") => "attributes___http___request___method".to_string(),
+        Some(other) => return Err(anyhow!("Unsupported group_by '{}'", other)),
+    };
+
+    let project_id = query.project_id.as_deref().unwrap_or("default").replace('\'', "''");
+
+    Ok(format!(
+        "SELECT to_unixtime(bucket) * 1000 AS ts_ms, series, value FROM (
+            SELECT
+                date_bin(INTERVAL '{interval_secs} seconds', timestamp, TIMESTAMP '1970-01-01T00:00:00') AS bucket,
+                CAST(COALESCE(CAST({series_expr} AS VARCHAR), 'unknown') AS VARCHAR) AS series,
+                {metric_expr} AS value
+            FROM otel_logs_and_spans
+            WHERE project_id = '{project_id}'
+              AND timestamp >= TIMESTAMP '{from}'
+              AND timestamp < TIMESTAMP '{to}'
+            GROUP BY 1, 2
+        ) ORDER BY ts_ms",
+        from = from.format("%Y-%m-%dT%H:%M:%S%.6f"),
+        to = to.format("%Y-%m-%dT%H:%M:%S%.6f"),
+    ))
+}
+
+/// Convert `(ts_ms, series, value)` result batches into Grafana series
+pub fn batches_to_series(batches: &[RecordBatch]) -> Result<Vec<TimeSeries>> {
+    let mut series: BTreeMap<String, Vec<(f64, i64)>> = BTreeMap::new();
+
+    for batch in batches {
+        let ts = cast(batch.column(0), &DataType::Int64)?;
+        let names = cast(batch.column(1), &DataType::Utf8)?;
+        let values = cast(batch.column(2), &DataType::Float64)?;
+        let (ts, names, values) = (ts.as_primitive::<Int64Type>(), names.as_string::<i32>(), values.as_primitive::<Float64Type>());
+
+        for i in 0..batch.num_rows() {
+            if ts.is_null(i) || values.is_null(i) {
+                continue;
+            }
+            series.entry(names.value(i).to_string()).or_default().push((values.value(i), ts.value(i)));
+        }
+    }
+
+    Ok(series.into_iter().map(|(target, datapoints)| TimeSeries { target, datapoints }).collect())
+}
+
+fn parse_time(value: Option<&str>) -> Result<Option<DateTime<Utc>>> {
+    value
+        .map(|v| DateTime::parse_from_rfc3339(v).map(|t| t.with_timezone(&Utc)).map_err(|e| anyhow!("Invalid timestamp '{}': {}", v, e)))
+        .transpose()
+}
+
+fn parse_interval(value: &str) -> Result<u64> {
+    let (digits, multiplier) = match value.chars().last() {
+        Some('s') => (&value[..value.len() - 1], 1),
+        Some('m') => (&value[..value.len() - 1], 60),
+        Some('h') => (&value[..value.len() - 1], 3600),
+        Some('d') => (&value[..value.len() - 1], 86400),
+        _ => (value, 1),
+    };
+    match digits.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(n * multiplier),
+        _ => Err(anyhow!("Invalid interval '{}'", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use datafusion::arrow::{
+        array::{Float64Array, Int64Array, StringArray},
+        datatypes::{Field, Schema},
+    };
+
+    use super::*;
+
+    fn query(metric: &str, group_by: Option<&str>) -> GrafanaQuery {
+        GrafanaQuery {
+            project_id: Some("p'1".to_string()),
+            metric: metric.to_string(),
+            from: Some("2023-01-01T10:00:00Z".to_string()),
+            to: Some("2023-01-01T11:00:00Z".to_string()),
+            interval: Some("5m".to_string()),
+            group_by: group_by.map(|g| g.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_build_sql() {
+        let sql = build_sql(&query("request_rate", Some("service"))).unwrap();
+        assert!(sql.contains("INTERVAL '300 seconds'"));
+        assert!(sql.contains("COUNT(*) AS DOUBLE) / 300"));
+        assert!(sql.contains("resource___service___name"));
+        assert!(sql.contains("project_id = 'p''1'"));
+        assert!(sql.contains("TIMESTAMP '2023-01-01T10:00:00.000000'"));
+
+        assert!(build_sql(&query("p95_latency", None)).unwrap().contains("approx_percentile_cont"));
+        assert!(build_sql(&query("bogus", None)).is_err());
+        assert!(build_sql(&query("count", Some("attributes; DROP TABLE x"))).is_err());
+    }
+
+    #[test]
+    fn test_batches_to_series() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("ts_ms", DataType::Int64, true),
+            Field::new("series", DataType::Utf8, true),
+            Field::new("value", DataType::Float64, true),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int64Array::from(vec![1000, 1000, 2000])),
+                Arc::new(StringArray::from(vec!["api", "web", "api"])),
+                Arc::new(Float64Array::from(vec![1.5, 2.0, 3.5])),
+            ],
+        )
+        .unwrap();
+
+        let series = batches_to_series(&[batch]).unwrap();
+        assert_eq!(
+            series,
+            vec![
+                TimeSeries {
+                    target: "api".to_string(),
+                    datapoints: vec![(1.5, 1000), (3.5, 2000)],
+                },
+                TimeSeries {
+                    target: "web".to_string(),
+                    datapoints: vec![(2.0, 1000)],
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,142 @@
+// ingest_limit.rs - Bounding in-flight ingest requests per client (MAX_CONCURRENT_INGEST_PER_SOURCE)
+use std::{collections::HashMap, env, sync::Mutex};
+
+use actix_web::HttpRequest;
+
+/// Counts in-flight ingest requests per source, the client's IP address, and turns away
+/// requests beyond MAX_CONCURRENT_INGEST_PER_SOURCE. Unlike a rate limit this bounds how much work one client has
+/// open at once, so many slow parallel uploads can't tie up every worker.
+#[derive(Debug, Default)]
+pub struct IngestLimiter {
+    max_per_source: Option<usize>,
+    in_flight: Mutex<HashMap<String, usize>>,
+}
+
+/// Held for the duration of an ingest request, releasing its slot when dropped
+#[derive(Debug)]
+pub struct IngestPermit<'a> {
+    limiter: &'a IngestLimiter,
+    source: Option<String>,
+}
+
+impl IngestLimiter {
+    /// Limited with MAX_CONCURRENT_INGEST_PER_SOURCE, unlimited when unset or 0
+    pub fn from_env() -> Self {
+        Self::new(env::var("MAX_CONCURRENT_INGEST_PER_SOURCE").ok().and_then(|v| v.parse().ok()).filter(|max| *max > 0))
+    }
+
+    pub fn new(max_per_source: Option<usize>) -> Self {
+        Self {
+            max_per_source,
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn max_per_source(&self) -> Option<usize> {
+        self.max_per_source
+    }
+
+    /// The peer IP. Ingest API keys aren't authenticated, so a client could send a new one with every request to
+    /// get fresh slots, and tokens shouldn't be kept in memory as map keys; forwarded-for headers are just as easy
+    /// to forge.
+    pub fn source_of(req: &HttpRequest) -> String {
+        format!("ip:{}", req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default())
+    }
+
+    /// Take a slot for `source`, or `None` when it already has MAX_CONCURRENT_INGEST_PER_SOURCE requests in flight
+    pub fn try_acquire(&self, source: &str) -> Option<IngestPermit<'_>> {
+        let Some(max) = self.max_per_source else {
+            return Some(IngestPermit { limiter: self, source: None });
+        };
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        let count = in_flight.entry(source.to_string()).or_insert(0);
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(IngestPermit {
+            limiter: self,
+            source: Some(source.to_string()),
+        })
+    }
+
+    /// Requests currently in flight for `source`
+    pub fn in_flight(&self, source: &str) -> usize {
+        self.in_flight.lock().unwrap_or_else(|e| e.into_inner()).get(source).copied().unwrap_or(0)
+    }
+}
+
+impl Drop for IngestPermit<'_> {
+    fn drop(&mut self) {
+        let Some(source) = &self.source else {
+            return;
+        };
+        let mut in_flight = self.limiter.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = in_flight.get_mut(source) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(source);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::test::TestRequest;
+    use tokio::sync::Barrier;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_concurrent_ingests_beyond_limit_are_rejected() {
+        let limiter = Arc::new(IngestLimiter::new(Some(3)));
+        let barrier = Arc::new(Barrier::new(10));
+
+        // Ten requests from the same client are in flight at once, only three get through
+        let tasks: Vec<_> = (0..10)
+            .map(|_| {
+                let (limiter, barrier) = (Arc::clone(&limiter), Arc::clone(&barrier));
+                tokio::spawn(async move {
+                    let permit = limiter.try_acquire("ip:10.0.0.9");
+                    barrier.wait().await;
+                    permit.is_some()
+                })
+            })
+            .collect();
+        let mut accepted = 0;
+        for task in tasks {
+            accepted += task.await.unwrap() as usize;
+        }
+        assert_eq!(accepted, 3);
+        assert_eq!(limiter.in_flight("ip:10.0.0.9"), 0, "permits are released when requests finish");
+
+        // Other sources have their own slots
+        let held: Vec<_> = (0..3).map(|_| limiter.try_acquire("ip:10.0.0.1").unwrap()).collect();
+        assert!(limiter.try_acquire("ip:10.0.0.1").is_none());
+        assert!(limiter.try_acquire("ip:10.0.0.2").is_some());
+        drop(held);
+        assert!(limiter.try_acquire("ip:10.0.0.1").is_some());
+
+        // Without a limit every request gets through
+        let unlimited = IngestLimiter::new(None);
+        let _held: Vec<_> = (0..100).map(|_| unlimited.try_acquire("ip:10.0.0.9").unwrap()).collect();
+    }
+
+    #[test]
+    fn test_source_of() {
+        let req = TestRequest::default().peer_addr("10.0.0.1:1234".parse().unwrap()).to_http_request();
+        assert_eq!(IngestLimiter::source_of(&req), "ip:10.0.0.1");
+        // Unauthenticated keys don't give a client new slots
+        for key in ["k1", "k2"] {
+            let req = TestRequest::default()
+                .insert_header(("X-Api-Key", key))
+                .insert_header(("Authorization", format!("Bearer {}", key)))
+                .peer_addr("10.0.0.1:1234".parse().unwrap())
+                .to_http_request();
+            assert_eq!(IngestLimiter::source_of(&req), "ip:10.0.0.1");
+        }
+    }
+}
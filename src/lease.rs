@@ -0,0 +1,159 @@
+// lease.rs - Object store lease electing the single replica that runs scheduled maintenance
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::Bytes;
+use chrono::{DateTime, Duration, Utc};
+use object_store::{ObjectStore, PutMode, UpdateVersion, path::Path};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LeaseRecord {
+    holder: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// A lease stored as a small JSON object next to the tables, e.g. `_timefusion/maintenance.lease`.
+///
+/// Replicas sharing a bucket call `try_acquire` before compaction, vacuum and retention; only the holder
+/// runs them. The holder renews the lease on every call, and anyone may take it over once it has
+/// expired, so a replica that dies mid rolling restart hands over maintenance after `ttl`. Writes use
+/// conditional puts; stores without them (S3 unless `AWS_CONDITIONAL_PUT=etag`) fall back to
+/// last-writer-wins followed by a read back.
+#[derive(Debug)]
+pub struct MaintenanceLease {
+    store: Arc<dyn ObjectStore>,
+    path: Path,
+    holder: String,
+    ttl: Duration,
+}
+
+impl MaintenanceLease {
+    pub fn new(store: Arc<dyn ObjectStore>, path: Path, holder: impl Into<String>, ttl: Duration) -> Self {
+        Self {
+            store,
+            path,
+            holder: holder.into(),
+            ttl,
+        }
+    }
+
+    pub fn holder(&self) -> &str {
+        &self.holder
+    }
+
+    /// Acquire or renew the lease, returning whether this instance holds it. Errors are logged and count as not held.
+    pub async fn holds(&self) -> bool {
+        match self.try_acquire().await {
+            Ok(held) => held,
+            Err(e) => {
+                error!("Failed to acquire maintenance lease: {}", e);
+                false
+            }
+        }
+    }
+
+    pub async fn try_acquire(&self) -> Result<bool> {
+        self.try_acquire_at(Utc::now()).await
+    }
+
+    pub async fn try_acquire_at(&self, now: DateTime<Utc>) -> Result<bool> {
+        let current = match self.store.get(&self.path).await {
+            Ok(result) => {
+                let meta = result.meta.clone();
+                let record = serde_json::from_slice::<LeaseRecord>(&result.bytes().await?).ok();
+                Some((record, meta))
+            }
+            Err(object_store::Error::NotFound { .. }) => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        let payload = Bytes::from(serde_json::to_vec(&LeaseRecord {
+            holder: self.holder.clone(),
+            expires_at: now + self.ttl,
+        })?);
+
+        let acquired = match current {
+            None => self.write(payload, PutMode::Create).await?,
+            // Our own lease, an expired one or an unreadable one: take it over unless someone else wrote first
+            Some((record, meta)) if record.as_ref().is_none_or(|r| r.holder == self.holder || r.expires_at <= now) => {
+                let taking_over = record.is_some_and(|r| r.holder != self.holder);
+                let version = UpdateVersion {
+                    e_tag: meta.e_tag,
+                    version: meta.version,
+                };
+                let acquired = self.write(payload, PutMode::Update(version)).await?;
+                if acquired && taking_over {
+                    info!("Instance '{}' took over the expired maintenance lease", self.holder);
+                }
+                acquired
+            }
+            Some(_) => false,
+        };
+        Ok(acquired)
+    }
+
+    /// Give the lease up, e.g. on shutdown, so another replica can take over without waiting for it to expire
+    pub async fn release(&self) -> Result<()> {
+        if let Ok(result) = self.store.get(&self.path).await {
+            let record = serde_json::from_slice::<LeaseRecord>(&result.bytes().await?).ok();
+            if record.is_some_and(|r| r.holder == self.holder) {
+                self.store.delete(&self.path).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn write(&self, payload: Bytes, mode: PutMode) -> Result<bool> {
+        match self.store.put_opts(&self.path, payload.clone().into(), mode.into()).await {
+            Ok(_) => Ok(true),
+            Err(object_store::Error::AlreadyExists { .. } | object_store::Error::Precondition { .. }) => Ok(false),
+            Err(object_store::Error::NotImplemented) => {
+                self.store.put(&self.path, payload.into()).await?;
+                let result = self.store.get(&self.path).await?;
+                let record = serde_json::from_slice::<LeaseRecord>(&result.bytes().await?).ok();
+                Ok(record.is_some_and(|r| r.holder == self.holder))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use object_store::memory::InMemory;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_two_instances_contend_for_lease() -> Result<()> {
+        let store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let path = Path::from("_timefusion/maintenance.lease");
+        let a = MaintenanceLease::new(store.clone(), path.clone(), "instance-a", Duration::seconds(60));
+        let b = MaintenanceLease::new(store, path, "instance-b", Duration::seconds(60));
+        let t0 = Utc::now();
+
+        assert!(a.try_acquire_at(t0).await?);
+        assert!(!b.try_acquire_at(t0).await?, "lease is held by a");
+
+        // a renews, pushing expiry to t0 + 90s
+        assert!(a.try_acquire_at(t0 + Duration::seconds(30)).await?);
+        assert!(!b.try_acquire_at(t0 + Duration::seconds(60)).await?, "renewed lease hasn't expired yet");
+
+        // a stops renewing (e.g. restarting), b takes over once the lease expires
+        assert!(b.try_acquire_at(t0 + Duration::seconds(91)).await?);
+        assert!(
+            !a.try_acquire_at(t0 + Duration::seconds(92)).await?,
+            "a must not run maintenance after losing the lease"
+        );
+
+        // Releasing hands over immediately
+        a.release().await?;
+        assert!(!a.try_acquire_at(t0 + Duration::seconds(93)).await?, "a can't release b's lease");
+        b.release().await?;
+        assert!(a.try_acquire_at(t0 + Duration::seconds(94)).await?);
+
+        Ok(())
+    }
+}
@@ -1,4 +1,26 @@
 // lib.rs - Export modules for use in tests
 pub mod batch_queue;
+pub mod conn_string;
 pub mod database;
+pub mod dead_letter;
+pub mod decode;
+pub mod dedup;
+pub mod dml;
+pub mod encryption;
+pub mod enrichment;
+pub mod export;
+pub mod grafana;
+pub mod ingest_limit;
+pub mod lease;
+pub mod metrics;
+pub mod otel_metrics;
+pub mod otlp;
 pub mod persistent_queue;
+pub mod pg_auth;
+pub mod pg_compat;
+pub mod pg_copy;
+pub mod pg_errors;
+pub mod project_registry;
+pub mod query_cache;
+pub mod query_timeout;
+pub mod selftest;
@@ -1,17 +1,46 @@
 // main.rs
 mod batch_queue;
+mod conn_string;
 mod database;
+mod dead_letter;
+mod decode;
+mod dedup;
+mod dml;
+mod encryption;
+mod enrichment;
+mod export;
+mod grafana;
+mod ingest_limit;
+mod lease;
+mod metrics;
+mod otel_metrics;
+mod otlp;
 mod persistent_queue;
-use actix_web::{App, HttpResponse, HttpServer, Responder, middleware::Logger, post, web};
-use batch_queue::BatchQueue;
-use database::Database;
+mod pg_auth;
+mod pg_compat;
+mod pg_copy;
+mod pg_errors;
+mod project_registry;
+mod query_cache;
+mod query_timeout;
+mod selftest;
+use actix_web::{App, HttpRequest, HttpResponse, HttpServer, Responder, ResponseError, delete, dev::Service, get, middleware::Logger, post, put, routes, web};
+use batch_queue::{BatchQueue, QueueFull};
+use database::{Database, ProjectAccess};
 use dotenv::dotenv;
 use futures::TryFutureExt;
+use ingest_limit::IngestLimiter;
+use opentelemetry_proto::tonic::collector::{
+    metrics::v1::{ExportMetricsPartialSuccess, ExportMetricsServiceRequest, ExportMetricsServiceResponse},
+    trace::v1::{ExportTracePartialSuccess, ExportTraceServiceRequest, ExportTraceServiceResponse},
+};
+use otlp::{IngestError, SchemaStrictness};
+use prost::Message;
 use serde::Deserialize;
 use std::{env, sync::Arc};
-use tokio::time::{Duration, sleep};
+use tokio::time::Duration;
 use tokio_util::sync::CancellationToken;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 use tracing_subscriber::EnvFilter;
 
 #[derive(Clone)]
@@ -24,22 +53,136 @@ struct RegisterProjectRequest {
     access_key: String,
     secret_key: String,
     endpoint: Option<String>,
+    compaction_interval_secs: Option<u64>,
+    encrypted_columns: Option<Vec<String>>,
+    read_only: Option<bool>,
+    display_timezone: Option<String>,
+    #[serde(default)]
+    replace: bool,
 }
 
 #[post("/register_project")]
-async fn register_project(req: web::Json<RegisterProjectRequest>, db: web::Data<Arc<Database>>) -> impl Responder {
+async fn register_project(req: HttpRequest, body: web::Json<RegisterProjectRequest>, db: web::Data<Arc<Database>>) -> impl Responder {
+    if !is_admin(&req) {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin token required"
+        }));
+    }
+    if let Err(e) = database::validate_project_id(&body.project_id) {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": e.to_string()
+        }));
+    }
+    // Only an explicit `replace` re-points an existing project, `default` included, at another bucket
+    let registered = if body.replace {
+        db.register_project(
+            &body.project_id,
+            &body.bucket,
+            Some(&body.access_key),
+            Some(&body.secret_key),
+            body.endpoint.as_deref(),
+        )
+        .await
+    } else {
+        db.create_project(
+            &body.project_id,
+            &body.bucket,
+            Some(&body.access_key),
+            Some(&body.secret_key),
+            body.endpoint.as_deref(),
+        )
+        .await
+    };
+    match registered {
+        Ok(()) => {
+            if let Some(secs) = body.compaction_interval_secs {
+                if let Err(e) = db.set_compaction_interval(&body.project_id, Duration::from_secs(secs)).await {
+                    return HttpResponse::BadRequest().json(serde_json::json!({
+                        "error": format!("Invalid compaction interval: {}", e)
+                    }));
+                }
+            }
+            if let Some(columns) = &body.encrypted_columns {
+                if let Err(e) = db.set_encrypted_columns(&body.project_id, columns.clone()).await {
+                    return HttpResponse::BadRequest().json(serde_json::json!({
+                        "error": format!("Invalid encrypted columns: {}", e)
+                    }));
+                }
+            }
+            if let Some(read_only) = body.read_only {
+                if let Err(e) = db.set_project_read_only(&body.project_id, read_only).await {
+                    return HttpResponse::BadRequest().json(serde_json::json!({
+                        "error": format!("{}", e)
+                    }));
+                }
+            }
+            if let Some(timezone) = &body.display_timezone {
+                if let Err(e) = db.set_display_timezone(&body.project_id, Some(timezone.clone())).await {
+                    return HttpResponse::BadRequest().json(serde_json::json!({
+                        "error": format!("Invalid display time zone: {}", e)
+                    }));
+                }
+            }
+            HttpResponse::Ok().json(serde_json::json!({
+                "message": format!("Project '{}' registered successfully", body.project_id)
+            }))
+        }
+        Err(e) if e.downcast_ref::<database::ProjectAlreadyRegistered>().is_some() => HttpResponse::Conflict().json(serde_json::json!({
+            "error": e.to_string()
+        })),
+        Err(e) if e.downcast_ref::<database::ProjectLimitReached>().is_some() => HttpResponse::TooManyRequests().json(serde_json::json!({
+            "error": e.to_string()
+        })),
+        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": format!("Failed to register project: {:?}", e)
+        })),
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateProjectRequest {
+    project_id: String,
+    bucket: String,
+    endpoint: Option<String>,
+    access_key: String,
+    secret_key: String,
+}
+
+/// Onboard a tenant whose data lives in its own bucket, at `s3://{bucket}/{TIMEFUSION_TABLE_PREFIX}/{project_id}/`
+#[post("/projects")]
+async fn create_project(req: HttpRequest, body: web::Json<CreateProjectRequest>, db: web::Data<Arc<Database>>) -> impl Responder {
+    if !is_admin(&req) {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin token required"
+        }));
+    }
+    if let Err(e) = database::validate_project_id(&body.project_id).and_then(|_| database::validate_bucket_name(&body.bucket)) {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": e.to_string()
+        }));
+    }
+
+    let prefix = env::var("TIMEFUSION_TABLE_PREFIX").unwrap_or_else(|_| "timefusion".to_string());
+    let conn_str = format!("s3://{}/{}/{}/", body.bucket, prefix, body.project_id);
     match db
-        .register_project(
-            &req.project_id,
-            &req.bucket,
-            Some(&req.access_key),
-            Some(&req.secret_key),
-            req.endpoint.as_deref(),
+        .create_project(
+            &body.project_id,
+            &conn_str,
+            Some(&body.access_key),
+            Some(&body.secret_key),
+            body.endpoint.as_deref(),
         )
         .await
     {
-        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
-            "message": format!("Project '{}' registered successfully", req.project_id)
+        Ok(()) => HttpResponse::Created().json(serde_json::json!({
+            "project_id": body.project_id,
+            "uri": conn_str,
+        })),
+        Err(e) if e.downcast_ref::<database::ProjectAlreadyRegistered>().is_some() => HttpResponse::Conflict().json(serde_json::json!({
+            "error": e.to_string()
+        })),
+        Err(e) if e.downcast_ref::<database::ProjectLimitReached>().is_some() => HttpResponse::TooManyRequests().json(serde_json::json!({
+            "error": e.to_string()
         })),
         Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
             "error": format!("Failed to register project: {:?}", e)
@@ -47,6 +190,763 @@ async fn register_project(req: web::Json<RegisterProjectRequest>, db: web::Data<
     }
 }
 
+/// Registered projects with their table location and settings; credentials are never returned
+#[get("/projects")]
+async fn list_projects(db: web::Data<Arc<Database>>) -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({ "projects": db.list_projects().await }))
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    limit: Option<usize>,
+}
+
+#[get("/projects/{id}/history")]
+async fn project_history(req: HttpRequest, path: web::Path<String>, query: web::Query<HistoryQuery>, db: web::Data<Arc<Database>>) -> impl Responder {
+    let project_id = path.into_inner();
+    if let Err(denied) = authorize_read(&req, &project_id) {
+        return denied;
+    }
+    match db.project_history(&project_id, Some(query.limit.unwrap_or(20))).await {
+        Ok(Some(history)) => HttpResponse::Ok().json(history),
+        Ok(None) => HttpResponse::NotFound().json(serde_json::json!({
+            "error": format!("Project '{}' not found", project_id)
+        })),
+        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": format!("Failed to read history: {:?}", e)
+        })),
+    }
+}
+
+#[derive(Deserialize)]
+struct TraceQuery {
+    project_id: Option<String>,
+    #[serde(default)]
+    include_logs: bool,
+}
+
+/// A trace's spans, plus its correlated logs with `?include_logs=true`
+#[get("/traces/{trace_id}")]
+async fn get_trace(req: HttpRequest, path: web::Path<String>, query: web::Query<TraceQuery>, db: web::Data<Arc<Database>>) -> impl Responder {
+    let trace_id = path.into_inner();
+    let project_id = query.project_id.as_deref().unwrap_or("default");
+    let access = match authorize_read(&req, project_id) {
+        Ok(access) => access,
+        Err(denied) => return denied,
+    };
+    match db.trace_view(project_id, &trace_id, query.include_logs, &access).await {
+        Ok(view) if view.spans.is_empty() && view.logs.as_ref().is_none_or(|l| l.is_empty()) => HttpResponse::NotFound().json(serde_json::json!({
+            "error": format!("Trace '{}' not found in project '{}'", trace_id, project_id)
+        })),
+        Ok(view) => HttpResponse::Ok().json(view),
+        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": format!("Failed to read trace: {:?}", e)
+        })),
+    }
+}
+
+#[derive(Deserialize)]
+struct CompactionScheduleRequest {
+    interval_secs: u64,
+}
+
+#[get("/projects/{id}/compaction")]
+async fn get_compaction_schedule(path: web::Path<String>, db: web::Data<Arc<Database>>) -> impl Responder {
+    let project_id = path.into_inner();
+    match db.compaction_schedule(&project_id).await {
+        Some(schedule) => HttpResponse::Ok().json(serde_json::json!({
+            "project_id": project_id,
+            "interval_secs": schedule.interval.as_secs(),
+            "secs_since_last_compaction": schedule.last_compacted.elapsed().as_secs(),
+        })),
+        None => HttpResponse::NotFound().json(serde_json::json!({
+            "error": format!("Project '{}' not found", project_id)
+        })),
+    }
+}
+
+#[put("/projects/{id}/compaction")]
+async fn update_compaction_schedule(
+    req: HttpRequest, path: web::Path<String>, body: web::Json<CompactionScheduleRequest>, db: web::Data<Arc<Database>>,
+) -> impl Responder {
+    if !is_admin(&req) {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin token required"
+        }));
+    }
+    let project_id = path.into_inner();
+    if db.compaction_schedule(&project_id).await.is_none() {
+        return HttpResponse::NotFound().json(serde_json::json!({
+            "error": format!("Project '{}' not found", project_id)
+        }));
+    }
+    match db.set_compaction_interval(&project_id, Duration::from_secs(body.interval_secs)).await {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
+            "project_id": project_id,
+            "interval_secs": body.interval_secs,
+        })),
+        Err(e) => HttpResponse::BadRequest().json(serde_json::json!({
+            "error": format!("{}", e)
+        })),
+    }
+}
+
+#[derive(Deserialize)]
+struct ReadOnlyRequest {
+    read_only: bool,
+}
+
+#[get("/projects/{id}/read_only")]
+async fn get_project_read_only(path: web::Path<String>, db: web::Data<Arc<Database>>) -> impl Responder {
+    let project_id = path.into_inner();
+    if db.compaction_schedule(&project_id).await.is_none() {
+        return HttpResponse::NotFound().json(serde_json::json!({
+            "error": format!("Project '{}' not found", project_id)
+        }));
+    }
+    HttpResponse::Ok().json(serde_json::json!({
+        "project_id": project_id,
+        "read_only": db.is_project_read_only(&project_id).await,
+    }))
+}
+
+/// Freeze a project so it rejects writes while still serving queries, or unfreeze it
+#[put("/projects/{id}/read_only")]
+async fn update_project_read_only(req: HttpRequest, path: web::Path<String>, body: web::Json<ReadOnlyRequest>, db: web::Data<Arc<Database>>) -> impl Responder {
+    if !is_admin(&req) {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin token required"
+        }));
+    }
+    let project_id = path.into_inner();
+    match db.set_project_read_only(&project_id, body.read_only).await {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
+            "project_id": project_id,
+            "read_only": body.read_only,
+        })),
+        Err(e) => HttpResponse::NotFound().json(serde_json::json!({
+            "error": format!("{}", e)
+        })),
+    }
+}
+
+/// Compare each project's Delta table schema with the schema this build expects
+#[get("/admin/schema_check")]
+async fn schema_check(db: web::Data<Arc<Database>>) -> impl Responder {
+    match db.schema_check().await {
+        Ok(report) => {
+            let compatible = report.values().all(|diff| diff.is_empty());
+            HttpResponse::Ok().json(serde_json::json!({
+                "compatible": compatible,
+                "projects": report,
+            }))
+        }
+        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": format!("Schema check failed: {:?}", e)
+        })),
+    }
+}
+
+#[derive(Deserialize)]
+struct SchemaDdlQuery {
+    /// postgres_fdw server to generate a `CREATE FOREIGN TABLE` for
+    foreign_server: Option<String>,
+}
+
+/// `CREATE TABLE` statement for `otel_logs_and_spans` with PostgreSQL types, generated from the table schema, or
+/// `CREATE FOREIGN TABLE` with `?foreign_server=<name>`
+#[get("/schema/ddl")]
+async fn schema_ddl(query: web::Query<SchemaDdlQuery>) -> impl Responder {
+    use persistent_queue::OtelLogsAndSpans;
+
+    let schema = OtelLogsAndSpans::schema_ref();
+    let ddl = match &query.foreign_server {
+        Some(server) => pg_compat::create_foreign_table_ddl(&OtelLogsAndSpans::table_name(), &schema, server),
+        None => pg_compat::create_table_ddl(&OtelLogsAndSpans::table_name(), &schema, &OtelLogsAndSpans::partitions()),
+    };
+    HttpResponse::Ok().content_type("application/sql; charset=utf-8").body(ddl)
+}
+
+#[derive(Deserialize)]
+struct DeleteRangeRequest {
+    from: chrono::DateTime<chrono::Utc>,
+    to: chrono::DateTime<chrono::Utc>,
+}
+
+/// Delete a project's records within `[from, to)`
+#[post("/projects/{id}/delete_range")]
+async fn delete_range(req: HttpRequest, path: web::Path<String>, body: web::Json<DeleteRangeRequest>, db: web::Data<Arc<Database>>) -> impl Responder {
+    if !is_admin(&req) {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin token required"
+        }));
+    }
+    let project_id = path.into_inner();
+    if body.from >= body.to {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "'from' must be before 'to'"
+        }));
+    }
+    match db.delete_range(&project_id, body.from, body.to).await {
+        Ok(records_deleted) => HttpResponse::Ok().json(serde_json::json!({
+            "project_id": project_id,
+            "records_deleted": records_deleted,
+            "records_deleted_total": metrics::counter_value(metrics::RECORDS_DELETED_TOTAL, &project_id),
+        })),
+        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": format!("Failed to delete records: {:?}", e)
+        })),
+    }
+}
+
+#[derive(Deserialize)]
+struct DeleteOlderThanQuery {
+    before: chrono::DateTime<chrono::Utc>,
+}
+
+/// Retention delete: remove all of a project's data older than `before`
+#[delete("/projects/{id}/data")]
+async fn delete_older_than(req: HttpRequest, path: web::Path<String>, query: web::Query<DeleteOlderThanQuery>, db: web::Data<Arc<Database>>) -> impl Responder {
+    if !is_admin(&req) {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin token required"
+        }));
+    }
+    let project_id = path.into_inner();
+    match db.delete_older_than(&project_id, query.before).await {
+        Ok(summary) => HttpResponse::Ok().json(serde_json::json!({
+            "project_id": project_id,
+            "records_deleted": summary.rows_deleted,
+            "files_removed": summary.files_removed,
+            "records_deleted_total": metrics::counter_value(metrics::RECORDS_DELETED_TOTAL, &project_id),
+        })),
+        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": format!("Failed to delete records: {:?}", e)
+        })),
+    }
+}
+
+/// Liveness check that also reports the ingest settings clients care about
+#[get("/health")]
+async fn health(db: web::Data<Arc<Database>>) -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": "ok",
+        "ingest_paused": db.is_ingest_paused(),
+        "schema_strictness": SchemaStrictness::from_env().name(),
+        "http_requests_total": metrics::counter_total(metrics::HTTP_REQUESTS_TOTAL),
+        "projects": db.project_count().await,
+        "max_projects": database::max_projects(),
+    }))
+}
+
+/// Batch queue backlog: pending batches and rows, age of the oldest pending batch and rows per second in and out
+#[get("/queue_stats")]
+async fn queue_stats(batch_queue: web::Data<Arc<BatchQueue>>) -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({
+        "stats": batch_queue.stats(),
+        "max_queued_rows": BatchQueue::max_queued_rows(),
+        "flush_thresholds": batch_queue.flush_thresholds(),
+        "dead_letter_entries": batch_queue.dead_letter().map(|store| store.len()),
+    }))
+}
+
+/// Counters and gauges in the Prometheus text format, for scraping
+#[get("/metrics")]
+async fn prometheus_metrics(batch_queue: web::Data<Arc<BatchQueue>>) -> impl Responder {
+    metrics::set_gauge(metrics::QUEUE_PENDING_BATCHES, "", batch_queue.pending_batches() as f64);
+    metrics::set_gauge(metrics::QUEUE_PENDING_ROWS, "", batch_queue.pending_rows() as f64);
+    metrics::set_gauge(metrics::QUEUE_PENDING_BYTES, "", batch_queue.stats().pending_bytes as f64);
+    metrics::set_gauge(
+        metrics::QUEUE_OLDEST_AGE_SECONDS,
+        "",
+        batch_queue.stats().oldest_pending_age_secs.unwrap_or(0.0),
+    );
+    HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(metrics::render_prometheus())
+}
+
+/// Pause ingestion for a maintenance window; ingest endpoints return 503 until resumed, queries keep working
+#[post("/admin/ingest/pause")]
+async fn pause_ingest(req: HttpRequest, db: web::Data<Arc<Database>>) -> impl Responder {
+    if !is_admin(&req) {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin token required"
+        }));
+    }
+    match db.pause_ingest() {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({ "ingest_paused": true })),
+        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": format!("Failed to pause ingestion: {:?}", e)
+        })),
+    }
+}
+
+#[post("/admin/ingest/resume")]
+async fn resume_ingest(req: HttpRequest, db: web::Data<Arc<Database>>) -> impl Responder {
+    if !is_admin(&req) {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin token required"
+        }));
+    }
+    match db.resume_ingest() {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({ "ingest_paused": false })),
+        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": format!("Failed to resume ingestion: {:?}", e)
+        })),
+    }
+}
+
+/// Compact the small files of every project now instead of waiting for its schedule
+#[post("/admin/compact")]
+async fn compact_all_projects(req: HttpRequest, db: web::Data<Arc<Database>>) -> impl Responder {
+    if !is_admin(&req) {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin token required"
+        }));
+    }
+    match db.compact_all_projects().await {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({ "message": "All projects compacted" })),
+        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": format!("{}", e)
+        })),
+    }
+}
+
+/// Reopen one project's table so queries see data written to its bucket by other processes
+#[post("/admin/projects/{id}/reload")]
+async fn reload_project(req: HttpRequest, path: web::Path<String>, db: web::Data<Arc<Database>>) -> impl Responder {
+    if !is_admin(&req) {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin token required"
+        }));
+    }
+    let project_id = path.into_inner();
+    match db.reload_project(&project_id).await {
+        Ok(version) => HttpResponse::Ok().json(serde_json::json!({
+            "project_id": project_id,
+            "version": version
+        })),
+        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": format!("{}", e)
+        })),
+    }
+}
+
+#[derive(Deserialize)]
+struct ZOrderRequest {
+    project_id: String,
+    columns: Vec<String>,
+}
+
+/// Z-order a project's table by the given columns, e.g. `["context___trace_id", "timestamp"]`
+#[post("/admin/zorder")]
+async fn zorder_project(req: HttpRequest, body: web::Json<ZOrderRequest>, db: web::Data<Arc<Database>>) -> impl Responder {
+    if !is_admin(&req) {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin token required"
+        }));
+    }
+    if let Err(e) = database::validate_zorder_columns(&body.columns) {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": format!("{}", e)
+        }));
+    }
+    match db.zorder_project(&body.project_id, &body.columns).await {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({
+            "message": format!("Project '{}' Z-ordered by {:?}", body.project_id, body.columns)
+        })),
+        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": format!("{}", e)
+        })),
+    }
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers().get("Authorization").and_then(|v| v.to_str().ok()).and_then(|v| v.strip_prefix("Bearer "))
+}
+
+/// Check the `Authorization: Bearer <token>` header against ADMIN_TOKEN. Without ADMIN_TOKEN set, guarded
+/// endpoints are disabled.
+fn is_admin(req: &HttpRequest) -> bool {
+    let Ok(admin_token) = env::var("ADMIN_TOKEN") else {
+        return false;
+    };
+    bearer_token(req).is_some_and(|token| !admin_token.is_empty() && token == admin_token)
+}
+
+/// Projects the bearer token of a read request may see: every project with the admin token, or those
+/// PROJECT_READ_TOKENS (`<project_id>=<token>,...`) gives the token to. Fails with 403 unless `project_id` is one of them.
+fn authorize_read(req: &HttpRequest, project_id: &str) -> Result<ProjectAccess, HttpResponse> {
+    if is_admin(req) {
+        return Ok(ProjectAccess::all());
+    }
+    let tokens = env::var("PROJECT_READ_TOKENS").unwrap_or_default();
+    let projects: Vec<&str> = match bearer_token(req).filter(|token| !token.is_empty()) {
+        Some(token) => tokens
+            .split(',')
+            .filter_map(|entry| entry.split_once('='))
+            .filter(|(_, t)| t.trim() == token)
+            .map(|(p, _)| p.trim())
+            .collect(),
+        None => Vec::new(),
+    };
+    if projects.is_empty() {
+        return Err(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin token or project read token required"
+        })));
+    }
+    let access = ProjectAccess::new(projects);
+    if access.check(project_id).is_err() {
+        return Err(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": format!("Access denied: project '{}' is not authorized for this token", project_id)
+        })));
+    }
+    Ok(access)
+}
+
+/// Add the columns this build expects to a project's existing table, see `GET /admin/schema_check`
+#[post("/admin/projects/{id}/evolve_schema")]
+async fn evolve_schema(req: HttpRequest, path: web::Path<String>, db: web::Data<Arc<Database>>) -> impl Responder {
+    if !is_admin(&req) {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin token required"
+        }));
+    }
+    let project_id = path.into_inner();
+    match db.evolve_schema(&project_id).await {
+        Ok(added) => HttpResponse::Ok().json(serde_json::json!({
+            "project_id": project_id,
+            "added_columns": added,
+        })),
+        Err(e) => HttpResponse::BadRequest().json(serde_json::json!({
+            "error": format!("{}", e)
+        })),
+    }
+}
+
+#[derive(Deserialize)]
+struct VacuumRequest {
+    project_id: String,
+    retention_hours: u64,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Delete files no longer referenced within the retention period. Use `dry_run` to preview.
+#[post("/admin/vacuum")]
+async fn vacuum_project(req: HttpRequest, body: web::Json<VacuumRequest>, db: web::Data<Arc<Database>>) -> impl Responder {
+    if !is_admin(&req) {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin token required"
+        }));
+    }
+    match db.vacuum_project(&body.project_id, body.retention_hours, body.dry_run).await {
+        Ok(files) => HttpResponse::Ok().json(serde_json::json!({
+            "project_id": body.project_id,
+            "dry_run": body.dry_run,
+            "files_deleted": files,
+        })),
+        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": format!("Vacuum failed: {:?}", e)
+        })),
+    }
+}
+
+#[derive(Deserialize)]
+struct ExportRequest {
+    sql: String,
+    destination: String,
+}
+
+/// Run a query and write its result as Parquet files to `destination` (`s3://bucket/prefix`), returning the object keys
+#[post("/export/to_s3")]
+async fn export_to_s3(req: HttpRequest, body: web::Json<ExportRequest>, db: web::Data<Arc<Database>>) -> impl Responder {
+    if !is_admin(&req) {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin token required"
+        }));
+    }
+    let destination = match export::ExportDestination::parse(&body.destination) {
+        Ok(destination) => destination,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "error": format!("{}", e)
+            }));
+        }
+    };
+
+    let result = async {
+        let store = destination.store()?;
+        let df = db.query(&body.sql).await?;
+        export::export_to_s3(df, &destination, store).await
+    }
+    .await;
+
+    match result {
+        Ok(keys) => HttpResponse::Ok().json(serde_json::json!({
+            "bucket": destination.bucket,
+            "keys": keys,
+        })),
+        Err(e) => {
+            error!("Export to {} failed: {:?}", body.destination, e);
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Export failed: {:?}", e)
+            }))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DeadLetterQuery {
+    limit: Option<usize>,
+}
+
+/// Batches the queue gave up on after MAX_WRITE_ATTEMPTS failed writes, oldest first
+#[get("/dead_letter")]
+async fn list_dead_letters(req: HttpRequest, query: web::Query<DeadLetterQuery>, batch_queue: web::Data<Arc<BatchQueue>>) -> impl Responder {
+    if !is_admin(&req) {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin token required"
+        }));
+    }
+    let Some(store) = batch_queue.dead_letter() else {
+        return HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Dead-letter store is not enabled, set DEAD_LETTER_PATH"
+        }));
+    };
+    match store.list(query.limit.unwrap_or(100)) {
+        Ok(entries) => HttpResponse::Ok().json(serde_json::json!({
+            "total": store.len(),
+            "entries": entries,
+        })),
+        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": format!("Failed to read dead-letter store: {:?}", e)
+        })),
+    }
+}
+
+/// Requeue the dead-lettered batches matching a failure reason and/or project in bulk, optionally moving them to
+/// another project or replacing invalid timestamps first
+#[routes]
+#[post("/dead_letter/replay")]
+#[post("/dlq/replay")]
+async fn replay_dead_letters(req: HttpRequest, body: web::Json<dead_letter::ReplayRequest>, batch_queue: web::Data<Arc<BatchQueue>>) -> impl Responder {
+    if !is_admin(&req) {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin token required"
+        }));
+    }
+    if batch_queue.dead_letter().is_none() {
+        return HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Dead-letter store is not enabled, set DEAD_LETTER_PATH"
+        }));
+    }
+    match batch_queue.replay_dead_letters(&body) {
+        Ok(summary) => HttpResponse::Ok().json(summary),
+        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": format!("Replay failed: {:?}", e)
+        })),
+    }
+}
+
+/// Put a dead-lettered batch back on the queue, with its attempt count reset
+#[post("/dead_letter/{key}/retry")]
+async fn retry_dead_letter(req: HttpRequest, path: web::Path<String>, batch_queue: web::Data<Arc<BatchQueue>>) -> impl Responder {
+    if !is_admin(&req) {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "Admin token required"
+        }));
+    }
+    let Some(store) = batch_queue.dead_letter() else {
+        return HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Dead-letter store is not enabled, set DEAD_LETTER_PATH"
+        }));
+    };
+    let key = path.into_inner();
+    let (entry, batch) = match store.get(&key) {
+        Ok(Some(found)) => found,
+        Ok(None) => {
+            return HttpResponse::NotFound().json(serde_json::json!({
+                "error": format!("Dead-letter entry {} not found", key)
+            }));
+        }
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Failed to read dead-letter entry: {:?}", e)
+            }));
+        }
+    };
+
+    // An entry that doesn't fit in the queue stays dead-lettered as a whole
+    let requeued_rows = batch.num_rows();
+    if let Err(e) = batch_queue.queue(batch) {
+        let Some(full) = e.downcast_ref::<QueueFull>() else {
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Failed to requeue: {:?}", e)
+            }));
+        };
+        return IngestError::QueueFull {
+            depth: full.depth,
+            limit: full.limit,
+        }
+        .error_response();
+    }
+    if let Err(e) = store.remove(&key) {
+        error!("Failed to remove requeued dead-letter entry {}: {:?}", key, e);
+    }
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "key": key,
+        "requeued_rows": requeued_rows,
+    }))
+}
+
+/// Hits, misses and evictions of the query result cache used by the HTTP query endpoints
+#[get("/cache/stats")]
+async fn cache_stats(db: web::Data<Arc<Database>>) -> impl Responder {
+    match db.query_cache_stats() {
+        Some(stats) => HttpResponse::Ok().json(serde_json::json!({
+            "enabled": true,
+            "stats": stats,
+        })),
+        None => HttpResponse::Ok().json(serde_json::json!({ "enabled": false })),
+    }
+}
+
+/// Time series endpoint for Grafana JSON/Infinity datasources, see `grafana::GrafanaQuery` for parameters.
+#[get("/grafana/query")]
+async fn grafana_query(req: HttpRequest, query: web::Query<grafana::GrafanaQuery>, db: web::Data<Arc<Database>>) -> impl Responder {
+    let access = match authorize_read(&req, query.project_id.as_deref().unwrap_or("default")) {
+        Ok(access) => access,
+        Err(denied) => return denied,
+    };
+    let sql = match grafana::build_sql(&query) {
+        Ok(sql) => sql,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "error": format!("{}", e)
+            }));
+        }
+    };
+
+    let cache_control = req.headers().get("Cache-Control").and_then(|v| v.to_str().ok());
+    let mode = query_cache::CacheMode::from_cache_control(cache_control);
+    let result = async {
+        let (batches, status) = db.query_cached(&sql, mode, &access).await?;
+        Ok::<_, anyhow::Error>((grafana::batches_to_series(&batches)?, status))
+    }
+    .await;
+
+    match result {
+        Ok((series, status)) => HttpResponse::Ok().insert_header(("X-Cache", status.as_str())).json(series),
+        Err(e) => {
+            error!("Grafana query failed: {:?}", e);
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": format!("Query failed: {:?}", e)
+            }))
+        }
+    }
+}
+
+/// Seconds each project's newest record is behind now, refreshed every minute
+#[get("/metrics/ingest_lag")]
+async fn ingest_lag() -> impl Responder {
+    let lag: serde_json::Map<String, serde_json::Value> = metrics::gauge_values(metrics::INGEST_LAG_SECONDS)
+        .into_iter()
+        .map(|(project_id, lag)| (project_id, serde_json::json!(lag)))
+        .collect();
+    HttpResponse::Ok().json(serde_json::json!({ "ingest_lag_seconds": lag }))
+}
+
+/// Take one of the client's MAX_CONCURRENT_INGEST_PER_SOURCE slots for the rest of the request
+fn acquire_ingest_permit<'a>(req: &HttpRequest, limiter: &'a IngestLimiter) -> Result<ingest_limit::IngestPermit<'a>, IngestError> {
+    let source = IngestLimiter::source_of(req);
+    limiter.try_acquire(&source).ok_or_else(|| {
+        let project_id = req.headers().get("X-Project-Id").and_then(|v| v.to_str().ok()).unwrap_or("default");
+        metrics::increment_counter(metrics::INGEST_CONCURRENCY_REJECTIONS_TOTAL, project_id, 1);
+        IngestError::TooManyConcurrent(limiter.max_per_source().unwrap_or_default())
+    })
+}
+
+/// OTLP/HTTP metrics receiver. Accepts a protobuf `ExportMetricsServiceRequest`; the target
+/// project is taken from the `X-Project-Id` header and defaults to "default".
+#[post("/v1/metrics")]
+async fn ingest_metrics(
+    req: HttpRequest, payload: web::Payload, db: web::Data<Arc<Database>>, limiter: web::Data<IngestLimiter>,
+) -> Result<HttpResponse, IngestError> {
+    if db.is_ingest_paused() {
+        return Err(IngestError::Paused);
+    }
+    let _permit = acquire_ingest_permit(&req, &limiter)?;
+
+    let body = decode::read_body(&req, payload).await?;
+
+    let request = ExportMetricsServiceRequest::decode(body).map_err(|e| IngestError::InvalidPayload(e.to_string()))?;
+
+    let project_id = req.headers().get("X-Project-Id").and_then(|v| v.to_str().ok()).unwrap_or("default");
+    if db.is_project_read_only(project_id).await {
+        return Err(IngestError::ReadOnly(project_id.to_string()));
+    }
+    let (rows, rejected) = otlp::metrics_request_to_rows(&request, project_id);
+
+    db.insert_metrics(&rows).await.map_err(|e| {
+        error!("Failed to insert metrics: {:?}", e);
+        IngestError::Storage(e.to_string())
+    })?;
+
+    let response = ExportMetricsServiceResponse {
+        partial_success: (rejected > 0).then(|| ExportMetricsPartialSuccess {
+            rejected_data_points: rejected,
+            error_message: "only Sum and Gauge metrics are supported".to_string(),
+        }),
+    };
+    Ok(HttpResponse::Ok().content_type("application/x-protobuf").body(response.encode_to_vec()))
+}
+
+/// OTLP/HTTP traces receiver. Accepts a protobuf `ExportTraceServiceRequest`; the target
+/// project is taken from the `X-Project-Id` header and defaults to "default".
+#[post("/v1/traces")]
+async fn ingest_traces(
+    req: HttpRequest, payload: web::Payload, db: web::Data<Arc<Database>>, limiter: web::Data<IngestLimiter>,
+) -> Result<HttpResponse, IngestError> {
+    if db.is_ingest_paused() {
+        return Err(IngestError::Paused);
+    }
+    let _permit = acquire_ingest_permit(&req, &limiter)?;
+
+    let body = decode::read_body(&req, payload).await?;
+
+    let request = ExportTraceServiceRequest::decode(body).map_err(|e| IngestError::InvalidPayload(e.to_string()))?;
+
+    SchemaStrictness::from_env().validate_traces(&request)?;
+
+    let project_id = req.headers().get("X-Project-Id").and_then(|v| v.to_str().ok()).unwrap_or("default");
+    let (rows, rejected) = otlp::traces_request_to_rows(&request, project_id);
+
+    // A full queue rejects the whole request, so the exporter retries all of it rather than dropping a part
+    if let Err(e) = db.ingest_many(&rows).await {
+        if let Some(full) = e.downcast_ref::<QueueFull>() {
+            warn!("{}", full);
+            return Err(IngestError::QueueFull {
+                depth: full.depth,
+                limit: full.limit,
+            });
+        }
+        if let Some(read_only) = e.downcast_ref::<database::ProjectReadOnly>() {
+            return Err(IngestError::ReadOnly(read_only.project_id.clone()));
+        }
+        error!("Failed to ingest spans: {:?}", e);
+        return Err(IngestError::Storage(e.to_string()));
+    }
+
+    let response = ExportTraceServiceResponse {
+        partial_success: (rejected > 0).then(|| ExportTracePartialSuccess {
+            rejected_spans: rejected,
+            error_message: "spans without a start time are not supported".to_string(),
+        }),
+    };
+    Ok(HttpResponse::Ok().content_type("application/x-protobuf").body(response.encode_to_vec()))
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // Initialize environment and logging
@@ -59,6 +959,20 @@ async fn main() -> anyhow::Result<()> {
     let mut db = Database::new().await?;
     info!("Database initialized successfully");
 
+    // Validate configuration, credentials and storage connectivity, then exit instead of serving
+    if selftest::enabled() {
+        match selftest::run(&db).await {
+            Ok(()) => {
+                info!("Self-test passed");
+                return Ok(());
+            }
+            Err(e) => {
+                error!("Self-test failed: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
     // Setup batch processing with configurable params
     let interval_ms = env::var("BATCH_INTERVAL_MS").ok().and_then(|v| v.parse().ok()).unwrap_or(1000);
     let max_size = env::var("MAX_BATCH_SIZE").ok().and_then(|v| v.parse().ok()).unwrap_or(1000);
@@ -77,6 +991,7 @@ async fn main() -> anyhow::Result<()> {
     db = db.start_maintenance_schedulers().await?;
     let session_context = db.create_session_context();
     db.setup_session_context(&session_context)?;
+    pg_compat::register_session_options(&session_context);
 
     // Wrap for sharing
     let db = Arc::new(db);
@@ -113,12 +1028,63 @@ async fn main() -> anyhow::Result<()> {
 
     // Start HTTP server
     let http_addr = format!("0.0.0.0:{}", env::var("PORT").unwrap_or_else(|_| "80".to_string()));
+    let http_batch_queue = Arc::clone(&batch_queue);
+    let ingest_limiter = web::Data::new(IngestLimiter::from_env());
     let http_server = HttpServer::new(move || {
         App::new()
             .wrap(Logger::default())
+            .wrap_fn(|req, srv| {
+                let method = req.method().to_string();
+                let started = std::time::Instant::now();
+                let response = srv.call(req);
+                async move {
+                    let response = response.await;
+                    let status = match &response {
+                        Ok(res) => res.status(),
+                        Err(e) => e.as_response_error().status_code(),
+                    };
+                    let status = status.as_u16().to_string();
+                    metrics::increment_labeled_counter(metrics::HTTP_REQUESTS_TOTAL, &[("method", &method), ("status", &status)], 1);
+                    metrics::observe_histogram(metrics::HTTP_REQUEST_DURATION_SECONDS, &[("method", &method)], started.elapsed().as_secs_f64());
+                    response
+                }
+            })
             .app_data(web::Data::new(db.clone()))
+            .app_data(web::Data::new(http_batch_queue.clone()))
             .app_data(app_info.clone())
+            .app_data(ingest_limiter.clone())
+            .service(health)
+            .service(prometheus_metrics)
             .service(register_project)
+            .service(ingest_metrics)
+            .service(ingest_traces)
+            .service(ingest_lag)
+            .service(project_history)
+            .service(get_trace)
+            .service(grafana_query)
+            .service(cache_stats)
+            .service(get_compaction_schedule)
+            .service(update_compaction_schedule)
+            .service(get_project_read_only)
+            .service(create_project)
+            .service(list_projects)
+            .service(update_project_read_only)
+            .service(schema_check)
+            .service(schema_ddl)
+            .service(delete_range)
+            .service(delete_older_than)
+            .service(pause_ingest)
+            .service(resume_ingest)
+            .service(compact_all_projects)
+            .service(reload_project)
+            .service(zorder_project)
+            .service(vacuum_project)
+            .service(evolve_schema)
+            .service(export_to_s3)
+            .service(list_dead_letters)
+            .service(queue_stats)
+            .service(retry_dead_letter)
+            .service(replay_dead_letters)
     });
 
     let server = match http_server.bind(&http_addr) {
@@ -150,11 +1116,10 @@ async fn main() -> anyhow::Result<()> {
         _ = tokio::signal::ctrl_c() => {
             info!("Received Ctrl+C, initiating shutdown");
 
-            // Shutdown in order: batch queue first to flush pending data
-            batch_queue.shutdown().await;
+            // Stop accepting requests, then write what is still queued before exiting
             shutdown_token.cancel();
             http_server_handle.stop(true).await;
-            sleep(Duration::from_secs(1)).await;
+            batch_queue.shutdown().await;
         }
     }
 
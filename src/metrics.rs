@@ -0,0 +1,212 @@
+// metrics.rs - Process-wide counters, gauges and histograms for ingestion, HTTP and data lifecycle operations
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex};
+
+use lazy_static::lazy_static;
+
+pub const RECORDS_DELETED_TOTAL: &str = "timefusion_records_deleted_total";
+pub const FILES_VACUUMED_TOTAL: &str = "timefusion_files_vacuumed_total";
+pub const INGEST_LAG_SECONDS: &str = "timefusion_ingest_lag_seconds";
+pub const RECORDS_INGESTED_TOTAL: &str = "timefusion_records_ingested_total";
+pub const INGEST_ERRORS_TOTAL: &str = "timefusion_ingest_errors_total";
+pub const FAILED_COMMITS_TOTAL: &str = "timefusion_failed_commits_total";
+pub const RECORDS_DROPPED_TOTAL: &str = "timefusion_records_dropped_total";
+pub const INGEST_CONCURRENCY_REJECTIONS_TOTAL: &str = "timefusion_ingest_concurrency_rejections_total";
+pub const QUEUE_PENDING_BATCHES: &str = "timefusion_queue_pending_batches";
+pub const QUEUE_PENDING_ROWS: &str = "timefusion_queue_pending_rows";
+pub const QUEUE_PENDING_BYTES: &str = "timefusion_queue_pending_bytes";
+pub const QUEUE_OLDEST_AGE_SECONDS: &str = "timefusion_queue_oldest_age_seconds";
+pub const QUEUE_TO_COMMIT_SECONDS: &str = "timefusion_queue_to_commit_seconds";
+pub const HTTP_REQUESTS_TOTAL: &str = "timefusion_http_requests_total";
+pub const HTTP_REQUEST_DURATION_SECONDS: &str = "timefusion_http_request_duration_seconds";
+
+/// Upper bounds, in seconds, of the latency histogram buckets
+pub const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+lazy_static! {
+    static ref COUNTERS: Mutex<BTreeMap<(&'static str, Labels), u64>> = Mutex::new(BTreeMap::new());
+    static ref GAUGES: Mutex<BTreeMap<(&'static str, Labels), f64>> = Mutex::new(BTreeMap::new());
+    static ref HISTOGRAMS: Mutex<BTreeMap<(&'static str, Labels), Histogram>> = Mutex::new(BTreeMap::new());
+}
+
+/// Per-project label set; process-wide values use an empty project id and get no label
+fn project_labels(project_id: &str) -> Labels {
+    if project_id.is_empty() { vec![] } else { vec![("project", project_id.to_string())] }
+}
+
+fn owned_labels(labels: &[(&'static str, &str)]) -> Labels {
+    labels.iter().map(|(k, v)| (*k, v.to_string())).collect()
+}
+
+/// Add `value` to the counter `name` for `project_id`
+pub fn increment_counter(name: &'static str, project_id: &str, value: u64) {
+    let mut counters = COUNTERS.lock().unwrap_or_else(|e| e.into_inner());
+    *counters.entry((name, project_labels(project_id))).or_insert(0) += value;
+}
+
+/// Add `value` to the counter `name` with arbitrary labels, e.g. `[("method", "GET"), ("status", "200")]`
+pub fn increment_labeled_counter(name: &'static str, labels: &[(&'static str, &str)], value: u64) {
+    let mut counters = COUNTERS.lock().unwrap_or_else(|e| e.into_inner());
+    *counters.entry((name, owned_labels(labels))).or_insert(0) += value;
+}
+
+/// Current value of the counter `name` for `project_id`
+pub fn counter_value(name: &'static str, project_id: &str) -> u64 {
+    let counters = COUNTERS.lock().unwrap_or_else(|e| e.into_inner());
+    counters.get(&(name, project_labels(project_id))).copied().unwrap_or(0)
+}
+
+/// Sum of the counter `name` over all its label sets
+pub fn counter_total(name: &'static str) -> u64 {
+    let counters = COUNTERS.lock().unwrap_or_else(|e| e.into_inner());
+    counters.iter().filter(|((n, _), _)| *n == name).map(|(_, value)| *value).sum()
+}
+
+/// Set the gauge `name` for `project_id`
+pub fn set_gauge(name: &'static str, project_id: &str, value: f64) {
+    let mut gauges = GAUGES.lock().unwrap_or_else(|e| e.into_inner());
+    gauges.insert((name, project_labels(project_id)), value);
+}
+
+/// All projects' values of the gauge `name`, ordered by project
+pub fn gauge_values(name: &'static str) -> Vec<(String, f64)> {
+    let gauges = GAUGES.lock().unwrap_or_else(|e| e.into_inner());
+    gauges
+        .iter()
+        .filter(|((n, _), _)| *n == name)
+        .map(|((_, labels), value)| (labels.iter().find(|(k, _)| *k == "project").map(|(_, v)| v.clone()).unwrap_or_default(), *value))
+        .collect()
+}
+
+/// Record one observation, in seconds, in the latency histogram `name`
+pub fn observe_histogram(name: &'static str, labels: &[(&'static str, &str)], seconds: f64) {
+    let mut histograms = HISTOGRAMS.lock().unwrap_or_else(|e| e.into_inner());
+    let histogram = histograms.entry((name, owned_labels(labels))).or_default();
+    for (bucket, bound) in histogram.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+        if seconds <= bound {
+            *bucket += 1;
+        }
+    }
+    histogram.sum += seconds;
+    histogram.count += 1;
+}
+
+/// Number of observations in the latency histogram `name` with exactly these labels
+pub fn histogram_count(name: &'static str, labels: &[(&'static str, &str)]) -> u64 {
+    let histograms = HISTOGRAMS.lock().unwrap_or_else(|e| e.into_inner());
+    histograms.get(&(name, owned_labels(labels))).map(|h| h.count).unwrap_or(0)
+}
+
+fn render_labels(labels: &[(&str, String)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let pairs: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")))
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+/// All counters, gauges and histograms in the Prometheus text exposition format
+pub fn render_prometheus() -> String {
+    let mut out = String::new();
+    let counters = COUNTERS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .map(|((n, l), v)| (*n, l.clone(), *v as f64))
+        .collect::<Vec<_>>();
+    let gauges = GAUGES.lock().unwrap_or_else(|e| e.into_inner()).iter().map(|((n, l), v)| (*n, l.clone(), *v)).collect::<Vec<_>>();
+
+    for (kind, samples) in [("counter", counters), ("gauge", gauges)] {
+        let mut current = None;
+        for (name, labels, value) in samples {
+            if current != Some(name) {
+                let _ = writeln!(out, "# TYPE {} {}", name, kind);
+                current = Some(name);
+            }
+            let _ = writeln!(out, "{}{} {}", name, render_labels(&labels), value);
+        }
+    }
+
+    let histograms = HISTOGRAMS.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    let mut current = None;
+    for ((name, labels), histogram) in histograms {
+        if current != Some(name) {
+            let _ = writeln!(out, "# TYPE {} histogram", name);
+            current = Some(name);
+        }
+        for (count, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+            let mut bucket_labels = labels.clone();
+            bucket_labels.push(("le", bound.to_string()));
+            let _ = writeln!(out, "{}_bucket{} {}", name, render_labels(&bucket_labels), count);
+        }
+        let mut inf_labels = labels.clone();
+        inf_labels.push(("le", "+Inf".to_string()));
+        let _ = writeln!(out, "{}_bucket{} {}", name, render_labels(&inf_labels), histogram.count);
+        let _ = writeln!(out, "{}_sum{} {}", name, render_labels(&labels), histogram.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, render_labels(&labels), histogram.count);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_prometheus() {
+        increment_counter(RECORDS_INGESTED_TOTAL, "render_test", 3);
+        increment_counter(RECORDS_DELETED_TOTAL, "", 1);
+        set_gauge(QUEUE_PENDING_BATCHES, "", 2.0);
+
+        let text = render_prometheus();
+        assert!(text.contains("# TYPE timefusion_records_ingested_total counter\n"), "{}", text);
+        assert!(text.contains("timefusion_records_ingested_total{project=\"render_test\"} 3\n"), "{}", text);
+        assert!(
+            text.contains("# TYPE timefusion_queue_pending_batches gauge\ntimefusion_queue_pending_batches 2\n"),
+            "{}",
+            text
+        );
+        assert!(text.lines().any(|l| l.starts_with("timefusion_records_deleted_total ")), "{}", text);
+    }
+
+    #[test]
+    fn test_http_request_metrics() {
+        let before = counter_total(HTTP_REQUESTS_TOTAL);
+        increment_labeled_counter(HTTP_REQUESTS_TOTAL, &[("method", "GET"), ("status", "200")], 1);
+        increment_labeled_counter(HTTP_REQUESTS_TOTAL, &[("method", "POST"), ("status", "503")], 1);
+        assert_eq!(counter_total(HTTP_REQUESTS_TOTAL), before + 2);
+
+        observe_histogram(HTTP_REQUEST_DURATION_SECONDS, &[("method", "TEST")], 0.03);
+        observe_histogram(HTTP_REQUEST_DURATION_SECONDS, &[("method", "TEST")], 20.0);
+
+        let text = render_prometheus();
+        assert!(text.contains("timefusion_http_requests_total{method=\"POST\",status=\"503\"} "), "{}", text);
+        assert!(
+            text.contains("timefusion_http_request_duration_seconds_bucket{method=\"TEST\",le=\"0.025\"} 0\n"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("timefusion_http_request_duration_seconds_bucket{method=\"TEST\",le=\"0.05\"} 1\n"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("timefusion_http_request_duration_seconds_bucket{method=\"TEST\",le=\"+Inf\"} 2\n"),
+            "{}",
+            text
+        );
+        assert!(text.contains("timefusion_http_request_duration_seconds_count{method=\"TEST\"} 2\n"), "{}", text);
+    }
+}
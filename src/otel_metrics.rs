@@ -0,0 +1,96 @@
+use std::sync::Arc;
+
+use arrow_schema::{DataType, Field, FieldRef, Schema, SchemaRef};
+use delta_kernel::schema::StructField;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_arrow::schema::{SchemaLike, TracingOptions};
+use serde_json::json;
+
+use crate::persistent_queue::default_on_empty_string;
+
+/// A single OTLP metric data point, flattened into a row of the `otel_metrics` table.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct OtelMetrics {
+    #[serde(with = "chrono::serde::ts_microseconds")]
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+
+    #[serde(with = "chrono::serde::ts_microseconds_option")]
+    pub start_timestamp: Option<chrono::DateTime<chrono::Utc>>,
+
+    pub name: String,
+    pub description: Option<String>,
+    pub unit: Option<String>,
+    pub metric_type: String, // sum, gauge
+
+    pub value: f64,
+
+    // Sum specific
+    pub is_monotonic: Option<bool>,
+    pub aggregation_temporality: Option<String>, // delta, cumulative
+
+    pub attributes: Option<String>, // data point attributes as json
+
+    pub resource: Option<String>, // resource as json
+    pub resource___service___name: Option<String>,
+
+    pub scope_name: Option<String>,
+    pub scope_version: Option<String>,
+
+    // Partition columns are kept at the bottom for the same reason as in OtelLogsAndSpans.
+    pub project_id: String,
+
+    #[serde(default)]
+    #[serde(deserialize_with = "default_on_empty_string")]
+    pub date: chrono::NaiveDate,
+}
+
+impl OtelMetrics {
+    pub fn table_name() -> String {
+        "otel_metrics".to_string()
+    }
+
+    pub fn fields() -> anyhow::Result<Vec<FieldRef>> {
+        let tracing_options = TracingOptions::default()
+            .strings_as_large_utf8(false)
+            .overwrite("project_id", json!({"name": "project_id", "data_type": "Utf8", "nullable": false}))?
+            .overwrite("date", json!({"name": "date", "data_type": "Date32", "nullable": false}))?
+            .overwrite("name", json!({"name": "name", "data_type": "Utf8", "nullable": false}))?
+            .overwrite("metric_type", json!({"name": "metric_type", "data_type": "Utf8", "nullable": false}))?
+            .overwrite("value", json!({"name": "value", "data_type": "Float64", "nullable": false}))?
+            .overwrite(
+                "timestamp",
+                json!({"name": "timestamp", "data_type": "Timestamp(Microsecond, None)", "nullable": false}),
+            )?
+            .overwrite(
+                "start_timestamp",
+                json!({"name": "start_timestamp", "data_type": "Timestamp(Microsecond, None)", "nullable": true}),
+            )?;
+
+        Ok(Vec::<FieldRef>::from_type::<OtelMetrics>(tracing_options)?)
+    }
+
+    pub fn columns() -> anyhow::Result<Vec<StructField>> {
+        let fields = OtelMetrics::fields()?;
+        let vec_refs: Vec<StructField> = fields.iter().map(|arc_field| arc_field.as_ref().try_into().unwrap()).collect();
+        assert_eq!(fields[fields.len() - 2].data_type(), &DataType::Utf8);
+        assert_eq!(fields[fields.len() - 1].data_type(), &DataType::Date32);
+        debug!("otel_metrics columns {:?}", vec_refs);
+        Ok(vec_refs)
+    }
+
+    pub fn schema_ref() -> SchemaRef {
+        let columns = OtelMetrics::columns().unwrap_or_else(|e| {
+            log::error!("Failed to get metrics columns: {:?}", e);
+            Vec::new()
+        });
+
+        let arrow_fields: Vec<Field> = columns.iter().filter_map(|sf| sf.try_into().ok()).collect();
+
+        Arc::new(Schema::new(arrow_fields))
+    }
+
+    pub fn partitions() -> Vec<String> {
+        vec!["project_id".to_string(), "date".to_string()]
+    }
+}
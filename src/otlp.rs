@@ -0,0 +1,749 @@
+// otlp.rs - Mapping of OTLP export requests into TimeFusion rows
+use std::{env, fmt};
+
+use actix_web::{HttpResponse, ResponseError, http::StatusCode};
+use chrono::{DateTime, Utc};
+use opentelemetry_proto::tonic::{
+    collector::{metrics::v1::ExportMetricsServiceRequest, trace::v1::ExportTraceServiceRequest},
+    common::v1::{AnyValue, KeyValue, any_value},
+    metrics::v1::{AggregationTemporality, NumberDataPoint, metric::Data, number_data_point},
+    trace::v1::{Span, span::SpanKind, status::StatusCode as SpanStatusCode},
+};
+use serde_json::{Map, Value, json};
+use tracing::debug;
+
+use crate::otel_metrics::OtelMetrics;
+use crate::persistent_queue::OtelLogsAndSpans;
+
+/// Errors returned by the OTLP ingest endpoints.
+///
+/// Rendered as `{"error": "...", "code": "...", "receipt": null}` so clients can tell a rejected
+/// payload (4xx, don't retry) from a temporary condition (503, retry later) or a storage failure (500).
+#[derive(Debug)]
+pub enum IngestError {
+    Paused,
+    InvalidPayload(String),
+    UnsupportedEncoding(String),
+    PayloadTooLarge(usize),
+    SchemaViolation(String),
+    QueueFull { depth: usize, limit: usize },
+    TooManyConcurrent(usize),
+    ReadOnly(String),
+    Storage(String),
+}
+
+impl IngestError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            IngestError::Paused => "ingest_paused",
+            IngestError::InvalidPayload(_) => "invalid_payload",
+            IngestError::UnsupportedEncoding(_) => "unsupported_encoding",
+            IngestError::PayloadTooLarge(_) => "payload_too_large",
+            IngestError::SchemaViolation(_) => "schema_violation",
+            IngestError::QueueFull { .. } => "queue_full",
+            IngestError::TooManyConcurrent(_) => "too_many_concurrent_requests",
+            IngestError::ReadOnly(_) => "project_read_only",
+            IngestError::Storage(_) => "storage_error",
+        }
+    }
+}
+
+impl fmt::Display for IngestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IngestError::Paused => write!(f, "Ingestion is paused for maintenance"),
+            IngestError::InvalidPayload(e) => write!(f, "Invalid OTLP payload: {}", e),
+            IngestError::UnsupportedEncoding(encoding) => write!(f, "Unsupported Content-Encoding '{}', expected gzip or zstd", encoding),
+            IngestError::PayloadTooLarge(limit) => write!(f, "Request body exceeds {} bytes after decompression", limit),
+            IngestError::SchemaViolation(e) => write!(f, "Rejected by strict schema validation: {}", e),
+            IngestError::QueueFull { depth, limit } => write!(f, "Ingest queue is full ({} of {} rows pending), retry later", depth, limit),
+            IngestError::TooManyConcurrent(limit) => write!(f, "Too many concurrent ingest requests from this client (limit {}), retry later", limit),
+            IngestError::ReadOnly(project_id) => write!(f, "Project '{}' is read-only and doesn't accept writes", project_id),
+            IngestError::Storage(e) => write!(f, "Failed to store records: {}", e),
+        }
+    }
+}
+
+impl ResponseError for IngestError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            IngestError::Paused => StatusCode::SERVICE_UNAVAILABLE,
+            IngestError::InvalidPayload(_) => StatusCode::BAD_REQUEST,
+            IngestError::UnsupportedEncoding(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            IngestError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            IngestError::SchemaViolation(_) => StatusCode::BAD_REQUEST,
+            IngestError::QueueFull { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            IngestError::TooManyConcurrent(_) => StatusCode::TOO_MANY_REQUESTS,
+            IngestError::ReadOnly(_) => StatusCode::FORBIDDEN,
+            IngestError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        let mut body = serde_json::json!({
+            "error": self.to_string(),
+            "code": self.code(),
+            "receipt": null,
+        });
+        if let IngestError::QueueFull { depth, limit } = self {
+            // The queue drains once per flush interval, so that's the earliest a retry can succeed
+            let interval_ms: u64 = std::env::var("BATCH_INTERVAL_MS").ok().and_then(|v| v.parse().ok()).unwrap_or(1000);
+            response.insert_header(("Retry-After", interval_ms.div_ceil(1000).max(1).to_string()));
+            body["queue_depth"] = serde_json::json!(depth);
+            body["queue_limit"] = serde_json::json!(limit);
+        }
+        if let IngestError::TooManyConcurrent(_) = self {
+            response.insert_header(("Retry-After", "1"));
+        }
+        response.json(body)
+    }
+}
+
+/// How ingestion treats span attributes that don't fit the table schema (SCHEMA_STRICTNESS)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SchemaStrictness {
+    /// Keep unknown or mistyped attributes in the `attributes` JSON column (default)
+    #[default]
+    Lenient,
+    /// Reject the whole request when a span attribute has no column or a value of the wrong type
+    Strict,
+}
+
+impl SchemaStrictness {
+    pub fn from_env() -> Self {
+        match env::var("SCHEMA_STRICTNESS").unwrap_or_default().to_lowercase().as_str() {
+            "strict" => SchemaStrictness::Strict,
+            _ => SchemaStrictness::Lenient,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SchemaStrictness::Lenient => "lenient",
+            SchemaStrictness::Strict => "strict",
+        }
+    }
+
+    /// In strict mode, fail with the first span attribute that lenient mapping would stash in `attributes`,
+    /// and with resource attributes whose value doesn't fit their column
+    pub fn validate_traces(&self, request: &ExportTraceServiceRequest) -> Result<(), IngestError> {
+        if *self == SchemaStrictness::Lenient {
+            return Ok(());
+        }
+        let mut scratch = OtelLogsAndSpans::default();
+        for resource_spans in &request.resource_spans {
+            let resource_attributes = resource_spans.resource.as_ref().map(|r| r.attributes.as_slice()).unwrap_or_default();
+            for kv in resource_attributes {
+                if let (Some(slot), Some(value)) = (resource_slot(&mut scratch, &kv.key), kv.value.as_ref()) {
+                    if !assign(slot, value) {
+                        return Err(IngestError::SchemaViolation(format!("resource attribute '{}' has the wrong type", kv.key)));
+                    }
+                }
+            }
+            for span in resource_spans.scope_spans.iter().flat_map(|s| &s.spans) {
+                for kv in &span.attributes {
+                    let Some(value) = kv.value.as_ref() else {
+                        continue;
+                    };
+                    match attribute_slot(&mut scratch, &kv.key) {
+                        None => return Err(IngestError::SchemaViolation(format!("span '{}' has unknown attribute '{}'", span.name, kv.key))),
+                        Some(slot) if !assign(slot, value) => {
+                            return Err(IngestError::SchemaViolation(format!(
+                                "span '{}' attribute '{}' has the wrong type",
+                                span.name, kv.key
+                            )));
+                        }
+                        Some(_) => {}
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Convert an OTLP `AnyValue` into its JSON representation.
+pub fn any_value_to_json(value: &AnyValue) -> Value {
+    match &value.value {
+        Some(any_value::Value::StringValue(s)) => Value::String(s.clone()),
+        Some(any_value::Value::BoolValue(b)) => Value::Bool(*b),
+        Some(any_value::Value::IntValue(i)) => Value::from(*i),
+        Some(any_value::Value::DoubleValue(d)) => Value::from(*d),
+        Some(any_value::Value::ArrayValue(arr)) => Value::Array(arr.values.iter().map(any_value_to_json).collect()),
+        Some(any_value::Value::KvlistValue(kv)) => key_values_to_json(&kv.values),
+        Some(any_value::Value::BytesValue(bytes)) => Value::String(bytes.iter().map(|b| format!("{:02x}", b)).collect()),
+        None => Value::Null,
+    }
+}
+
+/// Convert a list of OTLP attributes into a JSON object.
+pub fn key_values_to_json(attributes: &[KeyValue]) -> Value {
+    let map: Map<String, Value> = attributes.iter().map(|kv| (kv.key.clone(), kv.value.as_ref().map(any_value_to_json).unwrap_or(Value::Null))).collect();
+    Value::Object(map)
+}
+
+/// Look up a string attribute by key.
+pub fn string_attribute(attributes: &[KeyValue], key: &str) -> Option<String> {
+    attributes.iter().find(|kv| kv.key == key).and_then(|kv| match kv.value.as_ref().and_then(|v| v.value.as_ref()) {
+        Some(any_value::Value::StringValue(s)) => Some(s.clone()),
+        _ => None,
+    })
+}
+
+/// Convert OTLP nanoseconds since epoch into a UTC timestamp.
+/// A value of 0 means the field was not set.
+pub fn nanos_to_datetime(nanos: u64) -> Option<DateTime<Utc>> {
+    if nanos == 0 {
+        return None;
+    }
+    Some(DateTime::from_timestamp_nanos(nanos as i64))
+}
+
+/// Flatten an OTLP metrics export request into `otel_metrics` rows.
+///
+/// Only `Sum` and `Gauge` metrics are mapped, one row per data point. `Histogram`,
+/// `ExponentialHistogram` and `Summary` metrics have no single value per point and are
+/// skipped for now; they are counted and returned so the caller can report them as
+/// rejected data points in the OTLP partial-success response.
+pub fn metrics_request_to_rows(request: &ExportMetricsServiceRequest, project_id: &str) -> (Vec<OtelMetrics>, i64) {
+    let mut rows = Vec::new();
+    let mut skipped = 0i64;
+
+    for resource_metrics in &request.resource_metrics {
+        let resource_attributes = resource_metrics.resource.as_ref().map(|r| r.attributes.as_slice()).unwrap_or_default();
+        let resource = (!resource_attributes.is_empty()).then(|| key_values_to_json(resource_attributes).to_string());
+        let service_name = string_attribute(resource_attributes, "service.name");
+
+        for scope_metrics in &resource_metrics.scope_metrics {
+            let scope_name = scope_metrics.scope.as_ref().map(|s| s.name.clone()).filter(|s| !s.is_empty());
+            let scope_version = scope_metrics.scope.as_ref().map(|s| s.version.clone()).filter(|s| !s.is_empty());
+
+            for metric in &scope_metrics.metrics {
+                let (metric_type, data_points, is_monotonic, temporality) = match &metric.data {
+                    Some(Data::Sum(sum)) => ("sum", &sum.data_points, Some(sum.is_monotonic), temporality_name(sum.aggregation_temporality)),
+                    Some(Data::Gauge(gauge)) => ("gauge", &gauge.data_points, None, None),
+                    Some(Data::Histogram(h)) => {
+                        debug!("Skipping histogram metric '{}' ({} data points)", metric.name, h.data_points.len());
+                        skipped += h.data_points.len() as i64;
+                        continue;
+                    }
+                    Some(Data::ExponentialHistogram(h)) => {
+                        debug!("Skipping exponential histogram metric '{}' ({} data points)", metric.name, h.data_points.len());
+                        skipped += h.data_points.len() as i64;
+                        continue;
+                    }
+                    Some(Data::Summary(s)) => {
+                        debug!("Skipping summary metric '{}' ({} data points)", metric.name, s.data_points.len());
+                        skipped += s.data_points.len() as i64;
+                        continue;
+                    }
+                    None => continue,
+                };
+
+                for point in data_points {
+                    let Some(row) = number_point_to_row(point, project_id) else {
+                        skipped += 1;
+                        continue;
+                    };
+                    rows.push(OtelMetrics {
+                        name: metric.name.clone(),
+                        description: Some(metric.description.clone()).filter(|s| !s.is_empty()),
+                        unit: Some(metric.unit.clone()).filter(|s| !s.is_empty()),
+                        metric_type: metric_type.to_string(),
+                        is_monotonic,
+                        aggregation_temporality: temporality.clone(),
+                        resource: resource.clone(),
+                        resource___service___name: service_name.clone(),
+                        scope_name: scope_name.clone(),
+                        scope_version: scope_version.clone(),
+                        ..row
+                    });
+                }
+            }
+        }
+    }
+
+    (rows, skipped)
+}
+
+fn number_point_to_row(point: &NumberDataPoint, project_id: &str) -> Option<OtelMetrics> {
+    let value = match point.value.as_ref()? {
+        number_data_point::Value::AsDouble(v) => *v,
+        number_data_point::Value::AsInt(v) => *v as f64,
+    };
+    let timestamp = nanos_to_datetime(point.time_unix_nano)?;
+
+    Some(OtelMetrics {
+        timestamp,
+        start_timestamp: nanos_to_datetime(point.start_time_unix_nano),
+        value,
+        attributes: (!point.attributes.is_empty()).then(|| key_values_to_json(&point.attributes).to_string()),
+        project_id: project_id.to_string(),
+        date: timestamp.date_naive(),
+        ..Default::default()
+    })
+}
+
+fn temporality_name(value: i32) -> Option<String> {
+    match AggregationTemporality::try_from(value) {
+        Ok(AggregationTemporality::Delta) => Some("delta".to_string()),
+        Ok(AggregationTemporality::Cumulative) => Some("cumulative".to_string()),
+        _ => None,
+    }
+}
+
+/// Flatten an OTLP trace export request into `otel_logs_and_spans` rows, one per span.
+///
+/// Semantic-convention attributes with a dedicated `attributes___*` / `resource___*` column are written
+/// there; every other span attribute is kept in the `attributes` JSON column, and the full resource in
+/// `resource`. OTLP nanosecond timestamps are stored with microsecond precision. Spans without a start
+/// time can't be partitioned and are counted as rejected.
+pub fn traces_request_to_rows(request: &ExportTraceServiceRequest, project_id: &str) -> (Vec<OtelLogsAndSpans>, i64) {
+    let mut rows = Vec::new();
+    let mut rejected = 0i64;
+    let observed = Utc::now();
+
+    for resource_spans in &request.resource_spans {
+        let resource_attributes = resource_spans.resource.as_ref().map(|r| r.attributes.as_slice()).unwrap_or_default();
+        let mut resource_row = OtelLogsAndSpans {
+            resource: (!resource_attributes.is_empty()).then(|| key_values_to_json(resource_attributes).to_string()),
+            ..Default::default()
+        };
+        for kv in resource_attributes {
+            if let (Some(slot), Some(value)) = (resource_slot(&mut resource_row, &kv.key), kv.value.as_ref()) {
+                assign(slot, value);
+            }
+        }
+
+        for scope_spans in &resource_spans.scope_spans {
+            for span in &scope_spans.spans {
+                match span_to_row(span, project_id, observed, &resource_row) {
+                    Some(row) => rows.push(row),
+                    None => {
+                        debug!("Rejecting span '{}' without a start time", span.name);
+                        rejected += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    (rows, rejected)
+}
+
+fn span_to_row(span: &Span, project_id: &str, observed: DateTime<Utc>, resource_row: &OtelLogsAndSpans) -> Option<OtelLogsAndSpans> {
+    let start_time = nanos_to_datetime(span.start_time_unix_nano)?;
+    let end_time = nanos_to_datetime(span.end_time_unix_nano);
+    let trace_id = hex(&span.trace_id);
+    let span_id = hex(&span.span_id);
+    let status = span.status.as_ref();
+
+    let mut row = OtelLogsAndSpans {
+        timestamp: start_time,
+        observed_timestamp: Some(observed),
+        id: span_id.clone().unwrap_or_default(),
+        parent_id: hex(&span.parent_span_id),
+        name: Some(span.name.clone()).filter(|s| !s.is_empty()),
+        kind: span_kind_name(span.kind),
+        status_code: status.and_then(|s| status_code_name(s.code)),
+        status_message: status.map(|s| s.message.clone()).filter(|s| !s.is_empty()),
+        duration: end_time.map(|_| span.end_time_unix_nano.saturating_sub(span.start_time_unix_nano)),
+        start_time: Some(start_time),
+        end_time,
+        context: Some(
+            json!({
+                "trace_id": trace_id,
+                "span_id": span_id,
+                "trace_state": span.trace_state,
+                "trace_flags": span.flags,
+            })
+            .to_string(),
+        ),
+        context___trace_id: trace_id,
+        context___span_id: span_id,
+        context___trace_state: Some(span.trace_state.clone()).filter(|s| !s.is_empty()),
+        context___trace_flags: Some(span.flags.to_string()),
+        events: (!span.events.is_empty()).then(|| {
+            Value::Array(
+                span.events
+                    .iter()
+                    .map(|e| json!({"name": e.name, "timestamp": e.time_unix_nano, "attributes": key_values_to_json(&e.attributes)}))
+                    .collect(),
+            )
+            .to_string()
+        }),
+        links: (!span.links.is_empty()).then(|| {
+            Value::Array(
+                span.links
+                    .iter()
+                    .map(|l| {
+                        json!({
+                            "trace_id": hex(&l.trace_id),
+                            "span_id": hex(&l.span_id),
+                            "trace_state": l.trace_state,
+                            "attributes": key_values_to_json(&l.attributes),
+                        })
+                    })
+                    .collect(),
+            )
+            .to_string()
+        }),
+        project_id: project_id.to_string(),
+        date: start_time.date_naive(),
+        ..resource_row.clone()
+    };
+
+    let mut unmapped = Map::new();
+    for kv in &span.attributes {
+        let Some(value) = kv.value.as_ref() else {
+            continue;
+        };
+        let mapped = attribute_slot(&mut row, &kv.key).is_some_and(|slot| assign(slot, value));
+        if !mapped {
+            unmapped.insert(kv.key.clone(), any_value_to_json(value));
+        }
+    }
+    row.attributes = (!unmapped.is_empty()).then(|| Value::Object(unmapped).to_string());
+
+    Some(row)
+}
+
+/// A typed column that an OTLP attribute can be written to
+enum Slot<'a> {
+    Str(&'a mut Option<String>),
+    Num(&'a mut Option<u32>),
+}
+
+/// Store `value` in `slot`, returning false when the value's type doesn't fit the column
+fn assign(slot: Slot<'_>, value: &AnyValue) -> bool {
+    match (slot, &value.value) {
+        (Slot::Str(field), Some(any_value::Value::StringValue(s))) => *field = Some(s.clone()),
+        (Slot::Num(field), Some(any_value::Value::IntValue(i))) => match u32::try_from(*i) {
+            Ok(n) => *field = Some(n),
+            Err(_) => return false,
+        },
+        _ => return false,
+    }
+    true
+}
+
+fn attribute_slot<'a>(row: &'a mut OtelLogsAndSpans, key: &str) -> Option<Slot<'a>> {
+    Some(match key {
+        "client.address" => Slot::Str(&mut row.attributes___client___address),
+        "client.port" => Slot::Num(&mut row.attributes___client___port),
+        "server.address" => Slot::Str(&mut row.attributes___server___address),
+        "server.port" => Slot::Num(&mut row.attributes___server___port),
+        "network.local.address" => Slot::Str(&mut row.attributes___network___local__address),
+        "network.local.port" => Slot::Num(&mut row.attributes___network___local__port),
+        "network.peer.address" => Slot::Str(&mut row.attributes___network___peer___address),
+        "network.peer.port" => Slot::Num(&mut row.attributes___network___peer__port),
+        "network.protocol.name" => Slot::Str(&mut row.attributes___network___protocol___name),
+        "network.protocol.version" => Slot::Str(&mut row.attributes___network___protocol___version),
+        "network.transport" => Slot::Str(&mut row.attributes___network___transport),
+        "network.type" => Slot::Str(&mut row.attributes___network___type),
+        "code.line.number" => Slot::Num(&mut row.attributes___code___line___number),
+        "log.record.original" => Slot::Str(&mut row.attributes___log__record___original),
+        "log.record.uid" => Slot::Str(&mut row.attributes___log__record___uid),
+        "error.type" => Slot::Str(&mut row.attributes___error___type),
+        "exception.type" => Slot::Str(&mut row.attributes___exception___type),
+        "exception.message" => Slot::Str(&mut row.attributes___exception___message),
+        "exception.stacktrace" => Slot::Str(&mut row.attributes___exception___stacktrace),
+        "url.fragment" => Slot::Str(&mut row.attributes___url___fragment),
+        "url.full" => Slot::Str(&mut row.attributes___url___full),
+        "url.path" => Slot::Str(&mut row.attributes___url___path),
+        "url.query" => Slot::Str(&mut row.attributes___url___query),
+        "url.scheme" => Slot::Str(&mut row.attributes___url___scheme),
+        "user_agent.original" => Slot::Str(&mut row.attributes___user_agent___original),
+        "http.request.method" => Slot::Str(&mut row.attributes___http___request___method),
+        "http.request.method_original" => Slot::Str(&mut row.attributes___http___request___method_original),
+        "http.response.status_code" => Slot::Num(&mut row.attributes___http___response___status_code),
+        "http.request.resend_count" => Slot::Num(&mut row.attributes___http___request___resend_count),
+        "http.request.body.size" => Slot::Num(&mut row.attributes___http___request___body___size),
+        "session.id" => Slot::Str(&mut row.attributes___session___id),
+        "session.previous_id" => Slot::Str(&mut row.attributes___session___previous___id),
+        "db.system.name" => Slot::Str(&mut row.attributes___db___system___name),
+        "db.collection.name" => Slot::Str(&mut row.attributes___db___collection___name),
+        "db.namespace" => Slot::Str(&mut row.attributes___db___namespace),
+        "db.operation.name" => Slot::Str(&mut row.attributes___db___operation___name),
+        "db.response.status_code" => Slot::Str(&mut row.attributes___db___response___status_code),
+        "db.operation.batch.size" => Slot::Num(&mut row.attributes___db___operation___batch___size),
+        "db.query.summary" => Slot::Str(&mut row.attributes___db___query___summary),
+        "db.query.text" => Slot::Str(&mut row.attributes___db___query___text),
+        "user.id" => Slot::Str(&mut row.attributes___user___id),
+        "user.email" => Slot::Str(&mut row.attributes___user___email),
+        "user.full_name" => Slot::Str(&mut row.attributes___user___full_name),
+        "user.name" => Slot::Str(&mut row.attributes___user___name),
+        "user.hash" => Slot::Str(&mut row.attributes___user___hash),
+        _ => return None,
+    })
+}
+
+fn resource_slot<'a>(row: &'a mut OtelLogsAndSpans, key: &str) -> Option<Slot<'a>> {
+    Some(match key {
+        "service.name" => Slot::Str(&mut row.resource___service___name),
+        "service.version" => Slot::Str(&mut row.resource___service___version),
+        "service.instance.id" => Slot::Str(&mut row.resource___service___instance___id),
+        "service.namespace" => Slot::Str(&mut row.resource___service___namespace),
+        "telemetry.sdk.language" => Slot::Str(&mut row.resource___telemetry___sdk___language),
+        "telemetry.sdk.name" => Slot::Str(&mut row.resource___telemetry___sdk___name),
+        "telemetry.sdk.version" => Slot::Str(&mut row.resource___telemetry___sdk___version),
+        "user_agent.original" => Slot::Str(&mut row.resource___user_agent___original),
+        _ => return None,
+    })
+}
+
+/// Lowercase hex of an OTLP id, `None` for an empty (unset) id
+fn hex(bytes: &[u8]) -> Option<String> {
+    (!bytes.is_empty()).then(|| bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn span_kind_name(value: i32) -> Option<String> {
+    let name = match SpanKind::try_from(value).ok()? {
+        SpanKind::Unspecified => return None,
+        SpanKind::Internal => "internal",
+        SpanKind::Server => "server",
+        SpanKind::Client => "client",
+        SpanKind::Producer => "producer",
+        SpanKind::Consumer => "consumer",
+    };
+    Some(name.to_string())
+}
+
+fn status_code_name(value: i32) -> Option<String> {
+    let name = match SpanStatusCode::try_from(value).ok()? {
+        SpanStatusCode::Unset => "UNSET",
+        SpanStatusCode::Ok => "OK",
+        SpanStatusCode::Error => "ERROR",
+    };
+    Some(name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry_proto::tonic::{
+        metrics::v1::{Gauge, Histogram, HistogramDataPoint, Metric, ResourceMetrics, ScopeMetrics, Sum},
+        resource::v1::Resource,
+        trace::v1::{ResourceSpans, ScopeSpans, Status, span::Event},
+    };
+
+    use super::*;
+
+    fn string_kv(key: &str, value: &str) -> KeyValue {
+        KeyValue {
+            key: key.to_string(),
+            value: Some(AnyValue {
+                value: Some(any_value::Value::StringValue(value.to_string())),
+            }),
+        }
+    }
+
+    fn point(time_unix_nano: u64, value: number_data_point::Value) -> NumberDataPoint {
+        NumberDataPoint {
+            attributes: vec![string_kv("http.route", "/users")],
+            time_unix_nano,
+            value: Some(value),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_metrics_request_to_rows() {
+        let ts = 1_672_567_200_000_000_000u64; // 2023-01-01T10:00:00Z
+        let request = ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: Some(Resource {
+                    attributes: vec![string_kv("service.name", "checkout")],
+                    ..Default::default()
+                }),
+                scope_metrics: vec![ScopeMetrics {
+                    metrics: vec![
+                        Metric {
+                            name: "http.server.requests".to_string(),
+                            unit: "1".to_string(),
+                            data: Some(Data::Sum(Sum {
+                                data_points: vec![point(ts, number_data_point::Value::AsInt(42))],
+                                aggregation_temporality: AggregationTemporality::Cumulative as i32,
+                                is_monotonic: true,
+                            })),
+                            ..Default::default()
+                        },
+                        Metric {
+                            name: "process.memory.usage".to_string(),
+                            unit: "By".to_string(),
+                            data: Some(Data::Gauge(Gauge {
+                                data_points: vec![point(ts, number_data_point::Value::AsDouble(1024.5))],
+                            })),
+                            ..Default::default()
+                        },
+                        Metric {
+                            name: "http.server.duration".to_string(),
+                            data: Some(Data::Histogram(Histogram {
+                                data_points: vec![HistogramDataPoint::default()],
+                                aggregation_temporality: AggregationTemporality::Delta as i32,
+                            })),
+                            ..Default::default()
+                        },
+                    ],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        };
+
+        let (rows, skipped) = metrics_request_to_rows(&request, "test_project");
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(skipped, 1);
+
+        let sum = &rows[0];
+        assert_eq!(sum.metric_type, "sum");
+        assert_eq!(sum.value, 42.0);
+        assert_eq!(sum.is_monotonic, Some(true));
+        assert_eq!(sum.aggregation_temporality.as_deref(), Some("cumulative"));
+        assert_eq!(sum.resource___service___name.as_deref(), Some("checkout"));
+        assert_eq!(sum.attributes.as_deref(), Some(r#"{"http.route":"/users"}"#));
+        assert_eq!(sum.timestamp.to_rfc3339(), "2023-01-01T10:00:00+00:00");
+        assert_eq!(sum.date.to_string(), "2023-01-01");
+        assert_eq!(sum.project_id, "test_project");
+
+        let gauge = &rows[1];
+        assert_eq!(gauge.metric_type, "gauge");
+        assert_eq!(gauge.value, 1024.5);
+        assert_eq!(gauge.unit.as_deref(), Some("By"));
+        assert_eq!(gauge.is_monotonic, None);
+    }
+
+    #[test]
+    fn test_traces_request_to_rows() {
+        let start = 1_672_567_200_123_456_789u64; // 2023-01-01T10:00:00.123456789Z
+        let int_kv = |key: &str, value: i64| KeyValue {
+            key: key.to_string(),
+            value: Some(AnyValue {
+                value: Some(any_value::Value::IntValue(value)),
+            }),
+        };
+        let request = ExportTraceServiceRequest {
+            resource_spans: vec![ResourceSpans {
+                resource: Some(Resource {
+                    attributes: vec![string_kv("service.name", "checkout"), string_kv("deployment.environment", "prod")],
+                    ..Default::default()
+                }),
+                scope_spans: vec![ScopeSpans {
+                    spans: vec![
+                        Span {
+                            trace_id: vec![0xab; 16],
+                            span_id: vec![0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08],
+                            name: "GET /users".to_string(),
+                            kind: SpanKind::Server as i32,
+                            start_time_unix_nano: start,
+                            end_time_unix_nano: start + 2_500_000,
+                            attributes: vec![
+                                string_kv("http.request.method", "GET"),
+                                int_kv("http.response.status_code", 200),
+                                string_kv("feature.flag", "beta"),
+                            ],
+                            events: vec![Event {
+                                name: "cache.miss".to_string(),
+                                time_unix_nano: start + 1_000,
+                                ..Default::default()
+                            }],
+                            status: Some(Status {
+                                code: SpanStatusCode::Error as i32,
+                                message: "boom".to_string(),
+                            }),
+                            ..Default::default()
+                        },
+                        Span {
+                            name: "no start time".to_string(),
+                            ..Default::default()
+                        },
+                    ],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        };
+
+        let (rows, rejected) = traces_request_to_rows(&request, "test_project");
+
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rejected, 1);
+
+        let row = &rows[0];
+        assert_eq!(row.timestamp.timestamp_micros(), 1_672_567_200_123_456);
+        assert_eq!(row.duration, Some(2_500_000));
+        assert_eq!(row.id, "0102030405060708");
+        assert_eq!(row.parent_id, None);
+        assert_eq!(row.context___trace_id.as_deref(), Some("abababababababababababababababab"));
+        assert_eq!(row.kind.as_deref(), Some("server"));
+        assert_eq!(row.status_code.as_deref(), Some("ERROR"));
+        assert_eq!(row.status_message.as_deref(), Some("boom"));
+        assert_eq!(row.attributes___http___request___method.as_deref(), Some("GET"));
+        assert_eq!(row.attributes___http___response___status_code, Some(200));
+        assert_eq!(row.attributes.as_deref(), Some(r#"{"feature.flag":"beta"}"#));
+        assert_eq!(row.resource___service___name.as_deref(), Some("checkout"));
+        assert!(row.resource.as_deref().unwrap().contains(r#""deployment.environment":"prod""#));
+        assert!(row.events.as_deref().unwrap().contains(r#""name":"cache.miss""#));
+        assert_eq!(row.date.to_string(), "2023-01-01");
+        assert_eq!(row.project_id, "test_project");
+    }
+
+    #[test]
+    fn test_schema_strictness() {
+        let span = |attributes: Vec<KeyValue>| ExportTraceServiceRequest {
+            resource_spans: vec![ResourceSpans {
+                scope_spans: vec![ScopeSpans {
+                    spans: vec![Span {
+                        name: "GET /users".to_string(),
+                        start_time_unix_nano: 1_672_567_200_000_000_000,
+                        attributes,
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        };
+        let known = span(vec![string_kv("http.request.method", "GET")]);
+        let unknown = span(vec![string_kv("http.request.method", "GET"), string_kv("feature.flag", "beta")]);
+        let mistyped = span(vec![string_kv("http.response.status_code", "200")]);
+
+        // Lenient accepts everything, unknown attributes end up in the attributes column
+        for request in [&known, &unknown, &mistyped] {
+            assert!(SchemaStrictness::Lenient.validate_traces(request).is_ok());
+        }
+        let (rows, _) = traces_request_to_rows(&unknown, "test_project");
+        assert_eq!(rows[0].attributes.as_deref(), Some(r#"{"feature.flag":"beta"}"#));
+
+        assert!(SchemaStrictness::Strict.validate_traces(&known).is_ok());
+        let err = SchemaStrictness::Strict.validate_traces(&unknown).unwrap_err();
+        assert!(matches!(err, IngestError::SchemaViolation(_)));
+        assert!(err.to_string().contains("feature.flag"), "error should name the attribute: {}", err);
+        assert!(matches!(
+            SchemaStrictness::Strict.validate_traces(&mistyped),
+            Err(IngestError::SchemaViolation(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_ingest_error_responses() {
+        for (err, status, code) in [
+            (IngestError::Paused, 503, "ingest_paused"),
+            (IngestError::InvalidPayload("truncated".to_string()), 400, "invalid_payload"),
+            (IngestError::UnsupportedEncoding("br".to_string()), 415, "unsupported_encoding"),
+            (IngestError::PayloadTooLarge(1024), 413, "payload_too_large"),
+            (IngestError::SchemaViolation("unknown attribute".to_string()), 400, "schema_violation"),
+            (IngestError::Storage("disk full".to_string()), 500, "storage_error"),
+        ] {
+            let response = err.error_response();
+            assert_eq!(response.status().as_u16(), status);
+
+            let body = actix_web::body::to_bytes(response.into_body()).await.unwrap();
+            let json: Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(json["code"], code);
+            assert_eq!(json["error"], err.to_string());
+            assert!(json["receipt"].is_null());
+        }
+    }
+}
@@ -0,0 +1,144 @@
+// pg_auth.rs - Password authentication for PGWire connections
+use std::{env, fmt::Debug, sync::Arc};
+
+use async_trait::async_trait;
+use datafusion::execution::context::SessionContext;
+use datafusion_postgres::HandlerFactory;
+use futures::Sink;
+use pgwire::{
+    api::{
+        ClientInfo, PgWireConnectionState, PgWireServerHandlers,
+        auth::{AuthSource, DefaultServerParameterProvider, LoginInfo, Password, StartupHandler, cleartext::CleartextPasswordAuthStartupHandler},
+    },
+    error::{PgWireError, PgWireResult},
+    messages::{PgWireBackendMessage, PgWireFrontendMessage},
+};
+use tokio::sync::Notify;
+use tracing::error;
+
+use crate::database::{Database, ProjectAccess};
+
+type DefaultStartupHandler = <HandlerFactory as PgWireServerHandlers>::StartupHandler;
+
+/// The single account configured with PGWIRE_USER (default `postgres`) and PGWIRE_PASSWORD, allowed to read the
+/// projects listed in PGWIRE_PROJECTS
+#[derive(Debug)]
+pub struct PasswordAuthSource {
+    user: String,
+    password: String,
+    projects: Option<ProjectAccess>,
+}
+
+impl PasswordAuthSource {
+    /// None when PGWIRE_PASSWORD is unset, in which case connections are not authenticated
+    pub fn from_env() -> Option<Self> {
+        let password = env::var("PGWIRE_PASSWORD").ok().filter(|p| !p.is_empty())?;
+        let user = env::var("PGWIRE_USER").unwrap_or_else(|_| "postgres".to_string());
+        let projects = env::var("PGWIRE_PROJECTS").ok().filter(|p| !p.trim().is_empty()).map(|p| ProjectAccess::from_spec(&p));
+        Some(Self { user, password, projects })
+    }
+
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+}
+
+#[async_trait]
+impl AuthSource for PasswordAuthSource {
+    async fn get_password(&self, login: &LoginInfo) -> PgWireResult<Password> {
+        let user = login.user().unwrap_or_default();
+        if user != self.user {
+            return Err(PgWireError::InvalidPassword(user.to_string()));
+        }
+        Ok(Password::new(None, self.password.as_bytes().to_vec()))
+    }
+}
+
+/// Startup handler that asks the client for its password (AuthenticationCleartextPassword) when
+/// PGWIRE_PASSWORD is set, and otherwise accepts connections like the datafusion-postgres default.
+/// The password travels in clear text, so expose the port only on a trusted network or behind TLS.
+/// Once the login succeeds, the user's PGWIRE_PROJECTS are attached to the connection's session context and `ready`
+/// is notified, which ends the handshake timeout. When PGWIRE_PROJECTS names a single project with a `display_timezone`,
+/// that zone becomes the connection's `timefusion.timezone`. The client receives the connection's cancel key in BackendKeyData.
+pub struct TimeFusionStartupHandler {
+    auth: StartupAuth,
+    session: SessionContext,
+    database: Database,
+    cancel_key: (i32, i32),
+    ready: Arc<Notify>,
+}
+
+enum StartupAuth {
+    Trust(Arc<DefaultStartupHandler>),
+    Password(
+        CleartextPasswordAuthStartupHandler<PasswordAuthSource, DefaultServerParameterProvider>,
+        Option<ProjectAccess>,
+    ),
+}
+
+impl TimeFusionStartupHandler {
+    pub fn new(default: Arc<DefaultStartupHandler>, session: SessionContext, database: Database, cancel_key: (i32, i32)) -> Self {
+        let auth = match PasswordAuthSource::from_env() {
+            Some(mut source) => {
+                let projects = source.projects.take();
+                StartupAuth::Password(
+                    CleartextPasswordAuthStartupHandler::new(Arc::new(source), Arc::new(DefaultServerParameterProvider::default())),
+                    projects,
+                )
+            }
+            None => StartupAuth::Trust(default),
+        };
+        Self {
+            auth,
+            session,
+            database,
+            cancel_key,
+            ready: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Display time zone of the project a login is restricted to, if it is restricted to just one
+    async fn project_timezone(&self, projects: &ProjectAccess) -> Option<String> {
+        self.database.display_timezone(projects.single()?).await
+    }
+
+    /// Notified once the connection has completed its startup and can run queries
+    pub fn ready(&self) -> Arc<Notify> {
+        self.ready.clone()
+    }
+}
+
+#[async_trait]
+impl StartupHandler for TimeFusionStartupHandler {
+    async fn on_startup<C>(&self, client: &mut C, message: PgWireFrontendMessage) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        if matches!(message, PgWireFrontendMessage::Startup(_)) {
+            let (pid, secret) = self.cancel_key;
+            client.set_pid_and_secret_key(pid, secret);
+        }
+        match &self.auth {
+            StartupAuth::Trust(handler) => handler.on_startup(client, message).await?,
+            StartupAuth::Password(handler, projects) => {
+                handler.on_startup(client, message).await?;
+                if let Some(projects) = projects {
+                    if matches!(client.state(), PgWireConnectionState::ReadyForQuery) {
+                        projects.clone().attach_to(&self.session);
+                        if let Some(timezone) = self.project_timezone(projects).await {
+                            if let Err(e) = self.session.state_ref().write().config_mut().options_mut().set("timefusion.timezone", &timezone) {
+                                error!("Failed to apply display time zone {}: {}", timezone, e);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        if matches!(client.state(), PgWireConnectionState::ReadyForQuery) {
+            self.ready.notify_one();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,490 @@
+// pg_compat.rs - PostgreSQL compatibility: planning pg-specific SQL and describing tables in pg terms
+use std::{collections::HashSet, env, fmt::Write, sync::Arc};
+
+use datafusion::{
+    arrow::{
+        array::timezone::Tz,
+        datatypes::{DataType, Schema, TimeUnit},
+    },
+    common::{Column, extensions_options, tree_node::TreeNode},
+    config::{ConfigExtension, ConfigOptions},
+    error::{DataFusionError, Result as DFResult},
+    execution::context::SessionContext,
+    functions::expr_fn::to_char,
+    logical_expr::{Expr, LogicalPlan, LogicalPlanBuilder, cast, lit, planner::TypePlanner},
+    optimizer::AnalyzerRule,
+    sql::sqlparser::{
+        ast::{self, TimezoneInfo},
+        dialect::PostgreSqlDialect,
+        parser::Parser,
+    },
+};
+use tracing::info;
+
+/// Maps PostgreSQL cast targets that clients and BI tools send, e.g. `::jsonb`, `::regclass` or `::timestamptz`, to
+/// Arrow types. JSON is kept as text, like the `attributes`/`resource` columns, and `timestamptz` uses the tables'
+/// microsecond UTC timestamps so comparisons against `timestamp` need no further cast. Other types are left to
+/// DataFusion, which reports those it doesn't support as `0A000 feature_not_supported`.
+#[derive(Debug, Default)]
+pub struct PgTypePlanner;
+
+impl TypePlanner for PgTypePlanner {
+    fn plan_type(&self, sql_type: &ast::DataType) -> DFResult<Option<DataType>> {
+        Ok(match sql_type {
+            ast::DataType::JSON | ast::DataType::JSONB | ast::DataType::Regclass => Some(DataType::Utf8),
+            ast::DataType::Timestamp(_, TimezoneInfo::Tz | TimezoneInfo::WithTimeZone) => Some(DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into()))),
+            ast::DataType::Int2(_) => Some(DataType::Int16),
+            ast::DataType::Int4(_) => Some(DataType::Int32),
+            ast::DataType::Int8(_) => Some(DataType::Int64),
+            ast::DataType::Float4 => Some(DataType::Float32),
+            ast::DataType::Float8 => Some(DataType::Float64),
+            ast::DataType::Custom(name, modifiers) if modifiers.is_empty() && name.to_string().eq_ignore_ascii_case("name") => Some(DataType::Utf8),
+            _ => None,
+        })
+    }
+}
+
+extensions_options! {
+    /// TimeFusion session settings, changed with `SET timefusion.<name> = <value>`
+    pub struct TimeFusionOptions {
+        /// Row limit applied to top-level SELECTs without a LIMIT, 0 for none
+        pub default_select_limit: usize, default = 0
+        /// Client statement timeout in milliseconds, set by `SET statement_timeout`, 0 for the server default
+        pub statement_timeout_ms: u64, default = 0
+        /// Time zone timestamps are rendered in, e.g. `America/New_York`; empty returns them as stored, in UTC
+        pub timezone: String, default = String::new()
+        /// Send NoticeResponses, e.g. for INSERTs that leave columns NULL; `false` silences them
+        pub notices: bool, default = true
+    }
+}
+
+impl ConfigExtension for TimeFusionOptions {
+    const PREFIX: &'static str = "timefusion";
+}
+
+/// Limit for unbounded SELECTs over PGWire (DEFAULT_SELECT_LIMIT, none when unset)
+pub fn default_select_limit() -> usize {
+    env::var("DEFAULT_SELECT_LIMIT").ok().and_then(|v| v.parse().ok()).unwrap_or(0)
+}
+
+/// Register `TimeFusionOptions` with the `DefaultSelectLimit` and `DisplayTimezone` rules with a session, limiting its
+/// unbounded SELECTs to DEFAULT_SELECT_LIMIT rows until `SET timefusion.default_select_limit = 0` lifts it. PGWire
+/// connections each get a copy of these options from `Database::connection_context`, so a `SET` only affects the
+/// connection issuing it.
+pub fn register_session_options(ctx: &SessionContext) {
+    let options = TimeFusionOptions {
+        default_select_limit: default_select_limit(),
+        ..Default::default()
+    };
+    if options.default_select_limit > 0 {
+        info!("Top-level SELECTs without a LIMIT return at most {} rows", options.default_select_limit);
+    }
+    ctx.state_ref().write().config_mut().options_mut().extensions.insert(options);
+    ctx.add_analyzer_rule(Arc::new(DefaultSelectLimit));
+    ctx.add_analyzer_rule(Arc::new(DisplayTimezone));
+}
+
+/// Caps the rows of a query that reads a table and has no LIMIT of its own at `timefusion.default_select_limit`, so
+/// a bare `SELECT * FROM otel_logs_and_spans` doesn't materialize the whole table. Only the outermost query is
+/// limited; subqueries, DML and DDL are left as they are.
+#[derive(Debug, Default)]
+pub struct DefaultSelectLimit;
+
+impl AnalyzerRule for DefaultSelectLimit {
+    fn analyze(&self, plan: LogicalPlan, config: &ConfigOptions) -> DFResult<LogicalPlan> {
+        let limit = config.extensions.get::<TimeFusionOptions>().map_or(0, |options| options.default_select_limit);
+        if limit == 0 || !is_unbounded_query(&plan)? {
+            return Ok(plan);
+        }
+        info!("Applying default LIMIT {} to a query without one", limit);
+        LogicalPlanBuilder::from(plan).limit(0, Some(limit))?.build()
+    }
+
+    fn name(&self) -> &str {
+        "default_select_limit"
+    }
+}
+
+fn is_unbounded_query(plan: &LogicalPlan) -> DFResult<bool> {
+    match plan {
+        LogicalPlan::Limit(_) => Ok(false),
+        _ if !is_query(plan) => Ok(false),
+        _ => plan.exists(|node| Ok(matches!(node, LogicalPlan::TableScan(_)))),
+    }
+}
+
+fn is_query(plan: &LogicalPlan) -> bool {
+    !matches!(
+        plan,
+        LogicalPlan::Dml(_)
+            | LogicalPlan::Ddl(_)
+            | LogicalPlan::Copy(_)
+            | LogicalPlan::Explain(_)
+            | LogicalPlan::Analyze(_)
+            | LogicalPlan::Statement(_)
+            | LogicalPlan::DescribeTable(_)
+    )
+}
+
+/// How `DisplayTimezone` renders timestamps, e.g. `2024-03-02 05:00:00-05:00`
+const DISPLAY_TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f%:z";
+
+/// Check that `timezone` is an IANA time zone name or a fixed offset such as `+05:30`
+pub fn validate_timezone(timezone: &str) -> DFResult<()> {
+    timezone.parse::<Tz>().map(|_| ()).map_err(|_| DataFusionError::Plan(format!("Unknown time zone '{}'", timezone)))
+}
+
+/// Renders the timestamp columns of a query's result in `timefusion.timezone` with `to_char`, as
+/// `to_char(ts AT TIME ZONE '<zone>', ...)` would. Only the output is converted: filters and grouping still see the
+/// stored UTC values, and writes are left alone.
+#[derive(Debug, Default)]
+pub struct DisplayTimezone;
+
+impl AnalyzerRule for DisplayTimezone {
+    fn analyze(&self, plan: LogicalPlan, config: &ConfigOptions) -> DFResult<LogicalPlan> {
+        let timezone = config.extensions.get::<TimeFusionOptions>().map_or("", |options| options.timezone.as_str());
+        let has_timestamps = plan.schema().fields().iter().any(|field| matches!(field.data_type(), DataType::Timestamp(..)));
+        if timezone.is_empty() || !has_timestamps || !is_query(&plan) {
+            return Ok(plan);
+        }
+        validate_timezone(timezone)?;
+
+        let exprs: Vec<Expr> = plan
+            .schema()
+            .iter()
+            .map(|(qualifier, field)| {
+                let column = Expr::Column(Column::new(qualifier.cloned(), field.name()));
+                match field.data_type() {
+                    // Naive timestamps are UTC, so they are marked as such before moving to the display zone
+                    DataType::Timestamp(unit, _) => {
+                        let utc = cast(column, DataType::Timestamp(*unit, Some("UTC".into())));
+                        let local = cast(utc, DataType::Timestamp(*unit, Some(timezone.into())));
+                        to_char(local, lit(DISPLAY_TIMESTAMP_FORMAT)).alias_qualified(qualifier.cloned(), field.name())
+                    }
+                    _ => column,
+                }
+            })
+            .collect();
+        LogicalPlanBuilder::from(plan).project(exprs)?.build()
+    }
+
+    fn name(&self) -> &str {
+        "display_timezone"
+    }
+}
+
+/// Most columns a notice names before summing up the rest
+const NOTICE_COLUMNS: usize = 10;
+
+/// Notice for an INSERT whose column list leaves out nullable columns of its table, which are then NULL. None for any
+/// other statement, an INSERT without a column list or into an unknown table, or when `timefusion.notices` is off.
+pub async fn insert_null_columns_notice(ctx: &SessionContext, sql: &str) -> Option<String> {
+    let is_insert = sql.trim_start().get(..6).is_some_and(|word| word.eq_ignore_ascii_case("insert"));
+    if !is_insert || !ctx.state_ref().read().config_options().extensions.get::<TimeFusionOptions>().is_none_or(|options| options.notices) {
+        return None;
+    }
+    let statements = Parser::parse_sql(&PostgreSqlDialect {}, sql).ok()?;
+    let [ast::Statement::Insert(insert)] = statements.as_slice() else {
+        return None;
+    };
+    let ast::TableObject::TableName(table) = &insert.table else {
+        return None;
+    };
+    if insert.columns.is_empty() {
+        return None;
+    }
+    // Unquoted names are folded to lower case, as DataFusion plans them
+    let listed: HashSet<String> = insert
+        .columns
+        .iter()
+        .map(|ident| if ident.quote_style.is_some() { ident.value.clone() } else { ident.value.to_lowercase() })
+        .collect();
+    let provider = ctx.table_provider(table.to_string()).await.ok()?;
+    let schema = provider.schema();
+    let missing: Vec<&str> = schema
+        .fields()
+        .iter()
+        .filter(|field| field.is_nullable() && !listed.contains(field.name()))
+        .map(|field| field.name().as_str())
+        .collect();
+    if missing.is_empty() {
+        return None;
+    }
+    let mut notice = format!(
+        "columns not in the INSERT column list are NULL: {}",
+        missing[..missing.len().min(NOTICE_COLUMNS)].join(", ")
+    );
+    if missing.len() > NOTICE_COLUMNS {
+        let _ = write!(notice, " and {} more", missing.len() - NOTICE_COLUMNS);
+    }
+    Some(notice)
+}
+
+/// Closest PostgreSQL type of an Arrow type. Nested structs and maps become `JSONB`, unknown types `TEXT`.
+pub fn pg_type_name(data_type: &DataType) -> String {
+    match data_type {
+        DataType::Boolean => "BOOLEAN".to_string(),
+        DataType::Int8 | DataType::Int16 | DataType::UInt8 => "SMALLINT".to_string(),
+        DataType::Int32 | DataType::UInt16 => "INTEGER".to_string(),
+        DataType::Int64 | DataType::UInt32 => "BIGINT".to_string(),
+        DataType::UInt64 => "NUMERIC(20)".to_string(),
+        DataType::Float16 | DataType::Float32 => "REAL".to_string(),
+        DataType::Float64 => "DOUBLE PRECISION".to_string(),
+        DataType::Decimal128(precision, scale) | DataType::Decimal256(precision, scale) => format!("NUMERIC({}, {})", precision, scale),
+        DataType::Date32 | DataType::Date64 => "DATE".to_string(),
+        DataType::Timestamp(_, Some(_)) => "TIMESTAMPTZ".to_string(),
+        DataType::Timestamp(_, None) => "TIMESTAMP".to_string(),
+        DataType::Binary | DataType::LargeBinary | DataType::BinaryView | DataType::FixedSizeBinary(_) => "BYTEA".to_string(),
+        DataType::List(field) | DataType::LargeList(field) | DataType::FixedSizeList(field, _) => format!("{}[]", pg_type_name(field.data_type())),
+        DataType::Struct(_) | DataType::Map(..) => "JSONB".to_string(),
+        _ => "TEXT".to_string(),
+    }
+}
+
+/// `CREATE TABLE` statement for `schema` with PostgreSQL types, preceded by a comment naming the partition columns
+pub fn create_table_ddl(table_name: &str, schema: &Schema, partitions: &[String]) -> String {
+    let mut ddl = String::new();
+    if !partitions.is_empty() {
+        let _ = writeln!(ddl, "-- Partitioned by: {}", partitions.join(", "));
+    }
+    let _ = writeln!(ddl, "CREATE TABLE {} (", quote_identifier(table_name));
+    let _ = writeln!(ddl, "{}", column_definitions(schema));
+    ddl.push_str(");\n");
+    ddl
+}
+
+/// `CREATE FOREIGN TABLE` statement attaching `table_name` through the postgres_fdw server `server`, for use
+/// instead of `IMPORT FOREIGN SCHEMA`, which needs the PostgreSQL catalog
+pub fn create_foreign_table_ddl(table_name: &str, schema: &Schema, server: &str) -> String {
+    format!(
+        "CREATE FOREIGN TABLE {} (\n{}\n) SERVER {} OPTIONS (schema_name 'public', table_name '{}');\n",
+        quote_identifier(table_name),
+        column_definitions(schema),
+        quote_identifier(server),
+        table_name.replace('\'', "''")
+    )
+}
+
+fn column_definitions(schema: &Schema) -> String {
+    let columns: Vec<String> = schema
+        .fields()
+        .iter()
+        .map(|field| {
+            let not_null = if field.is_nullable() { "" } else { " NOT NULL" };
+            format!("  {} {}{}", quote_identifier(field.name()), pg_type_name(field.data_type()), not_null)
+        })
+        .collect();
+    columns.join(",\n")
+}
+
+fn quote_identifier(name: &str) -> String {
+    let plain =
+        name.starts_with(|c: char| c.is_ascii_lowercase() || c == '_') && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if plain { name.to_string() } else { format!("\"{}\"", name.replace('"', "\"\"")) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use datafusion::{
+        arrow::util::pretty::pretty_format_batches,
+        execution::{SessionStateBuilder, context::SessionContext},
+    };
+
+    use super::*;
+
+    fn pg_context() -> SessionContext {
+        let state = SessionStateBuilder::new().with_default_features().with_type_planner(Arc::new(PgTypePlanner)).build();
+        SessionContext::new_with_state(state)
+    }
+
+    #[tokio::test]
+    async fn test_pg_casts() -> DFResult<()> {
+        let ctx = pg_context();
+        for (sql, expected) in [
+            ("SELECT 42::text AS v", "42"),
+            ("SELECT '{\"a\": 1}'::jsonb AS v", "{\"a\": 1}"),
+            ("SELECT 'otel_logs_and_spans'::regclass AS v", "otel_logs_and_spans"),
+            ("SELECT '7'::int4 + 1::int8 AS v", "8"),
+            ("SELECT '2024-03-02 10:00:00+00'::timestamptz AS v", "2024-03-02T10:00:00"),
+            ("SELECT 'pg_catalog'::name AS v", "pg_catalog"),
+        ] {
+            let batches = ctx.sql(sql).await?.collect().await?;
+            let text = pretty_format_batches(&batches)?.to_string();
+            assert!(text.contains(&format!("| {}", expected)), "{}: {}", sql, text);
+        }
+
+        // now() is native, and comparable with the casted timestamps
+        let batches = ctx.sql("SELECT now() > '2024-03-02 10:00:00+00'::timestamptz AS v").await?.collect().await?;
+        assert!(pretty_format_batches(&batches)?.to_string().contains("| true"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_types_are_reported_as_such() {
+        let err = pg_context().sql("SELECT 'a'::tsvector").await.unwrap_err();
+        assert_eq!(crate::pg_errors::sqlstate_for(&err), crate::pg_errors::FEATURE_NOT_SUPPORTED, "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_default_select_limit() -> DFResult<()> {
+        use datafusion::arrow::{array::Int32Array, datatypes::Field, record_batch::RecordBatch};
+
+        let ctx = pg_context();
+        let schema = Arc::new(Schema::new(vec![Field::new("v", DataType::Int32, false)]));
+        let batch = RecordBatch::try_new(schema, vec![Arc::new(Int32Array::from((0..10).collect::<Vec<_>>()))])?;
+        ctx.register_batch("t", batch)?;
+        register_session_options(&ctx);
+        ctx.sql("SET timefusion.default_select_limit = 3").await?.collect().await?;
+
+        let rows = |sql: &'static str| {
+            let ctx = ctx.clone();
+            async move { DFResult::Ok(ctx.sql(sql).await?.collect().await?.iter().map(|b| b.num_rows()).sum::<usize>()) }
+        };
+        assert_eq!(rows("SELECT * FROM t").await?, 3);
+        assert_eq!(rows("SELECT * FROM t WHERE v IN (SELECT v FROM t)").await?, 3);
+        assert_eq!(rows("SELECT * FROM t LIMIT 5").await?, 5);
+        assert_eq!(rows("SELECT count(*) FROM t").await?, 1);
+        let batches = ctx.sql("SELECT count(*) AS n FROM (SELECT * FROM t)").await?.collect().await?;
+        assert!(pretty_format_batches(&batches)?.to_string().contains("| 10"));
+
+        // Each PGWire connection has its own copy of the setting, so lifting the cap on one keeps it on the others
+        let lifted = crate::database::Database::connection_context(&ctx);
+        let other = crate::database::Database::connection_context(&ctx);
+        lifted.sql("SET timefusion.default_select_limit = 0").await?.collect().await?;
+        let count =
+            |ctx: SessionContext| async move { DFResult::Ok(ctx.sql("SELECT * FROM t").await?.collect().await?.iter().map(|b| b.num_rows()).sum::<usize>()) };
+        assert_eq!(count(lifted).await?, 10);
+        assert_eq!(count(other).await?, 3);
+        assert_eq!(rows("SELECT * FROM t").await?, 3);
+
+        ctx.sql("SET timefusion.default_select_limit = 0").await?.collect().await?;
+        assert_eq!(rows("SELECT * FROM t").await?, 10);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_display_timezone() -> DFResult<()> {
+        use datafusion::arrow::{array::TimestampMicrosecondArray, datatypes::Field, record_batch::RecordBatch};
+
+        let ctx = pg_context();
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            "timestamp",
+            DataType::Timestamp(TimeUnit::Microsecond, None),
+            false,
+        )]));
+        // 2024-03-02 10:00:00 UTC
+        let batch = RecordBatch::try_new(schema, vec![Arc::new(TimestampMicrosecondArray::from(vec![1_709_373_600_000_000]))])?;
+        ctx.register_batch("t", batch)?;
+        register_session_options(&ctx);
+
+        let render = |sql: &'static str| {
+            let ctx = ctx.clone();
+            async move { DFResult::Ok(pretty_format_batches(&ctx.sql(sql).await?.collect().await?)?.to_string()) }
+        };
+        assert!(render("SELECT timestamp FROM t").await?.contains("| 2024-03-02T10:00:00 "));
+
+        ctx.sql("SET timefusion.timezone = 'America/New_York'").await?.collect().await?;
+        assert!(render("SELECT timestamp FROM t").await?.contains("| 2024-03-02 05:00:00-05:00 "));
+        // Filters still compare against the stored UTC value
+        assert!(
+            render("SELECT timestamp FROM t WHERE timestamp = '2024-03-02T10:00:00'")
+                .await?
+                .contains("| 2024-03-02 05:00:00-05:00 ")
+        );
+
+        ctx.sql("SET timefusion.timezone = 'Asia/Tokyo'").await?.collect().await?;
+        assert!(render("SELECT timestamp FROM t").await?.contains("| 2024-03-02 19:00:00+09:00 "));
+
+        ctx.sql("SET timefusion.timezone = 'Mars/Olympus_Mons'").await?.collect().await?;
+        assert!(render("SELECT timestamp FROM t").await.unwrap_err().to_string().contains("Unknown time zone"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_insert_null_columns_notice() -> DFResult<()> {
+        use datafusion::arrow::{
+            array::{Int32Array, StringArray},
+            datatypes::Field,
+            record_batch::RecordBatch,
+        };
+
+        let ctx = pg_context();
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int32, false),
+            Field::new("name", DataType::Utf8, true),
+            Field::new("level", DataType::Utf8, true),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int32Array::from(vec![1])),
+                Arc::new(StringArray::from(vec![Some("a")])),
+                Arc::new(StringArray::from(vec![None::<&str>])),
+            ],
+        )?;
+        ctx.register_batch("t", batch)?;
+        register_session_options(&ctx);
+
+        assert_eq!(
+            insert_null_columns_notice(&ctx, "INSERT INTO t (id, NAME) VALUES (2, 'b')").await.as_deref(),
+            Some("columns not in the INSERT column list are NULL: level")
+        );
+        assert_eq!(
+            insert_null_columns_notice(&ctx, "INSERT INTO t (id, name, level) VALUES (2, 'b', 'info')").await,
+            None
+        );
+        assert_eq!(insert_null_columns_notice(&ctx, "INSERT INTO t VALUES (2, 'b', 'info')").await, None);
+        assert_eq!(insert_null_columns_notice(&ctx, "SELECT * FROM t").await, None);
+        assert_eq!(insert_null_columns_notice(&ctx, "INSERT INTO missing (id) VALUES (2)").await, None);
+
+        ctx.sql("SET timefusion.notices = false").await?.collect().await?;
+        assert_eq!(insert_null_columns_notice(&ctx, "INSERT INTO t (id) VALUES (2)").await, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_table_ddl() {
+        use datafusion::sql::sqlparser::{ast::Statement, dialect::PostgreSqlDialect, parser::Parser};
+
+        use crate::persistent_queue::OtelLogsAndSpans;
+
+        let schema = OtelLogsAndSpans::schema_ref();
+        let ddl = create_table_ddl(&OtelLogsAndSpans::table_name(), &schema, &OtelLogsAndSpans::partitions());
+        assert!(
+            ddl.starts_with("-- Partitioned by: project_id, date\nCREATE TABLE otel_logs_and_spans ("),
+            "{}",
+            ddl
+        );
+        assert!(ddl.contains("  timestamp TIMESTAMP NOT NULL,\n"), "{}", ddl);
+
+        let statements = Parser::parse_sql(&PostgreSqlDialect {}, &ddl).expect("DDL should parse");
+        let Statement::CreateTable(create) = &statements[0] else {
+            panic!("Expected a CREATE TABLE statement, got {:?}", statements);
+        };
+        let columns: Vec<String> = create.columns.iter().map(|c| c.name.value.clone()).collect();
+        let expected: Vec<String> = schema.fields().iter().map(|f| f.name().clone()).collect();
+        assert_eq!(columns, expected);
+
+        let foreign = create_foreign_table_ddl(&OtelLogsAndSpans::table_name(), &schema, "timefusion");
+        assert!(foreign.starts_with("CREATE FOREIGN TABLE otel_logs_and_spans (\n"), "{}", foreign);
+        assert!(
+            foreign.ends_with("\n) SERVER timefusion OPTIONS (schema_name 'public', table_name 'otel_logs_and_spans');\n"),
+            "{}",
+            foreign
+        );
+        assert!(foreign.contains("  timestamp TIMESTAMP NOT NULL,\n"), "{}", foreign);
+
+        assert_eq!(quote_identifier("Mixed Case"), "\"Mixed Case\"");
+        assert_eq!(
+            pg_type_name(&DataType::List(std::sync::Arc::new(datafusion::arrow::datatypes::Field::new(
+                "item",
+                DataType::Utf8,
+                true
+            )))),
+            "TEXT[]"
+        );
+    }
+}
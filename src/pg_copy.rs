@@ -0,0 +1,569 @@
+// pg_copy.rs - COPY otel_logs_and_spans FROM STDIN and COPY ... TO STDOUT over the PGWire simple query protocol
+use std::{
+    env,
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use datafusion::{
+    arrow::{
+        array::{Array, ArrayRef, AsArray, Date32Array, ListArray, ListBuilder, StringArray, StringBuilder, new_empty_array, new_null_array},
+        buffer::OffsetBuffer,
+        compute::{CastOptions, cast_with_options},
+        csv::{ReaderBuilder, WriterBuilder, reader::Decoder},
+        datatypes::{DataType, Field, FieldRef, Schema, SchemaRef},
+        record_batch::RecordBatch,
+    },
+    error::{DataFusionError, Result as DFResult},
+    execution::{SendableRecordBatchStream, context::SessionContext},
+    sql::sqlparser::{
+        ast::{CopyLegacyCsvOption, CopyLegacyOption, CopyOption, CopySource, CopyTarget, Statement},
+        dialect::PostgreSqlDialect,
+        parser::Parser,
+    },
+};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use pgwire::{
+    api::{
+        ClientInfo,
+        copy::CopyHandler,
+        query::SimpleQueryHandler,
+        results::{CopyResponse, Response, Tag},
+    },
+    error::{ErrorInfo, PgWireError, PgWireResult},
+    messages::{
+        PgWireBackendMessage,
+        copy::{CopyData, CopyDone, CopyFail},
+    },
+};
+
+use crate::{
+    database::{Database, ProjectAccess},
+    persistent_queue::OtelLogsAndSpans,
+    pg_errors::QUERY_CANCELED,
+};
+
+/// Rows a COPY FROM writes per commit (COPY_BATCH_ROWS, default 100000). A load of any size is written in batches of
+/// this many rows instead of being buffered whole.
+pub fn copy_batch_rows() -> usize {
+    env::var("COPY_BATCH_ROWS").ok().and_then(|v| v.parse().ok()).filter(|rows| *rows > 0).unwrap_or(100_000)
+}
+
+/// A COPY statement TimeFusion can run
+#[derive(Debug, Clone, PartialEq)]
+pub enum CopyStatement {
+    /// `COPY otel_logs_and_spans [(columns)] FROM STDIN`, loading the listed columns, or all in table order
+    From { columns: Vec<String>, format: CopyFormat },
+    /// `COPY table [(columns)] TO STDOUT` or `COPY (query) TO STDOUT`, as the query whose rows are sent
+    To { query: String, format: CopyFormat },
+}
+
+/// CSV options of a COPY. Only `FORMAT csv` is supported, the text and binary formats are not.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CopyFormat {
+    pub delimiter: u8,
+    pub header: bool,
+}
+
+impl CopyFormat {
+    fn from_options(options: &[CopyOption], legacy_options: &[CopyLegacyOption]) -> DFResult<Self> {
+        let unsupported = |option: String| DataFusionError::NotImplemented(format!("COPY option {}", option));
+        let (mut csv, mut delimiter, mut header) = (false, ',', false);
+        for option in options {
+            match option {
+                CopyOption::Format(name) if name.value.eq_ignore_ascii_case("csv") => csv = true,
+                CopyOption::Delimiter(c) => delimiter = *c,
+                CopyOption::Header(h) => header = *h,
+                other => return Err(unsupported(other.to_string())),
+            }
+        }
+        for option in legacy_options {
+            match option {
+                CopyLegacyOption::Csv(csv_options) => {
+                    csv = true;
+                    for csv_option in csv_options {
+                        match csv_option {
+                            CopyLegacyCsvOption::Header => header = true,
+                            other => return Err(unsupported(other.to_string())),
+                        }
+                    }
+                }
+                CopyLegacyOption::Delimiter(c) => delimiter = *c,
+                other => return Err(unsupported(other.to_string())),
+            }
+        }
+        if !csv {
+            return Err(DataFusionError::NotImplemented(
+                "COPY needs FORMAT csv, the text and binary formats are not supported".to_string(),
+            ));
+        }
+        let delimiter = u8::try_from(delimiter)
+            .ok()
+            .filter(u8::is_ascii)
+            .ok_or_else(|| DataFusionError::Plan(format!("COPY delimiter must be a single one-byte character, got '{}'", delimiter)))?;
+        Ok(Self { delimiter, header })
+    }
+
+    fn decoder(&self, schema: SchemaRef) -> Decoder {
+        ReaderBuilder::new(schema)
+            .with_delimiter(self.delimiter)
+            .with_header(self.header)
+            .with_batch_size(copy_batch_rows())
+            .build_decoder()
+    }
+
+    fn encode(&self, batch: &RecordBatch, header: bool) -> DFResult<Bytes> {
+        let mut writer = WriterBuilder::new().with_header(header).with_delimiter(self.delimiter).build(Vec::new());
+        writer.write(&to_text_lists(batch)?)?;
+        Ok(Bytes::from(writer.into_inner()))
+    }
+}
+
+/// Parse `sql` when it is a COPY statement, None for any other statement
+pub fn parse_copy(sql: &str) -> Option<DFResult<CopyStatement>> {
+    let first = sql.trim_start().split(|c: char| c.is_whitespace() || c == '(').next()?;
+    first.eq_ignore_ascii_case("copy").then(|| parse_copy_statement(sql))
+}
+
+fn parse_copy_statement(sql: &str) -> DFResult<CopyStatement> {
+    let mut statements = Parser::parse_sql(&PostgreSqlDialect {}, sql).map_err(|e| DataFusionError::SQL(e, None))?;
+    if statements.len() != 1 {
+        return Err(DataFusionError::NotImplemented("COPY must be the only statement of the query".to_string()));
+    }
+    let Statement::Copy {
+        source,
+        to,
+        target,
+        options,
+        legacy_options,
+        ..
+    } = statements.remove(0)
+    else {
+        return Err(DataFusionError::Plan("expected a COPY statement".to_string()));
+    };
+    let format = CopyFormat::from_options(&options, &legacy_options)?;
+    match (source, to, target) {
+        (CopySource::Table { table_name, columns }, false, CopyTarget::Stdin) => {
+            let table = table_name.to_string();
+            if table.rsplit('.').next() != Some(OtelLogsAndSpans::table_name().as_str()) {
+                return Err(DataFusionError::NotImplemented(format!(
+                    "COPY FROM only loads into otel_logs_and_spans, not {}",
+                    table
+                )));
+            }
+            Ok(CopyStatement::From {
+                columns: columns.into_iter().map(|c| c.value).collect(),
+                format,
+            })
+        }
+        (CopySource::Table { table_name, columns }, true, CopyTarget::Stdout) => {
+            let projection = if columns.is_empty() {
+                "*".to_string()
+            } else {
+                columns.iter().map(|c| c.to_string()).collect::<Vec<_>>().join(", ")
+            };
+            Ok(CopyStatement::To {
+                query: format!("SELECT {} FROM {}", projection, table_name),
+                format,
+            })
+        }
+        (CopySource::Query(query), true, CopyTarget::Stdout) => Ok(CopyStatement::To {
+            query: query.to_string(),
+            format,
+        }),
+        _ => Err(DataFusionError::NotImplemented("COPY only reads FROM STDIN and writes TO STDOUT".to_string())),
+    }
+}
+
+/// A COPY FROM between the CopyInResponse and the client's CopyDone
+struct CopyIn {
+    decoder: Decoder,
+    columns: usize,
+    /// Full batches decoded but not written yet
+    batches: Vec<RecordBatch>,
+    /// Rows written so far
+    loaded: usize,
+}
+
+impl CopyIn {
+    /// Get ready to receive CSV rows of `columns`, or of all the table's columns in order when none are listed
+    fn new(columns: Vec<String>, format: CopyFormat) -> DFResult<Self> {
+        let table = OtelLogsAndSpans::schema_ref();
+        let columns = if columns.is_empty() { table.fields().iter().map(|f| f.name().clone()).collect() } else { columns };
+        let fields = columns
+            .iter()
+            .map(|column| match table.field_with_name(column) {
+                Ok(_) => Ok(Field::new(column, DataType::Utf8, true)),
+                Err(_) => Err(DataFusionError::Plan(format!("column \"{}\" not found in otel_logs_and_spans", column))),
+            })
+            .collect::<DFResult<Vec<_>>>()?;
+        Ok(Self {
+            columns: fields.len(),
+            decoder: format.decoder(Arc::new(Schema::new(fields))),
+            batches: Vec::new(),
+            loaded: 0,
+        })
+    }
+
+    fn decode(&mut self, mut data: &[u8]) -> DFResult<()> {
+        while !data.is_empty() {
+            let read = self.decoder.decode(data)?;
+            data = &data[read..];
+            if self.decoder.capacity() == 0 {
+                self.batches.extend(self.decoder.flush()?);
+            } else if read == 0 {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> DFResult<Vec<RecordBatch>> {
+        self.batches.extend(self.decoder.flush()?);
+        Ok(self.batches)
+    }
+}
+
+/// Loads COPY FROM STDIN data into otel_logs_and_spans through `Database::insert_records_batch`, like an INSERT with
+/// the same column list. `CopyQueryHandler` starts the COPY, this handler receives the client's data.
+pub struct TimeFusionCopyHandler {
+    database: Database,
+    session: SessionContext,
+    running: Mutex<Option<CopyIn>>,
+}
+
+impl TimeFusionCopyHandler {
+    pub fn new(database: Database, session: SessionContext) -> Self {
+        Self {
+            database,
+            session,
+            running: Mutex::new(None),
+        }
+    }
+
+    /// Get ready to receive rows of `columns`, returning how many columns each row has
+    pub(crate) fn start(&self, columns: Vec<String>, format: CopyFormat) -> DFResult<usize> {
+        let copy = CopyIn::new(columns, format)?;
+        let count = copy.columns;
+        *self.running.lock().unwrap() = Some(copy);
+        Ok(count)
+    }
+
+    /// Decode a CopyData message, writing every COPY_BATCH_ROWS rows as soon as they are complete. Batches written
+    /// before a failure stay committed.
+    pub(crate) async fn receive(&self, data: &[u8]) -> PgWireResult<()> {
+        let full = {
+            let mut running = self.running.lock().unwrap();
+            let copy = running.as_mut().ok_or_else(no_copy_running)?;
+            copy.decode(data).map_err(api_error)?;
+            std::mem::take(&mut copy.batches)
+        };
+        match self.load(full).await {
+            Ok(rows) => {
+                if let Some(copy) = self.running.lock().unwrap().as_mut() {
+                    copy.loaded += rows;
+                }
+                Ok(())
+            }
+            Err(e) => {
+                self.running.lock().unwrap().take();
+                Err(PgWireError::ApiError(e.into()))
+            }
+        }
+    }
+
+    /// Write the rows left when the client sends CopyDone, returning how many rows the whole COPY loaded
+    pub(crate) async fn complete(&self) -> PgWireResult<usize> {
+        let copy = self.running.lock().unwrap().take().ok_or_else(no_copy_running)?;
+        let loaded = copy.loaded;
+        let rest = copy.finish().map_err(api_error)?;
+        let rows = self.load(rest).await.map_err(|e| PgWireError::ApiError(e.into()))?;
+        Ok(loaded + rows)
+    }
+
+    /// Write the decoded rows, returning how many were loaded
+    async fn load(&self, decoded: Vec<RecordBatch>) -> anyhow::Result<usize> {
+        let table = OtelLogsAndSpans::schema_ref();
+        let batches = decoded.iter().map(|batch| to_table_batch(batch, &table)).collect::<DFResult<Vec<_>>>()?;
+        let rows = batches.iter().map(|b| b.num_rows()).sum();
+        if rows == 0 {
+            return Ok(0);
+        }
+        if let Some(access) = self.session.state().config().get_extension::<ProjectAccess>() {
+            for project_id in Database::group_by_project(batches.clone())?.keys() {
+                access.check(project_id)?;
+            }
+        }
+        self.database.insert_records_batch("", batches, false).await?;
+        Ok(rows)
+    }
+}
+
+#[async_trait]
+impl CopyHandler for TimeFusionCopyHandler {
+    async fn on_copy_data<C>(&self, _client: &mut C, copy_data: CopyData) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.receive(&copy_data.data).await
+    }
+
+    async fn on_copy_done<C>(&self, client: &mut C, _done: CopyDone) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let rows = self.complete().await?;
+        client.send(PgWireBackendMessage::CommandComplete(Tag::new("COPY").with_rows(rows).into())).await?;
+        Ok(())
+    }
+
+    async fn on_copy_fail<C>(&self, _client: &mut C, fail: CopyFail) -> PgWireError
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.running.lock().unwrap().take();
+        PgWireError::UserError(Box::new(ErrorInfo::new(
+            "ERROR".to_string(),
+            QUERY_CANCELED.to_string(),
+            format!("COPY from stdin failed: {}", fail.message),
+        )))
+    }
+}
+
+/// PGWire simple query handler that runs COPY statements and passes every other query on. COPY isn't available over
+/// the extended protocol.
+pub struct CopyQueryHandler<H> {
+    inner: Arc<H>,
+    session: SessionContext,
+    copy: Arc<TimeFusionCopyHandler>,
+}
+
+impl<H> CopyQueryHandler<H> {
+    pub fn new(inner: Arc<H>, session: SessionContext, copy: Arc<TimeFusionCopyHandler>) -> Self {
+        Self { inner, session, copy }
+    }
+}
+
+#[async_trait]
+impl<H: SimpleQueryHandler> SimpleQueryHandler for CopyQueryHandler<H> {
+    async fn do_query<'a, C>(&self, client: &mut C, query: &'a str) -> PgWireResult<Vec<Response<'a>>>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let Some(statement) = parse_copy(query) else {
+            return self.inner.do_query(client, query).await;
+        };
+        match statement.map_err(api_error)? {
+            CopyStatement::From { columns, format } => {
+                let columns = self.copy.start(columns, format).map_err(api_error)?;
+                Ok(vec![Response::CopyIn(CopyResponse::new(
+                    0,
+                    columns,
+                    futures::stream::empty::<PgWireResult<CopyData>>(),
+                ))])
+            }
+            CopyStatement::To { query, format } => {
+                let df = self.session.sql(&query).await.map_err(api_error)?;
+                let columns = df.schema().fields().len();
+                let stream = df.execute_stream().await.map_err(api_error)?;
+                Ok(vec![Response::CopyOut(CopyResponse::new(0, columns, copy_out(stream, format)))])
+            }
+        }
+    }
+}
+
+/// The query's rows as CSV, one CopyData message per batch
+fn copy_out(stream: SendableRecordBatchStream, format: CopyFormat) -> impl Stream<Item = PgWireResult<CopyData>> + Send + 'static {
+    let mut header = format.header;
+    stream.map(move |batch| {
+        let data = batch.and_then(|batch| format.encode(&batch, std::mem::take(&mut header)));
+        data.map(CopyData::new).map_err(api_error)
+    })
+}
+
+/// `decoded` with the table's columns in schema order. Columns left out of the COPY column list are NULL, like in an
+/// INSERT, except `hashes`, which is empty, and `date`, which is taken from the row's timestamp when it is written.
+fn to_table_batch(decoded: &RecordBatch, table: &SchemaRef) -> DFResult<RecordBatch> {
+    let rows = decoded.num_rows();
+    let columns = table
+        .fields()
+        .iter()
+        .map(|field| match decoded.column_by_name(field.name()) {
+            Some(text) => from_text(text.as_string::<i32>(), field),
+            None => missing_column(field, rows),
+        })
+        .collect::<DFResult<Vec<_>>>()?;
+    Ok(RecordBatch::try_new(Arc::clone(table), columns)?)
+}
+
+fn missing_column(field: &FieldRef, rows: usize) -> DFResult<ArrayRef> {
+    match field.data_type() {
+        data_type if field.is_nullable() => Ok(new_null_array(data_type, rows)),
+        // A zero date is resolved from the row's timestamp by insert_records_batch
+        DataType::Date32 => Ok(Arc::new(Date32Array::from(vec![0; rows]))),
+        DataType::List(item) => Ok(Arc::new(ListArray::try_new(
+            Arc::clone(item),
+            OffsetBuffer::new_zeroed(rows),
+            new_empty_array(item.data_type()),
+            None,
+        )?)),
+        _ => Err(DataFusionError::Plan(format!("COPY needs a value for column \"{}\"", field.name()))),
+    }
+}
+
+/// Cast a CSV column to the table's type. Lists of strings are read from PostgreSQL array literals such as `{a,b}`.
+fn from_text(text: &StringArray, field: &FieldRef) -> DFResult<ArrayRef> {
+    let item = match field.data_type() {
+        DataType::List(item) if item.data_type() == &DataType::Utf8 => item,
+        data_type => {
+            let options = CastOptions {
+                safe: false,
+                ..Default::default()
+            };
+            return Ok(cast_with_options(text, data_type, &options)?);
+        }
+    };
+    let mut builder = ListBuilder::new(StringBuilder::new()).with_field(Arc::clone(item));
+    for value in text.iter() {
+        let Some(value) = value else {
+            builder.append_null();
+            continue;
+        };
+        let items = value
+            .trim()
+            .strip_prefix('{')
+            .and_then(|v| v.strip_suffix('}'))
+            .ok_or_else(|| DataFusionError::Plan(format!("malformed array literal for column \"{}\": \"{}\"", field.name(), value)))?;
+        for item in items.split(',').filter(|item| !item.is_empty()) {
+            builder.values().append_value(item.trim_matches('"'));
+        }
+        builder.append(true);
+    }
+    Ok(Arc::new(builder.finish()))
+}
+
+/// `batch` with its lists of strings written as PostgreSQL array literals, which the CSV writer can't encode
+fn to_text_lists(batch: &RecordBatch) -> DFResult<RecordBatch> {
+    if !batch.schema().fields().iter().any(|f| matches!(f.data_type(), DataType::List(_))) {
+        return Ok(batch.clone());
+    }
+    let mut fields = Vec::with_capacity(batch.num_columns());
+    let mut columns = Vec::with_capacity(batch.num_columns());
+    for (field, column) in batch.schema().fields().iter().zip(batch.columns()) {
+        let Some(list) = column.as_list_opt::<i32>() else {
+            fields.push(Arc::clone(field));
+            columns.push(Arc::clone(column));
+            continue;
+        };
+        let text: StringArray = (0..list.len())
+            .map(|i| {
+                list.is_valid(i).then(|| {
+                    let values = list.value(i);
+                    let items: Vec<&str> = values.as_string_opt::<i32>().map(|s| s.iter().flatten().collect()).unwrap_or_default();
+                    format!("{{{}}}", items.join(","))
+                })
+            })
+            .collect();
+        fields.push(Arc::new(Field::new(field.name(), DataType::Utf8, field.is_nullable())));
+        columns.push(Arc::new(text) as ArrayRef);
+    }
+    Ok(RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)?)
+}
+
+fn api_error(e: DataFusionError) -> PgWireError {
+    PgWireError::ApiError(Box::new(e))
+}
+
+fn no_copy_running() -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "ERROR".to_string(),
+        "08P01".to_string(),
+        "COPY data received without a COPY in progress".to_string(),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_copy() {
+        let csv = CopyFormat {
+            delimiter: b',',
+            header: false,
+        };
+        assert_eq!(
+            parse_copy("COPY otel_logs_and_spans (project_id, id) FROM STDIN (FORMAT csv)").map(|r| r.unwrap()),
+            Some(CopyStatement::From {
+                columns: vec!["project_id".to_string(), "id".to_string()],
+                format: csv,
+            })
+        );
+        assert_eq!(
+            parse_copy("copy (SELECT id FROM otel_logs_and_spans) to stdout with csv header").map(|r| r.unwrap()),
+            Some(CopyStatement::To {
+                query: "SELECT id FROM otel_logs_and_spans".to_string(),
+                format: CopyFormat { header: true, ..csv },
+            })
+        );
+        assert_eq!(
+            parse_copy("COPY otel_logs_and_spans (id, name) TO STDOUT (FORMAT csv, DELIMITER '|')").map(|r| r.unwrap()),
+            Some(CopyStatement::To {
+                query: "SELECT id, name FROM otel_logs_and_spans".to_string(),
+                format: CopyFormat { delimiter: b'|', ..csv },
+            })
+        );
+        assert!(parse_copy("SELECT 1").is_none());
+
+        for unsupported in [
+            "COPY otel_logs_and_spans FROM STDIN",
+            "COPY otel_logs_and_spans FROM STDIN (FORMAT binary)",
+            "COPY other_table FROM STDIN (FORMAT csv)",
+            "COPY otel_logs_and_spans FROM '/tmp/rows.csv' (FORMAT csv)",
+        ] {
+            assert!(parse_copy(unsupported).unwrap().is_err(), "{}", unsupported);
+        }
+    }
+
+    #[test]
+    fn test_copy_in_fills_table_columns() -> DFResult<()> {
+        let format = CopyFormat { delimiter: b',', header: true };
+        let mut copy = CopyIn::new(vec!["id".into(), "timestamp".into(), "project_id".into(), "hashes".into()], format)?;
+        assert_eq!(copy.columns, 4);
+        assert_eq!(CopyIn::new(vec![], format)?.columns, OtelLogsAndSpans::schema_ref().fields().len());
+        assert!(CopyIn::new(vec!["no_such_column".into()], format).is_err());
+
+        // Rows may be split anywhere across CopyData messages
+        copy.decode(b"id,timestamp,project_id,hashes\nspan_1,2024-01-02T03:04:05Z,p1,\"{h1,h2}\"\nspan_2,2024-01-02T03")?;
+        copy.decode(b":04:06Z,p1,{}\n")?;
+        let decoded = copy.finish()?;
+
+        let table = OtelLogsAndSpans::schema_ref();
+        let batch = to_table_batch(&decoded[0], &table)?;
+        assert_eq!(batch.schema(), table);
+        assert_eq!(batch.num_rows(), 2);
+        let hashes = batch.column_by_name("hashes").unwrap().as_list::<i32>();
+        assert_eq!(hashes.value(0).as_string::<i32>().iter().flatten().collect::<Vec<_>>(), ["h1", "h2"]);
+        assert_eq!(hashes.value(1).len(), 0);
+        assert_eq!(batch.column_by_name("name").unwrap().null_count(), 2);
+
+        // The list column is written back as an array literal
+        let csv = format.encode(&batch.project(&[table.index_of("id")?, table.index_of("hashes")?])?, true)?;
+        assert_eq!(String::from_utf8(csv.to_vec()).unwrap(), "id,hashes\nspan_1,\"{h1,h2}\"\nspan_2,{}\n");
+
+        // Values that don't fit the column's type are refused rather than loaded as NULL
+        let timestamp = Arc::new(table.field_with_name("timestamp")?.clone());
+        assert!(from_text(&StringArray::from(vec!["not a timestamp"]), &timestamp).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+// pg_errors.rs - PGWire handlers that report query failures with Postgres SQLSTATE codes
+use std::sync::Arc;
+
+use datafusion::{error::DataFusionError, execution::context::SessionContext};
+use datafusion_postgres::HandlerFactory;
+use pgwire::{
+    api::{ClientInfo, ErrorHandler, PgWireServerHandlers},
+    error::{ErrorInfo, PgWireError},
+};
+
+use crate::{
+    database::Database,
+    pg_auth::TimeFusionStartupHandler,
+    pg_copy::{CopyQueryHandler, TimeFusionCopyHandler},
+    query_timeout::StatementTimeoutHandler,
+};
+
+type DefaultSimpleQueryHandler = <HandlerFactory as PgWireServerHandlers>::SimpleQueryHandler;
+
+pub const UNDEFINED_TABLE: &str = "42P01";
+pub const UNDEFINED_COLUMN: &str = "42703";
+pub const SYNTAX_ERROR: &str = "42601";
+pub const INSUFFICIENT_PRIVILEGE: &str = "42501";
+pub const FEATURE_NOT_SUPPORTED: &str = "0A000";
+pub const DATA_EXCEPTION: &str = "22000";
+pub const CONFIGURATION_LIMIT_EXCEEDED: &str = "53400";
+pub const QUERY_CANCELED: &str = "57014";
+pub const INTERNAL_ERROR: &str = "XX000";
+
+/// SQLSTATE code for a DataFusion error, looking through context and shared wrappers
+pub fn sqlstate_for(err: &DataFusionError) -> &'static str {
+    match err.find_root() {
+        DataFusionError::SQL(..) => SYNTAX_ERROR,
+        DataFusionError::SchemaError(..) => UNDEFINED_COLUMN,
+        DataFusionError::NotImplemented(_) => FEATURE_NOT_SUPPORTED,
+        DataFusionError::ResourcesExhausted(_) => CONFIGURATION_LIMIT_EXCEEDED,
+        DataFusionError::ArrowError(..) => DATA_EXCEPTION,
+        root => sqlstate_for_message(&root.to_string()),
+    }
+}
+
+/// Fallback for errors that only carry a message, e.g. plan errors or errors raised by table providers
+pub fn sqlstate_for_message(message: &str) -> &'static str {
+    let message = message.to_lowercase();
+    if message.contains("table") && (message.contains("not found") || message.contains("does not exist")) {
+        UNDEFINED_TABLE
+    } else if message.contains("no field named") || (message.contains("column") && message.contains("not found")) {
+        UNDEFINED_COLUMN
+    } else if message.contains("access denied") {
+        INSUFFICIENT_PRIVILEGE
+    } else if message.contains("timed out") || message.contains("cancel") {
+        QUERY_CANCELED
+    } else if message.contains("sql error") || message.contains("parsererror") {
+        SYNTAX_ERROR
+    } else {
+        INTERNAL_ERROR
+    }
+}
+
+/// Rewrites `PgWireError::ApiError` into a user error carrying a SQLSTATE so drivers can branch on the code
+#[derive(Debug, Default)]
+pub struct SqlStateErrorHandler;
+
+impl ErrorHandler for SqlStateErrorHandler {
+    fn on_error<C>(&self, _client: &C, error: &mut PgWireError)
+    where
+        C: ClientInfo,
+    {
+        if let PgWireError::ApiError(inner) = error {
+            let code = match inner.downcast_ref::<DataFusionError>() {
+                Some(df_err) => sqlstate_for(df_err),
+                None => sqlstate_for_message(&inner.to_string()),
+            };
+            let message = if code == FEATURE_NOT_SUPPORTED {
+                format!("Not supported by TimeFusion: {}", inner)
+            } else {
+                inner.to_string()
+            };
+            let info = ErrorInfo::new("ERROR".to_string(), code.to_string(), message);
+            *error = PgWireError::UserError(Box::new(info));
+        }
+    }
+}
+
+/// The datafusion-postgres handlers with SQLSTATE-aware error reporting, optional password authentication,
+/// `SET statement_timeout` and COPY support, for the connection whose queries run on `session` and that `cancel_key`
+/// cancels
+pub struct TimeFusionHandlers {
+    inner: HandlerFactory,
+    startup: Arc<TimeFusionStartupHandler>,
+    simple_query: Arc<StatementTimeoutHandler<CopyQueryHandler<DefaultSimpleQueryHandler>>>,
+    copy: Arc<TimeFusionCopyHandler>,
+    errors: Arc<SqlStateErrorHandler>,
+}
+
+impl TimeFusionHandlers {
+    pub fn new(inner: HandlerFactory, session: SessionContext, database: Database, cancel_key: (i32, i32)) -> Self {
+        let startup = Arc::new(TimeFusionStartupHandler::new(
+            inner.startup_handler(),
+            session.clone(),
+            database.clone(),
+            cancel_key,
+        ));
+        let copy = Arc::new(TimeFusionCopyHandler::new(database, session.clone()));
+        let copy_query = Arc::new(CopyQueryHandler::new(inner.simple_query_handler(), session.clone(), copy.clone()));
+        let simple_query = Arc::new(StatementTimeoutHandler::new(copy_query, session));
+        Self {
+            inner,
+            startup,
+            simple_query,
+            copy,
+            errors: Arc::new(SqlStateErrorHandler),
+        }
+    }
+
+    /// Notified once the connection has completed its startup and can run queries
+    pub fn ready(&self) -> Arc<tokio::sync::Notify> {
+        self.startup.ready()
+    }
+}
+
+impl PgWireServerHandlers for TimeFusionHandlers {
+    type StartupHandler = TimeFusionStartupHandler;
+    type SimpleQueryHandler = StatementTimeoutHandler<CopyQueryHandler<DefaultSimpleQueryHandler>>;
+    type ExtendedQueryHandler = <HandlerFactory as PgWireServerHandlers>::ExtendedQueryHandler;
+    type CopyHandler = TimeFusionCopyHandler;
+    type ErrorHandler = SqlStateErrorHandler;
+
+    fn simple_query_handler(&self) -> Arc<Self::SimpleQueryHandler> {
+        self.simple_query.clone()
+    }
+
+    fn extended_query_handler(&self) -> Arc<Self::ExtendedQueryHandler> {
+        self.inner.extended_query_handler()
+    }
+
+    fn startup_handler(&self) -> Arc<Self::StartupHandler> {
+        self.startup.clone()
+    }
+
+    fn copy_handler(&self) -> Arc<Self::CopyHandler> {
+        self.copy.clone()
+    }
+
+    fn error_handler(&self) -> Arc<Self::ErrorHandler> {
+        self.errors.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::prelude::SessionContext;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sqlstate_for_datafusion_errors() {
+        let ctx = SessionContext::new();
+
+        let err = ctx.sql("SELECT * FROM no_such_table").await.unwrap_err();
+        assert_eq!(sqlstate_for(&err), UNDEFINED_TABLE);
+
+        let err = ctx.sql("SELEC 1").await.unwrap_err();
+        assert_eq!(sqlstate_for(&err), SYNTAX_ERROR);
+
+        let err = DataFusionError::ResourcesExhausted("memory limit".to_string()).context("running query");
+        assert_eq!(sqlstate_for(&err), CONFIGURATION_LIMIT_EXCEEDED);
+
+        assert_eq!(
+            sqlstate_for_message("Access denied: project 'p1' is not authorized for this session"),
+            INSUFFICIENT_PRIVILEGE
+        );
+        assert_eq!(sqlstate_for_message("something unexpected"), INTERNAL_ERROR);
+    }
+}
@@ -0,0 +1,191 @@
+// project_registry.rs - Registered projects persisted to a local file so they survive restarts
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+
+use crate::encryption::{ColumnCipher, master_key_from_env};
+
+/// What is needed to register a project again on startup, with its settings
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegisteredProject {
+    pub project_id: String,
+    pub conn_str: String,
+    pub access_key: Option<String>,
+    pub secret_key: Option<String>,
+    pub endpoint: Option<String>,
+    #[serde(default)]
+    pub encrypted_columns: Vec<String>,
+    #[serde(default)]
+    pub read_only: bool,
+    /// Compaction interval in seconds, the default schedule when unset
+    #[serde(default)]
+    pub compaction_interval_secs: Option<u64>,
+    /// Time zone the project's PGWire sessions render timestamps in, UTC when unset
+    #[serde(default)]
+    pub display_timezone: Option<String>,
+}
+
+/// JSON file mapping each project id to its encrypted `RegisteredProject`.
+///
+/// Entries are encrypted whole with a key derived from COLUMN_ENCRYPTION_KEY, apart from the column keys, and bound to the
+/// project id, since both the credentials and the connection string's query parameters may hold secrets.
+pub struct ProjectRegistry {
+    path: PathBuf,
+    master_key: Vec<u8>,
+    write_lock: Mutex<()>,
+}
+
+impl std::fmt::Debug for ProjectRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProjectRegistry").field("path", &self.path).finish()
+    }
+}
+
+impl ProjectRegistry {
+    /// Enabled when PROJECT_REGISTRY_PATH is set, which then requires COLUMN_ENCRYPTION_KEY
+    pub fn from_env() -> Result<Option<Self>> {
+        let Some(path) = env::var("PROJECT_REGISTRY_PATH").ok().filter(|p| !p.is_empty()) else {
+            return Ok(None);
+        };
+        let master_key = master_key_from_env()?.ok_or_else(|| anyhow!("PROJECT_REGISTRY_PATH requires COLUMN_ENCRYPTION_KEY to encrypt credentials"))?;
+        log::info!("Persisting registered projects to {}", path);
+        Ok(Some(Self::new(path, master_key)))
+    }
+
+    pub fn new(path: impl Into<PathBuf>, master_key: Vec<u8>) -> Self {
+        Self {
+            path: path.into(),
+            master_key,
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    /// Add or replace a project
+    pub fn save(&self, project: &RegisteredProject) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut entries = self.read_entries()?;
+        let sealed = self.cipher(&project.project_id)?.encrypt(&serde_json::to_string(project)?)?;
+        entries.insert(project.project_id.clone(), sealed);
+        self.write_entries(&entries)
+    }
+
+    /// Change a persisted project's entry, returning false when the project isn't persisted
+    pub fn update(&self, project_id: &str, change: impl FnOnce(&mut RegisteredProject)) -> Result<bool> {
+        let _guard = self.write_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut entries = self.read_entries()?;
+        let Some(sealed) = entries.get(project_id) else {
+            return Ok(false);
+        };
+        let cipher = self.cipher(project_id)?;
+        let mut project: RegisteredProject = serde_json::from_str(&cipher.decrypt(sealed)?)?;
+        change(&mut project);
+        entries.insert(project_id.to_string(), cipher.encrypt(&serde_json::to_string(&project)?)?);
+        self.write_entries(&entries)?;
+        Ok(true)
+    }
+
+    /// All persisted projects. An entry that can't be decrypted is logged and skipped.
+    pub fn load(&self) -> Result<Vec<RegisteredProject>> {
+        Ok(self.load_all()?.into_iter().filter_map(|(_, project)| project.ok()).collect())
+    }
+
+    /// Every persisted entry by project id, with the error for those that can't be decrypted
+    pub fn load_all(&self) -> Result<Vec<(String, Result<RegisteredProject>)>> {
+        let mut projects = Vec::new();
+        for (project_id, sealed) in self.read_entries()? {
+            let decoded = self
+                .cipher(&project_id)
+                .and_then(|cipher| cipher.decrypt(&sealed))
+                .and_then(|json| Ok(serde_json::from_str::<RegisteredProject>(&json)?));
+            if let Err(e) = &decoded {
+                log::error!("Can't read persisted project '{}': {}", project_id, e);
+            }
+            projects.push((project_id, decoded));
+        }
+        Ok(projects)
+    }
+
+    fn read_entries(&self) -> Result<BTreeMap<String, String>> {
+        if !Path::new(&self.path).exists() {
+            return Ok(BTreeMap::new());
+        }
+        let bytes = fs::read(&self.path)?;
+        serde_json::from_slice(&bytes).map_err(|e| anyhow!("Malformed project registry {}: {}", self.path.display(), e))
+    }
+
+    /// Write to a temporary file first so a crash never leaves a truncated registry behind
+    fn write_entries(&self, entries: &BTreeMap<String, String>) -> Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(entries)?)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    fn cipher(&self, project_id: &str) -> Result<ColumnCipher> {
+        ColumnCipher::for_project_registry(&self.master_key, project_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_project_registry_round_trip() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("projects.json");
+        let registry = ProjectRegistry::new(&path, vec![7u8; 32]);
+        assert!(registry.load()?.is_empty());
+
+        let project = RegisteredProject {
+            project_id: "tenant".to_string(),
+            conn_str: "s3://tenant-bucket/timefusion/tenant/".to_string(),
+            access_key: Some("AKIAEXAMPLE".to_string()),
+            secret_key: Some("hunter2".to_string()),
+            endpoint: Some("http://minio:9000".to_string()),
+            encrypted_columns: vec!["attributes___user___email".to_string()],
+            read_only: true,
+            compaction_interval_secs: Some(600),
+            display_timezone: Some("America/New_York".to_string()),
+        };
+        registry.save(&project)?;
+        registry.save(&RegisteredProject {
+            project_id: "other".to_string(),
+            ..project.clone()
+        })?;
+
+        // Nothing is stored in plain text
+        let raw = fs::read_to_string(&path)?;
+        assert!(
+            !raw.contains("hunter2") && !raw.contains("AKIAEXAMPLE") && !raw.contains("tenant-bucket"),
+            "{}",
+            raw
+        );
+
+        let loaded = ProjectRegistry::new(&path, vec![7u8; 32]).load()?;
+        assert_eq!(loaded.len(), 2);
+        assert!(loaded.contains(&project));
+
+        // Settings can be changed in place
+        assert!(registry.update("other", |p| p.encrypted_columns.clear())?);
+        assert!(!registry.update("missing", |p| p.encrypted_columns.clear())?);
+        let loaded = registry.load()?;
+        assert!(loaded.iter().any(|p| p.project_id == "other" && p.encrypted_columns.is_empty()));
+        assert!(loaded.contains(&project));
+
+        // An entry can't be opened with the project's column key, which `decrypt()` holds
+        let entries = registry.read_entries()?;
+        assert!(ColumnCipher::for_project(&[7u8; 32], "tenant")?.decrypt(&entries["tenant"]).is_err());
+
+        // With the wrong key the entries are skipped rather than failing the load
+        assert!(ProjectRegistry::new(&path, vec![8u8; 32]).load()?.is_empty());
+
+        Ok(())
+    }
+}
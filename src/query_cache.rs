@@ -0,0 +1,380 @@
+// query_cache.rs - LRU cache of HTTP query results, invalidated by writes to the projects a query reads
+use std::{
+    collections::HashMap,
+    env,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use datafusion::{
+    arrow::record_batch::RecordBatch,
+    sql::sqlparser::{
+        dialect::PostgreSqlDialect,
+        tokenizer::{Token, Tokenizer},
+    },
+};
+use serde::Serialize;
+
+/// Hit/miss counters as reported by `GET /cache/stats`
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct QueryCacheStats {
+    pub entries: usize,
+    pub max_entries: usize,
+    /// Arrow memory held by the cached results
+    pub bytes: usize,
+    pub max_bytes: usize,
+    pub ttl_secs: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Results not cached because they alone exceed `max_bytes`
+    pub too_large: u64,
+}
+
+/// How a request wants the cache used, taken from its `Cache-Control` header
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CacheMode {
+    #[default]
+    Use,
+    /// `no-cache`: run the query and replace the cached entry with its result
+    Refresh,
+    /// `no-store`: run the query without reading or storing an entry
+    Bypass,
+}
+
+impl CacheMode {
+    pub fn from_cache_control(value: Option<&str>) -> Self {
+        let directives: Vec<String> = value.unwrap_or_default().split(',').map(|d| d.trim().to_ascii_lowercase()).collect();
+        if directives.iter().any(|d| d == "no-store") {
+            CacheMode::Bypass
+        } else if directives.iter().any(|d| d == "no-cache") {
+            CacheMode::Refresh
+        } else {
+            CacheMode::Use
+        }
+    }
+}
+
+/// Where a query result came from, reported in the `X-Cache` response header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheStatus {
+    Hit,
+    /// Run and stored
+    Miss,
+    /// Run without the cache: it is disabled, the query isn't cacheable or the request opted out
+    Bypass,
+}
+
+impl CacheStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "HIT",
+            CacheStatus::Miss => "MISS",
+            CacheStatus::Bypass => "BYPASS",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CachedResult {
+    batches: Vec<RecordBatch>,
+    size: usize,
+    /// Projects the query reads, `None` when they couldn't be determined
+    projects: Option<Vec<String>>,
+    cached_at: Instant,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<String, CachedResult>,
+    bytes: usize,
+    /// Last write per project, and to any project
+    written_at: HashMap<String, Instant>,
+    last_write: Option<Instant>,
+    clock: u64,
+}
+
+/// Results of read-only queries keyed on their normalized SQL. An entry is served until TIMEFUSION_QUERY_CACHE_TTL
+/// passes or one of the projects it reads is written to, so counts are never stale right after ingest. Queries whose
+/// projects are unknown, e.g. without a `project_id` filter, are invalidated by a write to any project.
+#[derive(Debug)]
+pub struct QueryCache {
+    state: Mutex<CacheState>,
+    ttl: Duration,
+    max_entries: usize,
+    max_bytes: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    too_large: AtomicU64,
+}
+
+impl CacheState {
+    fn remove(&mut self, normalized_sql: &str) {
+        if let Some(entry) = self.entries.remove(normalized_sql) {
+            self.bytes -= entry.size;
+        }
+    }
+}
+
+impl QueryCache {
+    /// Enabled with TIMEFUSION_QUERY_CACHE=true; TIMEFUSION_QUERY_CACHE_TTL (seconds, default 30),
+    /// TIMEFUSION_QUERY_CACHE_MAX_ENTRIES (default 256) and TIMEFUSION_QUERY_CACHE_MAX_BYTES (default 64 MiB) size it
+    pub fn from_env() -> Option<Self> {
+        if !env::var("TIMEFUSION_QUERY_CACHE").is_ok_and(|v| v == "true") {
+            return None;
+        }
+        let ttl = env::var("TIMEFUSION_QUERY_CACHE_TTL").ok().and_then(|v| v.parse().ok()).unwrap_or(30);
+        let max_entries = env::var("TIMEFUSION_QUERY_CACHE_MAX_ENTRIES").ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(256);
+        let max_bytes = env::var("TIMEFUSION_QUERY_CACHE_MAX_BYTES").ok().and_then(|v| v.parse().ok()).filter(|n| *n > 0).unwrap_or(64 << 20);
+        log::info!(
+            "Query result cache enabled (ttl {}s, max {} entries, max {} bytes)",
+            ttl,
+            max_entries,
+            max_bytes
+        );
+        Some(Self::new(Duration::from_secs(ttl), max_entries, max_bytes))
+    }
+
+    pub fn new(ttl: Duration, max_entries: usize, max_bytes: usize) -> Self {
+        Self {
+            state: Mutex::new(CacheState::default()),
+            ttl,
+            max_entries,
+            max_bytes,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            too_large: AtomicU64::new(0),
+        }
+    }
+
+    /// Cache key of a query: surrounding whitespace and a trailing `;` removed, whitespace and comments between tokens
+    /// collapsed to one space. Literals and quoted identifiers are kept exactly as written.
+    pub fn normalize(sql: &str) -> String {
+        let Ok(mut tokens) = Tokenizer::new(&PostgreSqlDialect {}, sql).with_unescape(false).tokenize() else {
+            // Such a query fails to plan anyway, the raw text keeps it apart from every other entry
+            return sql.to_string();
+        };
+        while matches!(tokens.last(), Some(Token::Whitespace(_) | Token::SemiColon)) {
+            tokens.pop();
+        }
+        let mut normalized = String::with_capacity(sql.len());
+        let mut space = false;
+        for token in tokens {
+            match token {
+                Token::Whitespace(_) => space = !normalized.is_empty(),
+                token => {
+                    if space {
+                        normalized.push(' ');
+                        space = false;
+                    }
+                    normalized.push_str(&token.to_string());
+                }
+            }
+        }
+        normalized
+    }
+
+    /// Only plain reads are cached, never INSERT/UPDATE/DELETE or DDL
+    pub fn is_cacheable(normalized_sql: &str) -> bool {
+        let first = normalized_sql.split(|c: char| c.is_whitespace() || c == '(').find(|w| !w.is_empty()).unwrap_or_default();
+        first.eq_ignore_ascii_case("select") || first.eq_ignore_ascii_case("with")
+    }
+
+    pub fn get(&self, normalized_sql: &str) -> Option<Vec<RecordBatch>> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.clock += 1;
+        let clock = state.clock;
+        let fresh = state.entries.get(normalized_sql).map(|entry| self.is_fresh(&state, entry));
+        let result = match fresh {
+            Some(true) => state.entries.get_mut(normalized_sql).map(|entry| {
+                entry.last_used = clock;
+                entry.batches.clone()
+            }),
+            Some(false) => {
+                state.remove(normalized_sql);
+                None
+            }
+            None => None,
+        };
+        let counter = if result.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        result
+    }
+
+    /// Store a result whose query started at `started`, evicting the least recently used entries until it fits.
+    /// Writes since `started` already make it stale, and a result larger than `max_bytes` on its own isn't stored.
+    pub fn put(&self, normalized_sql: String, batches: Vec<RecordBatch>, projects: Option<Vec<String>>, started: Instant) {
+        let size: usize = batches.iter().map(|batch| batch.get_array_memory_size()).sum();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.remove(&normalized_sql);
+        if size > self.max_bytes {
+            self.too_large.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        state.clock += 1;
+        let clock = state.clock;
+        while state.entries.len() >= self.max_entries || state.bytes + size > self.max_bytes {
+            let Some(oldest) = state.entries.iter().min_by_key(|(_, entry)| entry.last_used).map(|(key, _)| key.clone()) else {
+                break;
+            };
+            state.remove(&oldest);
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        state.bytes += size;
+        state.entries.insert(
+            normalized_sql,
+            CachedResult {
+                batches,
+                size,
+                projects,
+                cached_at: started,
+                last_used: clock,
+            },
+        );
+    }
+
+    /// Record a write to `project_id`, which makes results read from it stale
+    pub fn note_write(&self, project_id: &str) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        state.written_at.insert(project_id.to_string(), now);
+        state.last_write = Some(now);
+    }
+
+    pub fn stats(&self) -> QueryCacheStats {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        QueryCacheStats {
+            entries: state.entries.len(),
+            max_entries: self.max_entries,
+            bytes: state.bytes,
+            max_bytes: self.max_bytes,
+            ttl_secs: self.ttl.as_secs(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            too_large: self.too_large.load(Ordering::Relaxed),
+        }
+    }
+
+    fn is_fresh(&self, state: &CacheState, entry: &CachedResult) -> bool {
+        let written_since = |at: Option<&Instant>| at.is_some_and(|at| *at >= entry.cached_at);
+        entry.cached_at.elapsed() < self.ttl
+            && match &entry.projects {
+                Some(projects) => !projects.iter().any(|p| written_since(state.written_at.get(p))),
+                None => !written_since(state.last_write.as_ref()),
+            }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use datafusion::arrow::{
+        array::Int32Array,
+        datatypes::{DataType, Field, Schema},
+    };
+
+    use super::*;
+
+    fn batch() -> RecordBatch {
+        let schema = Arc::new(Schema::new(vec![Field::new("v", DataType::Int32, false)]));
+        RecordBatch::try_new(schema, vec![Arc::new(Int32Array::from(vec![1]))]).unwrap()
+    }
+
+    #[test]
+    fn test_query_cache() {
+        let cache = QueryCache::new(Duration::from_secs(60), 2, 1 << 20);
+        let sql = QueryCache::normalize("  SELECT count(*)\n  FROM otel_logs_and_spans WHERE project_id = 'p1' ; ");
+        assert_eq!(sql, "SELECT count(*) FROM otel_logs_and_spans WHERE project_id = 'p1'");
+        assert!(QueryCache::is_cacheable(&sql));
+        assert!(QueryCache::is_cacheable("with t as (select 1) select * from t"));
+        for write in ["INSERT INTO otel_logs_and_spans VALUES (1)", "DELETE FROM t", "update t set a = 1", "CREATE TABLE t (a int)"] {
+            assert!(!QueryCache::is_cacheable(write), "{}", write);
+        }
+
+        assert!(cache.get(&sql).is_none());
+        cache.put(sql.clone(), vec![batch()], Some(vec!["p1".to_string()]), Instant::now());
+        assert_eq!(cache.get(&sql).map(|b| b.len()), Some(1));
+
+        // Writes to other projects keep the entry, a write to p1 drops it
+        cache.note_write("p2");
+        assert!(cache.get(&sql).is_some());
+        cache.note_write("p1");
+        assert!(cache.get(&sql).is_none());
+
+        // Entries with unknown projects are dropped by any write
+        cache.put("SELECT 1".to_string(), vec![batch()], None, Instant::now());
+        cache.note_write("p3");
+        assert!(cache.get("SELECT 1").is_none());
+
+        // The least recently used entry is evicted when full
+        cache.put("a".to_string(), vec![], None, Instant::now());
+        cache.put("b".to_string(), vec![], None, Instant::now());
+        assert!(cache.get("a").is_some());
+        cache.put("c".to_string(), vec![], None, Instant::now());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("a").is_some() && cache.get("c").is_some());
+
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.evictions), (2, 1));
+        assert_eq!((stats.hits, stats.misses), (5, 4));
+    }
+
+    #[test]
+    fn test_normalize_keeps_literals() {
+        let spaced = QueryCache::normalize("SELECT * FROM otel_logs_and_spans WHERE name = 'a  b'");
+        let single = QueryCache::normalize("SELECT *  FROM otel_logs_and_spans\n WHERE name = 'a b';");
+        assert_eq!(spaced, "SELECT * FROM otel_logs_and_spans WHERE name = 'a  b'");
+        assert_eq!(single, "SELECT * FROM otel_logs_and_spans WHERE name = 'a b'");
+        assert_ne!(spaced, single);
+
+        // Quoted identifiers and escaped quotes are kept too
+        assert_eq!(
+            QueryCache::normalize(r#"SELECT "a  b" FROM t WHERE s = 'it''s' ;"#),
+            r#"SELECT "a  b" FROM t WHERE s = 'it''s'"#
+        );
+    }
+
+    #[test]
+    fn test_query_cache_byte_cap() {
+        let size = batch().get_array_memory_size();
+        let cache = QueryCache::new(Duration::from_secs(60), 10, size * 2);
+        cache.put("a".to_string(), vec![batch()], None, Instant::now());
+        cache.put("b".to_string(), vec![batch()], None, Instant::now());
+        assert_eq!(cache.stats().bytes, size * 2);
+
+        // A third result evicts the least recently used one to stay under the cap
+        assert!(cache.get("a").is_some());
+        cache.put("c".to_string(), vec![batch()], None, Instant::now());
+        assert!(cache.get("b").is_none());
+        assert_eq!((cache.stats().entries, cache.stats().bytes, cache.stats().evictions), (2, size * 2, 1));
+
+        // A result larger than the whole cache isn't stored
+        cache.put("big".to_string(), vec![batch(), batch(), batch()], None, Instant::now());
+        assert!(cache.get("big").is_none());
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.too_large), (2, 1));
+    }
+
+    #[test]
+    fn test_cache_mode_from_cache_control() {
+        assert_eq!(CacheMode::from_cache_control(None), CacheMode::Use);
+        assert_eq!(CacheMode::from_cache_control(Some("max-age=60")), CacheMode::Use);
+        assert_eq!(CacheMode::from_cache_control(Some("No-Cache")), CacheMode::Refresh);
+        assert_eq!(CacheMode::from_cache_control(Some("no-cache, no-store")), CacheMode::Bypass);
+    }
+
+    #[test]
+    fn test_query_cache_ttl() {
+        let cache = QueryCache::new(Duration::ZERO, 10, 1 << 20);
+        cache.put("SELECT 1".to_string(), vec![batch()], Some(vec![]), Instant::now());
+        assert!(cache.get("SELECT 1").is_none());
+    }
+}
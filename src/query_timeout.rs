@@ -0,0 +1,467 @@
+// query_timeout.rs - Canceling queries that run past QUERY_TIMEOUT_SECS or the client's statement_timeout, or whose
+// PGWire client sends a CancelRequest
+use std::{
+    any::Any,
+    collections::HashMap,
+    env, fmt,
+    fmt::Debug,
+    future::Future,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicI32, Ordering},
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use datafusion::{
+    config::ConfigOptions,
+    error::{DataFusionError, Result as DFResult},
+    execution::{SendableRecordBatchStream, SessionStateBuilder, TaskContext, context::SessionContext},
+    physical_optimizer::PhysicalOptimizerRule,
+    physical_plan::{DisplayAs, DisplayFormatType, ExecutionPlan, PlanProperties, stream::RecordBatchStreamAdapter},
+};
+use futures::{Sink, SinkExt, StreamExt};
+use pgwire::{
+    api::{
+        ClientInfo,
+        query::SimpleQueryHandler,
+        results::{Response, Tag},
+    },
+    error::{ErrorInfo, PgWireError, PgWireResult},
+    messages::PgWireBackendMessage,
+};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::pg_compat::{TimeFusionOptions, insert_null_columns_notice};
+
+/// Longest a query may run (QUERY_TIMEOUT_SECS, unlimited when unset)
+pub fn query_timeout() -> Option<Duration> {
+    env::var("QUERY_TIMEOUT_SECS").ok().and_then(|v| v.parse().ok()).filter(|secs| *secs > 0).map(Duration::from_secs)
+}
+
+/// Timeout of a session's queries: its `statement_timeout`, capped by QUERY_TIMEOUT_SECS when both are set
+pub fn session_query_timeout(config: &ConfigOptions) -> Option<Duration> {
+    let statement_timeout = config
+        .extensions
+        .get::<TimeFusionOptions>()
+        .map(|options| options.statement_timeout_ms)
+        .filter(|ms| *ms > 0)
+        .map(Duration::from_millis);
+    match (statement_timeout, query_timeout()) {
+        (Some(client), Some(server)) => Some(client.min(server)),
+        (client, server) => client.or(server),
+    }
+}
+
+/// Wrap a plan in a `TimeoutExec` when the session has a query timeout or the query can be canceled with `cancel`
+pub fn with_query_timeout(config: &ConfigOptions, plan: Arc<dyn ExecutionPlan>, cancel: Option<CancellationToken>) -> Arc<dyn ExecutionPlan> {
+    let timeout = session_query_timeout(config);
+    if plan.as_any().is::<TimeoutExec>() || (timeout.is_none() && cancel.is_none()) {
+        return plan;
+    }
+    Arc::new(TimeoutExec::new(plan, timeout, cancel))
+}
+
+/// Run a query future, such as planning and collecting an HTTP query, failing it with "query canceled" once
+/// QUERY_TIMEOUT_SECS has passed. Dropping the future cancels the query.
+pub async fn run_with_query_timeout<T>(query: impl Future<Output = DFResult<T>>) -> DFResult<T> {
+    match query_timeout() {
+        Some(timeout) => tokio::time::timeout(timeout, query).await.unwrap_or_else(|_| Err(canceled(timeout))),
+        None => query.await,
+    }
+}
+
+fn canceled(timeout: Duration) -> DataFusionError {
+    DataFusionError::Execution(format!("query canceled: exceeded {:?}", timeout))
+}
+
+fn canceled_by_client() -> DataFusionError {
+    DataFusionError::Execution("canceling statement due to user request".to_string())
+}
+
+const QUERY_TIMEOUT_RULE: &str = "query_timeout";
+
+/// Physical optimizer rule, run last, that wraps the root of every plan in a `TimeoutExec`, so the timeout bounds
+/// sorts, joins and aggregations as well as the scans below them
+#[derive(Debug, Default)]
+pub struct QueryTimeoutRule {
+    /// Set on PGWire connections, whose client can cancel the queries it runs
+    canceler: Option<Arc<QueryCanceler>>,
+}
+
+impl PhysicalOptimizerRule for QueryTimeoutRule {
+    fn optimize(&self, plan: Arc<dyn ExecutionPlan>, config: &ConfigOptions) -> DFResult<Arc<dyn ExecutionPlan>> {
+        Ok(with_query_timeout(config, plan, self.canceler.as_ref().map(|canceler| canceler.token())))
+    }
+
+    fn name(&self) -> &str {
+        QUERY_TIMEOUT_RULE
+    }
+
+    fn schema_check(&self) -> bool {
+        true
+    }
+}
+
+/// Cancels the queries of one PGWire connection when its client sends a CancelRequest
+#[derive(Debug, Default)]
+pub struct QueryCanceler {
+    running: Mutex<CancellationToken>,
+}
+
+impl QueryCanceler {
+    /// Token watched by the queries planned from now on
+    pub fn token(&self) -> CancellationToken {
+        self.running.lock().unwrap().clone()
+    }
+
+    /// Cancel the queries planned so far. Later queries get a new token, so a cancel doesn't outlive them.
+    pub fn cancel(&self) {
+        std::mem::take(&mut *self.running.lock().unwrap()).cancel();
+    }
+
+    /// Make the plans of the connection's session context watch this canceler
+    pub fn attach_to(self: &Arc<Self>, ctx: &SessionContext) {
+        let state = ctx.state();
+        let rules = state
+            .physical_optimizers()
+            .iter()
+            .map(|rule| -> Arc<dyn PhysicalOptimizerRule + Send + Sync> {
+                if rule.name() == QUERY_TIMEOUT_RULE {
+                    Arc::new(QueryTimeoutRule {
+                        canceler: Some(Arc::clone(self)),
+                    })
+                } else {
+                    Arc::clone(rule)
+                }
+            })
+            .collect();
+        *ctx.state_ref().write() = SessionStateBuilder::new_from_existing(state).with_physical_optimizer_rules(rules).build();
+    }
+}
+
+/// Cancel keys of the open PGWire connections. A connection sends its key to the client in BackendKeyData at startup,
+/// and a CancelRequest carrying that key, which arrives on a new connection, cancels the connection's queries.
+#[derive(Debug, Default)]
+pub struct CancelKeys {
+    connections: Mutex<HashMap<(i32, i32), Arc<QueryCanceler>>>,
+    next_pid: AtomicI32,
+}
+
+impl CancelKeys {
+    /// A key for a new connection, valid until the returned `CancelKey` is dropped
+    pub fn register(self: &Arc<Self>) -> CancelKey {
+        let pid = self.next_pid.fetch_add(1, Ordering::Relaxed) + 1;
+        let secret = Uuid::new_v4().as_u128() as i32;
+        let canceler = Arc::new(QueryCanceler::default());
+        self.connections.lock().unwrap().insert((pid, secret), Arc::clone(&canceler));
+        CancelKey {
+            keys: Arc::clone(self),
+            pid,
+            secret,
+            canceler,
+        }
+    }
+
+    /// Cancel the queries of the connection with this key, false when no open connection has it
+    pub fn cancel(&self, pid: i32, secret: i32) -> bool {
+        let canceler = self.connections.lock().unwrap().get(&(pid, secret)).cloned();
+        canceler.map(|canceler| canceler.cancel()).is_some()
+    }
+}
+
+/// Process id and secret key of one PGWire connection
+pub struct CancelKey {
+    keys: Arc<CancelKeys>,
+    pub pid: i32,
+    pub secret: i32,
+    pub canceler: Arc<QueryCanceler>,
+}
+
+impl Drop for CancelKey {
+    fn drop(&mut self) {
+        self.keys.connections.lock().unwrap().remove(&(self.pid, self.secret));
+    }
+}
+
+/// Parse `SET [SESSION] statement_timeout { = | TO } <value>` and `RESET statement_timeout`, returning the timeout
+/// in milliseconds (0 for none or the default), or None for any other statement. Values are milliseconds unless they
+/// carry a PostgreSQL unit (`ms`, `s`, `min`, `h`, `d`).
+pub fn parse_statement_timeout(sql: &str) -> Option<DFResult<u64>> {
+    let sql = sql.trim().trim_end_matches(';').trim().to_lowercase();
+    if sql.split_whitespace().eq(["reset", "statement_timeout"]) {
+        return Some(Ok(0));
+    }
+    let rest = sql.strip_prefix("set ")?.trim_start();
+    let rest = rest.strip_prefix("session ").unwrap_or(rest).trim_start();
+    let rest = rest.strip_prefix("statement_timeout")?.trim_start();
+    let value = rest.strip_prefix('=').or_else(|| rest.strip_prefix("to "))?.trim().trim_matches(|c| c == '\'' || c == '"').trim();
+
+    let invalid = || DataFusionError::Plan(format!("invalid value for parameter \"statement_timeout\": \"{}\"", value));
+    if value == "default" {
+        return Some(Ok(0));
+    }
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let Ok(amount) = value[..split].parse::<u64>() else {
+        return Some(Err(invalid()));
+    };
+    let millis_per_unit = match value[split..].trim() {
+        "" | "ms" => 1,
+        "s" => 1_000,
+        "min" => 60_000,
+        "h" => 3_600_000,
+        "d" => 86_400_000,
+        _ => return Some(Err(invalid())),
+    };
+    Some(Ok(amount.saturating_mul(millis_per_unit)))
+}
+
+/// PGWire simple query handler that takes `SET statement_timeout` into the connection's session settings, since
+/// DataFusion only accepts namespaced settings, and passes every other query on. Over the extended protocol the same
+/// setting is changed with `SET timefusion.statement_timeout_ms = ...`. An INSERT that leaves columns NULL is preceded
+/// by a NoticeResponse naming them, unless `timefusion.notices` is off.
+pub struct StatementTimeoutHandler<H> {
+    inner: Arc<H>,
+    session: SessionContext,
+}
+
+impl<H> StatementTimeoutHandler<H> {
+    pub fn new(inner: Arc<H>, session: SessionContext) -> Self {
+        Self { inner, session }
+    }
+}
+
+#[async_trait]
+impl<H: SimpleQueryHandler> SimpleQueryHandler for StatementTimeoutHandler<H> {
+    async fn do_query<'a, C>(&self, client: &mut C, query: &'a str) -> PgWireResult<Vec<Response<'a>>>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let Some(timeout_ms) = parse_statement_timeout(query) else {
+            if let Some(notice) = insert_null_columns_notice(&self.session, query).await {
+                let info = ErrorInfo::new("NOTICE".to_string(), "00000".to_string(), notice);
+                client.send(PgWireBackendMessage::NoticeResponse(info.into())).await?;
+            }
+            return self.inner.do_query(client, query).await;
+        };
+        let set = timeout_ms.and_then(|ms| self.session.state_ref().write().config_mut().options_mut().set("timefusion.statement_timeout_ms", &ms.to_string()));
+        set.map_err(|e| PgWireError::ApiError(Box::new(e)))?;
+        Ok(vec![Response::Execution(Tag::new("SET"))])
+    }
+}
+
+/// Fails its input's streams with "query canceled" once `timeout` has passed since they started, or as soon as
+/// `cancel` is canceled. `QueryTimeoutRule` puts one at the root of every plan, whether the query comes over PGWire or
+/// HTTP, and dropping the root streams cancels the rest of the plan.
+#[derive(Debug)]
+pub struct TimeoutExec {
+    input: Arc<dyn ExecutionPlan>,
+    timeout: Option<Duration>,
+    cancel: Option<CancellationToken>,
+}
+
+impl TimeoutExec {
+    pub fn new(input: Arc<dyn ExecutionPlan>, timeout: Option<Duration>, cancel: Option<CancellationToken>) -> Self {
+        Self { input, timeout, cancel }
+    }
+}
+
+impl DisplayAs for TimeoutExec {
+    fn fmt_as(&self, _t: DisplayFormatType, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.timeout {
+            Some(timeout) => write!(f, "TimeoutExec: timeout={:?}", timeout),
+            None => write!(f, "TimeoutExec: timeout=none"),
+        }
+    }
+}
+
+impl ExecutionPlan for TimeoutExec {
+    fn name(&self) -> &str {
+        "TimeoutExec"
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn properties(&self) -> &PlanProperties {
+        self.input.properties()
+    }
+
+    fn children(&self) -> Vec<&Arc<dyn ExecutionPlan>> {
+        vec![&self.input]
+    }
+
+    fn with_new_children(self: Arc<Self>, mut children: Vec<Arc<dyn ExecutionPlan>>) -> DFResult<Arc<dyn ExecutionPlan>> {
+        if children.len() != 1 {
+            return Err(DataFusionError::Internal("TimeoutExec expects exactly one child".to_string()));
+        }
+        Ok(Arc::new(Self::new(children.remove(0), self.timeout, self.cancel.clone())))
+    }
+
+    fn execute(&self, partition: usize, context: Arc<TaskContext>) -> DFResult<SendableRecordBatchStream> {
+        Ok(until_canceled(self.input.execute(partition, context)?, self.timeout, self.cancel.clone()))
+    }
+}
+
+/// End `stream` with a "query canceled" error if it is still running `timeout` from now, or once `cancel` is canceled
+pub fn until_canceled(stream: SendableRecordBatchStream, timeout: Option<Duration>, cancel: Option<CancellationToken>) -> SendableRecordBatchStream {
+    let schema = stream.schema();
+    let deadline = timeout.map(|timeout| (tokio::time::Instant::now() + timeout, timeout));
+    let cancel = cancel.unwrap_or_default();
+    let limited = futures::stream::unfold(Some(stream), move |state| {
+        let cancel = cancel.clone();
+        async move {
+            let mut stream = state?;
+            let expired = async {
+                match deadline {
+                    Some((at, timeout)) => {
+                        tokio::time::sleep_until(at).await;
+                        timeout
+                    }
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                batch = stream.next() => batch.map(|batch| (batch, Some(stream))),
+                timeout = expired => Some((Err(canceled(timeout)), None)),
+                _ = cancel.cancelled() => Some((Err(canceled_by_client()), None)),
+            }
+        }
+    });
+    Box::pin(RecordBatchStreamAdapter::new(schema, limited))
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::{
+        array::Int32Array,
+        datatypes::{DataType, Field, Schema},
+        record_batch::RecordBatch,
+    };
+
+    use super::*;
+    use crate::pg_errors::{QUERY_CANCELED, sqlstate_for};
+
+    #[test]
+    fn test_parse_statement_timeout() {
+        let parsed = |sql: &str| parse_statement_timeout(sql).map(|r| r.ok());
+        assert_eq!(parsed("SET statement_timeout = 5000"), Some(Some(5000)));
+        assert_eq!(parsed("set statement_timeout to '5s';"), Some(Some(5000)));
+        assert_eq!(parsed("SET SESSION statement_timeout = '2min'"), Some(Some(120_000)));
+        assert_eq!(parsed("SET statement_timeout = 0"), Some(Some(0)));
+        assert_eq!(parsed("SET statement_timeout TO DEFAULT"), Some(Some(0)));
+        assert_eq!(parsed("RESET statement_timeout"), Some(Some(0)));
+        assert_eq!(parsed("SET statement_timeout = 'soon'"), Some(None));
+        assert_eq!(parsed("SET lock_timeout = 5000"), None);
+        assert_eq!(parsed("SELECT 1"), None);
+    }
+
+    #[tokio::test]
+    async fn test_statement_timeout_cancels_slow_scan() {
+        let ctx = SessionContext::new();
+        crate::pg_compat::register_session_options(&ctx);
+        assert_eq!(session_query_timeout(ctx.state().config_options()), query_timeout());
+
+        let timeout_ms = parse_statement_timeout("SET statement_timeout = '50ms'").unwrap().unwrap();
+        ctx.state_ref()
+            .write()
+            .config_mut()
+            .options_mut()
+            .set("timefusion.statement_timeout_ms", &timeout_ms.to_string())
+            .unwrap();
+        let timeout = session_query_timeout(ctx.state().config_options()).unwrap();
+        assert_eq!(timeout, Duration::from_millis(50));
+
+        // A scan that never finishes is canceled at the session's bound
+        let schema = Arc::new(Schema::new(vec![Field::new("v", DataType::Int32, false)]));
+        let stuck = futures::stream::pending::<DFResult<RecordBatch>>();
+        let started = std::time::Instant::now();
+        let mut stream = until_canceled(Box::pin(RecordBatchStreamAdapter::new(schema, stuck)), Some(timeout), None);
+        let err = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "Execution error: query canceled: exceeded 50ms");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_timeout_wraps_whole_plan() -> DFResult<()> {
+        use datafusion::datasource::MemTable;
+
+        let state = SessionStateBuilder::new()
+            .with_default_features()
+            .with_physical_optimizer_rule(Arc::new(QueryTimeoutRule::default()))
+            .build();
+        let ctx = SessionContext::new_with_state(state);
+        crate::pg_compat::register_session_options(&ctx);
+        let schema = Arc::new(Schema::new(vec![Field::new("v", DataType::Int32, false)]));
+        let batch = RecordBatch::try_new(schema.clone(), vec![Arc::new(Int32Array::from(vec![2, 1]))])?;
+        ctx.register_table("t", Arc::new(MemTable::try_new(schema, vec![vec![batch]])?))?;
+        ctx.state_ref().write().config_mut().options_mut().set("timefusion.statement_timeout_ms", "50")?;
+
+        // The sort runs below the timeout rather than after it
+        let plan = ctx.sql("SELECT v FROM t ORDER BY v").await?.create_physical_plan().await?;
+        assert_eq!(plan.name(), "TimeoutExec");
+        let plan = QueryTimeoutRule::default().optimize(plan, ctx.state().config_options())?;
+        assert!(!plan.children()[0].as_any().is::<TimeoutExec>(), "the rule doesn't wrap a plan twice");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_until_canceled() {
+        let schema = Arc::new(Schema::new(vec![Field::new("v", DataType::Int32, false)]));
+        let batch = RecordBatch::try_new(schema.clone(), vec![Arc::new(Int32Array::from(vec![1, 2]))]).unwrap();
+
+        // A batch ready in time is passed through, then a stuck scan is canceled
+        let stuck = futures::stream::iter(vec![Ok::<_, DataFusionError>(batch)]).chain(futures::stream::pending());
+        let mut stream = until_canceled(
+            Box::pin(RecordBatchStreamAdapter::new(schema.clone(), stuck)),
+            Some(Duration::from_millis(50)),
+            None,
+        );
+        assert_eq!(stream.next().await.unwrap().unwrap().num_rows(), 2);
+        let err = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "Execution error: query canceled: exceeded 50ms");
+        assert_eq!(sqlstate_for(&err), QUERY_CANCELED);
+        assert!(stream.next().await.is_none());
+
+        // Streams that finish in time are unaffected
+        let done = futures::stream::empty::<DFResult<RecordBatch>>();
+        let mut stream = until_canceled(
+            Box::pin(RecordBatchStreamAdapter::new(schema.clone(), done)),
+            Some(Duration::from_millis(50)),
+            None,
+        );
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_key_cancels_running_query() {
+        let keys = Arc::new(CancelKeys::default());
+        let key = keys.register();
+        let other = keys.register();
+        assert_ne!(key.pid, other.pid);
+
+        let schema = Arc::new(Schema::new(vec![Field::new("v", DataType::Int32, false)]));
+        let stuck = futures::stream::pending::<DFResult<RecordBatch>>();
+        let mut stream = until_canceled(Box::pin(RecordBatchStreamAdapter::new(schema, stuck)), None, Some(key.canceler.token()));
+        let canceled = tokio::spawn(async move { stream.next().await.unwrap().unwrap_err() });
+
+        // A wrong secret cancels nothing, the connection's key cancels its running query
+        assert!(!keys.cancel(key.pid, key.secret.wrapping_add(1)));
+        assert!(keys.cancel(key.pid, key.secret));
+        let err = tokio::time::timeout(Duration::from_secs(5), canceled).await.unwrap().unwrap();
+        assert_eq!(err.to_string(), "Execution error: canceling statement due to user request");
+        assert_eq!(sqlstate_for(&err), QUERY_CANCELED);
+
+        // Queries planned after the cancel aren't affected, and the key is gone once the connection closes
+        assert!(!key.canceler.token().is_cancelled());
+        assert!(!other.canceler.token().is_cancelled());
+        let (pid, secret) = (key.pid, key.secret);
+        drop(key);
+        assert!(!keys.cancel(pid, secret));
+    }
+}
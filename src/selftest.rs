@@ -0,0 +1,67 @@
+// selftest.rs - One-shot startup check of the storage configuration and credentials (SELFTEST=1 or --selftest)
+use std::env;
+
+use anyhow::{Result, anyhow};
+use chrono::{Duration, Utc};
+use tracing::{error, info};
+
+use crate::{database::Database, persistent_queue::OtelLogsAndSpans};
+
+/// Whether to run the self-test instead of serving: SELFTEST=1/true or a `--selftest` argument
+pub fn enabled() -> bool {
+    matches!(env::var("SELFTEST").as_deref(), Ok("1") | Ok("true")) || env::args().any(|arg| arg == "--selftest")
+}
+
+/// Register a scratch project next to the default table, write a record, query it back and delete it.
+/// The scratch project's table is removed afterwards whether or not the steps succeeded.
+pub async fn run(db: &Database) -> Result<()> {
+    let project_id = format!("selftest_{}", uuid::Uuid::new_v4().simple());
+    let bucket = env::var("AWS_S3_BUCKET").map_err(|_| anyhow!("AWS_S3_BUCKET environment variable not set"))?;
+    let endpoint = env::var("AWS_S3_ENDPOINT").unwrap_or_else(|_| "https://s3.amazonaws.com".to_string());
+    let prefix = env::var("TIMEFUSION_TABLE_PREFIX").unwrap_or_else(|_| "timefusion".to_string());
+    let conn_str = format!("s3://{}/{}/_selftest/{}/?endpoint={}", bucket, prefix, project_id, endpoint);
+
+    step("register scratch project", db.register(&project_id, &conn_str, None, None, None, false).await)?;
+    let result = exercise(db, &project_id).await;
+    let cleanup = step("clean up scratch project", db.purge_project(&project_id).await.map(|_| ()));
+    result.and(cleanup)
+}
+
+async fn exercise(db: &Database, project_id: &str) -> Result<()> {
+    let timestamp = Utc::now();
+    let record = OtelLogsAndSpans {
+        project_id: project_id.to_string(),
+        timestamp,
+        observed_timestamp: Some(timestamp),
+        id: format!("{}-span", project_id),
+        name: Some("timefusion selftest".to_string()),
+        ..Default::default()
+    };
+    step("write record", db.write_many(std::slice::from_ref(&record)).await)?;
+
+    let ctx = db.create_session_context();
+    db.setup_session_context(&ctx)?;
+    let sql = format!(
+        "SELECT id FROM otel_logs_and_spans WHERE project_id = '{}' AND id = '{}'",
+        project_id, record.id
+    );
+    let queried = async {
+        let rows: usize = ctx.sql(&sql).await?.collect().await?.iter().map(|b| b.num_rows()).sum();
+        if rows == 1 { Ok(()) } else { Err(anyhow!("Expected the written record back, got {} rows", rows)) }
+    };
+    step("query record", queried.await)?;
+
+    let deleted = db.delete_range(project_id, timestamp - Duration::seconds(1), timestamp + Duration::seconds(1)).await;
+    step(
+        "delete record",
+        deleted.and_then(|rows| if rows == 1 { Ok(()) } else { Err(anyhow!("Expected to delete 1 record, deleted {}", rows)) }),
+    )
+}
+
+fn step<T>(name: &str, result: Result<T>) -> Result<T> {
+    match &result {
+        Ok(_) => info!("Self-test step '{}': ok", name),
+        Err(e) => error!("Self-test step '{}' failed: {:?}", name, e),
+    }
+    result
+}
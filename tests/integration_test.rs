@@ -9,7 +9,12 @@ mod integration {
     use std::sync::{Arc, Mutex};
     use std::time::{Duration, Instant};
     use timefusion::database::Database;
-    use tokio::{sync::Notify, time::sleep};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        sync::Notify,
+        time::sleep,
+    };
     use tokio_postgres::{Client, NoTls};
     use tokio_util::sync::CancellationToken;
     use uuid::Uuid;
@@ -43,6 +48,55 @@ mod integration {
         Ok((client, handle))
     }
 
+    /// Frontend speaking the simple query protocol, which COPY needs and tokio-postgres doesn't use for it
+    struct SimpleQueryConnection {
+        stream: TcpStream,
+    }
+
+    impl SimpleQueryConnection {
+        async fn connect(port: u16) -> Result<Self> {
+            let mut startup = 196608u32.to_be_bytes().to_vec();
+            for (key, value) in [("user", "postgres"), ("database", "postgres")] {
+                startup.extend_from_slice(format!("{key}\0{value}\0").as_bytes());
+            }
+            startup.push(0);
+            let mut stream = TcpStream::connect(("localhost", port)).await?;
+            stream.write_all(&((startup.len() + 4) as u32).to_be_bytes()).await?;
+            stream.write_all(&startup).await?;
+            let mut conn = Self { stream };
+            conn.read_until(b'Z').await?;
+            Ok(conn)
+        }
+
+        async fn send(&mut self, tag: u8, body: &[u8]) -> Result<()> {
+            self.stream.write_all(&[tag]).await?;
+            self.stream.write_all(&((body.len() + 4) as u32).to_be_bytes()).await?;
+            self.stream.write_all(body).await?;
+            Ok(())
+        }
+
+        async fn query(&mut self, sql: &str) -> Result<()> {
+            self.send(b'Q', format!("{sql}\0").as_bytes()).await
+        }
+
+        /// Messages up to and including the first one tagged `last`, failing on an ErrorResponse
+        async fn read_until(&mut self, last: u8) -> Result<Vec<(u8, Vec<u8>)>> {
+            let mut messages = Vec::new();
+            loop {
+                let tag = self.stream.read_u8().await?;
+                let mut body = vec![0; self.stream.read_u32().await? as usize - 4];
+                self.stream.read_exact(&mut body).await?;
+                if tag == b'E' {
+                    anyhow::bail!("Error response: {}", String::from_utf8_lossy(&body));
+                }
+                messages.push((tag, body));
+                if tag == last {
+                    return Ok(messages);
+                }
+            }
+        }
+    }
+
     async fn start_test_server() -> Result<(Arc<Notify>, String, u16)> {
         let test_id = Uuid::new_v4().to_string();
         let _ = env_logger::builder().is_test(true).try_init();
@@ -86,6 +140,48 @@ mod integration {
         Ok((shutdown_signal, test_id, port))
     }
 
+    /// Run the server binary, HTTP API included, with the batch queue flushing every 100ms. The process is killed
+    /// when the returned handle is dropped.
+    async fn start_http_server() -> Result<(tokio::process::Child, String, u16, u16)> {
+        let test_id = Uuid::new_v4().to_string();
+        dotenv().ok();
+        let mut rng = rand::thread_rng();
+        let pg_port = 5433 + (rng.gen_range(1..100) as u16);
+        let http_port = 8080 + (rng.gen_range(1..100) as u16);
+
+        let server = tokio::process::Command::new(env!("CARGO_BIN_EXE_timefusion"))
+            .env("PORT", http_port.to_string())
+            .env("PGWIRE_PORT", pg_port.to_string())
+            .env("TIMEFUSION_TABLE_PREFIX", format!("test-{}", test_id))
+            .env("ENABLE_BATCH_QUEUE", "true")
+            .env("BATCH_INTERVAL_MS", "100")
+            .kill_on_drop(true)
+            .spawn()?;
+        let _ = connect_with_retry(pg_port, Duration::from_secs(30)).await?;
+        Ok((server, test_id, pg_port, http_port))
+    }
+
+    /// POST an OTLP protobuf body to `path`, returning the response status line
+    async fn post_protobuf(port: u16, path: &str, project_id: &str, body: &[u8]) -> Result<String> {
+        let start = Instant::now();
+        let mut stream = loop {
+            match TcpStream::connect(("localhost", port)).await {
+                Ok(stream) => break stream,
+                Err(_) if start.elapsed() < Duration::from_secs(10) => sleep(Duration::from_millis(100)).await,
+                Err(e) => return Err(e.into()),
+            }
+        };
+        let head = format!(
+            "POST {path} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/x-protobuf\r\nX-Project-Id: {project_id}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(body).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok(String::from_utf8_lossy(&response).lines().next().unwrap_or_default().to_string())
+    }
+
     #[tokio::test]
     #[serial]
     async fn test_postgres_integration() -> Result<()> {
@@ -390,4 +486,270 @@ mod integration {
 
         Ok(())
     }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_stalled_handshake_is_terminated() -> Result<()> {
+        unsafe {
+            std::env::set_var("PGWIRE_HANDSHAKE_TIMEOUT_SECS", "1");
+        }
+        let (shutdown_signal, _test_id, port) = start_test_server().await?;
+        let shutdown_guard = scopeguard::guard((), |_| shutdown_signal.notify_one());
+
+        // Open a raw connection and never send the startup packet
+        let mut stream = TcpStream::connect(("localhost", port)).await?;
+        let mut buf = [0u8; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+            .await
+            .map_err(|_| anyhow::anyhow!("Server did not terminate the stalled handshake"))?;
+
+        // Either a clean close or a reset means the server dropped the connection
+        assert!(matches!(read, Ok(0) | Err(_)), "Stalled handshake should be terminated, got {:?}", read);
+
+        unsafe {
+            std::env::remove_var("PGWIRE_HANDSHAKE_TIMEOUT_SECS");
+        }
+        std::mem::drop(shutdown_guard);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_undefined_table_returns_sqlstate() -> Result<()> {
+        let (shutdown_signal, _test_id, port) = start_test_server().await?;
+        let shutdown_guard = scopeguard::guard((), |_| shutdown_signal.notify_one());
+
+        let (client, _) = connect_with_retry(port, Duration::from_secs(3))
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to connect to PostgreSQL: {}", e))?;
+
+        let err = client.query("SELECT * FROM no_such_table", &[]).await.expect_err("Query against a missing table should fail");
+        assert_eq!(
+            err.code(),
+            Some(&tokio_postgres::error::SqlState::UNDEFINED_TABLE),
+            "Unexpected error: {:?}",
+            err
+        );
+
+        std::mem::drop(shutdown_guard);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_password_authentication() -> Result<()> {
+        // connect_with_retry logs in as postgres/postgres
+        unsafe {
+            std::env::set_var("PGWIRE_PASSWORD", "postgres");
+        }
+        let server = start_test_server().await;
+        unsafe {
+            std::env::remove_var("PGWIRE_PASSWORD");
+        }
+        let (shutdown_signal, _test_id, port) = server?;
+        let shutdown_guard = scopeguard::guard((), |_| shutdown_signal.notify_one());
+
+        let wrong_password = format!("host=localhost port={port} user=postgres password=wrong");
+        assert!(
+            tokio_postgres::connect(&wrong_password, NoTls).await.is_err(),
+            "Wrong password should be rejected"
+        );
+        let no_password = format!("host=localhost port={port} user=postgres");
+        assert!(
+            tokio_postgres::connect(&no_password, NoTls).await.is_err(),
+            "Missing password should be rejected"
+        );
+
+        let (client, _) = connect_with_retry(port, Duration::from_secs(3))
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to connect with the configured password: {}", e))?;
+        let row = client.query_one("SELECT 1", &[]).await?;
+        assert_eq!(row.get::<_, i64>(0), 1);
+
+        std::mem::drop(shutdown_guard);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_describe_projected_statement() -> Result<()> {
+        let (shutdown_signal, _test_id, port) = start_test_server().await?;
+        let shutdown_guard = scopeguard::guard((), |_| shutdown_signal.notify_one());
+
+        let (client, _) = connect_with_retry(port, Duration::from_secs(3))
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to connect to PostgreSQL: {}", e))?;
+
+        // Describe must list exactly the projected columns, in query order rather than table order
+        let stmt = client.prepare("SELECT timestamp, id, name FROM otel_logs_and_spans WHERE project_id = $1").await?;
+        let described: Vec<(&str, &tokio_postgres::types::Type)> = stmt.columns().iter().map(|c| (c.name(), c.type_())).collect();
+        assert_eq!(
+            described,
+            vec![
+                ("timestamp", &tokio_postgres::types::Type::TIMESTAMP),
+                ("id", &tokio_postgres::types::Type::VARCHAR),
+                ("name", &tokio_postgres::types::Type::VARCHAR),
+            ]
+        );
+
+        std::mem::drop(shutdown_guard);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_cancel_request_stops_running_query() -> Result<()> {
+        let (shutdown_signal, _test_id, port) = start_test_server().await?;
+        let shutdown_guard = scopeguard::guard((), |_| shutdown_signal.notify_one());
+
+        let (client, _) = connect_with_retry(port, Duration::from_secs(3))
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to connect to PostgreSQL: {}", e))?;
+        let cancel_token = client.cancel_token();
+
+        // Counting this many rows takes far longer than the test waits
+        let query = tokio::spawn(async move { client.query("SELECT count(*) FROM generate_series(1, 1000000000000)", &[]).await });
+        sleep(Duration::from_millis(500)).await;
+        cancel_token.cancel_query(NoTls).await?;
+
+        let result = tokio::time::timeout(Duration::from_secs(10), query)
+            .await
+            .map_err(|_| anyhow::anyhow!("Query kept running after its CancelRequest"))??;
+        let err = result.expect_err("Canceled query should fail");
+        assert_eq!(
+            err.code(),
+            Some(&tokio_postgres::error::SqlState::QUERY_CANCELED),
+            "Unexpected error: {:?}",
+            err
+        );
+
+        std::mem::drop(shutdown_guard);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_copy_from_stdin_and_to_stdout() -> Result<()> {
+        let (shutdown_signal, _test_id, port) = start_test_server().await?;
+        let shutdown_guard = scopeguard::guard((), |_| shutdown_signal.notify_one());
+        let mut conn = SimpleQueryConnection::connect(port).await?;
+
+        conn.query("COPY otel_logs_and_spans (project_id, timestamp, id, name) FROM STDIN (FORMAT csv)").await?;
+        assert_eq!(conn.read_until(b'G').await?.len(), 1, "Expected a CopyInResponse");
+        conn.send(b'd', b"copy_project,2024-01-01T00:00:00Z,copy_1,first\ncopy_project,2024-01-01T00:00:01Z,").await?;
+        conn.send(b'd', b"copy_2,\"second, quoted\"\n").await?;
+        conn.send(b'c', &[]).await?;
+        let done = conn.read_until(b'Z').await?;
+        assert!(done.contains(&(b'C', b"COPY 2\0".to_vec())), "Unexpected messages: {:?}", done);
+
+        conn.query("COPY (SELECT id, name FROM otel_logs_and_spans WHERE project_id = 'copy_project' ORDER BY id) TO STDOUT (FORMAT csv, HEADER)")
+            .await?;
+        let messages = conn.read_until(b'Z').await?;
+        assert_eq!(messages[0].0, b'H', "Expected a CopyOutResponse");
+        let data: Vec<u8> = messages.iter().filter(|(tag, _)| *tag == b'd').flat_map(|(_, body)| body.clone()).collect();
+        assert_eq!(String::from_utf8(data)?, "id,name\ncopy_1,first\ncopy_2,\"second, quoted\"\n");
+
+        // Unsupported formats are refused before any data is sent
+        conn.query("COPY otel_logs_and_spans FROM STDIN (FORMAT binary)").await?;
+        assert!(conn.read_until(b'Z').await.is_err());
+
+        std::mem::drop(shutdown_guard);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_insert_leaving_columns_null_sends_notice() -> Result<()> {
+        let (shutdown_signal, _test_id, port) = start_test_server().await?;
+        let shutdown_guard = scopeguard::guard((), |_| shutdown_signal.notify_one());
+        let mut conn = SimpleQueryConnection::connect(port).await?;
+        let insert = |id: &str| {
+            format!(
+                "INSERT INTO otel_logs_and_spans (project_id, date, timestamp, id, name, hashes) \
+                 VALUES ('notice_project', '2024-01-01', '2024-01-01T00:00:00', '{id}', 'checkout', ARRAY[])"
+            )
+        };
+        let notices = |messages: &[(u8, Vec<u8>)]| -> Vec<String> {
+            messages.iter().filter(|(tag, _)| *tag == b'N').map(|(_, body)| String::from_utf8_lossy(body).to_string()).collect()
+        };
+
+        conn.query(&insert("notice_1")).await?;
+        let messages = conn.read_until(b'Z').await?;
+        let sent = notices(&messages);
+        assert_eq!(sent.len(), 1, "Expected one notice: {:?}", messages);
+        assert!(sent[0].contains("columns not in the INSERT column list are NULL: "), "{}", sent[0]);
+        assert!(sent[0].contains("status_code"), "{}", sent[0]);
+
+        // Noisy clients can turn them off for their connection
+        conn.query("SET timefusion.notices = false").await?;
+        conn.read_until(b'Z').await?;
+        conn.query(&insert("notice_2")).await?;
+        assert!(notices(&conn.read_until(b'Z').await?).is_empty());
+
+        std::mem::drop(shutdown_guard);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_otlp_traces_are_queued_flushed_and_queryable() -> Result<()> {
+        use opentelemetry_proto::tonic::{
+            collector::trace::v1::ExportTraceServiceRequest,
+            trace::v1::{ResourceSpans, ScopeSpans, Span},
+        };
+        use prost::Message;
+
+        let (_server, test_id, pg_port, http_port) = start_http_server().await?;
+        let project_id = format!("otlp_{}", &test_id[..8]);
+        let request = ExportTraceServiceRequest {
+            resource_spans: vec![ResourceSpans {
+                scope_spans: vec![ScopeSpans {
+                    spans: vec![
+                        Span {
+                            trace_id: vec![0xab; 16],
+                            span_id: vec![0x01; 8],
+                            name: "GET /users".to_string(),
+                            start_time_unix_nano: 1_704_067_200_000_000_000,
+                            end_time_unix_nano: 1_704_067_200_002_500_000,
+                            ..Default::default()
+                        },
+                        Span {
+                            trace_id: vec![0xab; 16],
+                            span_id: vec![0x02; 8],
+                            name: "SELECT users".to_string(),
+                            start_time_unix_nano: 1_704_067_200_001_000_000,
+                            end_time_unix_nano: 1_704_067_200_002_000_000,
+                            ..Default::default()
+                        },
+                    ],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        };
+        let status = post_protobuf(http_port, "/v1/traces", &project_id, &request.encode_to_vec()).await?;
+        assert!(status.starts_with("HTTP/1.1 200"), "Unexpected response: {}", status);
+
+        // The spans sit in the batch queue until its next flush writes them to Delta
+        let (client, _conn) = connect_with_retry(pg_port, Duration::from_secs(5)).await?;
+        let sql = format!("SELECT name FROM otel_logs_and_spans WHERE project_id = '{}' ORDER BY name", project_id);
+        let start = Instant::now();
+        let names = loop {
+            let rows = client.query(sql.as_str(), &[]).await?;
+            if rows.len() == 2 || start.elapsed() > Duration::from_secs(15) {
+                break rows.iter().map(|row| row.get::<_, String>(0)).collect::<Vec<_>>();
+            }
+            sleep(Duration::from_millis(200)).await;
+        };
+        assert_eq!(names, ["GET /users", "SELECT users"]);
+
+        Ok(())
+    }
 }